@@ -1,60 +1,74 @@
-use std::io::SeekFrom;
+use std::{io::SeekFrom, path::{Path, PathBuf}};
 
 use chrono::{DateTime, Utc};
-use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt}};
-use trip_tracker_lib::track_point::{TrackPoint, ENCODED_LENGTH};
+use tokio::{fs::{File, OpenOptions}, io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt}};
+use trip_tracker_lib::{track_point::{QuantizationProfile, TrackPoint}, track_session::SessionStatistics, tsf::{encode_fixed_record, RecordLayout, TsfDecoder, TsfError, TsfHeader, MAX_FIXED_RECORD_LENGTH}};
 
 use crate::DataManagerError;
 
 pub struct Buffer {
-    pub start_time: DateTime<Utc>,
+    pub header: TsfHeader,
     pub track_points: Vec<TrackPoint>,
-    pub file: File,
+    /// Kept up to date as points are added, so polling clients don't make us go through every point
+    pub statistics: SessionStatistics,
+    /// Bytes of a partially written track point at the end of the file, left when the server stopped while writing.
+    /// Points can't be appended until they are dropped with `drop_partial_record`
+    pub partial_record_bytes: usize,
+    pub path: PathBuf,
+    /// Opened for appending when the buffer is first written to, so loading and checking a buffer only reads it
+    file: Option<File>,
 }
 
 impl Buffer {
-    pub async fn load(mut file: File) -> Result<Self, DataManagerError> {
-        let file_size = file.metadata().await.map_err(|_| DataManagerError::BufferManager("Failed to get metadata for buffer file".to_string()))?.len();
+    /// Reads the buffer file without changing it.
+    pub async fn load(path: &Path) -> Result<Self, DataManagerError> {
+        let mut file = File::open(path).await.map_err(DataManagerError::io(format!("Failed to open buffer file {:?}", path)))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).await.map_err(DataManagerError::io("Failed to read buffer file"))?;
 
-        if file_size < 8 {
-            return Err(DataManagerError::BufferManager("Buffer file is too small".to_string()));
-        }
+        let mut decoder = TsfDecoder::new();
+        let track_points = decoder.feed(&bytes).collect::<Result<Vec<_>, _>>()
+            .map_err(|e| DataManagerError::Corrupt(format!("Buffer file doesn't decode: {e}")))?;
 
-        // Start time is the first 8 bytes of the file
-        let start_time =  {
-            let mut buffer = [0; 8];
-            file.seek(SeekFrom::Start(0)).await.map_err(|_| DataManagerError::BufferManager("Failed to seek to track point in buffer file".to_string()))?;
-            file.read_exact(&mut buffer).await.map_err(|_| DataManagerError::BufferManager("Failed to read start time from buffer file".to_string()))?;
-            let timestamp = i64::from_be_bytes(buffer);
-            DateTime::<Utc>::from_timestamp(timestamp, 0).ok_or(DataManagerError::BufferManager(format!("Failed to seek to track point in buffer file: {timestamp} {:?}", &buffer)))?
+        let mut partial_record_bytes = 0;
+        let header = match decoder.finish() {
+            Ok(header) => header,
+            Err(TsfError::TruncatedRecord) => {
+                partial_record_bytes = decoder.pending_length();
+                *decoder.header().unwrap()
+            },
+            Err(e) => return Err(DataManagerError::Corrupt(format!("Buffer file header doesn't parse: {e}"))),
         };
 
-        let mut track_points = Vec::new();
-        let mut buffer = [0; ENCODED_LENGTH];
-        for i in (8..file_size as usize).step_by(ENCODED_LENGTH) {
-            file.seek(SeekFrom::Start(i as u64)).await.map_err(|_| DataManagerError::BufferManager("Failed to seek to track point in buffer file".to_string()))?;
-            file.read_exact(&mut buffer).await.map_err(|_| DataManagerError::BufferManager("Failed to read track point from buffer file".to_string()))?;
-            let tp = TrackPoint::from_bytes(&buffer, start_time);
-            track_points.push(tp);
+        // Buffers are appended to one record at a time, so only fixed size layouts are used
+        if header.layout.fixed_record_length().is_none() {
+            return Err(DataManagerError::Corrupt("Buffer file does not have a fixed record layout".to_string()));
         }
 
         Ok(Self {
-            start_time,
+            header,
+            statistics: SessionStatistics::from_points(&track_points),
             track_points,
-            file,
+            partial_record_bytes,
+            path: path.to_path_buf(),
+            file: None,
         })
     }
 
-    pub async fn new(mut file: File, start_time: DateTime<Utc>) -> Result<Self, DataManagerError> {
-        // Write start time to file
-        let buffer = &start_time.timestamp().to_be_bytes();
-        file.write_all(buffer).await.map_err(|_| DataManagerError::BufferManager("Failed to write start time to buffer file".to_string()))?;
-        file.flush().await.map_err(|_| DataManagerError::BufferManager("Failed to flush buffer file".to_string()))?;
+    pub async fn new(path: &Path, start_time: DateTime<Utc>) -> Result<Self, DataManagerError> {
+        let mut file = open_for_append(path, true).await?;
+        // Write header to file. Extended records, so the point quality is kept until the session is stored
+        let header = TsfHeader::with_layout(start_time, RecordLayout::Extended);
+        file.write_all(&header.to_bytes()).await.map_err(DataManagerError::io("Failed to write header to buffer file"))?;
+        file.flush().await.map_err(DataManagerError::io("Failed to flush buffer file"))?;
 
         Ok(Self {
-            start_time,
+            header,
             track_points: Vec::new(),
-            file,
+            statistics: SessionStatistics::default(),
+            partial_record_bytes: 0,
+            path: path.to_path_buf(),
+            file: Some(file),
         })
     }
 
@@ -63,26 +77,171 @@ impl Buffer {
     }
 
     pub fn get_track_points_since(&self, timestamp: DateTime<Utc>) -> Vec<TrackPoint> {
-        self.track_points.iter().cloned().skip_while(|p| p.timestamp <= timestamp).collect()
+        self.track_points.iter().filter(|p| p.timestamp > timestamp).cloned().collect()
     }
 
     pub fn close(self) -> Vec<TrackPoint> {
         self.track_points
     }
 
+    /// Cuts the partially written track point off the end of the file, so new points are appended after the last complete one.
+    pub async fn drop_partial_record(&mut self) -> Result<(), DataManagerError> {
+        if self.partial_record_bytes == 0 {
+            return Ok(());
+        }
+
+        let partial_record_bytes = self.partial_record_bytes as u64;
+        let file = self.writable_file().await?;
+        let length = file.seek(SeekFrom::End(0)).await.map_err(DataManagerError::io("Failed to seek to end of buffer file"))?;
+        file.set_len(length - partial_record_bytes).await.map_err(DataManagerError::io("Failed to truncate buffer file"))?;
+        tracing::warn!("Dropped {} bytes of a partially written track point from buffer file {:?}", self.partial_record_bytes, self.path);
+        self.partial_record_bytes = 0;
+        Ok(())
+    }
+
+    /// Writes the points to the file before adding them, so a failed write leaves the buffer as it was.
     pub async fn add_points(&mut self, new_points: &[TrackPoint]) -> Result<(), DataManagerError> {
-        self.track_points.extend_from_slice(new_points);
+        if self.partial_record_bytes > 0 {
+            return Err(DataManagerError::Corrupt(format!("Buffer file {:?} ends in a partially written track point", self.path)));
+        }
+
+        // Points that the current profile would clamp, e.g. when taking off, switch the whole file to a better profile
+        if !new_points.iter().all(|tp| self.header.profile.fits(tp, self.header.start_time)) {
+            let all_points = [self.track_points.as_slice(), new_points].concat();
+            let profile = QuantizationProfile::select(&all_points, self.header.start_time);
+            if profile != self.header.profile {
+                let header = TsfHeader { profile, ..self.header };
+                self.rewrite_file(&header, &all_points).await?;
+                self.header = header;
+                self.track_points = all_points;
+                self.statistics.extend(new_points);
+                return Ok(());
+            }
+        }
+
         self.append_to_file(new_points).await?;
+        self.track_points.extend_from_slice(new_points);
+        self.statistics.extend(new_points);
+        Ok(())
+    }
+
+    /// Writes the whole buffer to a temporary file, which replaces the buffer file once it is on disk.
+    /// The buffer file is never left half written, even if the server stops while rewriting it.
+    async fn rewrite_file(&mut self, header: &TsfHeader, track_points: &[TrackPoint]) -> Result<(), DataManagerError> {
+        let mut bytes = header.to_bytes().to_vec();
+        bytes.extend(encode_records(header, track_points)?);
+
+        let file_name = self.path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let temp_path = self.path.with_file_name(format!(".{}.tmp", file_name));
+        let mut temp_file = File::create(&temp_path).await.map_err(DataManagerError::io(format!("Failed to create {:?}", temp_path)))?;
+        temp_file.write_all(&bytes).await.map_err(DataManagerError::io(format!("Failed to write {:?}", temp_path)))?;
+        temp_file.sync_all().await.map_err(DataManagerError::io(format!("Failed to sync {:?}", temp_path)))?;
+        drop(temp_file);
+
+        tokio::fs::rename(&temp_path, &self.path).await.map_err(DataManagerError::io(format!("Failed to replace buffer file {:?}", self.path)))?;
+        // The handle is of the replaced file, so the new one is opened when it is next written to
+        self.file = None;
         Ok(())
     }
 
-    async fn append_to_file(&mut self, track_point: &[TrackPoint]) -> Result<(), DataManagerError> {
-        self.file.seek(SeekFrom::End(0)).await.map_err(|_| DataManagerError::BufferManager("Failed to seek to start of buffer file".to_string()))?;
-        for tp in track_point {
-            let bytes = tp.to_bytes(self.start_time);
-            self.file.write_all(&bytes).await.map_err(|_| DataManagerError::BufferManager("Failed to write track point to buffer file".to_string()))?;
+    /// Appends the points in a single write. If it fails, the file is cut back to where it ended.
+    async fn append_to_file(&mut self, track_points: &[TrackPoint]) -> Result<(), DataManagerError> {
+        let bytes = encode_records(&self.header, track_points)?;
+        let file = self.writable_file().await?;
+        let length = file.seek(SeekFrom::End(0)).await.map_err(DataManagerError::io("Failed to seek to end of buffer file"))?;
+
+        let written = match file.write_all(&bytes).await {
+            Ok(()) => file.flush().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            let _ = file.set_len(length).await;
+            return Err(DataManagerError::Io { context: "Failed to write track points to buffer file".to_string(), source: e });
         }
-        self.file.flush().await.map_err(|_| DataManagerError::BufferManager("Failed to flush buffer file".to_string()))?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// The file, which is opened for appending the first time it is written to.
+    async fn writable_file(&mut self) -> Result<&mut File, DataManagerError> {
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => open_for_append(&self.path, false).await?,
+        };
+        Ok(self.file.insert(file))
+    }
+}
+
+fn encode_records(header: &TsfHeader, track_points: &[TrackPoint]) -> Result<Vec<u8>, DataManagerError> {
+    let mut bytes = Vec::with_capacity(track_points.len() * MAX_FIXED_RECORD_LENGTH);
+    let mut record = [0; MAX_FIXED_RECORD_LENGTH];
+    for tp in track_points {
+        let len = encode_fixed_record(header, tp, &mut record).map_err(|e| DataManagerError::InvalidInput(format!("Track point doesn't fit the buffer file: {e}")))?;
+        bytes.extend_from_slice(&record[..len]);
+    }
+    Ok(bytes)
+}
+
+async fn open_for_append(path: &Path, create: bool) -> Result<File, DataManagerError> {
+    OpenOptions::new()
+        .write(true)
+        .append(true)
+        .create(create)
+        .open(path).await
+        .map_err(DataManagerError::io(format!("Failed to open buffer file {:?}", path)))
+}
+
+#[tokio::test]
+async fn rewrite_on_profile_switch() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("1_Flight");
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+    let mut buffer = Buffer::new(&path, start).await.unwrap();
+    let ground = TrackPoint::new(start + chrono::TimeDelta::seconds(1), 40.18, 44.51, 1000., 80., true);
+    buffer.add_points(&[ground]).await.unwrap();
+    let profile = buffer.header.profile;
+
+    // Cruising altitude doesn't fit the ground profile, so the whole file is rewritten
+    let airborne = TrackPoint::new(start + chrono::TimeDelta::seconds(2), 40.5, 44.0, 11000., 850., true);
+    buffer.add_points(&[airborne]).await.unwrap();
+    assert_ne!(buffer.header.profile, profile);
+    assert_eq!(buffer.track_points.len(), 2);
+
+    let landed = TrackPoint::new(start + chrono::TimeDelta::seconds(3), 41.0, 43.5, 500., 0., true);
+    buffer.add_points(&[landed]).await.unwrap();
+    drop(buffer);
+
+    let loaded = Buffer::load(&path).await.unwrap();
+    assert_eq!(loaded.header.profile, QuantizationProfile::select(&loaded.track_points, start));
+    assert_eq!(loaded.track_points.len(), 3);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn partial_record_kept_until_dropped() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("1_Day 1");
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let point = |seconds| TrackPoint::new(start + chrono::TimeDelta::seconds(seconds), 40.18, 44.51, 1000., 80., true);
+
+    let mut buffer = Buffer::new(&path, start).await.unwrap();
+    buffer.add_points(&[point(1), point(2)]).await.unwrap();
+    drop(buffer);
+    std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(&[0, 0]).unwrap();
+    let length = std::fs::metadata(&path).unwrap().len();
+
+    // Loading only reports the partial record, and doesn't open the file for writing
+    let mut buffer = Buffer::load(&path).await.unwrap();
+    assert_eq!(buffer.partial_record_bytes, 2);
+    assert!(buffer.file.is_none());
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), length);
+    assert!(buffer.add_points(&[point(3)]).await.is_err());
+
+    buffer.drop_partial_record().await.unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), length - 2);
+    buffer.add_points(&[point(3)]).await.unwrap();
+    drop(buffer);
+    assert_eq!(Buffer::load(&path).await.unwrap().track_points.len(), 3);
+}
@@ -1,122 +1,203 @@
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::Arc};
 
 use chrono::{DateTime, Utc};
-use tokio::{fs::OpenOptions, sync::Mutex};
-use trip_tracker_lib::{track_point::TrackPoint, track_session::TrackSession};
+use tokio::sync::Mutex;
+use trip_tracker_lib::{track_point::TrackPoint, track_session::{SessionStatistics, TrackSession}};
 
-use crate::{DataManagerError, BUFFER_FILE_DIR};
+use crate::{DataManagerError, Entity};
 
 use super::buffer::Buffer;
 
+/// Directory in the buffer file directory that buffer files are moved to when they can't be used
+pub const QUARANTINE_DIR: &str = "quarantine";
+
 #[derive(Clone)]
 pub struct BufferManager {
     buffer_map: Arc<Mutex<HashMap<i64, Buffer>>>,
+    buffer_file_dir: PathBuf,
+    /// Files in the buffer file directory that couldn't be loaded, and are left where they are until quarantined
+    unreadable_files: Arc<Mutex<Vec<PathBuf>>>,
 }
 
 impl BufferManager {
-    pub async fn start() -> Result<Self, DataManagerError> {
+    /// Opens the buffer files in the directory, which is created if it doesn't exist.
+    /// Files are only read. Those that can't be loaded are skipped, and listed by `unreadable_files`.
+    pub async fn start(buffer_file_dir: &Path) -> Result<Self, DataManagerError> {
         // Open all buffer files
-        let root: PathBuf = project_root::get_project_root().unwrap();
-        let buffer_file_dir = root.join(BUFFER_FILE_DIR);
+        let buffer_file_dir = buffer_file_dir.to_path_buf();
 
         // Create dir if it doesn't exist
         if !buffer_file_dir.exists() {
             tokio::fs::create_dir_all(&buffer_file_dir).await
-                .map_err(|_| DataManagerError::BufferManager(format!("Failed to create buffer file directory: {:?}", buffer_file_dir)))?;
+                .map_err(DataManagerError::io(format!("Failed to create buffer file directory {:?}", buffer_file_dir)))?;
         }
 
         let mut buffer_map = HashMap::new();
-        for entry in buffer_file_dir.read_dir().map_err(|_| DataManagerError::BufferManager(format!("Failed to read buffer files from {:?}", buffer_file_dir)))? {
+        let mut unreadable_files = Vec::new();
+        for entry in buffer_file_dir.read_dir().map_err(DataManagerError::io(format!("Failed to read buffer files from {:?}", buffer_file_dir)))? {
             let path = entry.map(|entry| entry.path())
-                .map_err(|_| DataManagerError::BufferManager(format!("Failed to read buffer files from {:?}", buffer_file_dir)))?;
-
-            let Some(session_id) = path.file_stem()
-                .and_then(|stem| stem.to_str())
-                .and_then(|stem| stem.split("_").next())
-                .and_then(|prefix| prefix.parse::<i64>().ok()) else {
-                return Err(DataManagerError::BufferManager(format!("Data file had illegal path: {:?}", path)));
-            };
-
-            let file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .append(true)
-                .open(&path).await
-                .map_err(|_| DataManagerError::BufferManager(format!("Failed to open buffer file: {:?}", path)))?;
-
-            buffer_map.insert(session_id, Buffer::load(file).await?);
+                .map_err(DataManagerError::io(format!("Failed to read buffer files from {:?}", buffer_file_dir)))?;
+            if !path.is_file() {
+                continue;
+            }
+
+            match Self::load(&path, &buffer_map).await {
+                Ok((session_id, buffer)) => {
+                    if buffer.partial_record_bytes > 0 {
+                        tracing::warn!("Buffer file {:?} ends in {} bytes of a partially written track point", path, buffer.partial_record_bytes);
+                    }
+                    buffer_map.insert(session_id, buffer);
+                },
+                Err(e) => {
+                    tracing::warn!("Skipping buffer file {:?}: {}", path, e);
+                    unreadable_files.push(path);
+                },
+            }
         }
 
         Ok(BufferManager {
-            buffer_map: Arc::new(Mutex::new(buffer_map))
+            buffer_map: Arc::new(Mutex::new(buffer_map)),
+            buffer_file_dir,
+            unreadable_files: Arc::new(Mutex::new(unreadable_files)),
         })
     }
 
+    async fn load(path: &Path, buffer_map: &HashMap<i64, Buffer>) -> Result<(i64, Buffer), DataManagerError> {
+        let session_id = session_id_of(path)
+            .ok_or_else(|| DataManagerError::Corrupt("Buffer file has no session id in its name".to_string()))?;
+        if buffer_map.contains_key(&session_id) {
+            return Err(DataManagerError::Conflict(format!("Session {} already has a buffer file", session_id)));
+        }
+
+        Ok((session_id, Buffer::load(path).await?))
+    }
+
+    /// The sessions with an open buffer, which are the active sessions.
+    pub async fn session_ids(&self) -> Vec<i64> {
+        self.buffer_map.lock().await.keys().copied().collect()
+    }
+
     pub async fn start_session(&self, session: &TrackSession) -> Result<(), DataManagerError> {
         let mut buffer_map = self.buffer_map.lock().await;
 
         if session.session_id == -1 {
-            return Err(DataManagerError::BufferManager("Session ID must be set".to_string()));
+            return Err(DataManagerError::InvalidInput("Session ID must be set".to_string()));
         }
 
-        let root: PathBuf = project_root::get_project_root().unwrap();
-        let buffer_file_dir = root.join(BUFFER_FILE_DIR);
-
-        let buffer_file_name = buffer_file_dir.join(format!("{}_{}", session.session_id, session.title));
-
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(&buffer_file_name).await
-            .map_err(|_| DataManagerError::BufferManager(format!("Failed to open buffer file: {:?}", buffer_file_name)))?;
+        let buffer_file_name = self.buffer_file_dir.join(format!("{}_{}", session.session_id, session.title));
 
-        buffer_map.insert(session.session_id, Buffer::new(file, session.start_time).await?);
+        buffer_map.insert(session.session_id, Buffer::new(&buffer_file_name, session.start_time).await?);
 
         Ok(())
     }
 
     pub async fn append_track_points(&self, session_id: i64, track_points: &[TrackPoint]) -> Result<(), DataManagerError> {
         let mut buffer_map = self.buffer_map.lock().await;
-        let buffer = buffer_map.get_mut(&session_id).ok_or(DataManagerError::BufferManager(format!("No buffer file for session {}", session_id)))?;
+        let buffer = buffer_map.get_mut(&session_id).ok_or(DataManagerError::NotFound(Entity::Buffer, session_id))?;
         buffer.add_points(track_points).await?;
         Ok(())
     }
 
     pub async fn close_session(&self, session_id: i64) -> Result<Vec<TrackPoint>, DataManagerError> {
         let mut buffer_map = self.buffer_map.lock().await;
-        let buffer = buffer_map.remove(&session_id).ok_or(DataManagerError::BufferManager(format!("No buffer file for session {}", session_id)))?;
+        let buffer = buffer_map.remove(&session_id).ok_or(DataManagerError::NotFound(Entity::Buffer, session_id))?;
         let track_points = buffer.close();
 
-        // delete file
-        let root: PathBuf = project_root::get_project_root().unwrap();
-        let buffer_file_dir = root.join(BUFFER_FILE_DIR);
-        
-        // Find file that starts with session_id
-        let buffer_file_name = buffer_file_dir.read_dir().map_err(|_| DataManagerError::BufferManager(format!("Failed to read buffer files from {:?}", buffer_file_dir)))?
-            .filter_map(|entry| entry.map(|entry| entry.path()).ok())
-            .find(|path| path.file_stem()
-                             .map(|stem| stem.to_str().unwrap().starts_with(format!("{}_", session_id).as_str()))
-                             .unwrap_or(false))
-            .ok_or(DataManagerError::BufferManager(format!("No buffer file for session {}", session_id)))?;
-
-        tokio::fs::remove_file(&buffer_file_name).await.map_err(|_| DataManagerError::BufferManager(format!("Failed to remove buffer file: {:?}", buffer_file_name)))?;
+        let buffer_file_name = self.buffer_file_path(session_id)?;
+        tokio::fs::remove_file(&buffer_file_name).await.map_err(DataManagerError::io(format!("Failed to remove buffer file {:?}", buffer_file_name)))?;
 
         Ok(track_points)
     }
 
     pub async fn read_all_track_points(&self, session_id: i64) -> Result<Vec<TrackPoint>, DataManagerError> {
         let mut buffer_map = self.buffer_map.lock().await;
-        let buffer = buffer_map.get_mut(&session_id).ok_or(DataManagerError::BufferManager(format!("No buffer file for session {}", session_id)))?;
+        let buffer = buffer_map.get_mut(&session_id).ok_or(DataManagerError::NotFound(Entity::Buffer, session_id))?;
         let track_points = buffer.get_all_track_points().to_vec();
         Ok(track_points)
     }
 
+    pub async fn track_point_count(&self, session_id: i64) -> Result<usize, DataManagerError> {
+        let buffer_map = self.buffer_map.lock().await;
+        let buffer = buffer_map.get(&session_id).ok_or(DataManagerError::NotFound(Entity::Buffer, session_id))?;
+        Ok(buffer.get_all_track_points().len())
+    }
+
+    pub async fn statistics(&self, session_id: i64) -> Result<SessionStatistics, DataManagerError> {
+        let buffer_map = self.buffer_map.lock().await;
+        let buffer = buffer_map.get(&session_id).ok_or(DataManagerError::NotFound(Entity::Buffer, session_id))?;
+        Ok(buffer.statistics.clone())
+    }
+
     pub async fn read_track_points_since(&self, session_id: i64, timestamp: DateTime<Utc>) -> Result<Vec<TrackPoint>, DataManagerError> {
+        let buffer_map = self.buffer_map.lock().await;
+        let buffer = buffer_map.get(&session_id).ok_or(DataManagerError::NotFound(Entity::Buffer, session_id))?;
+        Ok(buffer.get_track_points_since(timestamp))
+    }
+
+    /// Files that were skipped when the buffers were loaded, because their name has no session id or they don't decode.
+    pub async fn unreadable_files(&self) -> Vec<PathBuf> {
+        self.unreadable_files.lock().await.clone()
+    }
+
+    /// Sessions whose buffer file ends in a partially written track point, and how many bytes of it there are.
+    pub async fn partial_records(&self) -> Vec<(i64, usize)> {
+        let buffer_map = self.buffer_map.lock().await;
+        buffer_map.iter()
+            .filter(|(_, buffer)| buffer.partial_record_bytes > 0)
+            .map(|(session_id, buffer)| (*session_id, buffer.partial_record_bytes))
+            .collect()
+    }
+
+    /// Cuts the partially written track point off the end of the session's buffer file.
+    pub async fn drop_partial_record(&self, session_id: i64) -> Result<(), DataManagerError> {
         let mut buffer_map = self.buffer_map.lock().await;
-        let buffer = buffer_map.get_mut(&session_id).ok_or(DataManagerError::BufferManager(format!("No buffer file for session {}", session_id)))?;
-        let track_points = buffer.get_track_points_since(timestamp).to_vec();
-        Ok(track_points)
+        let buffer = buffer_map.get_mut(&session_id).ok_or(DataManagerError::NotFound(Entity::Buffer, session_id))?;
+        buffer.drop_partial_record().await
+    }
+
+    /// Moves a file that couldn't be loaded to the quarantine directory, where it is kept for recovering by hand.
+    pub async fn quarantine_file(&self, path: &Path) -> Result<PathBuf, DataManagerError> {
+        let mut unreadable_files = self.unreadable_files.lock().await;
+        let quarantined = self.move_to_quarantine(path).await?;
+        unreadable_files.retain(|file| file != path);
+        Ok(quarantined)
+    }
+
+    /// Closes the buffer of the session and moves its file to the quarantine directory, without removing it.
+    pub async fn quarantine_session(&self, session_id: i64) -> Result<PathBuf, DataManagerError> {
+        let mut buffer_map = self.buffer_map.lock().await;
+        let path = self.buffer_file_path(session_id)?;
+        let quarantined = self.move_to_quarantine(&path).await?;
+        buffer_map.remove(&session_id);
+        Ok(quarantined)
     }
-}
\ No newline at end of file
+
+    async fn move_to_quarantine(&self, path: &Path) -> Result<PathBuf, DataManagerError> {
+        let quarantine_dir = self.buffer_file_dir.join(QUARANTINE_DIR);
+        tokio::fs::create_dir_all(&quarantine_dir).await
+            .map_err(DataManagerError::io(format!("Failed to create quarantine directory {:?}", quarantine_dir)))?;
+
+        let file_name = path.file_name().ok_or_else(|| DataManagerError::InvalidInput(format!("{:?} isn't a file", path)))?;
+        let quarantined = quarantine_dir.join(file_name);
+        tokio::fs::rename(path, &quarantined).await
+            .map_err(DataManagerError::io(format!("Failed to move {:?} to {:?}", path, quarantined)))?;
+        Ok(quarantined)
+    }
+
+    /// The buffer file of the session, which is the file whose name starts with its id.
+    fn buffer_file_path(&self, session_id: i64) -> Result<PathBuf, DataManagerError> {
+        let buffer_file_dir = &self.buffer_file_dir;
+        buffer_file_dir.read_dir().map_err(DataManagerError::io(format!("Failed to read buffer files from {:?}", buffer_file_dir)))?
+            .filter_map(|entry| entry.map(|entry| entry.path()).ok())
+            .find(|path| path.is_file() && session_id_of(path) == Some(session_id))
+            .ok_or(DataManagerError::NotFound(Entity::Buffer, session_id))
+    }
+}
+
+/// Buffer files are named after the id and the title of their session, like `12_Day 1`.
+fn session_id_of(path: &Path) -> Option<i64> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.split("_").next())
+        .and_then(|prefix| prefix.parse::<i64>().ok())
+}
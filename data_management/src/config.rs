@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+
+use crate::{BUFFER_FILE_DIR, COUNTRY_FILE, DATABASE_PATH, DATA_DIR, TIMEZONE_FILE};
+
+/// Where the database is kept.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DatabaseLocation {
+    File(PathBuf),
+    /// Lost when the data manager is dropped, for tests
+    InMemory,
+}
+
+/// Where the data manager keeps and finds its data.
+/// The default is the `data` directory of the project, which is where the server and the CLI keep it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataManagerConfig {
+    /// Imported and exported GPX, TSF and GeoJSON files are in directories in here
+    pub data_dir: PathBuf,
+    pub database: DatabaseLocation,
+    /// Buffer files of the active sessions
+    pub buffer_dir: PathBuf,
+    /// Country borders, as GeoJSON
+    pub country_file: PathBuf,
+    /// Time zones, as GeoJSON
+    pub timezone_file: PathBuf,
+}
+
+impl Default for DataManagerConfig {
+    fn default() -> Self {
+        let root: PathBuf = project_root::get_project_root().unwrap();
+        Self {
+            data_dir: root.join(DATA_DIR),
+            database: DatabaseLocation::File(root.join(DATABASE_PATH)),
+            buffer_dir: root.join(BUFFER_FILE_DIR),
+            country_file: root.join(COUNTRY_FILE),
+            timezone_file: root.join(TIMEZONE_FILE),
+        }
+    }
+}
+
+impl DataManagerConfig {
+    /// Keeps everything in the directory, laid out like the project's `data` directory.
+    pub fn in_dir(data_dir: impl Into<PathBuf>) -> Self {
+        let data_dir = data_dir.into();
+        Self {
+            database: DatabaseLocation::File(data_dir.join("database.db")),
+            buffer_dir: data_dir.join("buffer_files"),
+            country_file: data_dir.join("countries.geojson"),
+            timezone_file: data_dir.join("time_zones.geojson"),
+            data_dir,
+        }
+    }
+
+    pub fn gpx_dir(&self) -> PathBuf {
+        self.data_dir.join("gpx")
+    }
+
+    pub fn tsf_dir(&self) -> PathBuf {
+        self.data_dir.join("tsf")
+    }
+
+    pub fn geojson_dir(&self) -> PathBuf {
+        self.data_dir.join("geojson")
+    }
+}
@@ -1,38 +1,80 @@
-use std::{net::IpAddr, path::PathBuf};
+use std::net::IpAddr;
 
-use chrono::{DateTime, Utc};
-use trip_tracker_lib::{track_point::TrackPoint, track_session::{SessionUpdate, TrackSession}, traffic::Visit, trip::Trip};
+use chrono::{DateTime, TimeDelta, Utc};
+use trip_tracker_lib::{filter::{FilterConfig, FilterReport}, local_time::UtcOffset, simplify, smooth::KalmanSmoother, stops::{Stop, StopDetector}, telemetry::{Telemetry, TelemetryRecord}, track_point::TrackPoint, track_session::{SessionUpdate, TrackSession}, traffic::Visit, trip::{Trip, TripStatus}};
 
-use crate::{buffer::buffer_manager::BufferManager, database::db::TripDatabase, geonames::CountryLookup, DataManagerError, DATA_DIR};
+use crate::{buffer::buffer_manager::BufferManager, database::db::TripDatabase, geonames::{CountryLookup, TimezoneLookup}, split::SplitConfig, DataManagerConfig, DataManagerError};
+
+/// Zoom level new points of live sessions are simplified for
+const UPDATE_ZOOM: u8 = 14;
+/// How far before the new points of live sessions smoothing starts
+const SMOOTHING_LEAD_IN_SECS: i64 = 60;
 
 pub struct DataManager {
+    pub(crate) config: DataManagerConfig,
     pub(crate) database: TripDatabase,
     pub(crate) buffer_manager: BufferManager,
     country_lookup: CountryLookup,
+    timezone_lookup: TimezoneLookup,
+    /// Smooths the points of sessions as they are read, when set
+    smoother: Option<KalmanSmoother>,
+    /// Splits sessions at their gaps as they end, when set
+    auto_split: Option<SplitConfig>,
 }
 
 /// The public interface for all trip tracker data management.
 impl DataManager {
-    pub async fn start() -> Result<Self, DataManagerError> {
+    /// Opens the data, and repairs the buffers and sessions a crash left inconsistent. For the server, which owns the buffers.
+    pub async fn start(config: DataManagerConfig) -> Result<Self, DataManagerError> {
+        let data_manager = Self::open(config).await?;
+
+        let report = data_manager.reconcile(true).await?;
+        for inconsistency in &report.inconsistencies {
+            tracing::warn!("Repaired: {}", inconsistency);
+        }
+        tracing::info!("Reconciled buffers of {} active sessions, repaired {} inconsistencies",
+            data_manager.buffer_manager.session_ids().await.len(), report.inconsistencies.len());
+
+        Ok(data_manager)
+    }
+
+    /// Opens the data without repairing anything, for tools that run alongside the server.
+    /// Buffer files are only read, so a partially written point the server is appending is left alone.
+    pub async fn open(config: DataManagerConfig) -> Result<Self, DataManagerError> {
         // Create data dir if it doesn't exist
-        let root: PathBuf = project_root::get_project_root().unwrap();
-        let data_dir = root.join(DATA_DIR);
+        let data_dir = &config.data_dir;
         if !data_dir.exists() {
-            std::fs::create_dir_all(&data_dir)
-                .map_err(|_| DataManagerError::Database(format!("Failed to create data directory: {:?}", data_dir)))?;
+            std::fs::create_dir_all(data_dir)
+                .map_err(DataManagerError::io(format!("Failed to create data directory {:?}", data_dir)))?;
         }
 
-        let buffer_manager = BufferManager::start().await?;
-        let database = TripDatabase::connect().await?;
-        let country_lookup = CountryLookup::new();
+        let buffer_manager = BufferManager::start(&config.buffer_dir).await?;
+        let database = TripDatabase::connect(&config.database).await?;
+        let country_lookup = CountryLookup::open(&config.country_file);
+        let timezone_lookup = TimezoneLookup::open(&config.timezone_file);
 
         Ok(DataManager {
+            config,
             database,
             buffer_manager,
             country_lookup,
+            timezone_lookup,
+            smoother: None,
+            auto_split: None,
         })
     }
 
+    /// Sets the smoother applied to the points of sessions as they are read, and to the points their stops are detected from.
+    /// Stored points are never changed. Sessions are read as they are stored unless set.
+    pub fn set_smoother(&mut self, smoother: Option<KalmanSmoother>) {
+        self.smoother = smoother;
+    }
+
+    /// Sets how sessions are split by `end_session`, or `None` to keep them whole.
+    pub fn set_auto_split(&mut self, config: Option<SplitConfig>) {
+        self.auto_split = config;
+    }
+
     pub async fn register_new_trip(&self, title: String, description: String, start_time: DateTime<Utc>) -> Result<Trip, DataManagerError> {
         //let mut api_token = rand::
         let mut api_token = [0u8; 32];
@@ -41,10 +83,23 @@ impl DataManager {
     }
 
     pub async fn register_new_session(&self, trip_id: i64, title: String, description: String) -> Result<TrackSession, DataManagerError> {
+        let trip = self.database.get_trip(trip_id).await?;
+        if !trip.status.accepts_sessions() {
+            return Err(DataManagerError::Conflict(format!("Trip {} is {} and doesn't accept new sessions", trip_id, trip.status)));
+        }
         self.database.insert_track_session(trip_id, title, description, chrono::Utc::now(), false).await
     }
 
+    /// Starts a live session, which puts a planned trip live. Finished and archived trips don't accept live sessions.
     pub async fn register_new_live_session(&self, trip_id: i64, title: String, description: String) -> Result<TrackSession, DataManagerError> {
+        let trip = self.database.get_trip(trip_id).await?;
+        if !trip.status.accepts_live_sessions() {
+            return Err(DataManagerError::Conflict(format!("Trip {} is {} and doesn't accept live sessions", trip_id, trip.status)));
+        }
+        if trip.status == TripStatus::Planned {
+            self.database.set_trip_status(trip_id, TripStatus::Live).await?;
+        }
+
         let session = self.database.insert_track_session(trip_id, title, description, chrono::Utc::now(), true).await?;
         self.buffer_manager.start_session(&session).await?;
         Ok(session)
@@ -54,67 +109,149 @@ impl DataManager {
         self.database.get_trips().await
     }
 
+    /// The trips listed to visitors, which are the public trips that aren't archived.
+    pub async fn get_listed_trips(&self) -> Result<Vec<Trip>, DataManagerError> {
+        Ok(self.get_trips().await?.into_iter().filter(|trip| trip.is_listed()).collect())
+    }
+
+    /// The trip of the session.
+    pub async fn get_session_trip(&self, session_id: i64) -> Result<Trip, DataManagerError> {
+        let session = self.database.get_session_info(session_id).await?;
+        self.database.get_trip(session.trip_id).await
+    }
+
     pub async fn get_trip(&self, trip_id: i64) -> Result<Trip, DataManagerError> {
         self.database.get_trip(trip_id).await
     }
 
     pub async fn get_trip_sessions(&self, trip_id: i64) -> Result<Vec<TrackSession>, DataManagerError> {
-        let mut sessions = self.database.get_trip_sessions(trip_id).await.unwrap();
+        let mut sessions = self.database.get_trip_sessions(trip_id).await?;
 
-        for session in sessions.iter_mut().filter(|session| session.active) {
-            let buffered_points = self.buffer_manager.read_all_track_points(session.session_id).await?;
-            session.track_points = buffered_points;
+        for session in sessions.iter_mut() {
+            if session.active {
+                session.track_points = self.buffer_manager.read_all_track_points(session.session_id).await?;
+                session.statistics = self.buffer_manager.statistics(session.session_id).await?;
+            }
+            session.utc_offset = self.local_offset(&session.track_points);
         }
 
         Ok(sessions)
     }
 
     pub async fn get_session(&self, session_id: i64) -> Result<TrackSession, DataManagerError> {
+        let session = self.read_session(session_id).await?;
+        Ok(self.smoothed(session))
+    }
+
+    /// The session as stored, without smoothing.
+    async fn read_session(&self, session_id: i64) -> Result<TrackSession, DataManagerError> {
         let mut session = self.database.get_session(session_id).await?;
         if session.active {
             // read buffer
-            let buffered_points = self.buffer_manager.read_all_track_points(session_id).await?;
-            session.track_points = buffered_points;
-            Ok(session)
-        } else {
-            // read from database
-            Ok(session)
+            session.track_points = self.buffer_manager.read_all_track_points(session_id).await?;
+            session.statistics = self.buffer_manager.statistics(session_id).await?;
         }
+        session.utc_offset = self.local_offset(&session.track_points);
+        Ok(session)
+    }
+
+    /// Local offset at the first of the points.
+    fn local_offset(&self, track_points: &[TrackPoint]) -> Option<UtcOffset> {
+        track_points.first().map(|point| self.timezone_lookup.get_offset(point.latitude, point.longitude))
+    }
+
+    /// Sets the offset the trip's times are shown in, or `None` to show them in local time.
+    pub async fn set_trip_utc_offset(&self, trip_id: i64, utc_offset: Option<UtcOffset>) -> Result<(), DataManagerError> {
+        self.database.set_trip_utc_offset(trip_id, utc_offset).await
     }
 
+    /// The points added to the session after `timestamp`, for clients polling a live session.
+    /// The new points are smoothed together with the points just before them, and the statistics are of the points as they are stored, like those of `get_session`.
     pub async fn get_session_update(&self, session_id: i64, timestamp: DateTime<Utc>) -> Result<SessionUpdate, DataManagerError> {
-        let session = self.get_session(session_id).await?;
+        let session = self.database.get_session_info(session_id).await?;
 
-        let mut misssing_points;
-        if session.active {
-            // read buffer
-            misssing_points = self.buffer_manager.read_track_points_since(session_id, timestamp).await?;
+        // The client has the points before `timestamp` already. Smoothing starts a little before them, so the new points join them without a jump
+        let since = match self.smoother {
+            Some(_) => timestamp - TimeDelta::seconds(SMOOTHING_LEAD_IN_SECS),
+            None => timestamp,
+        };
+        let (mut missing_points, statistics) = if session.active {
+            (self.buffer_manager.read_track_points_since(session_id, since).await?, self.buffer_manager.statistics(session_id).await?)
         } else {
-            // read from database
-            misssing_points = self.database.get_session(session_id).await?.track_points.iter().cloned().skip_while(|p| p.timestamp <= timestamp).collect();
+            // Clients poll once more after the session ends, and stop
+            (self.database.get_track_points_since(session_id, since).await?, session.statistics)
+        };
+
+        if let Some(smoother) = &self.smoother {
+            missing_points = smoother.smooth(&missing_points);
+            missing_points.retain(|p| p.timestamp > timestamp);
         }
 
-        misssing_points = misssing_points.into_iter().step_by(6).collect();
+        // Simplified on their own, as the client has drawn the points before them already
+        if let Some(first_point) = missing_points.first() {
+            let tolerance = simplify::tolerance_for_zoom(UPDATE_ZOOM, first_point.latitude);
+            missing_points = simplify::douglas_peucker(&missing_points, tolerance).into_iter().map(|i| missing_points[i].clone()).collect();
+        }
 
         Ok(SessionUpdate {
             session_id,
             title: session.title,
             description: session.description,
-            new_track_points: misssing_points,
+            new_track_points: missing_points,
             still_active: session.active,
+            statistics,
         })
     }
 
     pub async fn end_session(&self, session_id: i64) -> Result<(), DataManagerError> {
+        if !self.database.get_session_info(session_id).await?.active {
+            return Err(DataManagerError::Conflict(format!("Session {} isn't active", session_id)));
+        }
+
+        // The buffer is removed once its points are stored, so a crash before then leaves it to be reconciled
+        let points = self.buffer_manager.read_all_track_points(session_id).await?;
+        let stored = points.len();
+        self.database.end_session(session_id, points).await?;
         let points = self.buffer_manager.close_session(session_id).await?;
-        self.database.set_session_track_points(session_id, points).await?;
-        self.database.set_session_active(session_id, false).await?;
-        
+        // Points appended while the session was ending are only in the buffer
+        if points.len() > stored {
+            self.database.append_track_points(session_id, &points[stored..]).await?;
+        }
+
+        if let Some(config) = &self.auto_split
+            && !self.split_session(session_id, config).await?.is_empty() {
+            return Ok(());
+        }
+        self.detect_stops(session_id).await?;
+
         Ok(())
     }
 
-    pub async fn append_gps_points(&self, session_id: i64, points: &[TrackPoint]) -> Result<(), DataManagerError> {
+    /// Splits an ended session at its gaps into new sessions, and hides the original session.
+    /// Returns the new sessions, which is none when there was nothing to split.
+    pub async fn split_session(&self, session_id: i64, config: &SplitConfig) -> Result<Vec<TrackSession>, DataManagerError> {
         let session = self.database.get_session(session_id).await?;
+        if session.active {
+            return Err(DataManagerError::Conflict(format!("Session {} must be ended before it is split", session_id)));
+        }
+
+        let parts = config.split(&session.track_points);
+        if parts.len() < 2 {
+            return Ok(Vec::new());
+        }
+
+        let parts = parts.iter().map(|part| part.to_vec()).collect::<Vec<_>>();
+        let sessions = self.database.insert_split_sessions(&session, &parts).await?;
+        // Stops can be detected again, so they are not part of the split
+        for new_session in &sessions {
+            self.detect_stops(new_session.session_id).await?;
+        }
+
+        Ok(sessions)
+    }
+
+    pub async fn append_gps_points(&self, session_id: i64, points: &[TrackPoint]) -> Result<(), DataManagerError> {
+        let session = self.database.get_session_info(session_id).await?;
         let trip = self.database.get_trip(session.trip_id).await?;
         let mut countries = trip.country_list.clone();
         let mut prev_country = None;
@@ -142,6 +279,21 @@ impl DataManager {
         }
     }
 
+    /// The session without its track points, which aren't read.
+    pub async fn get_session_info(&self, session_id: i64) -> Result<TrackSession, DataManagerError> {
+        self.database.get_session_info(session_id).await
+    }
+
+    /// The number of points stored for the session, which a tracker resumes uploading from.
+    pub async fn get_session_point_count(&self, session_id: i64) -> Result<usize, DataManagerError> {
+        let session = self.database.get_session_info(session_id).await?;
+        if session.active {
+            self.buffer_manager.track_point_count(session_id).await
+        } else {
+            self.database.track_point_count(session_id).await
+        }
+    }
+
     pub async fn get_nonhidden_trip_session_ids(&self, trip_id: i64) -> Result<Vec<i64>, DataManagerError> {
         self.database.get_nonhidden_trip_session_ids(trip_id).await
     }
@@ -153,17 +305,127 @@ impl DataManager {
         };
         self.database.insert_visit(visit).await
     }
+
+    pub async fn record_telemetry(&self, session_id: i64, telemetry: &Telemetry<'_>) -> Result<(), DataManagerError> {
+        self.database.insert_telemetry(TelemetryRecord::new(session_id, chrono::Utc::now(), telemetry)).await
+    }
+
+    /// The latest `limit` telemetry reports of the trip, oldest first.
+    pub async fn get_trip_telemetry(&self, trip_id: i64, limit: u32) -> Result<Vec<TelemetryRecord>, DataManagerError> {
+        self.database.get_trip_telemetry(trip_id, limit).await
+    }
+
+    /// The filter configured for the trip, or the default filter.
+    pub async fn get_filter_config(&self, trip_id: i64) -> Result<FilterConfig, DataManagerError> {
+        Ok(self.database.get_filter_config(trip_id).await?.unwrap_or_default())
+    }
+
+    pub async fn set_filter_config(&self, trip_id: i64, config: &FilterConfig) -> Result<(), DataManagerError> {
+        self.database.set_filter_config(trip_id, config).await
+    }
+
+    pub async fn clear_filter_config(&self, trip_id: i64) -> Result<(), DataManagerError> {
+        self.database.clear_filter_config(trip_id).await
+    }
+
+    /// The session with the points its trip's filter drops removed, and the report of what was dropped.
+    /// Points are filtered before they are smoothed, so outliers don't pull the smoothed track.
+    pub async fn get_filtered_session(&self, session_id: i64) -> Result<(TrackSession, FilterReport), DataManagerError> {
+        let mut session = self.read_session(session_id).await?;
+        let config = self.get_filter_config(session.trip_id).await?;
+        let (track_points, report) = config.apply(std::mem::take(&mut session.track_points));
+        session.track_points = track_points;
+        Ok((self.smoothed(session), report))
+    }
+
+    /// Detects the stops of the filtered and smoothed session, and stores them in place of its previous stops.
+    pub async fn detect_stops(&self, session_id: i64) -> Result<Vec<Stop>, DataManagerError> {
+        let (session, _) = self.get_filtered_session(session_id).await?;
+        let stops = StopDetector::default().detect(&session.track_points);
+        self.database.set_session_stops(session_id, &stops).await?;
+        Ok(stops)
+    }
+
+    /// Detects the stops of the trip's visible ended sessions again. Returns how many sessions were detected.
+    pub async fn detect_trip_stops(&self, trip_id: i64) -> Result<usize, DataManagerError> {
+        let mut detected = 0;
+        for session_id in self.database.get_nonhidden_trip_session_ids(trip_id).await? {
+            if !self.database.get_session_info(session_id).await?.active {
+                self.detect_stops(session_id).await?;
+                detected += 1;
+            }
+        }
+        Ok(detected)
+    }
+
+    /// Stops of the trip's visible sessions, in order of arrival. Stops of active sessions are detected as they are read.
+    pub async fn get_trip_stops(&self, trip_id: i64) -> Result<Vec<Stop>, DataManagerError> {
+        let mut stops = self.database.get_trip_stops(trip_id).await?;
+
+        for session_id in self.buffer_manager.session_ids().await {
+            let session = self.database.get_session_info(session_id).await?;
+            if session.trip_id != trip_id || session.hidden {
+                continue;
+            }
+            let (session, _) = self.get_filtered_session(session_id).await?;
+            stops.extend(StopDetector::default().detect(&session.track_points));
+        }
+
+        for stop in &mut stops {
+            stop.utc_offset = Some(self.timezone_lookup.get_offset(stop.latitude, stop.longitude));
+        }
+        stops.sort_by_key(|stop| stop.arrival);
+        Ok(stops)
+    }
+
+    /// The session with its points smoothed for display. Its statistics stay those of the stored points.
+    fn smoothed(&self, mut session: TrackSession) -> TrackSession {
+        if let Some(smoother) = &self.smoother {
+            session.track_points = smoother.smooth(&session.track_points);
+        }
+        session
+    }
+}
+
+/// A data manager with an in-memory database and its buffer files in a temporary directory, which is deleted when it is dropped.
+/// Other files are read from the project's data directory.
+#[cfg(test)]
+pub(crate) async fn test_data_manager() -> (DataManager, tempfile::TempDir) {
+    let buffer_dir = tempfile::tempdir().unwrap();
+    let config = DataManagerConfig {
+        database: crate::DatabaseLocation::InMemory,
+        buffer_dir: buffer_dir.path().to_path_buf(),
+        ..DataManagerConfig::default()
+    };
+    (DataManager::start(config).await.unwrap(), buffer_dir)
 }
 
 #[tokio::test]
 async fn init_trip() {
-    let dm = DataManager::start().await.unwrap();
-    let _ = dm.register_new_trip("Tour de Lada 2025".into(), "Silas og Joachim kører en Lada fra Armenien til Danmark.\n Lykkes det at finde en Lada i god stand? Får vi lov til at køre igennem Tyrkiet? Får vi den ind i Danmark? Følg med og find ud af det!".into(), chrono::Utc::now()).await.unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = DataManagerConfig::in_dir(data_dir.path());
+
+    let dm = DataManager::start(config.clone()).await.unwrap();
+    let trip = dm.register_new_trip("Tour de Lada 2025".into(), "Silas og Joachim kører en Lada fra Armenien til Danmark.\n Lykkes det at finde en Lada i god stand? Får vi lov til at køre igennem Tyrkiet? Får vi den ind i Danmark? Følg med og find ud af det!".into(), chrono::Utc::now()).await.unwrap();
+    drop(dm);
+
+    // Everything is kept in the data directory
+    assert!(data_dir.path().join("database.db").exists());
+    assert!(data_dir.path().join("buffer_files").is_dir());
+    let dm = DataManager::start(config).await.unwrap();
+    assert_eq!(dm.get_trip(trip.trip_id).await.unwrap(), trip);
 }
 
 #[tokio::test]
 async fn clear_sessions() {
-    let dm = DataManager::start().await.unwrap();
+    let (dm, buffer_dir) = test_data_manager().await;
+    let trip = dm.register_new_trip("Tour de Lada 2025".into(), String::new(), chrono::Utc::now()).await.unwrap();
+    let session = dm.register_new_live_session(trip.trip_id, "Day 1".into(), String::new()).await.unwrap();
+    let start = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let points = (0..10).map(|i| TrackPoint::new(start + chrono::TimeDelta::seconds(i), 40.18, 44.51, 0., 0., true)).collect::<Vec<_>>();
+    dm.append_gps_points(session.session_id, &points).await.unwrap();
+    assert_eq!(std::fs::read_dir(buffer_dir.path()).unwrap().count(), 1);
+
     let trips = dm.get_trips().await.unwrap();
     for trip in trips {
         let sessions = dm.get_trip_sessions(trip.trip_id).await.unwrap();
@@ -171,4 +433,62 @@ async fn clear_sessions() {
             dm.end_session(session.session_id).await.unwrap();
         }
     }
-}
\ No newline at end of file
+
+    let session = dm.get_session(session.session_id).await.unwrap();
+    assert!(!session.active);
+    assert_eq!(session.track_points.len(), 10);
+    assert_eq!(std::fs::read_dir(buffer_dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn error_kinds() {
+    let (dm, _buffer_dir) = test_data_manager().await;
+    assert!(matches!(dm.get_trip(42).await, Err(DataManagerError::NotFound(crate::Entity::Trip, 42))));
+    assert!(matches!(dm.get_session(42).await, Err(DataManagerError::NotFound(crate::Entity::Session, 42))));
+    assert!(matches!(dm.set_trip_utc_offset(42, None).await, Err(DataManagerError::NotFound(crate::Entity::Trip, 42))));
+
+    let trip = dm.register_new_trip("Tour de Lada 2025".into(), String::new(), chrono::Utc::now()).await.unwrap();
+    let session = dm.register_new_session(trip.trip_id, "Day 1".into(), String::new()).await.unwrap();
+    assert!(matches!(dm.end_session(session.session_id).await, Err(DataManagerError::Conflict(_))));
+
+    dm.database.set_trip_status(trip.trip_id, TripStatus::Finished).await.unwrap();
+    let err = dm.register_new_live_session(trip.trip_id, "Day 2".into(), String::new()).await.unwrap_err();
+    assert!(matches!(err, DataManagerError::Conflict(_)));
+    assert_eq!(err.exit_code(), 4);
+}
+
+#[tokio::test]
+async fn session_update_since() {
+    let (mut dm, _buffer_dir) = test_data_manager().await;
+    dm.set_smoother(Some(KalmanSmoother::default()));
+    let trip = dm.register_new_trip("Tour de Lada 2025".into(), String::new(), chrono::Utc::now()).await.unwrap();
+    let session = dm.register_new_live_session(trip.trip_id, "Day 1".into(), String::new()).await.unwrap();
+    let start = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let points = (0..10).map(|i| TrackPoint::new(start + chrono::TimeDelta::seconds(i * 10), 40.18 + i as f64 * 0.01, 44.51, 0., 0., true)).collect::<Vec<_>>();
+    dm.append_gps_points(session.session_id, &points).await.unwrap();
+
+    let update = dm.get_session_update(session.session_id, points[6].timestamp).await.unwrap();
+    assert!(update.still_active);
+    assert_eq!(update.statistics.point_count, 10);
+    // The same as the whole session's, which smoothing doesn't change
+    assert_eq!(update.statistics, dm.get_session(session.session_id).await.unwrap().statistics);
+    assert_eq!(update.new_track_points.first().map(|p| p.timestamp), Some(points[7].timestamp));
+    assert_eq!(update.new_track_points.last().map(|p| p.timestamp), Some(points[9].timestamp));
+
+    dm.end_session(session.session_id).await.unwrap();
+    let update = dm.get_session_update(session.session_id, points[9].timestamp).await.unwrap();
+    assert!(!update.still_active);
+    assert!(update.new_track_points.is_empty());
+    assert_eq!(update.statistics.point_count, 10);
+
+    // An ended session in several chunks, of which only the last is read
+    let session = dm.register_new_session(trip.trip_id, "Day 2".into(), String::new()).await.unwrap();
+    let points = (0..2500).map(|i| TrackPoint::new(start + chrono::TimeDelta::seconds(i), 40.18 + i as f64 * 1e-4, 44.51, 0., 0., true)).collect::<Vec<_>>();
+    dm.append_gps_points(session.session_id, &points).await.unwrap();
+    let update = dm.get_session_update(session.session_id, points[2400].timestamp).await.unwrap();
+    assert!(!update.still_active);
+    assert_eq!(update.statistics.point_count, 2500);
+    assert_eq!(update.statistics, dm.get_session(session.session_id).await.unwrap().statistics);
+    assert_eq!(update.new_track_points.first().map(|p| p.timestamp), Some(points[2401].timestamp));
+    assert_eq!(update.new_track_points.last().map(|p| p.timestamp), Some(points[2499].timestamp));
+}
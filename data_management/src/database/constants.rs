@@ -6,6 +6,13 @@ pub const TITLE: &str = "title";
 pub const DESCRIPTION: &str = "description";
 pub const API_TOKEN: &str = "api_token";
 pub const COUNTRY_LIST: &str = "country_list";
+/// Minutes
+pub const UTC_OFFSET: &str = "utc_offset";
+pub const PLANNED_START: &str = "planned_start";
+pub const PLANNED_END: &str = "planned_end";
+pub const STATUS: &str = "status";
+pub const VISIBILITY: &str = "visibility";
+pub const COVER_IMAGE: &str = "cover_image";
 
 pub const TRACK_SESSIONS_TABLE_NAME: &str = "TrackSessions";
 pub const SESSION_ID: &str = "session_id";
@@ -13,6 +20,7 @@ pub const TIMESTAMP: &str = "timestamp";
 pub const ACTIVE: &str = "active";
 pub const TRACK_POINTS: &str = "track_points";
 pub const HIDDEN: &str = "hidden";
+pub const STATISTICS: &str = "statistics";
 
 pub const VISIT_TABLE: &str = "Traffic";
 pub const VISIT_ID: &str = "visit_id";
@@ -23,4 +31,44 @@ pub const IP_INFO_TABLE_NAME: &str = "IpInfo";
 // IP
 pub const COUNTRY: &str = "country";
 pub const LATITUDE: &str = "latitude";
-pub const LONGITUDE: &str = "longitude";
\ No newline at end of file
+pub const LONGITUDE: &str = "longitude";
+
+pub const TELEMETRY_TABLE_NAME: &str = "Telemetry";
+pub const TELEMETRY_ID: &str = "telemetry_id";
+// Session ID
+// Timestamp
+pub const BATTERY_PERCENT: &str = "battery_percent";
+pub const POWER_SOURCE: &str = "power_source";
+pub const RSSI: &str = "rssi";
+pub const BER: &str = "ber";
+pub const SATELLITES: &str = "satellites";
+pub const SATELLITES_USED: &str = "satellites_used";
+pub const FREE_STORAGE_KIB: &str = "free_storage_kib";
+pub const UPTIME_SECS: &str = "uptime_secs";
+pub const FIRMWARE_VERSION: &str = "firmware_version";
+
+pub const FILTER_CONFIGS_TABLE_NAME: &str = "FilterConfigs";
+// Trip ID
+pub const FILTER_CONFIG: &str = "filter_config";
+
+pub const STOPS_TABLE_NAME: &str = "Stops";
+pub const STOP_ID: &str = "stop_id";
+// Session ID
+// Latitude
+// Longitude
+pub const ARRIVAL: &str = "arrival";
+pub const DEPARTURE: &str = "departure";
+pub const POINT_COUNT: &str = "point_count";
+
+pub const TRACK_CHUNKS_TABLE_NAME: &str = "TrackChunks";
+// Session ID
+pub const CHUNK_SEQ: &str = "chunk_seq";
+pub const START_TIME: &str = "start_time";
+pub const END_TIME: &str = "end_time";
+// Point count
+// Track points
+
+pub const SCHEMA_VERSION_TABLE_NAME: &str = "schema_version";
+pub const VERSION: &str = "version";
+// Description
+pub const APPLIED_AT: &str = "applied_at";
@@ -1,89 +1,70 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::collections::HashMap;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
 use const_format::concatcp;
-use sqlx::{query, query_as, sqlite::SqliteConnectOptions, Executor, Pool, Sqlite, SqlitePool, Row};
-use trip_tracker_lib::{track_point::{write_tsf, TrackPoint}, track_session::TrackSession, traffic::{IpInfo, SiteTrafficData, Visit}, trip::Trip};
+use sqlx::{query, query_as, sqlite::{SqliteConnectOptions, SqlitePoolOptions}, Pool, Sqlite, SqliteConnection, SqlitePool, Row};
+use trip_tracker_lib::{filter::FilterConfig, local_time::UtcOffset, stops::Stop, track_point::TrackPoint, track_session::{SessionStatistics, TrackSession}, tsf::{parse_tsf, write_tsf, RecordLayout}, telemetry::{PowerSource, TelemetryRecord}, traffic::{IpInfo, SiteTrafficData, Visit}, trip::{Trip, TripStatus, TripVisibility}};
 
-use crate::{DataManagerError, DATABASE_PATH};
+use crate::{DataManagerError, DatabaseLocation, Entity};
 
 use super::constants::*;
 
+/// Track points are stored in chunks of this many points, so appending only rewrites the last chunk
+pub const TRACK_CHUNK_SIZE: usize = 1000;
+
 #[derive(Clone)]
 pub struct TripDatabase {
-    pool: Pool<Sqlite>,
+    pub(super) pool: Pool<Sqlite>,
 }
 
 impl TripDatabase {
-    pub async fn connect() -> Result<Self, DataManagerError> {
-        let root: PathBuf = project_root::get_project_root().unwrap();
-        let options = SqliteConnectOptions::new()
-            .filename(root.join(DATABASE_PATH))
-            .foreign_keys(true)
-            .create_if_missing(true);
-        
-        let pool = SqlitePool::connect_with(options).await.map_err(|_| DataManagerError::Database("Failed to connect to database".to_string()))?;
-
-        let db = Self {
-            pool
-        };
+    /// Opens the database, and brings its schema up to date.
+    pub async fn connect(location: &DatabaseLocation) -> Result<Self, DataManagerError> {
+        let db = Self::open(location).await?;
 
-        db.init().await;
+        for migration in db.migrate().await? {
+            tracing::info!("Migrated database to version {}: {}", migration.version, migration.description);
+        }
 
         Ok(db)
     }
 
-    pub async fn init(&self) {
-        self.pool.execute(concatcp!("
-            CREATE TABLE IF NOT EXISTS ", TRIPS_TABLE_NAME, "(", 
-                TRIP_ID,      " INTEGER PRIMARY KEY AUTOINCREMENT,",
-                TIMESTAMP,    " TIMESTAMP NOT NULL,",
-                TITLE,        " TEXT NOT NULL,", 
-                DESCRIPTION,  " TEXT,", 
-                API_TOKEN,    " TEXT NOT NULL,",
-                COUNTRY_LIST, " BLOB NOT NULL
-            );
-
-            CREATE TABLE IF NOT EXISTS ", TRACK_SESSIONS_TABLE_NAME, "(",
-                SESSION_ID,   " INTEGER PRIMARY KEY AUTOINCREMENT,",
-                TRIP_ID,      " INTEGER NOT NULL,",
-                TITLE,        " TEXT NOT NULL,",
-                DESCRIPTION,  " TEXT,",
-                TIMESTAMP,    " TIMESTAMP NOT NULL,",
-                ACTIVE,       " BOOLEAN NOT NULL,",
-                TRACK_POINTS, " BLOB NOT NULL, ",
-                HIDDEN,       " BOOLEAN NOT NULL,
-                FOREIGN KEY(", TRIP_ID, ") REFERENCES ", TRIPS_TABLE_NAME, "(", TRIP_ID, ") ON DELETE CASCADE
-            );
-
-            CREATE TABLE IF NOT EXISTS ", VISIT_TABLE, "(",
-                VISIT_ID,   " INTEGER PRIMARY KEY AUTOINCREMENT,",
-                IP_ADDRESS, " TEXT NOT NULL,",
-                TIMESTAMP,  " TIMESTAMP NOT NULL
-            );
-
-            CREATE TABLE IF NOT EXISTS ", IP_INFO_TABLE_NAME, "(",
-                IP_ADDRESS, " TEXT PRIMARY KEY,",
-                COUNTRY,    " TEXT NOT NULL,",
-                LATITUDE,   " REAL NOT NULL,",
-                LONGITUDE,  " REAL NOT NULL
-            );
-
-            ")).await.unwrap();
+    /// Opens the database as it is, without migrating it.
+    pub async fn open(location: &DatabaseLocation) -> Result<Self, DataManagerError> {
+        let options = SqliteConnectOptions::new()
+            .foreign_keys(true)
+            .create_if_missing(true);
+
+        let pool = match location {
+            DatabaseLocation::File(path) => SqlitePool::connect_with(options.filename(path)).await,
+            // Every connection to an in-memory database has its own database, so the pool keeps a single connection open
+            DatabaseLocation::InMemory => SqlitePoolOptions::new()
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+                .connect_with(options.in_memory(true)).await,
+        }.map_err(DataManagerError::storage("Failed to connect to database"))?;
+
+        Ok(Self {
+            pool
+        })
     }
 
+    /// Inserts a trip that is planned and private, until it is changed.
     pub async fn insert_trip(&self, title: String, description: String, timestamp: DateTime<Utc>, api_token: String) -> Result<Trip, DataManagerError> {
         let id = query_as::<_, (i64,)>(concatcp!("
             INSERT INTO ", TRIPS_TABLE_NAME, "(", 
-            TRIP_ID, ", ", TIMESTAMP, ", ", TITLE, ", ", DESCRIPTION, ", ", API_TOKEN, ", ", COUNTRY_LIST, ")
-            VALUES (NULL, ?1, ?2, ?3, ?4, ?5) RETURNING ", TRIP_ID))
+            TRIP_ID, ", ", TIMESTAMP, ", ", TITLE, ", ", DESCRIPTION, ", ", API_TOKEN, ", ", COUNTRY_LIST, ", ", STATUS, ", ", VISIBILITY, ")
+            VALUES (NULL, ?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING ", TRIP_ID))
                 .bind(timestamp)
                 .bind(&title)
                 .bind(&description)
                 .bind(&api_token)
                 .bind(Vec::new())
+                .bind(TripStatus::default().id())
+                .bind(TripVisibility::default().id())
                 .fetch_one(&self.pool).await
-                .map_err(|e| DataManagerError::Database(format!("Failed to insert trip: {}", e)))
+                .map_err(DataManagerError::storage("Failed to insert trip"))
                 .map(|row| row.0)?;
 
         Ok(Trip::new(id, title.clone(), description.clone(), timestamp, api_token.clone()))
@@ -94,11 +75,89 @@ impl TripDatabase {
                 .bind(title)
                 .bind(trip_id)
                 .execute(&self.pool).await
-                .map_err(|e| DataManagerError::Database(format!("Failed to update trip title: {}", e)))
+                .map_err(DataManagerError::storage("Failed to update trip title"))
+                .map(|x| x.rows_affected())?;
+
+        if rows_affected != 1 {
+            Err(DataManagerError::NotFound(Entity::Trip, trip_id))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sets the offset times of the trip are shown in, or `None` to show local times.
+    pub async fn set_trip_utc_offset(&self, trip_id: i64, utc_offset: Option<UtcOffset>) -> Result<(), DataManagerError> {
+        let rows_affected = query(concatcp!("UPDATE ", TRIPS_TABLE_NAME, " SET ", UTC_OFFSET, " = ?1 WHERE ", TRIP_ID, " = ?2"))
+                .bind(utc_offset.map(|offset| offset.minutes()))
+                .bind(trip_id)
+                .execute(&self.pool).await
+                .map_err(DataManagerError::storage("Failed to update trip timezone"))
+                .map(|x| x.rows_affected())?;
+
+        if rows_affected != 1 {
+            Err(DataManagerError::NotFound(Entity::Trip, trip_id))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn set_trip_status(&self, trip_id: i64, status: TripStatus) -> Result<(), DataManagerError> {
+        let rows_affected = query(concatcp!("UPDATE ", TRIPS_TABLE_NAME, " SET ", STATUS, " = ?1 WHERE ", TRIP_ID, " = ?2"))
+                .bind(status.id())
+                .bind(trip_id)
+                .execute(&self.pool).await
+                .map_err(DataManagerError::storage("Failed to update trip status"))
+                .map(|x| x.rows_affected())?;
+
+        if rows_affected != 1 {
+            Err(DataManagerError::NotFound(Entity::Trip, trip_id))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn set_trip_visibility(&self, trip_id: i64, visibility: TripVisibility) -> Result<(), DataManagerError> {
+        let rows_affected = query(concatcp!("UPDATE ", TRIPS_TABLE_NAME, " SET ", VISIBILITY, " = ?1 WHERE ", TRIP_ID, " = ?2"))
+                .bind(visibility.id())
+                .bind(trip_id)
+                .execute(&self.pool).await
+                .map_err(DataManagerError::storage("Failed to update trip visibility"))
+                .map(|x| x.rows_affected())?;
+
+        if rows_affected != 1 {
+            Err(DataManagerError::NotFound(Entity::Trip, trip_id))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sets the planned dates of the trip, where `None` clears a date.
+    pub async fn set_trip_planned_dates(&self, trip_id: i64, planned_start: Option<NaiveDate>, planned_end: Option<NaiveDate>) -> Result<(), DataManagerError> {
+        let rows_affected = query(concatcp!("UPDATE ", TRIPS_TABLE_NAME, " SET ", PLANNED_START, " = ?1, ", PLANNED_END, " = ?2 WHERE ", TRIP_ID, " = ?3"))
+                .bind(planned_start)
+                .bind(planned_end)
+                .bind(trip_id)
+                .execute(&self.pool).await
+                .map_err(DataManagerError::storage("Failed to update trip dates"))
+                .map(|x| x.rows_affected())?;
+
+        if rows_affected != 1 {
+            Err(DataManagerError::NotFound(Entity::Trip, trip_id))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn set_trip_cover_image(&self, trip_id: i64, cover_image: Option<&str>) -> Result<(), DataManagerError> {
+        let rows_affected = query(concatcp!("UPDATE ", TRIPS_TABLE_NAME, " SET ", COVER_IMAGE, " = ?1 WHERE ", TRIP_ID, " = ?2"))
+                .bind(cover_image)
+                .bind(trip_id)
+                .execute(&self.pool).await
+                .map_err(DataManagerError::storage("Failed to update trip cover image"))
                 .map(|x| x.rows_affected())?;
 
         if rows_affected != 1 {
-            Err(DataManagerError::Database(format!("Trip was not found: {}", trip_id)))
+            Err(DataManagerError::NotFound(Entity::Trip, trip_id))
         } else {
             Ok(())
         }
@@ -109,11 +168,11 @@ impl TripDatabase {
                 .bind(description)
                 .bind(trip_id)
                 .execute(&self.pool).await
-                .map_err(|_| DataManagerError::Database("Failed to update trip description".to_string()))
+                .map_err(DataManagerError::storage("Failed to update trip description"))
                 .map(|x| x.rows_affected())?;
 
         if rows_affected != 1 {
-            Err(DataManagerError::Database(format!("Trip was not found: {}", trip_id)))
+            Err(DataManagerError::NotFound(Entity::Trip, trip_id))
         } else {
             Ok(())
         }
@@ -125,11 +184,11 @@ impl TripDatabase {
                 .bind(title)
                 .bind(session_id)
                 .execute(&self.pool).await
-                .map_err(|_| DataManagerError::Database("Failed to update session title".to_string()))
+                .map_err(DataManagerError::storage("Failed to update session title"))
                 .map(|x| x.rows_affected())?;
 
         if rows_affected != 1 {
-            Err(DataManagerError::Database(format!("Session was not found: {}", session_id)))
+            Err(DataManagerError::NotFound(Entity::Session, session_id))
         } else {
             Ok(())
         }
@@ -140,41 +199,73 @@ impl TripDatabase {
                 .bind(description)
                 .bind(session_id)
                 .execute(&self.pool).await
-                .map_err(|_| DataManagerError::Database("Failed to update session description".to_string()))
+                .map_err(DataManagerError::storage("Failed to update session description"))
                 .map(|x| x.rows_affected())?;
 
         if rows_affected != 1 {
-            Err(DataManagerError::Database(format!("Session was not found: {}", session_id)))
+            Err(DataManagerError::NotFound(Entity::Session, session_id))
         } else {
             Ok(())
         }
     }
 
     pub async fn insert_track_session(&self, trip_id: i64, title: String, description: String, start_time: DateTime<Utc>, active: bool) -> Result<TrackSession, DataManagerError> {
-        let session_id = query_as::<_, (i64,)>(concatcp!("
-            INSERT INTO ", TRACK_SESSIONS_TABLE_NAME, 
-            "(", SESSION_ID, ", ", TRIP_ID, ", ", TITLE, ", ", DESCRIPTION, ", ", TIMESTAMP, ", ", ACTIVE, ", ", TRACK_POINTS, ", ", HIDDEN, ")
-            VALUES (NULL, ?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING ", SESSION_ID))
-                .bind(trip_id)
-                .bind(&title)
-                .bind(&description)
-                .bind(&start_time)
-                .bind(active)
-                .bind(Vec::new())
-                .bind(false)
-                .fetch_one(&self.pool).await
-                .map_err(|e| DataManagerError::Database(format!("Failed to insert track session: {}", e)))
-                .map(|row| row.0)?;
+        let mut connection = self.pool.acquire().await
+            .map_err(DataManagerError::storage("Failed to insert track session"))?;
+        let session_id = insert_session_row(&mut connection, trip_id, &title, &description, start_time, active).await
+            .map_err(DataManagerError::storage("Failed to insert track session"))?;
 
         Ok(TrackSession::new(session_id, trip_id, title, description, start_time, active, Vec::new(), false))
     }
 
+    /// Stores each part of the session as a new session titled after it, and hides the session. Either all of it is stored, or none.
+    pub async fn insert_split_sessions(&self, session: &TrackSession, parts: &[Vec<TrackPoint>]) -> Result<Vec<TrackSession>, DataManagerError> {
+        let mut transaction = self.pool.begin().await
+            .map_err(DataManagerError::storage("Failed to begin transaction"))?;
+
+        let mut sessions = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            let title = format!("{} ({}/{})", session.title, i + 1, parts.len());
+            let start_time = part[0].timestamp;
+            let session_id = insert_session_row(&mut transaction, session.trip_id, &title, &session.description, start_time, false).await
+                .map_err(DataManagerError::storage("Failed to insert track session"))?;
+            insert_track_chunks(&mut transaction, session_id, 0, part).await
+                .map_err(DataManagerError::storage("Failed to set session track points"))?;
+
+            let mut new_session = TrackSession::new(session_id, session.trip_id, title, session.description.clone(), start_time, false, Vec::new(), false);
+            new_session.set_track_points(part.clone());
+            set_session_statistics(&mut transaction, session_id, &new_session.statistics).await
+                .map_err(DataManagerError::storage("Failed to set session statistics"))?;
+            sessions.push(new_session);
+        }
+
+        let rows_affected = query(concatcp!("UPDATE ", TRACK_SESSIONS_TABLE_NAME, " SET ", HIDDEN, " = true WHERE ", SESSION_ID, " = ?1"))
+            .bind(session.session_id)
+            .execute(&mut *transaction).await
+            .map_err(DataManagerError::storage("Failed to set session hidden"))?
+            .rows_affected();
+        if rows_affected != 1 {
+            return Err(DataManagerError::NotFound(Entity::Session, session.session_id));
+        }
+
+        transaction.commit().await
+            .map_err(DataManagerError::storage("Failed to commit split sessions"))?;
+        Ok(sessions)
+    }
+
+    /// The session with all of its track points.
     pub async fn get_session(&self, session_id: i64) -> Result<TrackSession, DataManagerError> {
+        let mut session = self.get_session_info(session_id).await?;
+        session.track_points = self.get_track_points(session_id).await?;
+        Ok(session)
+    }
+
+    /// The session with its statistics, but without its track points, which aren't read.
+    pub async fn get_session_info(&self, session_id: i64) -> Result<TrackSession, DataManagerError> {
         query_as::<_, TrackSession>(concatcp!("SELECT * FROM ", TRACK_SESSIONS_TABLE_NAME, " WHERE ", SESSION_ID, " = ?1"))
             .bind(session_id)
             .fetch_one(&self.pool).await
-            .map_err(|_| DataManagerError::Database("Failed to get session".to_string()))
-            .map(|row| row)
+            .map_err(DataManagerError::lookup(Entity::Session, session_id))
     }
 
     pub async fn set_session_active(&self, session_id: i64, active: bool) -> Result<(), DataManagerError> {
@@ -182,11 +273,11 @@ impl TripDatabase {
             .bind(active)
             .bind(session_id)
             .execute(&self.pool).await
-            .map_err(|_| DataManagerError::Database("Failed to set session active".to_string()))
+            .map_err(DataManagerError::storage("Failed to set session active"))
             .map(|x| x.rows_affected())?;
 
         if rows_affected != 1 {
-            Err(DataManagerError::Database(format!("Session was not found: {}", session_id)))
+            Err(DataManagerError::NotFound(Entity::Session, session_id))
         } else {
             Ok(())
         }
@@ -197,69 +288,166 @@ impl TripDatabase {
             .bind(hidden)
             .bind(session_id)
             .execute(&self.pool).await
-            .map_err(|_| DataManagerError::Database("Failed to set session active".to_string()))
+            .map_err(DataManagerError::storage("Failed to set session hidden"))
             .map(|x| x.rows_affected())?;
 
         if rows_affected != 1 {
-            Err(DataManagerError::Database(format!("Session was not found: {}", session_id)))
+            Err(DataManagerError::NotFound(Entity::Session, session_id))
         } else {
             Ok(())
         }
     }
 
+    /// Replaces all track points of the session, and its statistics.
     pub async fn set_session_track_points(&self, session_id: i64, track_points: Vec<TrackPoint>) -> Result<(), DataManagerError> {
-        let start_time = track_points.first().map(|point| point.timestamp).unwrap_or_else(|| Utc::now()); // If none, time will not be used, so it doesn't matter
-        query(concatcp!("UPDATE ", TRACK_SESSIONS_TABLE_NAME, " SET ", TRACK_POINTS, " = ?1 WHERE ", SESSION_ID, " = ?2"))
-            .bind(write_tsf(start_time, &track_points))
+        let mut transaction = self.pool.begin().await
+            .map_err(DataManagerError::storage("Failed to begin transaction"))?;
+
+        replace_track_points(&mut transaction, session_id, &track_points).await?;
+
+        transaction.commit().await
+            .map_err(DataManagerError::storage("Failed to commit session track points"))
+    }
+
+    /// Replaces all track points of the active session and ends it. Either both are stored, or neither.
+    pub async fn end_session(&self, session_id: i64, track_points: Vec<TrackPoint>) -> Result<(), DataManagerError> {
+        let mut transaction = self.pool.begin().await
+            .map_err(DataManagerError::storage("Failed to begin transaction"))?;
+
+        replace_track_points(&mut transaction, session_id, &track_points).await?;
+
+        let rows_affected = query(concatcp!("UPDATE ", TRACK_SESSIONS_TABLE_NAME, " SET ", ACTIVE, " = false WHERE ", SESSION_ID, " = ?1"))
             .bind(session_id)
-            .execute(&self.pool).await
-            .map_err(|_| DataManagerError::Database("Failed to set session track points".to_string()))
-            .map(|_| ())
+            .execute(&mut *transaction).await
+            .map_err(DataManagerError::storage("Failed to set session active"))?
+            .rows_affected();
+        if rows_affected != 1 {
+            return Err(DataManagerError::NotFound(Entity::Session, session_id));
+        }
+
+        transaction.commit().await
+            .map_err(DataManagerError::storage("Failed to commit ended session"))
     }
 
+    /// Appends to the last chunk of the session until it is full, and to new chunks after it, and updates the statistics of the session.
     pub async fn append_track_points(&self, session_id: i64, track_points: &[TrackPoint]) -> Result<(), DataManagerError> {
-        let session = self.get_session(session_id).await?;
-        let mut all_track_points = session.track_points.clone();
-        all_track_points.extend_from_slice(track_points);
-        self.set_session_track_points(session_id, all_track_points).await
+        if track_points.is_empty() {
+            return Ok(());
+        }
+
+        let mut transaction = self.pool.begin().await
+            .map_err(DataManagerError::storage("Failed to begin transaction"))?;
+
+        let last_chunk = query_as::<_, (i64, i64, Vec<u8>)>(concatcp!("SELECT ", CHUNK_SEQ, ", ", POINT_COUNT, ", ", TRACK_POINTS, " FROM ", TRACK_CHUNKS_TABLE_NAME,
+            " WHERE ", SESSION_ID, " = ?1 ORDER BY ", CHUNK_SEQ, " DESC LIMIT 1"))
+            .bind(session_id)
+            .fetch_optional(&mut *transaction).await
+            .map_err(DataManagerError::storage("Failed to get last track chunk"))?;
+
+        let (chunk_seq, mut chunk_points) = match last_chunk {
+            Some((chunk_seq, point_count, bytes)) if (point_count as usize) < TRACK_CHUNK_SIZE => (chunk_seq, parse_chunk(&bytes)?),
+            Some((chunk_seq, _, _)) => (chunk_seq + 1, Vec::new()),
+            None => (0, Vec::new()),
+        };
+        chunk_points.extend_from_slice(track_points);
+
+        insert_track_chunks(&mut transaction, session_id, chunk_seq, &chunk_points).await
+            .map_err(DataManagerError::storage("Failed to append track points"))?;
+
+        let (bytes,) = query_as::<_, (Option<Vec<u8>>,)>(concatcp!("SELECT ", STATISTICS, " FROM ", TRACK_SESSIONS_TABLE_NAME, " WHERE ", SESSION_ID, " = ?1"))
+            .bind(session_id)
+            .fetch_one(&mut *transaction).await
+            .map_err(DataManagerError::lookup(Entity::Session, session_id))?;
+        let mut statistics = match bytes {
+            Some(bytes) => bincode::deserialize::<SessionStatistics>(&bytes)
+                .map_err(|e| DataManagerError::Corrupt(format!("Statistics of session {} don't deserialize: {}", session_id, e)))?,
+            None => SessionStatistics::default(),
+        };
+        statistics.extend(track_points);
+        set_session_statistics(&mut transaction, session_id, &statistics).await
+            .map_err(DataManagerError::storage("Failed to set session statistics"))?;
+
+        transaction.commit().await
+            .map_err(DataManagerError::storage("Failed to commit track points"))
+    }
+
+    /// All track points of the session, in order.
+    pub async fn get_track_points(&self, session_id: i64) -> Result<Vec<TrackPoint>, DataManagerError> {
+        self.read_track_chunks(session_id, None).await
+    }
+
+    /// The track points of the session after the timestamp, wherever they are in the session. Only the chunks with points after it are read.
+    pub async fn get_track_points_since(&self, session_id: i64, timestamp: DateTime<Utc>) -> Result<Vec<TrackPoint>, DataManagerError> {
+        Ok(self.read_track_chunks(session_id, Some(timestamp)).await?
+            .into_iter()
+            .filter(|p| p.timestamp > timestamp)
+            .collect())
+    }
+
+    /// The number of track points of the session, without reading them.
+    pub async fn track_point_count(&self, session_id: i64) -> Result<usize, DataManagerError> {
+        query_as::<_, (i64,)>(concatcp!("SELECT COALESCE(SUM(", POINT_COUNT, "), 0) FROM ", TRACK_CHUNKS_TABLE_NAME, " WHERE ", SESSION_ID, " = ?1"))
+            .bind(session_id)
+            .fetch_one(&self.pool).await
+            .map_err(DataManagerError::storage("Failed to count track points"))
+            .map(|row| row.0 as usize)
+    }
+
+    async fn read_track_chunks(&self, session_id: i64, since: Option<DateTime<Utc>>) -> Result<Vec<TrackPoint>, DataManagerError> {
+        let chunks: Vec<(Vec<u8>,)> = query_as(concatcp!("SELECT ", TRACK_POINTS, " FROM ", TRACK_CHUNKS_TABLE_NAME,
+            " WHERE ", SESSION_ID, " = ?1 AND (?2 IS NULL OR ", END_TIME, " > ?2) ORDER BY ", CHUNK_SEQ))
+            .bind(session_id)
+            .bind(since)
+            .fetch_all(&self.pool).await
+            .map_err(DataManagerError::storage("Failed to get track points"))?;
+
+        let mut track_points = Vec::new();
+        for (bytes,) in chunks {
+            track_points.extend(parse_chunk(&bytes)?);
+        }
+        Ok(track_points)
     }
 
     pub async fn get_trip(&self, trip_id: i64) -> Result<Trip, DataManagerError> {
         query_as::<_, Trip>(concatcp!("SELECT * FROM ", TRIPS_TABLE_NAME, " WHERE ", TRIP_ID, " = ?1"))
             .bind(trip_id)
             .fetch_one(&self.pool).await
-            .map_err(|_| DataManagerError::Database("Failed to get trip".to_string()))
-            .map(|row| row)
+            .map_err(DataManagerError::lookup(Entity::Trip, trip_id))
     }
 
     pub async fn get_trips(&self) -> Result<Vec<Trip>, DataManagerError> {
-        query(concatcp!("SELECT * FROM ", TRIPS_TABLE_NAME))
+        query_as::<_, Trip>(concatcp!("SELECT * FROM ", TRIPS_TABLE_NAME))
             .fetch_all(&self.pool).await
-            .map_err(|_| DataManagerError::Database("Failed to get trips".to_string()))
-            .map(|rows| rows.into_iter()
-                .map(|row| Trip {
-                    trip_id: row.get(0),
-                    timestamp: row.get(1),
-                    title: row.get(2),
-                    description: row.get(3),
-                    api_token: row.get(4),
-                    country_list: Vec::new(), // TODO: Get country codes
-                }).collect()
-            )
+            .map_err(DataManagerError::storage("Failed to get trips"))
     }
 
     pub async fn get_trip_sessions(&self, trip_id: i64) -> Result<Vec<TrackSession>, DataManagerError> {
-        query_as::<_, TrackSession>(concatcp!("SELECT * FROM ", TRACK_SESSIONS_TABLE_NAME, " WHERE ", TRIP_ID, " = ?1"))
+        let mut sessions = query_as::<_, TrackSession>(concatcp!("SELECT * FROM ", TRACK_SESSIONS_TABLE_NAME, " WHERE ", TRIP_ID, " = ?1"))
             .bind(trip_id)
             .fetch_all(&self.pool).await
-            .map_err(|_| DataManagerError::Database("Failed to get session".to_string()))
+            .map_err(DataManagerError::storage(format!("Failed to get sessions of trip {}", trip_id)))?;
+
+        for session in sessions.iter_mut() {
+            session.track_points = self.get_track_points(session.session_id).await?;
+        }
+        Ok(sessions)
     }
 
     pub async fn get_nonhidden_trip_session_ids(&self, trip_id: i64) -> Result<Vec<i64>, DataManagerError> {
         query(concatcp!("SELECT ", SESSION_ID, " FROM ", TRACK_SESSIONS_TABLE_NAME, " WHERE ", TRIP_ID, " = ?1 AND ", HIDDEN, " = false"))
             .bind(trip_id)
             .fetch_all(&self.pool).await
-            .map_err(|_| DataManagerError::Database("Failed to get session".to_string()))
+            .map_err(DataManagerError::storage(format!("Failed to get sessions of trip {}", trip_id)))
+            .map(|rows| rows.into_iter()
+                .map(|row| row.get(0))
+                .collect()
+            )
+    }
+
+    pub async fn get_active_session_ids(&self) -> Result<Vec<i64>, DataManagerError> {
+        query(concatcp!("SELECT ", SESSION_ID, " FROM ", TRACK_SESSIONS_TABLE_NAME, " WHERE ", ACTIVE, " = true"))
+            .fetch_all(&self.pool).await
+            .map_err(DataManagerError::storage("Failed to get active sessions"))
             .map(|rows| rows.into_iter()
                 .map(|row| row.get(0))
                 .collect()
@@ -271,7 +459,7 @@ impl TripDatabase {
             .bind(bincode::serialize(&country_codes).unwrap())
             .bind(trip_id)
             .execute(&self.pool).await
-            .map_err(|_| DataManagerError::Database("Failed to set trip countries".to_string()))
+            .map_err(DataManagerError::storage("Failed to set trip countries"))
             .map(|_| ())
     }
 
@@ -287,7 +475,7 @@ impl TripDatabase {
                     })
                     .collect::<Vec<Visit>>()
             })
-            .map_err(|_| DataManagerError::Database("Failed to get site traffic data".to_string()))?;
+            .map_err(DataManagerError::storage("Failed to get site traffic data"))?;
 
         let mut visitor_infos = HashMap::new(); 
 
@@ -304,9 +492,7 @@ impl TripDatabase {
                     };
 
                     // Insert IP info into database
-                    self.insert_ip_info(ip_info.clone())
-                        .await
-                        .map_err(|_| DataManagerError::Database("Failed to insert IP info".to_string()))?;
+                    self.insert_ip_info(ip_info.clone()).await?;
 
                     ip_info
                 }
@@ -329,7 +515,7 @@ impl TripDatabase {
             .bind(ip_info.latitude)
             .bind(ip_info.longitude)
             .execute(&self.pool).await
-            .map_err(|_| DataManagerError::Database("Failed to insert IP info".to_string()))
+            .map_err(DataManagerError::storage("Failed to insert IP info"))
             .map(|_| ())
     }
 
@@ -339,33 +525,226 @@ impl TripDatabase {
             .bind(visit.ip)
             .bind(visit.timestamp)
             .execute(&self.pool).await
-            .map_err(|err| DataManagerError::Database(format!("Failed to record visit: {:?}", err)))
+            .map_err(DataManagerError::storage("Failed to record visit"))
             .map(|_| ())
     }
+
+    pub async fn insert_telemetry(&self, record: TelemetryRecord) -> Result<(), DataManagerError> {
+        query(concatcp!("INSERT INTO ", TELEMETRY_TABLE_NAME, "(",
+            SESSION_ID, ", ", TIMESTAMP, ", ", BATTERY_PERCENT, ", ", POWER_SOURCE, ", ", RSSI, ", ", BER, ", ",
+            SATELLITES, ", ", SATELLITES_USED, ", ", FREE_STORAGE_KIB, ", ", UPTIME_SECS, ", ", FIRMWARE_VERSION,
+            ") VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"))
+            .bind(record.session_id)
+            .bind(record.timestamp)
+            .bind(record.battery_percent)
+            .bind(record.power_source.id())
+            .bind(record.rssi)
+            .bind(record.ber)
+            .bind(record.satellites)
+            .bind(record.satellites_used)
+            .bind(record.free_storage_kib)
+            .bind(record.uptime_secs)
+            .bind(record.firmware_version)
+            .execute(&self.pool).await
+            .map_err(DataManagerError::storage("Failed to record telemetry"))
+            .map(|_| ())
+    }
+
+    /// Telemetry of all sessions of the trip, oldest first. Only the latest `limit` reports are returned.
+    pub async fn get_trip_telemetry(&self, trip_id: i64, limit: u32) -> Result<Vec<TelemetryRecord>, DataManagerError> {
+        query(concatcp!("SELECT * FROM (SELECT t.", SESSION_ID, ", t.", TIMESTAMP, ", ", BATTERY_PERCENT, ", ", POWER_SOURCE, ", ",
+            RSSI, ", ", BER, ", ", SATELLITES, ", ", SATELLITES_USED, ", ", FREE_STORAGE_KIB, ", ", UPTIME_SECS, ", ", FIRMWARE_VERSION, ", ", TELEMETRY_ID,
+            " FROM ", TELEMETRY_TABLE_NAME, " t JOIN ", TRACK_SESSIONS_TABLE_NAME, " s ON t.", SESSION_ID, " = s.", SESSION_ID,
+            " WHERE s.", TRIP_ID, " = ?1 ORDER BY ", TELEMETRY_ID, " DESC LIMIT ?2) ORDER BY ", TELEMETRY_ID))
+            .bind(trip_id)
+            .bind(limit)
+            .fetch_all(&self.pool).await
+            .map_err(DataManagerError::storage("Failed to get telemetry"))
+            .map(|rows| rows.into_iter()
+                .map(|row| TelemetryRecord {
+                    session_id: row.get(0),
+                    timestamp: row.get(1),
+                    battery_percent: row.get(2),
+                    power_source: PowerSource::from_id(row.get(3)),
+                    rssi: row.get(4),
+                    ber: row.get(5),
+                    satellites: row.get(6),
+                    satellites_used: row.get(7),
+                    free_storage_kib: row.get(8),
+                    uptime_secs: row.get(9),
+                    firmware_version: row.get(10),
+                }).collect()
+            )
+    }
+
+    /// The filter configured for the trip, if any.
+    pub async fn get_filter_config(&self, trip_id: i64) -> Result<Option<FilterConfig>, DataManagerError> {
+        let row = query(concatcp!("SELECT ", FILTER_CONFIG, " FROM ", FILTER_CONFIGS_TABLE_NAME, " WHERE ", TRIP_ID, " = ?1"))
+            .bind(trip_id)
+            .fetch_optional(&self.pool).await
+            .map_err(DataManagerError::storage("Failed to get filter config"))?;
+
+        row.map(|row| {
+            let bytes: Vec<u8> = row.get(0);
+            bincode::deserialize(&bytes).map_err(|e| DataManagerError::Corrupt(format!("Filter config of trip {} doesn't deserialize: {}", trip_id, e)))
+        }).transpose()
+    }
+
+    pub async fn set_filter_config(&self, trip_id: i64, config: &FilterConfig) -> Result<(), DataManagerError> {
+        query(concatcp!("INSERT INTO ", FILTER_CONFIGS_TABLE_NAME, "(", TRIP_ID, ", ", FILTER_CONFIG, ") VALUES (?1, ?2)
+            ON CONFLICT(", TRIP_ID, ") DO UPDATE SET ", FILTER_CONFIG, " = excluded.", FILTER_CONFIG))
+            .bind(trip_id)
+            .bind(bincode::serialize(config).unwrap())
+            .execute(&self.pool).await
+            .map_err(DataManagerError::storage("Failed to set filter config"))
+            .map(|_| ())
+    }
+
+    /// Removes the trip's filter config, so it uses the default filter.
+    pub async fn clear_filter_config(&self, trip_id: i64) -> Result<(), DataManagerError> {
+        query(concatcp!("DELETE FROM ", FILTER_CONFIGS_TABLE_NAME, " WHERE ", TRIP_ID, " = ?1"))
+            .bind(trip_id)
+            .execute(&self.pool).await
+            .map_err(DataManagerError::storage("Failed to clear filter config"))
+            .map(|_| ())
+    }
+
+    /// Replaces the stored stops of the session.
+    pub async fn set_session_stops(&self, session_id: i64, stops: &[Stop]) -> Result<(), DataManagerError> {
+        let mut transaction = self.pool.begin().await
+            .map_err(DataManagerError::storage("Failed to begin transaction"))?;
+
+        query(concatcp!("DELETE FROM ", STOPS_TABLE_NAME, " WHERE ", SESSION_ID, " = ?1"))
+            .bind(session_id)
+            .execute(&mut *transaction).await
+            .map_err(DataManagerError::storage("Failed to clear stops"))?;
+
+        for stop in stops {
+            query(concatcp!("INSERT INTO ", STOPS_TABLE_NAME, "(",
+                SESSION_ID, ", ", LATITUDE, ", ", LONGITUDE, ", ", ARRIVAL, ", ", DEPARTURE, ", ", POINT_COUNT,
+                ") VALUES (?1, ?2, ?3, ?4, ?5, ?6)"))
+                .bind(session_id)
+                .bind(stop.latitude)
+                .bind(stop.longitude)
+                .bind(stop.arrival)
+                .bind(stop.departure)
+                .bind(stop.point_count as i64)
+                .execute(&mut *transaction).await
+                .map_err(DataManagerError::storage("Failed to insert stop"))?;
+        }
+
+        transaction.commit().await
+            .map_err(DataManagerError::storage("Failed to commit stops"))
+    }
+
+    /// Stored stops of the trip's sessions that aren't hidden, in order of arrival.
+    pub async fn get_trip_stops(&self, trip_id: i64) -> Result<Vec<Stop>, DataManagerError> {
+        query(concatcp!("SELECT t.", LATITUDE, ", t.", LONGITUDE, ", t.", ARRIVAL, ", t.", DEPARTURE, ", t.", POINT_COUNT,
+            " FROM ", STOPS_TABLE_NAME, " t JOIN ", TRACK_SESSIONS_TABLE_NAME, " s ON t.", SESSION_ID, " = s.", SESSION_ID,
+            " WHERE s.", TRIP_ID, " = ?1 AND s.", HIDDEN, " = false ORDER BY t.", ARRIVAL))
+            .bind(trip_id)
+            .fetch_all(&self.pool).await
+            .map_err(DataManagerError::storage("Failed to get stops"))
+            .map(|rows| rows.into_iter()
+                .map(|row| Stop {
+                    latitude: row.get(0),
+                    longitude: row.get(1),
+                    arrival: row.get(2),
+                    departure: row.get(3),
+                    point_count: row.get::<i64, _>(4) as usize,
+                    utc_offset: None,
+                }).collect()
+            )
+    }
+}
+
+async fn insert_session_row(connection: &mut SqliteConnection, trip_id: i64, title: &str, description: &str, start_time: DateTime<Utc>, active: bool) -> Result<i64, sqlx::Error> {
+    query_as::<_, (i64,)>(concatcp!("
+        INSERT INTO ", TRACK_SESSIONS_TABLE_NAME,
+        "(", SESSION_ID, ", ", TRIP_ID, ", ", TITLE, ", ", DESCRIPTION, ", ", TIMESTAMP, ", ", ACTIVE, ", ", TRACK_POINTS, ", ", HIDDEN, ")
+        VALUES (NULL, ?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING ", SESSION_ID))
+            .bind(trip_id)
+            .bind(title)
+            .bind(description)
+            .bind(start_time)
+            .bind(active)
+            .bind(Vec::new())
+            .bind(false)
+            .fetch_one(&mut *connection).await
+            .map(|row| row.0)
+}
+
+/// Writes the points into chunks of `TRACK_CHUNK_SIZE`, numbered from `first_seq`, replacing chunks that exist.
+pub(super) async fn insert_track_chunks(connection: &mut SqliteConnection, session_id: i64, first_seq: i64, track_points: &[TrackPoint]) -> Result<(), sqlx::Error> {
+    for (i, chunk) in track_points.chunks(TRACK_CHUNK_SIZE).enumerate() {
+        // Points may be out of order, so the bounds cover all of them, and times are encoded from the earliest
+        let start_time = chunk.iter().map(|p| p.timestamp).min().unwrap();
+        let end_time = chunk.iter().map(|p| p.timestamp).max().unwrap();
+        query(concatcp!("INSERT OR REPLACE INTO ", TRACK_CHUNKS_TABLE_NAME, "(",
+            SESSION_ID, ", ", CHUNK_SEQ, ", ", START_TIME, ", ", END_TIME, ", ", POINT_COUNT, ", ", TRACK_POINTS,
+            ") VALUES (?1, ?2, ?3, ?4, ?5, ?6)"))
+            .bind(session_id)
+            .bind(first_seq + i as i64)
+            .bind(start_time)
+            .bind(end_time)
+            .bind(chunk.len() as i64)
+            .bind(write_tsf(start_time, chunk, RecordLayout::Compressed))
+            .execute(&mut *connection).await?;
+    }
+    Ok(())
+}
+
+async fn replace_track_points(connection: &mut SqliteConnection, session_id: i64, track_points: &[TrackPoint]) -> Result<(), DataManagerError> {
+    query(concatcp!("DELETE FROM ", TRACK_CHUNKS_TABLE_NAME, " WHERE ", SESSION_ID, " = ?1"))
+        .bind(session_id)
+        .execute(&mut *connection).await
+        .map_err(DataManagerError::storage("Failed to clear session track points"))?;
+
+    insert_track_chunks(connection, session_id, 0, track_points).await
+        .map_err(DataManagerError::storage("Failed to set session track points"))?;
+    set_session_statistics(connection, session_id, &SessionStatistics::from_points(track_points)).await
+        .map_err(DataManagerError::storage("Failed to set session statistics"))
+}
+
+/// Stores the statistics of the session, so they are read without reading its points.
+pub(super) async fn set_session_statistics(connection: &mut SqliteConnection, session_id: i64, statistics: &SessionStatistics) -> Result<(), sqlx::Error> {
+    query(concatcp!("UPDATE ", TRACK_SESSIONS_TABLE_NAME, " SET ", STATISTICS, " = ?1 WHERE ", SESSION_ID, " = ?2"))
+        .bind(bincode::serialize(statistics).unwrap())
+        .bind(session_id)
+        .execute(&mut *connection).await
+        .map(|_| ())
+}
+
+fn parse_chunk(bytes: &[u8]) -> Result<Vec<TrackPoint>, DataManagerError> {
+    parse_tsf(bytes)
+        .map(|(track_points, _)| track_points)
+        .map_err(|e| DataManagerError::Corrupt(format!("Track chunk doesn't parse: {}", e)))
 }
 
 async fn get_ip_info(ip: String) -> Result<IpInfo, DataManagerError> {
+    let failed = |message: String| DataManagerError::Io { context: "Failed to get IP info".to_string(), source: std::io::Error::other(message) };
+
     let response = reqwest::get(format!("http://ip-api.com/json/{}", ip))
         .await
-        .map_err(|_| DataManagerError::Database("Failed to get IP info".to_string()))?;
+        .map_err(|e| failed(e.to_string()))?;
 
     let response = response.text()
         .await
-        .map_err(|_| DataManagerError::Database("Failed to get IP info".to_string()))?;
+        .map_err(|e| failed(e.to_string()))?;
 
-    let mut json = json::parse(&response).map_err(|_| DataManagerError::Database("Failed to parse IP info".to_string()))?;
+    let mut json = json::parse(&response).map_err(|e| failed(e.to_string()))?;
 
     let country = json.remove("country");
     let Some(country) = country.as_str() else {
-        return Err(DataManagerError::Database("Failed to get country code".to_string()));
+        return Err(failed("No country code".to_string()));
     };
 
     let Some(latitude) = json.remove("lat").as_f64() else {
-        return Err(DataManagerError::Database("Failed to get latitude".to_string()));
+        return Err(failed("No latitude".to_string()));
     };
 
     let Some(longitude) = json.remove("lon").as_f64() else {
-        return Err(DataManagerError::Database("Failed to get longitude".to_string()));
+        return Err(failed("No longitude".to_string()));
     };
 
     Ok(IpInfo {
@@ -374,4 +753,96 @@ async fn get_ip_info(ip: String) -> Result<IpInfo, DataManagerError> {
         latitude: latitude as f32,
         longitude: longitude as f32,
     })
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn append_to_chunks() {
+    use chrono::TimeDelta;
+
+    let db = TripDatabase::open(&DatabaseLocation::InMemory).await.unwrap();
+    db.migrate().await.unwrap();
+
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let track_points = (0..2500).map(|i| TrackPoint::new(start + TimeDelta::seconds(i), 55., 10. + i as f64 * 1e-4, 0., 36., true)).collect::<Vec<_>>();
+    let trip = db.insert_trip("Tour de Lada".into(), String::new(), start, "token".into()).await.unwrap();
+    let session = db.insert_track_session(trip.trip_id, "Day 1".into(), String::new(), start, false).await.unwrap();
+
+    // Batches that fill the last chunk and spill over into new ones
+    for batch in track_points.chunks(700) {
+        db.append_track_points(session.session_id, batch).await.unwrap();
+    }
+    let chunk_sizes = query_as::<_, (i64,)>(concatcp!("SELECT ", POINT_COUNT, " FROM ", TRACK_CHUNKS_TABLE_NAME, " ORDER BY ", CHUNK_SEQ))
+        .fetch_all(&db.pool).await.unwrap();
+    assert_eq!(chunk_sizes, vec![(1000,), (1000,), (500,)]);
+    assert_eq!(db.track_point_count(session.session_id).await.unwrap(), 2500);
+
+    let timestamps = |points: Vec<TrackPoint>| points.iter().map(|p| p.timestamp).collect::<Vec<_>>();
+    let expected = timestamps(track_points.clone());
+    assert_eq!(timestamps(db.get_track_points(session.session_id).await.unwrap()), expected);
+    assert_eq!(timestamps(db.get_track_points_since(session.session_id, track_points[1999].timestamp).await.unwrap()), expected[2000..]);
+    assert_eq!(timestamps(db.get_track_points_since(session.session_id, track_points[1500].timestamp).await.unwrap()), expected[1501..]);
+    assert!(db.get_track_points_since(session.session_id, track_points[2499].timestamp).await.unwrap().is_empty());
+
+    db.set_session_track_points(session.session_id, track_points[..10].to_vec()).await.unwrap();
+    assert_eq!(db.track_point_count(session.session_id).await.unwrap(), 10);
+    assert_eq!(db.get_session(session.session_id).await.unwrap().statistics.point_count, 10);
+}
+
+#[tokio::test]
+async fn split_is_all_or_nothing() {
+    use chrono::TimeDelta;
+
+    let db = TripDatabase::open(&DatabaseLocation::InMemory).await.unwrap();
+    db.migrate().await.unwrap();
+
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let track_points = (0..20).map(|i| TrackPoint::new(start + TimeDelta::seconds(i), 55., 10., 0., 36., true)).collect::<Vec<_>>();
+    let parts = vec![track_points[..10].to_vec(), track_points[10..].to_vec()];
+    let trip = db.insert_trip("Tour de Lada".into(), String::new(), start, "token".into()).await.unwrap();
+    let session = db.insert_track_session(trip.trip_id, "Day 1".into(), String::new(), start, false).await.unwrap();
+
+    // The session to hide doesn't exist, so none of the parts are kept
+    let missing = TrackSession { session_id: 42, ..session.clone() };
+    assert!(matches!(db.insert_split_sessions(&missing, &parts).await, Err(DataManagerError::NotFound(Entity::Session, 42))));
+    assert_eq!(db.get_trip_sessions(trip.trip_id).await.unwrap().len(), 1);
+
+    let sessions = db.insert_split_sessions(&session, &parts).await.unwrap();
+    assert_eq!(sessions.iter().map(|s| s.title.as_str()).collect::<Vec<_>>(), ["Day 1 (1/2)", "Day 1 (2/2)"]);
+    assert_eq!(db.get_nonhidden_trip_session_ids(trip.trip_id).await.unwrap(), sessions.iter().map(|s| s.session_id).collect::<Vec<_>>());
+    let stored = db.get_track_points(sessions[1].session_id).await.unwrap();
+    assert_eq!(stored.iter().map(|p| p.timestamp).collect::<Vec<_>>(), parts[1].iter().map(|p| p.timestamp).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn trips_with_countries() {
+    let db = TripDatabase::open(&DatabaseLocation::InMemory).await.unwrap();
+    db.migrate().await.unwrap();
+
+    let trip = db.insert_trip("Tour de Lada".into(), String::new(), Utc::now(), "token".into()).await.unwrap();
+    db.set_trip_countries(trip.trip_id, vec!["AM".into(), "GE".into()]).await.unwrap();
+    let trips = db.get_trips().await.unwrap();
+    assert_eq!(trips, vec![db.get_trip(trip.trip_id).await.unwrap()]);
+    assert_eq!(trips[0].country_list, ["AM", "GE"]);
+}
+
+#[tokio::test]
+async fn out_of_order_chunk() {
+    use chrono::TimeDelta;
+
+    let db = TripDatabase::open(&DatabaseLocation::InMemory).await.unwrap();
+    db.migrate().await.unwrap();
+
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let trip = db.insert_trip("Tour de Lada".into(), String::new(), start, "token".into()).await.unwrap();
+    let session = db.insert_track_session(trip.trip_id, "Day 1".into(), String::new(), start, false).await.unwrap();
+
+    // A point recorded before the first one, e.g. sent late by the tracker
+    let track_points = [0, 10, -30, 20].map(|seconds| TrackPoint::new(start + TimeDelta::seconds(seconds), 55., 10., 0., 36., true));
+    db.set_session_track_points(session.session_id, track_points.to_vec()).await.unwrap();
+
+    let stored = db.get_track_points(session.session_id).await.unwrap();
+    assert_eq!(stored.iter().map(|p| p.timestamp).collect::<Vec<_>>(), track_points.iter().map(|p| p.timestamp).collect::<Vec<_>>());
+    // The late point isn't new, though it comes after the new ones
+    let since = db.get_track_points_since(session.session_id, start + TimeDelta::seconds(5)).await.unwrap();
+    assert_eq!(since.iter().map(|p| p.timestamp).collect::<Vec<_>>(), [track_points[1].timestamp, track_points[3].timestamp]);
+}
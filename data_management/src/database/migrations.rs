@@ -0,0 +1,382 @@
+use chrono::{DateTime, Utc};
+use const_format::concatcp;
+use sqlx::{query, query_as, Executor, Row, SqliteConnection};
+use trip_tracker_lib::{track_session::SessionStatistics, tsf::parse_tsf};
+
+use crate::DataManagerError;
+
+use super::{constants::*, db::{insert_track_chunks, set_session_statistics, TripDatabase}};
+
+/// A change to the schema, applied once in order of its version.
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub steps: &'static [Step],
+}
+
+pub enum Step {
+    /// Statements that are run as they are
+    Sql(&'static str),
+    /// Adds the column unless the table has it already, as databases from before migrations may have
+    AddColumn { table: &'static str, column: &'static str, definition: &'static str },
+    /// Moves the track points of every session from its BLOB into chunks, and empties the BLOB
+    ChunkTrackPoints,
+    /// Computes the statistics of every session from its chunks
+    StoreStatistics,
+}
+
+impl Step {
+    pub fn sql(&self) -> String {
+        match self {
+            Self::Sql(sql) => sql.trim().to_string(),
+            Self::AddColumn { table, column, definition } => format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, definition),
+            Self::ChunkTrackPoints => format!("-- Split the {} of every session into {} chunks", TRACK_POINTS, TRACK_CHUNKS_TABLE_NAME),
+            Self::StoreStatistics => format!("-- Compute the {} of every session from its {}", STATISTICS, TRACK_CHUNKS_TABLE_NAME),
+        }
+    }
+}
+
+/// Every migration, in order. Migrations must never change once released, so changes go in a new migration.
+/// Tables are created if they don't exist, as databases from before migrations have some of them already.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Trips, sessions and site traffic",
+        steps: &[Step::Sql(concatcp!("
+            CREATE TABLE IF NOT EXISTS ", TRIPS_TABLE_NAME, "(",
+                TRIP_ID,      " INTEGER PRIMARY KEY AUTOINCREMENT,",
+                TIMESTAMP,    " TIMESTAMP NOT NULL,",
+                TITLE,        " TEXT NOT NULL,",
+                DESCRIPTION,  " TEXT,",
+                API_TOKEN,    " TEXT NOT NULL,",
+                COUNTRY_LIST, " BLOB NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS ", TRACK_SESSIONS_TABLE_NAME, "(",
+                SESSION_ID,   " INTEGER PRIMARY KEY AUTOINCREMENT,",
+                TRIP_ID,      " INTEGER NOT NULL,",
+                TITLE,        " TEXT NOT NULL,",
+                DESCRIPTION,  " TEXT,",
+                TIMESTAMP,    " TIMESTAMP NOT NULL,",
+                ACTIVE,       " BOOLEAN NOT NULL,",
+                TRACK_POINTS, " BLOB NOT NULL, ",
+                HIDDEN,       " BOOLEAN NOT NULL,
+                FOREIGN KEY(", TRIP_ID, ") REFERENCES ", TRIPS_TABLE_NAME, "(", TRIP_ID, ") ON DELETE CASCADE
+            );
+
+            CREATE TABLE IF NOT EXISTS ", VISIT_TABLE, "(",
+                VISIT_ID,   " INTEGER PRIMARY KEY AUTOINCREMENT,",
+                IP_ADDRESS, " TEXT NOT NULL,",
+                TIMESTAMP,  " TIMESTAMP NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS ", IP_INFO_TABLE_NAME, "(",
+                IP_ADDRESS, " TEXT PRIMARY KEY,",
+                COUNTRY,    " TEXT NOT NULL,",
+                LATITUDE,   " REAL NOT NULL,",
+                LONGITUDE,  " REAL NOT NULL
+            );
+        "))],
+    },
+    Migration {
+        version: 2,
+        description: "Tracker telemetry",
+        steps: &[Step::Sql(concatcp!("
+            CREATE TABLE IF NOT EXISTS ", TELEMETRY_TABLE_NAME, "(",
+                TELEMETRY_ID,     " INTEGER PRIMARY KEY AUTOINCREMENT,",
+                SESSION_ID,       " INTEGER NOT NULL,",
+                TIMESTAMP,        " TIMESTAMP NOT NULL,",
+                BATTERY_PERCENT,  " INTEGER,",
+                POWER_SOURCE,     " INTEGER NOT NULL,",
+                RSSI,             " INTEGER,",
+                BER,              " INTEGER,",
+                SATELLITES,       " INTEGER,",
+                SATELLITES_USED,  " INTEGER,",
+                FREE_STORAGE_KIB, " INTEGER,",
+                UPTIME_SECS,      " INTEGER NOT NULL,",
+                FIRMWARE_VERSION, " TEXT NOT NULL,
+                FOREIGN KEY(", SESSION_ID, ") REFERENCES ", TRACK_SESSIONS_TABLE_NAME, "(", SESSION_ID, ") ON DELETE CASCADE
+            );
+        "))],
+    },
+    Migration {
+        version: 3,
+        description: "Outlier filter per trip",
+        steps: &[Step::Sql(concatcp!("
+            CREATE TABLE IF NOT EXISTS ", FILTER_CONFIGS_TABLE_NAME, "(",
+                TRIP_ID,       " INTEGER PRIMARY KEY,",
+                FILTER_CONFIG, " BLOB NOT NULL,
+                FOREIGN KEY(", TRIP_ID, ") REFERENCES ", TRIPS_TABLE_NAME, "(", TRIP_ID, ") ON DELETE CASCADE
+            );
+        "))],
+    },
+    Migration {
+        version: 4,
+        description: "Stops of sessions",
+        steps: &[Step::Sql(concatcp!("
+            CREATE TABLE IF NOT EXISTS ", STOPS_TABLE_NAME, "(",
+                STOP_ID,     " INTEGER PRIMARY KEY AUTOINCREMENT,",
+                SESSION_ID,  " INTEGER NOT NULL,",
+                LATITUDE,    " REAL NOT NULL,",
+                LONGITUDE,   " REAL NOT NULL,",
+                ARRIVAL,     " TIMESTAMP NOT NULL,",
+                DEPARTURE,   " TIMESTAMP NOT NULL,",
+                POINT_COUNT, " INTEGER NOT NULL,
+                FOREIGN KEY(", SESSION_ID, ") REFERENCES ", TRACK_SESSIONS_TABLE_NAME, "(", SESSION_ID, ") ON DELETE CASCADE
+            );
+        "))],
+    },
+    Migration {
+        version: 5,
+        description: "Timezone of trips",
+        steps: &[Step::AddColumn { table: TRIPS_TABLE_NAME, column: UTC_OFFSET, definition: "INTEGER" }],
+    },
+    Migration {
+        version: 6,
+        description: "Status, visibility, planned dates and cover image of trips. Existing trips stay live and public",
+        steps: &[
+            Step::AddColumn { table: TRIPS_TABLE_NAME, column: PLANNED_START, definition: "DATE" },
+            Step::AddColumn { table: TRIPS_TABLE_NAME, column: PLANNED_END, definition: "DATE" },
+            Step::AddColumn { table: TRIPS_TABLE_NAME, column: STATUS, definition: "INTEGER NOT NULL DEFAULT 1" },
+            Step::AddColumn { table: TRIPS_TABLE_NAME, column: VISIBILITY, definition: "INTEGER NOT NULL DEFAULT 0" },
+            Step::AddColumn { table: TRIPS_TABLE_NAME, column: COVER_IMAGE, definition: "TEXT" },
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "Track points in chunks, so appending to a session doesn't rewrite all of its points",
+        steps: &[
+            Step::Sql(concatcp!("
+                CREATE TABLE IF NOT EXISTS ", TRACK_CHUNKS_TABLE_NAME, "(",
+                    SESSION_ID,   " INTEGER NOT NULL,",
+                    CHUNK_SEQ,    " INTEGER NOT NULL,",
+                    START_TIME,   " TIMESTAMP NOT NULL,",
+                    END_TIME,     " TIMESTAMP NOT NULL,",
+                    POINT_COUNT,  " INTEGER NOT NULL,",
+                    TRACK_POINTS, " BLOB NOT NULL,
+                    PRIMARY KEY(", SESSION_ID, ", ", CHUNK_SEQ, "),
+                    FOREIGN KEY(", SESSION_ID, ") REFERENCES ", TRACK_SESSIONS_TABLE_NAME, "(", SESSION_ID, ") ON DELETE CASCADE
+                );
+            ")),
+            Step::ChunkTrackPoints,
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "Statistics of sessions, so they are read without reading the track points",
+        steps: &[
+            Step::AddColumn { table: TRACK_SESSIONS_TABLE_NAME, column: STATISTICS, definition: "BLOB" },
+            Step::StoreStatistics,
+        ],
+    },
+];
+
+/// The version the database is at after every migration.
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// A migration, and when it was applied to the database if it has been.
+pub struct MigrationStatus {
+    pub migration: &'static Migration,
+    pub applied_at: Option<DateTime<Utc>>,
+}
+
+impl TripDatabase {
+    /// The version of the schema, which is 0 for databases from before migrations.
+    pub async fn schema_version(&self) -> Result<i64, DataManagerError> {
+        self.create_schema_version_table().await?;
+        query_as::<_, (Option<i64>,)>(concatcp!("SELECT MAX(", VERSION, ") FROM ", SCHEMA_VERSION_TABLE_NAME))
+            .fetch_one(&self.pool).await
+            .map_err(DataManagerError::storage("Failed to get schema version"))
+            .map(|row| row.0.unwrap_or(0))
+    }
+
+    /// Every migration, with when it was applied.
+    pub async fn migration_status(&self) -> Result<Vec<MigrationStatus>, DataManagerError> {
+        self.create_schema_version_table().await?;
+        let applied: Vec<(i64, DateTime<Utc>)> = query(concatcp!("SELECT ", VERSION, ", ", APPLIED_AT, " FROM ", SCHEMA_VERSION_TABLE_NAME))
+            .fetch_all(&self.pool).await
+            .map_err(DataManagerError::storage("Failed to get applied migrations"))?
+            .into_iter().map(|row| (row.get(0), row.get(1))).collect();
+
+        Ok(MIGRATIONS.iter()
+            .map(|migration| MigrationStatus {
+                migration,
+                applied_at: applied.iter().find(|(version, _)| *version == migration.version).map(|(_, applied_at)| *applied_at),
+            })
+            .collect())
+    }
+
+    /// The migrations that haven't been applied, in order.
+    pub async fn pending_migrations(&self) -> Result<Vec<&'static Migration>, DataManagerError> {
+        let version = self.schema_version().await?;
+        Ok(MIGRATIONS.iter().filter(|migration| migration.version > version).collect())
+    }
+
+    /// Applies the pending migrations in order, each in its own transaction, and returns them.
+    /// A migration that fails is rolled back, and the ones after it aren't applied.
+    pub async fn migrate(&self) -> Result<Vec<&'static Migration>, DataManagerError> {
+        let pending = self.pending_migrations().await?;
+        for migration in &pending {
+            let mut transaction = self.pool.begin().await
+                .map_err(DataManagerError::storage("Failed to begin transaction"))?;
+
+            for step in migration.steps {
+                apply_step(&mut transaction, step).await
+                    .map_err(DataManagerError::storage(format!("Migration {} failed", migration.version)))?;
+            }
+
+            query(concatcp!("INSERT INTO ", SCHEMA_VERSION_TABLE_NAME, "(", VERSION, ", ", DESCRIPTION, ", ", APPLIED_AT, ") VALUES (?1, ?2, ?3)"))
+                .bind(migration.version)
+                .bind(migration.description)
+                .bind(Utc::now())
+                .execute(&mut *transaction).await
+                .map_err(DataManagerError::storage(format!("Failed to record migration {}", migration.version)))?;
+
+            transaction.commit().await
+                .map_err(DataManagerError::storage(format!("Failed to commit migration {}", migration.version)))?;
+        }
+        Ok(pending)
+    }
+
+    async fn create_schema_version_table(&self) -> Result<(), DataManagerError> {
+        self.pool.execute(concatcp!("
+            CREATE TABLE IF NOT EXISTS ", SCHEMA_VERSION_TABLE_NAME, "(",
+                VERSION,     " INTEGER PRIMARY KEY,",
+                DESCRIPTION, " TEXT NOT NULL,",
+                APPLIED_AT,  " TIMESTAMP NOT NULL
+            );
+            ")).await
+            .map_err(DataManagerError::storage("Failed to create schema version table"))
+            .map(|_| ())
+    }
+}
+
+async fn apply_step(connection: &mut SqliteConnection, step: &Step) -> Result<(), sqlx::Error> {
+    if let Step::ChunkTrackPoints = step {
+        return chunk_track_points(connection).await;
+    }
+    if let Step::StoreStatistics = step {
+        return store_statistics(connection).await;
+    }
+    if let Step::AddColumn { table, column, .. } = step {
+        let exists = query("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")
+            .bind(table)
+            .bind(column)
+            .fetch_optional(&mut *connection).await?
+            .is_some();
+        if exists {
+            return Ok(());
+        }
+    }
+    connection.execute(step.sql().as_str()).await.map(|_| ())
+}
+
+async fn chunk_track_points(connection: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    let sessions: Vec<(i64, Vec<u8>)> = query_as(concatcp!("SELECT ", SESSION_ID, ", ", TRACK_POINTS, " FROM ", TRACK_SESSIONS_TABLE_NAME, " WHERE LENGTH(", TRACK_POINTS, ") > 0"))
+        .fetch_all(&mut *connection).await?;
+
+    for (session_id, bytes) in sessions {
+        let track_points = parse_tsf(&bytes).map_err(|e| sqlx::Error::Decode(Box::new(e)))?.0;
+        insert_track_chunks(connection, session_id, 0, &track_points).await?;
+        query(concatcp!("UPDATE ", TRACK_SESSIONS_TABLE_NAME, " SET ", TRACK_POINTS, " = x'' WHERE ", SESSION_ID, " = ?1"))
+            .bind(session_id)
+            .execute(&mut *connection).await?;
+    }
+    Ok(())
+}
+
+async fn store_statistics(connection: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    let sessions: Vec<(i64,)> = query_as(concatcp!("SELECT ", SESSION_ID, " FROM ", TRACK_SESSIONS_TABLE_NAME))
+        .fetch_all(&mut *connection).await?;
+
+    for (session_id,) in sessions {
+        let chunks: Vec<(Vec<u8>,)> = query_as(concatcp!("SELECT ", TRACK_POINTS, " FROM ", TRACK_CHUNKS_TABLE_NAME, " WHERE ", SESSION_ID, " = ?1 ORDER BY ", CHUNK_SEQ))
+            .bind(session_id)
+            .fetch_all(&mut *connection).await?;
+
+        let mut statistics = SessionStatistics::default();
+        for (bytes,) in chunks {
+            statistics.extend(&parse_tsf(&bytes).map_err(|e| sqlx::Error::Decode(Box::new(e)))?.0);
+        }
+        set_session_statistics(connection, session_id, &statistics).await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn upgrade_existing_database() {
+    use trip_tracker_lib::trip::{TripStatus, TripVisibility};
+
+    let db = TripDatabase::open(&crate::DatabaseLocation::InMemory).await.unwrap();
+
+    // A database created before migrations, with the first tables only and a trip in it
+    db.pool.execute(MIGRATIONS[0].steps[0].sql().as_str()).await.unwrap();
+    query(concatcp!("INSERT INTO ", TRIPS_TABLE_NAME, " VALUES (1, '2025-05-01T08:00:00Z', 'Tour de Lada', '', 'token', x'')"))
+        .execute(&db.pool).await.unwrap();
+    assert_eq!(db.schema_version().await.unwrap(), 0);
+    assert_eq!(db.pending_migrations().await.unwrap().len(), MIGRATIONS.len());
+
+    let applied = db.migrate().await.unwrap();
+    assert_eq!(applied.iter().map(|migration| migration.version).collect::<Vec<_>>(), (1..=latest_version()).collect::<Vec<_>>());
+    assert_eq!(db.schema_version().await.unwrap(), latest_version());
+    assert!(db.migration_status().await.unwrap().iter().all(|status| status.applied_at.is_some()));
+
+    let trip = db.get_trip(1).await.unwrap();
+    assert_eq!(trip.title, "Tour de Lada");
+    assert_eq!((trip.status, trip.visibility), (TripStatus::Live, TripVisibility::Public));
+
+    // Nothing left to apply
+    assert!(db.migrate().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn columns_added_before_migrations() {
+    let db = TripDatabase::open(&crate::DatabaseLocation::InMemory).await.unwrap();
+
+    // Timezones were added to existing databases before there were migrations
+    db.pool.execute(MIGRATIONS[0].steps[0].sql().as_str()).await.unwrap();
+    db.pool.execute(concatcp!("ALTER TABLE ", TRIPS_TABLE_NAME, " ADD COLUMN ", UTC_OFFSET, " INTEGER")).await.unwrap();
+
+    db.migrate().await.unwrap();
+    assert_eq!(db.schema_version().await.unwrap(), latest_version());
+    let trip = db.insert_trip("Tour de Lada".into(), String::new(), Utc::now(), "token".into()).await.unwrap();
+    assert_eq!(db.get_trip(trip.trip_id).await.unwrap(), trip);
+}
+
+#[tokio::test]
+async fn track_points_moved_into_chunks() {
+    use chrono::TimeDelta;
+    use trip_tracker_lib::{track_point::TrackPoint, tsf::{write_tsf, RecordLayout}};
+
+    let db = TripDatabase::open(&crate::DatabaseLocation::InMemory).await.unwrap();
+
+    // Sessions kept all of their points in one BLOB before version 7
+    for migration in &MIGRATIONS[..6] {
+        for step in migration.steps {
+            db.pool.execute(step.sql().as_str()).await.unwrap();
+        }
+    }
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let track_points = (0..2500).map(|i| TrackPoint::new(start + TimeDelta::seconds(i), 55., 10. + i as f64 * 1e-4, 0., 36., true)).collect::<Vec<_>>();
+    let trip = db.insert_trip("Tour de Lada".into(), String::new(), start, "token".into()).await.unwrap();
+    let session = db.insert_track_session(trip.trip_id, "Day 1".into(), String::new(), start, false).await.unwrap();
+    query(concatcp!("UPDATE ", TRACK_SESSIONS_TABLE_NAME, " SET ", TRACK_POINTS, " = ?1"))
+        .bind(write_tsf(start, &track_points, RecordLayout::Compressed))
+        .execute(&db.pool).await.unwrap();
+
+    db.migrate().await.unwrap();
+
+    let chunks = query_as::<_, (i64,)>(concatcp!("SELECT ", POINT_COUNT, " FROM ", TRACK_CHUNKS_TABLE_NAME, " ORDER BY ", CHUNK_SEQ))
+        .fetch_all(&db.pool).await.unwrap();
+    assert_eq!(chunks, vec![(1000,), (1000,), (500,)]);
+    let migrated = db.get_session(session.session_id).await.unwrap();
+    assert_eq!(migrated.track_points.iter().map(|p| p.timestamp).collect::<Vec<_>>(), track_points.iter().map(|p| p.timestamp).collect::<Vec<_>>());
+    assert_eq!(migrated.statistics.point_count, 2500);
+
+    // The BLOB is emptied, so the points aren't stored twice
+    let (bytes,) = query_as::<_, (Vec<u8>,)>(concatcp!("SELECT ", TRACK_POINTS, " FROM ", TRACK_SESSIONS_TABLE_NAME))
+        .fetch_one(&db.pool).await.unwrap();
+    assert!(bytes.is_empty());
+}
@@ -1,2 +1,3 @@
 pub mod db;
+pub mod migrations;
 mod constants;
\ No newline at end of file
@@ -0,0 +1,88 @@
+use std::fmt::Display;
+
+/// What a `DataManagerError::NotFound` was looking for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entity {
+    Trip,
+    Session,
+    /// The buffer of an active session, by the id of the session
+    Buffer,
+}
+
+impl Display for Entity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Trip => write!(f, "Trip"),
+            Self::Session => write!(f, "Session"),
+            Self::Buffer => write!(f, "Buffer of session"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DataManagerError {
+    /// The entity with the id doesn't exist
+    NotFound(Entity, i64),
+    /// The request doesn't fit the state of the data, like a live session on a finished trip
+    Conflict(String),
+    /// The request itself is wrong, like a file that doesn't parse
+    InvalidInput(String),
+    /// The database failed
+    Storage { context: String, source: sqlx::Error },
+    /// A file couldn't be read or written
+    Io { context: String, source: std::io::Error },
+    /// Stored data doesn't decode, like a track chunk or a buffer file
+    Corrupt(String),
+}
+
+impl Display for DataManagerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(entity, id) => write!(f, "{} {} was not found", entity, id),
+            Self::Conflict(message) | Self::InvalidInput(message) | Self::Corrupt(message) => write!(f, "{}", message),
+            Self::Storage { context, .. } | Self::Io { context, .. } => write!(f, "{}", context),
+        }
+    }
+}
+
+impl std::error::Error for DataManagerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Storage { source, .. } => Some(source),
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl DataManagerError {
+    /// The exit code of the CLI. Clap already exits with 2 for invalid arguments.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::NotFound(..) => 3,
+            Self::Conflict(_) => 4,
+            Self::InvalidInput(_) => 5,
+            Self::Corrupt(_) => 6,
+            Self::Storage { .. } | Self::Io { .. } => 7,
+        }
+    }
+
+    /// Maps a failed query to a storage error, with what was being done.
+    pub(crate) fn storage(context: impl Into<String>) -> impl FnOnce(sqlx::Error) -> Self {
+        let context = context.into();
+        move |source| Self::Storage { context, source }
+    }
+
+    /// Maps a failed query of a single entity, where no row means it doesn't exist.
+    pub(crate) fn lookup(entity: Entity, id: i64) -> impl FnOnce(sqlx::Error) -> Self {
+        move |source| match source {
+            sqlx::Error::RowNotFound => Self::NotFound(entity, id),
+            source => Self::Storage { context: format!("Failed to get {} {}", entity, id), source },
+        }
+    }
+
+    pub(crate) fn io(context: impl Into<String>) -> impl FnOnce(std::io::Error) -> Self {
+        let context = context.into();
+        move |source| Self::Io { context, source }
+    }
+}
@@ -0,0 +1,65 @@
+use std::{fs::File, io::BufReader, path::{Path, PathBuf}};
+
+use geojson::{Feature, GeoJson};
+use trip_tracker_lib::track_session::TrackSession;
+
+use crate::{DataManager, DataManagerError};
+
+impl DataManager {
+    /// Adds the track of a GeoJSON file in the GeoJSON directory of the data directory to the trip, as a single session.
+    pub async fn add_geojson_to_trip(&self, filename: &str, trip_id: i64, title: Option<&str>) -> Result<i64, DataManagerError> {
+        let track_session = read_geojson(&self.config.geojson_dir().join(filename))?;
+        let session_id = self.register_new_session(trip_id, title.unwrap_or(track_session.title.as_str()).into(), track_session.description).await?.session_id;
+        self.append_gps_points(session_id, &track_session.track_points).await?;
+        Ok(session_id)
+    }
+
+    /// The session as shown on the map, as a GeoJSON LineString.
+    pub async fn session_geojson(&self, session_id: i64) -> Result<Feature, DataManagerError> {
+        let (session, _) = self.get_filtered_session(session_id).await?;
+        Ok(session.to_geojson())
+    }
+
+    /// The visible sessions of the trip as shown on the map, as a GeoJSON MultiLineString.
+    pub async fn trip_geojson(&self, trip_id: i64) -> Result<Feature, DataManagerError> {
+        let mut trip = self.get_trip(trip_id).await?;
+        trip.api_token = String::new();
+
+        let mut sessions = Vec::new();
+        for session_id in self.get_nonhidden_trip_session_ids(trip_id).await? {
+            sessions.push(self.get_filtered_session(session_id).await?.0);
+        }
+        sessions.sort_by_key(|session| session.track_points.first().map(|p| p.timestamp));
+        Ok(trip.to_geojson(&sessions))
+    }
+
+    /// Writes the session, or the whole trip, to a file in the GeoJSON directory named after its title.
+    pub async fn export_geojson(&self, id: i64, whole_trip: bool) -> Result<PathBuf, DataManagerError> {
+        let (title, feature) = if whole_trip {
+            (self.get_trip(id).await?.title, self.trip_geojson(id).await?)
+        } else {
+            (self.get_session(id).await?.title, self.session_geojson(id).await?)
+        };
+
+        let path = self.config.geojson_dir().join(format!("{}.geojson", title));
+        std::fs::create_dir_all(self.config.geojson_dir())
+            .and_then(|_| std::fs::write(&path, GeoJson::Feature(feature).to_string()))
+            .map_err(DataManagerError::io(format!("Failed to write {:?}", path)))?;
+        Ok(path)
+    }
+}
+
+/// The session of the first LineString or MultiLineString feature in the file.
+pub fn read_geojson(path: &Path) -> Result<TrackSession, DataManagerError> {
+    let file = File::open(path).map_err(DataManagerError::io(format!("Failed to open {:?}", path)))?;
+    let geojson = GeoJson::from_reader(BufReader::new(file)).map_err(|e| DataManagerError::InvalidInput(format!("{:?} isn't GeoJSON: {}", path, e)))?;
+
+    let features = match geojson {
+        GeoJson::Feature(feature) => vec![feature],
+        GeoJson::FeatureCollection(collection) => collection.features,
+        GeoJson::Geometry(geometry) => vec![Feature::from(geometry)],
+    };
+    features.iter()
+        .find_map(|feature| TrackSession::from_geojson(feature).ok())
+        .ok_or_else(|| DataManagerError::InvalidInput(format!("No LineString or MultiLineString in {:?}", path)))
+}
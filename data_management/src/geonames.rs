@@ -1,11 +1,14 @@
-use std::{collections::HashMap, fs::File, io::BufReader, path::PathBuf};
+use std::{collections::HashMap, fs::File, io::BufReader, path::{Path, PathBuf}};
 
 use celes::Country;
 use geo::{point, Contains, Geometry};
 use geojson::{FeatureCollection, GeoJson};
 
-use crate::COUNTRY_FILE;
+use trip_tracker_lib::local_time::UtcOffset;
 
+use crate::{COUNTRY_FILE, TIMEZONE_FILE};
+
+/// Countries from the Natural Earth country borders. No point is in a country when the file is missing.
 pub struct CountryLookup {
     countries: HashMap<String, CountryFeature>,
 }
@@ -13,8 +16,14 @@ pub struct CountryLookup {
 impl CountryLookup {
     pub fn new() -> Self {
         let root: PathBuf = project_root::get_project_root().unwrap();
-        let path = root.join(COUNTRY_FILE);
-        let file = File::open(path).unwrap();
+        Self::open(&root.join(COUNTRY_FILE))
+    }
+
+    pub fn open(path: &Path) -> Self {
+        let Ok(file) = File::open(path) else {
+            tracing::warn!("No country file at {:?}, so countries won't be found", path);
+            return Self { countries: HashMap::new() };
+        };
         let reader = BufReader::new(file);
 
         let geojson = GeoJson::from_reader(reader).unwrap();
@@ -71,6 +80,52 @@ struct CountryFeature {
     polygon: Geometry,
 }
 
+/// Local offsets from the Natural Earth time zones, which have the offset in hours as their `zone` property.
+/// Places outside the zones, or every place when the file is missing, get their nautical time zone.
+pub struct TimezoneLookup {
+    zones: Vec<(UtcOffset, Geometry)>,
+}
+
+impl Default for TimezoneLookup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimezoneLookup {
+    pub fn new() -> Self {
+        let root: PathBuf = project_root::get_project_root().unwrap();
+        Self::open(&root.join(TIMEZONE_FILE))
+    }
+
+    pub fn open(path: &Path) -> Self {
+        let Ok(file) = File::open(path) else {
+            return Self { zones: Vec::new() };
+        };
+
+        let geojson = GeoJson::from_reader(BufReader::new(file)).unwrap();
+        let features = FeatureCollection::try_from(geojson).unwrap();
+
+        let zones = features.features.into_iter()
+            .filter_map(|feature| {
+                let offset = feature.property("zone")?.as_f64().and_then(UtcOffset::from_hours)?;
+                Some((offset, Geometry::try_from(feature.geometry?).ok()?))
+            })
+            .collect();
+
+        Self {
+            zones
+        }
+    }
+
+    pub fn get_offset(&self, lat: f64, lon: f64) -> UtcOffset {
+        let pt = point!(x: lon, y: lat);
+        self.zones.iter()
+            .find(|(_, polygon)| polygon.contains(&pt))
+            .map_or_else(|| UtcOffset::nautical(lon), |(offset, _)| *offset)
+    }
+}
+
 #[test]
 fn test_country_lookup() {
     let before_load = std::time::Instant::now();
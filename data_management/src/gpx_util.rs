@@ -1,16 +1,16 @@
-use std::{fs::File, io::BufWriter, str::FromStr, time::SystemTime};
+use std::{fs::File, io::BufWriter, path::{Path, PathBuf}, str::FromStr, time::SystemTime};
 
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
 use geo::Point;
 use gpx::{GpxVersion, Time, Track, TrackSegment, Waypoint};
 use time::OffsetDateTime;
-use trip_tracker_lib::{track_point::TrackPoint, track_session::TrackSession};
+use trip_tracker_lib::{track_point::{PointQuality, TrackPoint}, track_session::TrackSession};
 
 use crate::{DataManager, DataManagerError};
 
 impl DataManager {
     pub async fn add_gpx_standalone(&self, path: &str) -> Result<(i64, i64), DataManagerError> {
-        let track_session = crate::gpx_util::read_gpx(path);
+        let track_session = crate::gpx_util::read_gpx(&self.config.gpx_dir().join(path))?;
         let trip = self.register_new_trip(track_session.title.clone(), track_session.description.clone(), track_session.start_time).await?;
         let session_id = self.register_new_session(trip.trip_id, track_session.title, track_session.description).await?.session_id;
         self.append_gps_points(session_id, &track_session.track_points).await?;
@@ -18,17 +18,18 @@ impl DataManager {
     }
 
     pub async fn add_gpx_to_trip(&self, path: &str, trip_id: i64, title: Option<&str>) -> Result<i64, DataManagerError> {
-        let track_session = crate::gpx_util::read_gpx(path);
+        let track_session = crate::gpx_util::read_gpx(&self.config.gpx_dir().join(path))?;
         let session_id = self.register_new_session(trip_id, title.unwrap_or(track_session.title.as_str()).into(), String::new()).await?.session_id;
         self.append_gps_points(session_id, &track_session.track_points).await?;
         Ok(session_id)
     }
 
-    pub async fn export_gpx(self, session_id: i64) {
+    /// Writes the session to a file in the GPX directory named after its title.
+    pub async fn export_gpx(self, session_id: i64) -> Result<PathBuf, DataManagerError> {
         let mut gpx = gpx::Gpx::default();
         gpx.version = GpxVersion::Gpx11;
     
-        let session = self.get_session(session_id).await.unwrap();
+        let session = self.get_session(session_id).await?;
     
         let start_time: SystemTime = session.start_time.into();
         let start_time: OffsetDateTime = start_time.into();
@@ -46,6 +47,13 @@ impl DataManager {
             let time: SystemTime = p.timestamp.into();
             let time: OffsetDateTime = time.into();
             wp.time = Some(Time::from(time));
+            wp.elevation = Some(p.altitude as f64);
+            wp.speed = Some(p.speed_kph as f64 / 3.6); // GPX speed is in m/s
+            // GPX 1.1 has no course element, so that is not exported
+            wp.pdop = p.quality.pdop.map(|dop| dop as f64);
+            wp.hdop = p.quality.hdop.map(|dop| dop as f64);
+            wp.vdop = p.quality.vdop.map(|dop| dop as f64);
+            wp.sat = p.quality.satellites_used.map(|sats| sats as u64);
             segment.points.push(wp);
         });
     
@@ -53,19 +61,20 @@ impl DataManager {
         gpx.tracks.push(track);
     
         // Create file at path
-        let gpx_file = File::create(format!("../data/gpx/{}.gpx", session.title)).unwrap();
+        let path = self.config.gpx_dir().join(format!("{}.gpx", session.title));
+        let gpx_file = File::create(&path).map_err(DataManagerError::io(format!("Failed to create {:?}", path)))?;
         let buf = BufWriter::new(gpx_file);
     
         // Write to file
-        gpx::write(&gpx, buf).unwrap();
+        gpx::write(&gpx, buf).map_err(|e| DataManagerError::Io { context: format!("Failed to write {:?}", path), source: std::io::Error::other(e) })?;
+        Ok(path)
     }
 }
 
-pub fn read_gpx(filename: &str) -> TrackSession {
-    let file_path = project_root::get_project_root().unwrap().join("data").join("gpx").join(filename);
-    let file = std::fs::File::open(file_path).unwrap();
+pub fn read_gpx(path: &Path) -> Result<TrackSession, DataManagerError> {
+    let file = std::fs::File::open(path).map_err(DataManagerError::io(format!("Failed to open {:?}", path)))?;
     let reader = std::io::BufReader::new(file);
-    let gpx = gpx::read(reader).unwrap();
+    let gpx = gpx::read(reader).map_err(|e| DataManagerError::InvalidInput(format!("{:?} isn't GPX: {}", path, e)))?;
     
     let mut time = DateTime::from_timestamp(0, 0).unwrap();
 
@@ -76,7 +85,7 @@ pub fn read_gpx(filename: &str) -> TrackSession {
         }
 
         if let Some(t) = meta.time {
-            time = DateTime::from_str(&t.format().unwrap()).unwrap();
+            time = parse_time(&t)?;
         }
     }
 
@@ -84,9 +93,18 @@ pub fn read_gpx(filename: &str) -> TrackSession {
     for track in gpx.tracks {
         for segment in track.segments {
             for point in segment.points {
+                let quality = PointQuality {
+                    course: None,
+                    pdop: point.pdop.map(|dop| dop as f32),
+                    hdop: point.hdop.map(|dop| dop as f32),
+                    vdop: point.vdop.map(|dop| dop as f32),
+                    satellites: None,
+                    satellites_used: point.sat.map(|sats| sats.min(u8::MAX as u64) as u8),
+                };
+
                 let track_point = if let Some(time) = point.time {
                     TrackPoint::new(
-                        DateTime::from_str(&time.format().unwrap()).unwrap(),
+                        parse_time(&time)?,
                         point.point().0.y,
                         point.point().0.x,
                         0.,
@@ -103,12 +121,18 @@ pub fn read_gpx(filename: &str) -> TrackSession {
                         true,
                     )
                 };
-                track_points.push(track_point);
+                track_points.push(track_point.with_quality(quality));
             }
         }
     }
 
-    TrackSession::new(-1, 0, title, "".into(), time, false, track_points, false)
+    Ok(TrackSession::new(-1, 0, title, "".into(), time, false, track_points, false))
+}
+
+fn parse_time(time: &Time) -> Result<DateTime<Utc>, DataManagerError> {
+    time.format().ok()
+        .and_then(|time| DateTime::from_str(&time).ok())
+        .ok_or_else(|| DataManagerError::InvalidInput("GPX file has an invalid time".to_string()))
 }
 
 #[cfg(test)]
@@ -119,19 +143,20 @@ mod tests {
     
     // Lada trip demo
     #[tokio::test]
+    #[ignore = "imports the GPX files in data/gpx/demo, which aren't checked in"]
     async fn add_lada_demo() {
         //let root: PathBuf = project_root::get_project_root().unwrap();
         //let _ = fs::remove_file(root.join("data/database.db")).await;
 
         // Dynamically add all gpx files in the demo folder to the database in sorted order
-        let data_manager = DataManager::start().await.unwrap();
+        let (data_manager, _buffer_dir) = crate::test_data_manager().await;
 
         let trip_id = data_manager.register_new_trip("Lada trip demo".into(), 
                                     "Demo of the Trip Tracker site for UI development".into(), 
                                     DateTime::parse_from_str("2025 May 22 12:09:14.274 +0000", "%Y %b %d %H:%M:%S%.3f %z").unwrap().into())
                     .await.unwrap().trip_id;
 
-        let mut path_stream = fs::read_dir("../data/gpx/demo").await.unwrap();
+        let mut path_stream = fs::read_dir(data_manager.config.gpx_dir().join("demo")).await.unwrap();
 
         let mut paths = Vec::new();
         while let Some(entry) = path_stream.next_entry().await.unwrap() {
@@ -146,14 +171,15 @@ mod tests {
         }
 
         let session = data_manager.register_new_live_session(trip_id, "Live".into(), "description".into()).await.unwrap();
-        let trip = read_gpx("demo/live.gpx");
+        let trip = read_gpx(&data_manager.config.gpx_dir().join("demo/live.gpx")).unwrap();
         data_manager.append_gps_points(session.session_id, &trip.track_points).await.unwrap();
     }
 
     // Mols bjerge
     #[tokio::test]
+    #[ignore = "imports the GPX files in data/gpx/mols, which aren't checked in"]
     async fn add_mols_trip() {
-        let data_manager = DataManager::start().await.unwrap();
+        let (data_manager, _buffer_dir) = crate::test_data_manager().await;
         let (trip_id, _) = data_manager.add_gpx_standalone("mols/etape1.gpx").await.unwrap();
         data_manager.add_gpx_to_trip("mols/etape2.gpx", trip_id, None).await.unwrap();
         data_manager.add_gpx_to_trip("mols/etape3.gpx", trip_id, None).await.unwrap();
@@ -162,7 +188,7 @@ mod tests {
     // Misc
     #[tokio::test]
     async fn add_gpx() {
-        let data_manager = DataManager::start().await.unwrap();
+        let (data_manager, _buffer_dir) = crate::test_data_manager().await;
         let (trip_id, _) = data_manager.add_gpx_standalone("Yerevan_i_sol.gpx").await.unwrap();
 
         println!("created trip with id: {trip_id}")
@@ -170,8 +196,9 @@ mod tests {
 
     // Misc
     #[tokio::test]
+    #[ignore = "imports data/gpx/errors.gpx, which isn't checked in"]
     async fn add_error_gpx() {
-        let data_manager = DataManager::start().await.unwrap();
+        let (data_manager, _buffer_dir) = crate::test_data_manager().await;
         let (trip_id, _) = data_manager.add_gpx_standalone("errors.gpx").await.unwrap();
 
         println!("created trip with id: {trip_id}")
@@ -1,8 +1,9 @@
-use std::{collections::HashSet, time::Duration};
+use std::{collections::HashSet, error::Error, process::ExitCode, time::Duration};
 
-use chrono::{FixedOffset, TimeZone};
+use chrono::{NaiveDate, TimeDelta};
 use clap::{Parser, Subcommand};
-use data_management::{database::db::TripDatabase, geonames::CountryLookup, DataManager};
+use data_management::{database::{db::TripDatabase, migrations}, geonames::CountryLookup, split::SplitConfig, DataManager, DataManagerConfig, DataManagerError};
+use trip_tracker_lib::{filter::FilterConfig, local_time::UtcOffset, smooth::KalmanSmoother, track_session::SessionStatistics, trip::{TripStatus, TripVisibility}};
 
 #[derive(Parser)]
 #[command(name = "TripCLI")]
@@ -21,6 +22,36 @@ enum Commands {
         trip_id: i64,
         new_description: String,
     },
+    /// Set the timezone times of the trip are shown in, like UTC+4, or "local" for the local time where they were recorded
+    Ttz {
+        trip_id: i64,
+        timezone: String,
+    },
+    /// Set the status of a trip: planned, live, finished or archived
+    Tstatus {
+        trip_id: i64,
+        status: String,
+    },
+    /// Set who can see a trip: public, unlisted or private
+    Tvis {
+        trip_id: i64,
+        visibility: String,
+    },
+    /// Set the planned dates of a trip, like 2025-05-24. Dates that aren't given are cleared
+    Tdates {
+        trip_id: i64,
+        #[arg(long)]
+        start: Option<NaiveDate>,
+        #[arg(long)]
+        end: Option<NaiveDate>,
+    },
+    /// Set the cover image of a trip, or clear it when no image is given
+    Tcover {
+        trip_id: i64,
+        cover_image: Option<String>,
+    },
+    /// List all trips
+    Trips,
     /// Set the title of a session
     Stitl { session_id: i64, new_title: String },
     /// Set the description of a session
@@ -60,68 +91,227 @@ enum Commands {
     ExportGpx {
         session_id: i64,
     },
+    /// Add the track of a GeoJSON file in data/geojson to a trip as a new session
+    AddGeojson {
+        trip_id: i64,
+        geojson_file: String,
+        title: String,
+    },
+    /// Write a session, as shown on the map, to a GeoJSON file in data/geojson
+    ExportGeojson {
+        session_id: i64,
+        /// Export the whole trip with this id instead, with a line per visible session
+        #[arg(long)]
+        trip: bool,
+    },
     FixTime {
         session_id: i64,
     },
     TimeGap {
         session_id: i64,
-    }
+    },
+    /// List the points the trip's outlier filter drops from a session, and why
+    Filter {
+        session_id: i64,
+    },
+    /// Tune the outlier filter of a trip. Thresholds that aren't given keep their current value
+    SetFilter {
+        trip_id: i64,
+        /// Start from the default filter
+        #[arg(long)]
+        reset: bool,
+        #[arg(long)]
+        max_speed_kph: Option<f64>,
+        /// m/s²
+        #[arg(long)]
+        max_acceleration: Option<f64>,
+        #[arg(long)]
+        max_hdop: Option<f32>,
+        /// Metres
+        #[arg(long)]
+        max_jump: Option<f64>,
+        #[arg(long)]
+        max_spike_ratio: Option<f64>,
+        #[arg(long)]
+        require_good_precision: Option<bool>,
+        #[arg(long)]
+        remove_duplicates: Option<bool>,
+        #[arg(long)]
+        remove_out_of_order: Option<bool>,
+        /// Thresholds to turn off: speed, acceleration, hdop, jump or spike
+        #[arg(long, value_delimiter = ',')]
+        disable: Vec<String>,
+    },
+    /// Smooth away the GNSS jitter of a session, and show how it changes the distance
+    Smooth {
+        session_id: i64,
+        /// Skip the backward pass, smoothing every point with the points before it only
+        #[arg(long)]
+        forward_only: bool,
+        /// Store the smoothed points as a new session, and hide the original session
+        #[arg(long)]
+        apply: bool,
+    },
+    /// List the stops of a trip
+    Stops {
+        trip_id: i64,
+        /// Detect the stops of the trip's ended sessions again, replacing the stored stops
+        #[arg(long)]
+        detect: bool,
+    },
+    /// Split an ended session into several sessions at its gaps, and hide the original session
+    Split {
+        session_id: i64,
+        #[arg(long)]
+        max_time_gap_mins: Option<i64>,
+        /// Metres
+        #[arg(long)]
+        max_distance_gap: Option<f64>,
+        #[arg(long)]
+        max_stop_hours: Option<i64>,
+        #[arg(long)]
+        min_points: Option<usize>,
+        /// Gaps not to split at: time, distance or stop
+        #[arg(long, value_delimiter = ',')]
+        disable: Vec<String>,
+        /// Only show where the session would be split
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Check the buffer files of live sessions against the sessions in the database, like the server does when it starts
+    Fsck {
+        /// Repair what is inconsistent. Don't while the server is running
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Show which schema migrations have been applied to the database. Every other command applies them first
+    Migrate {
+        /// Apply the pending migrations
+        #[arg(long)]
+        run: bool,
+        /// Show the SQL of the pending migrations without applying them
+        #[arg(long, conflicts_with = "run")]
+        dry_run: bool,
+    },
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     let cli = Cli::parse();
 
-    let db = TripDatabase::connect().await.unwrap();
+    match run(&cli.command).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            let mut source = err.source();
+            while let Some(cause) = source {
+                eprintln!("Caused by: {}", cause);
+                source = cause.source();
+            }
+            ExitCode::from(err.exit_code())
+        },
+    }
+}
+
+async fn run(command: &Commands) -> Result<(), DataManagerError> {
+    if let Commands::Migrate { run, dry_run } = command {
+        return migrate(*run, *dry_run).await;
+    }
 
-    match &cli.command {
+    let data_config = DataManagerConfig::default();
+    let db = TripDatabase::connect(&data_config.database).await?;
+
+    match command {
         Commands::Ttitl { trip_id, new_title } => {
-            db.set_trip_title(*trip_id, new_title).await.unwrap();
+            db.set_trip_title(*trip_id, new_title).await?;
         },
         Commands::Tdesc {trip_id, new_description} => {
             db.set_trip_description(*trip_id, new_description)
-                .await
-                .unwrap();
+                .await?;
+        },
+        Commands::Ttz { trip_id, timezone } => {
+            let utc_offset = match timezone.as_str() {
+                "local" => None,
+                offset => Some(offset.parse::<UtcOffset>().map_err(|_| invalid("Timezones are offsets like UTC+4 or UTC-3:30"))?),
+            };
+            db.set_trip_utc_offset(*trip_id, utc_offset).await?;
+        },
+        Commands::Tstatus { trip_id, status } => {
+            let status: TripStatus = status.parse().map_err(|_| invalid("Status must be planned, live, finished or archived"))?;
+            db.set_trip_status(*trip_id, status).await?;
+        },
+        Commands::Tvis { trip_id, visibility } => {
+            let visibility: TripVisibility = visibility.parse().map_err(|_| invalid("Visibility must be public, unlisted or private"))?;
+            db.set_trip_visibility(*trip_id, visibility).await?;
+        },
+        Commands::Tdates { trip_id, start, end } => {
+            db.set_trip_planned_dates(*trip_id, *start, *end).await?;
+        },
+        Commands::Tcover { trip_id, cover_image } => {
+            db.set_trip_cover_image(*trip_id, cover_image.as_deref()).await?;
+        },
+        Commands::Trips => {
+            for trip in db.get_trips().await? {
+                let dates = match (trip.planned_start, trip.planned_end) {
+                    (None, None) => "-".to_string(),
+                    (start, end) => format!("{} - {}",
+                        start.map_or("?".to_string(), |date| date.to_string()),
+                        end.map_or("?".to_string(), |date| date.to_string()),
+                    ),
+                };
+                println!("{}\t{}\t{}\t{}\t{}", trip.trip_id, trip.status, trip.visibility, dates, trip.title);
+            }
         },
         Commands::Stitl {session_id, new_title} => {
             db.set_session_title(*session_id, new_title)
-                .await
-                .unwrap();
+                .await?;
         },
         Commands::Sdesc {session_id, new_description} => {
             db.set_session_description(*session_id, new_description)
-                .await
-                .unwrap();
+                .await?;
         },
         Commands::Hide { session_id } => {
-            let session = db.get_session(*session_id).await.unwrap();
+            let session = db.get_session(*session_id).await?;
             if !session.active {
-                db.set_session_hidden(*session_id, true).await.unwrap()
+                db.set_session_hidden(*session_id, true).await?
             }
         },
         Commands::Unhide { session_id } => {
-            db.set_session_hidden(*session_id, false).await.unwrap()
+            db.set_session_hidden(*session_id, false).await?
         },
         Commands::List { trip_id } => {
-            let trip = db.get_trip(*trip_id).await.unwrap();
+            let data_manager = DataManager::open(data_config).await?;
+            let trip = data_manager.get_trip(*trip_id).await?;
             println!("{}", trip.title);
-            let sessions = db.get_trip_sessions(*trip_id).await.unwrap();
+            let sessions = data_manager.get_trip_sessions(*trip_id).await?;
             for session in sessions {
                 let time_str = if session.track_points.len() > 0 {
                     let ts = session.track_points[0].timestamp;
-                    FixedOffset::east_opt(2 * 3600).unwrap().from_utc_datetime(&ts.naive_utc()).format("%d/%m/%Y %H:%M (UTC+2)").to_string()
+                    trip.display_offset(session.utc_offset).format(ts, "%d/%m/%Y %H:%M")
                 } else {
                     "-".to_string()
                 };
-                println!("{}\t{}\t{}\t{}\t{}", session.session_id, if session.active {"A"} else if session.hidden {"H"} else {"."}, session.distance(), time_str, session.title)
+                let statistics = &session.statistics;
+                println!("{}\t{}\t{:.1} km\t{}\t{} moving\t{:.0} km/h max\t+{:.0}/-{:.0} m\t{}\t{}",
+                    session.session_id,
+                    if session.active {"A"} else if session.hidden {"H"} else {"."},
+                    session.distance(),
+                    format_duration(statistics.total_time()),
+                    format_duration(statistics.moving_time()),
+                    statistics.max_speed_kph,
+                    statistics.elevation_gain,
+                    statistics.elevation_loss,
+                    time_str,
+                    session.title
+                )
             }
         },
         Commands::Combine {trip_id, session_id_1, session_id_2} => {
-            let session1 = db.get_session(*session_id_1).await.unwrap();
-            let session2 = db.get_session(*session_id_2).await.unwrap();
+            let session1 = db.get_session(*session_id_1).await?;
+            let session2 = db.get_session(*session_id_2).await?;
 
             if session1.active || session2.active {
-                panic!("Both sessions must be inactive to combine!")
+                return Err(DataManagerError::Conflict("Both sessions must be inactive to combine!".to_string()));
             }
 
             let mut track_points = Vec::new();
@@ -133,28 +323,28 @@ async fn main() {
                 track_points.extend(session1.track_points);
             }
 
-            let session = db.insert_track_session(*trip_id, session1.title.clone(), session1.description.clone(), session1.start_time.clone(), session1.active).await.unwrap();
-            db.set_session_track_points(session.session_id, track_points).await.unwrap();
+            let session = db.insert_track_session(*trip_id, session1.title.clone(), session1.description.clone(), session1.start_time, session1.active).await?;
+            db.set_session_track_points(session.session_id, track_points).await?;
 
-            db.set_session_hidden(*session_id_1, true).await.unwrap();
-            db.set_session_hidden(*session_id_2, true).await.unwrap();
+            db.set_session_hidden(*session_id_1, true).await?;
+            db.set_session_hidden(*session_id_2, true).await?;
         },
         Commands::ApiKey { trip_ip } => {
-            println!("{}", db.get_trip(*trip_ip).await.unwrap().api_token)
+            println!("{}", db.get_trip(*trip_ip).await?.api_token)
         },
         Commands::Ends { session_id } => {
-            db.set_session_active(*session_id, false).await.unwrap()
+            db.set_session_active(*session_id, false).await?
         },
         Commands::RedoCountries { trip_id } => {
             let mut countries = HashSet::new(); 
 
             let mut prev_country = None;
-            let ids = db.get_nonhidden_trip_session_ids(*trip_id).await.unwrap();
+            let ids = db.get_nonhidden_trip_session_ids(*trip_id).await?;
 
-            let country_lookup = CountryLookup::new();
+            let country_lookup = CountryLookup::open(&data_config.country_file);
 
             for id in ids {
-                let session = db.get_session(id).await.unwrap();
+                let session = db.get_session(id).await?;
                 for point in session.track_points {
                     let country = country_lookup.get_country(point.latitude, point.longitude, prev_country.clone());
                     if let Some(country) = &country {
@@ -166,18 +356,29 @@ async fn main() {
                 }
             }
 
-            db.set_trip_countries(*trip_id, countries.into_iter().collect()).await.unwrap();
+            db.set_trip_countries(*trip_id, countries.into_iter().collect()).await?;
         },
         Commands::AddGpx { trip_id, gpx_file, title } => {
-            let data_manager = DataManager::start().await.unwrap();
-            data_manager.add_gpx_to_trip(gpx_file, *trip_id, Some(title)).await.unwrap();
+            let data_manager = DataManager::open(data_config).await?;
+            data_manager.add_gpx_to_trip(gpx_file, *trip_id, Some(title)).await?;
         },
         Commands::ExportGpx { session_id } => {
-            let data_manager = DataManager::start().await.unwrap();
-            data_manager.export_gpx(*session_id).await;
+            let data_manager = DataManager::open(data_config).await?;
+            let path = data_manager.export_gpx(*session_id).await?;
+            println!("Wrote {}", path.display());
+        },
+        Commands::AddGeojson { trip_id, geojson_file, title } => {
+            let data_manager = DataManager::open(data_config).await?;
+            let session_id = data_manager.add_geojson_to_trip(geojson_file, *trip_id, Some(title)).await?;
+            println!("Added session {}", session_id);
+        },
+        Commands::ExportGeojson { session_id, trip } => {
+            let data_manager = DataManager::open(data_config).await?;
+            let path = data_manager.export_geojson(*session_id, *trip).await?;
+            println!("Wrote {}", path.display());
         },
         Commands::FixTime { session_id } => {
-            let session = db.get_session(*session_id).await.unwrap();
+            let session = db.get_session(*session_id).await?;
             
             let start_time = session.start_time;
             let point_time = session.track_points[0].timestamp;
@@ -185,13 +386,13 @@ async fn main() {
 
             let track_points = session.track_points.iter().map(|p| {let mut p = p.clone(); p.timestamp -= offset; p}).collect::<Vec<_>>();
 
-            let new_session = db.insert_track_session(session.trip_id, session.title.clone(), session.description.clone(), start_time, session.active).await.unwrap();
-            db.set_session_track_points(new_session.session_id, track_points).await.unwrap();
+            let new_session = db.insert_track_session(session.trip_id, session.title.clone(), session.description.clone(), start_time, session.active).await?;
+            db.set_session_track_points(new_session.session_id, track_points).await?;
 
-            db.set_session_hidden(*session_id, true).await.unwrap();
+            db.set_session_hidden(*session_id, true).await?;
         },
         Commands::TimeGap { session_id } => {
-            let session = db.get_session(*session_id).await.unwrap();
+            let session = db.get_session(*session_id).await?;
             
             let start_time = session.start_time;
             let point_time = session.track_points[0].timestamp;
@@ -201,8 +402,167 @@ async fn main() {
             println!("Point time: {}", point_time);
             println!("Offset: {:?}", Duration::from_millis(offset.num_milliseconds().abs() as u64));
             println!("Duration: {:?}", Duration::from_millis(session.track_points.last().unwrap().timestamp.signed_duration_since(session.track_points[0].timestamp).num_milliseconds().abs() as u64));
+        },
+        Commands::Filter { session_id } => {
+            let session = db.get_session(*session_id).await?;
+            let config = db.get_filter_config(session.trip_id).await?.unwrap_or_default();
+            let report = config.filter(&session.track_points);
+
+            for dropped in &report.dropped {
+                println!("{}\t{}\t{}", dropped.index, session.track_points[dropped.index], dropped.reason);
+            }
+            println!("Kept {} of {} points", report.kept.len(), session.track_points.len());
+            for (reason, count) in report.reason_counts() {
+                println!("{}\t{}", count, reason);
+            }
+        },
+        Commands::SetFilter { trip_id, reset, max_speed_kph, max_acceleration, max_hdop, max_jump, max_spike_ratio, require_good_precision, remove_duplicates, remove_out_of_order, disable } => {
+            let mut config = if *reset {
+                FilterConfig::default()
+            } else {
+                db.get_filter_config(*trip_id).await?.unwrap_or_default()
+            };
+
+            config.max_speed_kph = max_speed_kph.or(config.max_speed_kph);
+            config.max_acceleration = max_acceleration.or(config.max_acceleration);
+            config.max_hdop = max_hdop.or(config.max_hdop);
+            config.max_jump = max_jump.or(config.max_jump);
+            config.max_spike_ratio = max_spike_ratio.or(config.max_spike_ratio);
+            config.require_good_precision = require_good_precision.unwrap_or(config.require_good_precision);
+            config.remove_duplicates = remove_duplicates.unwrap_or(config.remove_duplicates);
+            config.remove_out_of_order = remove_out_of_order.unwrap_or(config.remove_out_of_order);
+
+            for stage in disable {
+                match stage.as_str() {
+                    "speed" => config.max_speed_kph = None,
+                    "acceleration" => config.max_acceleration = None,
+                    "hdop" => config.max_hdop = None,
+                    "jump" => config.max_jump = None,
+                    "spike" => config.max_spike_ratio = None,
+                    _ => return Err(invalid(format!("Unknown filter stage: {}", stage))),
+                }
+            }
+
+            db.set_filter_config(*trip_id, &config).await?;
+            println!("{:#?}", config);
+        },
+        Commands::Smooth { session_id, forward_only, apply } => {
+            let session = db.get_session(*session_id).await?;
+            let smoother = KalmanSmoother { backward_pass: !*forward_only, ..Default::default() };
+            let track_points = smoother.smooth(&session.track_points);
+
+            let statistics = SessionStatistics::from_points(&track_points);
+            println!("Distance: {:.1} km -> {:.1} km", session.statistics.distance / 1000., statistics.distance / 1000.);
+            println!("Moving: {} -> {}", format_duration(session.statistics.moving_time()), format_duration(statistics.moving_time()));
+
+            if *apply {
+                if session.active {
+                    return Err(DataManagerError::Conflict("The session must be inactive to store smoothed points!".to_string()));
+                }
+                let new_session = db.insert_track_session(session.trip_id, session.title.clone(), session.description.clone(), session.start_time, session.active).await?;
+                db.set_session_track_points(new_session.session_id, track_points).await?;
+                db.set_session_hidden(*session_id, true).await?;
+                println!("Smoothed session: {}", new_session.session_id);
+            }
+        },
+        Commands::Stops { trip_id, detect } => {
+            let mut data_manager = DataManager::open(data_config).await?;
+            // Smoothed like the server does, so the stops are the ones it detects
+            data_manager.set_smoother(Some(KalmanSmoother::default()));
+            if *detect {
+                data_manager.detect_trip_stops(*trip_id).await?;
+            }
+
+            let trip = data_manager.get_trip(*trip_id).await?;
+            for stop in data_manager.get_trip_stops(*trip_id).await? {
+                println!("{}\t{}\t({:.5}, {:.5})\t{} points",
+                    trip.display_offset(stop.utc_offset).format(stop.arrival, "%d/%m/%Y %H:%M"),
+                    format_duration(stop.duration()),
+                    stop.latitude,
+                    stop.longitude,
+                    stop.point_count
+                );
+            }
+        },
+        Commands::Split { session_id, max_time_gap_mins, max_distance_gap, max_stop_hours, min_points, disable, dry_run } => {
+            let mut config = SplitConfig::default();
+            config.max_time_gap = max_time_gap_mins.map(TimeDelta::minutes).or(config.max_time_gap);
+            config.max_distance_gap = max_distance_gap.or(config.max_distance_gap);
+            config.max_stop = max_stop_hours.map(TimeDelta::hours).or(config.max_stop);
+            config.min_points = min_points.unwrap_or(config.min_points);
+            for gap in disable {
+                match gap.as_str() {
+                    "time" => config.max_time_gap = None,
+                    "distance" => config.max_distance_gap = None,
+                    "stop" => config.max_stop = None,
+                    _ => return Err(invalid(format!("Unknown gap: {}", gap))),
+                }
+            }
+
+            if *dry_run {
+                let session = db.get_session(*session_id).await?;
+                for part in config.split(&session.track_points) {
+                    if let (Some(first), Some(last)) = (part.first(), part.last()) {
+                        println!("{}\t{}\t{} points", first.timestamp, format_duration(last.timestamp - first.timestamp), part.len());
+                    }
+                }
+            } else {
+                let data_manager = DataManager::open(data_config).await?;
+                for session in data_manager.split_session(*session_id, &config).await? {
+                    println!("{}\t{:.1} km\t{}", session.session_id, session.distance(), session.title);
+                }
+            }
+        },
+        Commands::Fsck { repair } => {
+            let data_manager = DataManager::open(data_config).await?;
+            let report = data_manager.reconcile(*repair).await?;
+            for inconsistency in &report.inconsistencies {
+                println!("{}", inconsistency);
+            }
+            if report.is_consistent() {
+                println!("Buffers and sessions are consistent");
+            } else if report.repaired {
+                println!("Repaired {} inconsistencies", report.inconsistencies.len());
+            } else {
+                return Err(DataManagerError::Conflict(format!("Found {} inconsistencies, run with --repair to repair them", report.inconsistencies.len())));
+            }
+        },
+        Commands::Migrate { .. } => unreachable!("Migrations are handled before connecting, which applies them"),
+    }
+
+    println!("Success!");
+    Ok(())
+}
+
+fn invalid(message: impl Into<String>) -> DataManagerError {
+    DataManagerError::InvalidInput(message.into())
+}
+
+async fn migrate(run: bool, dry_run: bool) -> Result<(), DataManagerError> {
+    let db = TripDatabase::open(&DataManagerConfig::default().database).await?;
+
+    if dry_run {
+        for migration in db.pending_migrations().await? {
+            println!("-- {}: {}", migration.version, migration.description);
+            for step in migration.steps {
+                println!("{}", step.sql());
+            }
+        }
+    } else if run {
+        for migration in db.migrate().await? {
+            println!("Applied {}: {}", migration.version, migration.description);
+        }
+    } else {
+        for status in db.migration_status().await? {
+            let applied = status.applied_at.map_or("pending".to_string(), |time| time.format("%d/%m/%Y %H:%M").to_string());
+            println!("{}\t{}\t{}", status.migration.version, applied, status.migration.description);
         }
     }
 
-    println!("Success!")
+    println!("Schema version {} of {}", db.schema_version().await?, migrations::latest_version());
+    Ok(())
+}
+
+fn format_duration(duration: TimeDelta) -> String {
+    format!("{:02}h {:02}m", duration.num_hours(), duration.num_minutes() % 60)
 }
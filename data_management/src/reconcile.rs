@@ -0,0 +1,199 @@
+use std::{fmt::Display, path::PathBuf};
+
+use crate::{DataManager, DataManagerError};
+
+/// A way the buffer files and the sessions in the database disagree, which a crash or a force ended session leaves behind.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inconsistency {
+    /// A buffer file that has no session id in its name or doesn't decode. It is moved to the quarantine directory
+    UnreadableBuffer(PathBuf),
+    /// A buffer file that ends in a partially written track point. The partial point is dropped
+    TruncatedBuffer { session_id: i64, bytes: usize },
+    /// An active session without a buffer. It is ended, keeping the points that were stored for it
+    ActiveWithoutBuffer(i64),
+    /// A buffer of an ended session. Its points replace the stored points when there are more of them, and the buffer is removed
+    EndedWithBuffer { session_id: i64, buffered_points: usize, stored_points: usize },
+    /// A buffer of a session that doesn't exist. It is moved to the quarantine directory
+    UnknownSession(i64),
+}
+
+impl Display for Inconsistency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnreadableBuffer(path) => write!(f, "Buffer file {:?} can't be loaded", path),
+            Self::TruncatedBuffer { session_id, bytes } => write!(f, "Buffer of session {} ends in {} bytes of a partially written point", session_id, bytes),
+            Self::ActiveWithoutBuffer(session_id) => write!(f, "Session {} is active but has no buffer", session_id),
+            Self::EndedWithBuffer { session_id, buffered_points, stored_points } =>
+                write!(f, "Session {} has ended but has a buffer with {} points, and {} stored points", session_id, buffered_points, stored_points),
+            Self::UnknownSession(session_id) => write!(f, "Session {} has a buffer but doesn't exist", session_id),
+        }
+    }
+}
+
+/// What `DataManager::reconcile` found, and whether it was repaired.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReconcileReport {
+    pub inconsistencies: Vec<Inconsistency>,
+    pub repaired: bool,
+}
+
+impl ReconcileReport {
+    pub fn is_consistent(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
+
+impl DataManager {
+    /// Checks the buffers against the active sessions in the database, and repairs what disagrees when `repair` is set.
+    /// Only the server should repair, as it owns the buffers while it runs.
+    pub async fn reconcile(&self, repair: bool) -> Result<ReconcileReport, DataManagerError> {
+        let mut inconsistencies = Vec::new();
+
+        for path in self.buffer_manager.unreadable_files().await {
+            inconsistencies.push(Inconsistency::UnreadableBuffer(path));
+        }
+        let mut truncated = self.buffer_manager.partial_records().await;
+        truncated.sort();
+        for (session_id, bytes) in truncated {
+            inconsistencies.push(Inconsistency::TruncatedBuffer { session_id, bytes });
+        }
+
+        let mut buffered = self.buffer_manager.session_ids().await;
+        buffered.sort();
+        for session_id in self.database.get_active_session_ids().await? {
+            if !buffered.contains(&session_id) {
+                inconsistencies.push(Inconsistency::ActiveWithoutBuffer(session_id));
+            }
+        }
+        for &session_id in &buffered {
+            match self.database.get_session_info(session_id).await {
+                Ok(session) if session.active => {},
+                Ok(_) => inconsistencies.push(Inconsistency::EndedWithBuffer {
+                    session_id,
+                    buffered_points: self.buffer_manager.track_point_count(session_id).await?,
+                    stored_points: self.database.track_point_count(session_id).await?,
+                }),
+                Err(DataManagerError::NotFound(..)) => inconsistencies.push(Inconsistency::UnknownSession(session_id)),
+                Err(e) => return Err(e),
+            }
+        }
+
+        if repair {
+            for inconsistency in &inconsistencies {
+                self.repair(inconsistency).await?;
+            }
+        }
+
+        Ok(ReconcileReport { inconsistencies, repaired: repair })
+    }
+
+    async fn repair(&self, inconsistency: &Inconsistency) -> Result<(), DataManagerError> {
+        match inconsistency {
+            Inconsistency::UnreadableBuffer(path) => {
+                self.buffer_manager.quarantine_file(path).await?;
+            },
+            Inconsistency::TruncatedBuffer { session_id, .. } => {
+                self.buffer_manager.drop_partial_record(*session_id).await?;
+            },
+            Inconsistency::ActiveWithoutBuffer(session_id) => {
+                self.database.set_session_active(*session_id, false).await?;
+                self.detect_stops(*session_id).await?;
+            },
+            Inconsistency::EndedWithBuffer { session_id, buffered_points, stored_points } => {
+                // Stored before the buffer is removed, so the points are kept if storing them fails
+                if buffered_points > stored_points {
+                    let points = self.buffer_manager.read_all_track_points(*session_id).await?;
+                    self.database.set_session_track_points(*session_id, points).await?;
+                    self.detect_stops(*session_id).await?;
+                }
+                self.buffer_manager.close_session(*session_id).await?;
+            },
+            Inconsistency::UnknownSession(session_id) => {
+                self.buffer_manager.quarantine_session(*session_id).await?;
+            },
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn repair_after_crash() {
+    use std::io::Write;
+    use trip_tracker_lib::track_point::TrackPoint;
+
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = crate::DataManagerConfig::in_dir(data_dir.path());
+    let start = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let points = (0..10).map(|i| TrackPoint::new(start + chrono::TimeDelta::seconds(i), 40.18, 44.51, 0., 0., true)).collect::<Vec<_>>();
+
+    let dm = DataManager::start(config.clone()).await.unwrap();
+    let trip = dm.register_new_trip("Tour de Lada 2025".into(), String::new(), start).await.unwrap();
+    let live = dm.register_new_live_session(trip.trip_id, "Day 1".into(), String::new()).await.unwrap().session_id;
+    let force_ended = dm.register_new_live_session(trip.trip_id, "Day 2".into(), String::new()).await.unwrap().session_id;
+    dm.append_gps_points(live, &points).await.unwrap();
+    dm.append_gps_points(force_ended, &points).await.unwrap();
+    // Ended without storing its buffer, like the CLI's `ends`
+    dm.database.set_session_active(force_ended, false).await.unwrap();
+    // Registered, but the server stopped before its buffer was created
+    let unbuffered = dm.database.insert_track_session(trip.trip_id, "Day 3".into(), String::new(), start, true).await.unwrap().session_id;
+    drop(dm);
+
+    let live_file = config.buffer_dir.join(format!("{}_Day 1", live));
+    std::fs::copy(&live_file, config.buffer_dir.join("99_Ghost")).unwrap();
+    std::fs::OpenOptions::new().append(true).open(&live_file).unwrap().write_all(&[0]).unwrap();
+    std::fs::write(config.buffer_dir.join("notes.txt"), "").unwrap();
+    std::fs::write(config.buffer_dir.join("42_Garbage"), "garbage").unwrap();
+    let buffer_files = || {
+        let mut files = std::fs::read_dir(&config.buffer_dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_file())
+            .map(|path| (path.file_name().unwrap().to_owned(), std::fs::read(&path).unwrap()))
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    };
+    let before = buffer_files();
+
+    let dm = DataManager::open(config.clone()).await.unwrap();
+    let report = dm.reconcile(false).await.unwrap();
+    let mut unreadable = report.inconsistencies.iter()
+        .filter_map(|inconsistency| match inconsistency {
+            Inconsistency::UnreadableBuffer(path) => path.file_name().and_then(|name| name.to_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    unreadable.sort();
+    assert_eq!(unreadable, ["42_Garbage", "notes.txt"]);
+    assert_eq!(&report.inconsistencies[2..], [
+        Inconsistency::TruncatedBuffer { session_id: live, bytes: 1 },
+        Inconsistency::ActiveWithoutBuffer(unbuffered),
+        Inconsistency::EndedWithBuffer { session_id: force_ended, buffered_points: 10, stored_points: 0 },
+        Inconsistency::UnknownSession(99),
+    ]);
+    // Opening and checking leave the files as they were
+    assert_eq!(dm.reconcile(false).await.unwrap(), report);
+    assert_eq!(buffer_files(), before);
+    let live_length = std::fs::metadata(&live_file).unwrap().len();
+
+    let report = dm.reconcile(true).await.unwrap();
+    assert_eq!(report.inconsistencies.len(), 6);
+    assert!(dm.reconcile(false).await.unwrap().is_consistent());
+
+    assert_eq!(dm.get_session(live).await.unwrap().track_points.len(), 10);
+    assert_eq!(std::fs::metadata(&live_file).unwrap().len(), live_length - 1);
+    assert!(!dm.get_session(unbuffered).await.unwrap().active);
+    assert_eq!(dm.get_session(force_ended).await.unwrap().track_points.len(), 10);
+    let mut quarantined = std::fs::read_dir(config.buffer_dir.join(crate::buffer::buffer_manager::QUARANTINE_DIR)).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    quarantined.sort();
+    assert_eq!(quarantined, ["42_Garbage", "99_Ghost", "notes.txt"]);
+
+    // The live session carries on after a restart
+    drop(dm);
+    let dm = DataManager::start(config).await.unwrap();
+    assert!(dm.reconcile(false).await.unwrap().is_consistent());
+    let point = TrackPoint::new(start + chrono::TimeDelta::seconds(10), 40.18, 44.51, 0., 0., true);
+    dm.append_gps_points(live, &[point]).await.unwrap();
+    assert_eq!(dm.get_session(live).await.unwrap().track_points.len(), 11);
+}
@@ -0,0 +1,117 @@
+use chrono::TimeDelta;
+use trip_tracker_lib::{geo, stops::StopDetector, track_point::TrackPoint};
+
+/// Where a session is cut into several sessions. Gaps that are `None` are never cut at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SplitConfig {
+    /// Cut where no points were recorded for longer than this
+    pub max_time_gap: Option<TimeDelta>,
+    /// Cut where consecutive points are further apart than this, in metres, e.g. after a ferry with no fix
+    pub max_distance_gap: Option<f64>,
+    /// Cut after stops longer than this. The stop stays with the part before it
+    pub max_stop: Option<TimeDelta>,
+    /// Cuts that would leave a part with fewer points than this are skipped
+    pub min_points: usize,
+}
+
+impl Default for SplitConfig {
+    fn default() -> Self {
+        Self {
+            max_time_gap: Some(TimeDelta::hours(3)),
+            max_distance_gap: Some(5000.),
+            max_stop: Some(TimeDelta::hours(8)),
+            min_points: 10,
+        }
+    }
+}
+
+impl SplitConfig {
+    /// Indices of the points that start a new part, in order. The first point is never included.
+    pub fn cuts(&self, track_points: &[TrackPoint]) -> Vec<usize> {
+        let mut candidates: Vec<usize> = (1..track_points.len())
+            .filter(|&i| {
+                let (previous, point) = (&track_points[i - 1], &track_points[i]);
+                self.max_time_gap.is_some_and(|max| point.timestamp - previous.timestamp > max)
+                    || self.max_distance_gap.is_some_and(|max| geo::distance(previous.position(), point.position()) > max)
+            })
+            .collect();
+
+        if let Some(max_stop) = self.max_stop {
+            let mut stop_cuts = Vec::new();
+            let mut first = 0;
+            for stop in StopDetector::default().detect(track_points) {
+                first += track_points[first..].iter().position(|p| p.timestamp == stop.arrival).unwrap_or(0);
+                let last = first + stop.point_count - 1;
+                // The part after a stop starts after its last point. Stops across a gap are already cut at the gap
+                let has_gap = candidates.iter().any(|&cut| cut > first && cut <= last);
+                if stop.duration() > max_stop && !has_gap && last + 1 < track_points.len() {
+                    stop_cuts.push(last + 1);
+                }
+                first = last + 1;
+            }
+            candidates.extend(stop_cuts);
+            candidates.sort();
+        }
+
+        let mut cuts: Vec<usize> = Vec::new();
+        for cut in candidates {
+            let part_start = cuts.last().copied().unwrap_or(0);
+            if cut - part_start >= self.min_points && track_points.len() - cut >= self.min_points {
+                cuts.push(cut);
+            }
+        }
+        cuts
+    }
+
+    /// The points divided into parts at the cuts.
+    pub fn split<'a>(&self, track_points: &'a [TrackPoint]) -> Vec<&'a [TrackPoint]> {
+        let mut parts = Vec::new();
+        let mut start = 0;
+        for cut in self.cuts(track_points) {
+            parts.push(&track_points[start..cut]);
+            start = cut;
+        }
+        parts.push(&track_points[start..]);
+        parts
+    }
+}
+
+#[test]
+fn split_test() {
+    use chrono::DateTime;
+
+    let step = 10. / 111_250.;
+    let mut time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let mut latitude = 55.;
+    let mut track = Vec::new();
+
+    // Pause before, jump before, seconds recorded and distance between points
+    let segments = [
+        (TimeDelta::zero(), 0., 600, step),
+        (TimeDelta::hours(10), 0., 600, step), // Overnight
+        (TimeDelta::zero(), 0.1, 600, step), // A ferry without a fix
+        (TimeDelta::zero(), 0., 10 * 3600, 0.), // A day standing still
+        (TimeDelta::zero(), 0., 600, step),
+        (TimeDelta::minutes(30), 0., 600, step), // A short break
+        (TimeDelta::hours(10), 0., 50, step), // Too close to the end to be cut
+    ];
+    for (pause, jump, secs, step) in segments {
+        time += pause;
+        latitude += jump;
+        for _ in 0..secs / 10 {
+            latitude += step;
+            track.push(TrackPoint::new(time, latitude, 10., 0., 3.6, true));
+            time += TimeDelta::seconds(10);
+        }
+    }
+
+    let config = SplitConfig::default();
+    // The day standing still ends with the 3780th point
+    assert_eq!(config.cuts(&track), vec![60, 120, 3780]);
+    assert_eq!(config.split(&track).iter().map(|part| part.len()).sum::<usize>(), track.len());
+
+    let time_only = SplitConfig { max_distance_gap: None, max_stop: None, ..Default::default() };
+    assert_eq!(time_only.split(&track).len(), 2);
+    assert_eq!(SplitConfig { max_time_gap: None, ..time_only }.split(&track), vec![&track[..]]);
+    assert_eq!(config.split(&[]), vec![&[] as &[TrackPoint]]);
+}
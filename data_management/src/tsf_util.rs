@@ -1,12 +1,12 @@
-use std::io::Read;
+use std::path::Path;
 
-use trip_tracker_lib::{track_point::parse_tsf, track_session::TrackSession};
+use trip_tracker_lib::{track_session::TrackSession, tsf::TsfReader};
 
 use crate::{DataManager, DataManagerError};
 
 impl DataManager {
     pub async fn add_tsf_standalone(&self, path: &str) -> Result<(i64, i64), DataManagerError> {
-        let track_session = crate::tsf_util::read_tsf(path);
+        let track_session = crate::tsf_util::read_tsf(&self.config.tsf_dir().join(path))?;
         let trip = self.register_new_trip(track_session.title.clone(), track_session.description.clone(), track_session.start_time).await?;
         let session_id = self.register_new_session(trip.trip_id, track_session.title, track_session.description).await?.session_id;
         self.append_gps_points(session_id, &track_session.track_points).await?;
@@ -14,37 +14,54 @@ impl DataManager {
     }
 
     pub async fn add_tsf_to_trip(&self, path: &str, trip_id: i64, title: Option<&str>) -> Result<i64, DataManagerError> {
-        let track_session = crate::tsf_util::read_tsf(path);
+        let track_session = crate::tsf_util::read_tsf(&self.config.tsf_dir().join(path))?;
         let session_id = self.register_new_session(trip_id, title.unwrap_or(track_session.title.as_str()).into(), track_session.description).await?.session_id;
         self.append_gps_points(session_id, &track_session.track_points).await?;
         Ok(session_id)
     }
 }
 
-pub fn read_tsf(filename: &str) -> TrackSession {
-    let file_path = project_root::get_project_root().unwrap().join("data").join("tsf").join(filename);
-    let mut file = std::fs::File::open(file_path).unwrap();
-    let mut bytes = Vec::new();
-    file.read_to_end(&mut bytes).unwrap();
+pub fn read_tsf(path: &Path) -> Result<TrackSession, DataManagerError> {
+    let file = std::fs::File::open(path).map_err(DataManagerError::io(format!("Failed to open {:?}", path)))?;
 
-    let (track_points, start_time) = parse_tsf(&bytes).unwrap();
+    let invalid = |e| DataManagerError::InvalidInput(format!("{:?} isn't a TSF file: {:?}", path, e));
+    let mut reader = TsfReader::new(file).map_err(invalid)?;
+    let track_points = reader.by_ref().collect::<Result<Vec<_>, _>>().map_err(invalid)?;
 
-    TrackSession::new(-1, 0, "TSF session".into(), "".into(), start_time, false, track_points, false)
+    Ok(TrackSession::new(-1, 0, "TSF session".into(), "".into(), reader.header().start_time, false, track_points, false))
+}
+
+/// Writes a short session to the TSF directory, like the tracker writes to its SD card, and returns its points.
+#[cfg(test)]
+fn write_tsf_fixture(config: &crate::DataManagerConfig, name: &str) -> Vec<trip_tracker_lib::track_point::TrackPoint> {
+    use trip_tracker_lib::{track_point::TrackPoint, tsf::{write_tsf, RecordLayout}};
+
+    let start = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let track_points = (0..10).map(|i| TrackPoint::new(start + chrono::TimeDelta::seconds(i), 40.18 + i as f64 * 1e-4, 44.51, 1000., 30., true)).collect::<Vec<_>>();
+    std::fs::create_dir_all(config.tsf_dir()).unwrap();
+    std::fs::write(config.tsf_dir().join(name), write_tsf(start, &track_points, RecordLayout::Standard)).unwrap();
+    track_points
 }
 
 // Misc
 #[tokio::test]
 async fn add_tsf() {
-    let data_manager = DataManager::start().await.unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = crate::DataManagerConfig::in_dir(data_dir.path());
+    let track_points = write_tsf_fixture(&config, "SESSION.TSF");
+    let data_manager = DataManager::start(config).await.unwrap();
 
-    let (_trip_id, _) = data_manager.add_tsf_standalone("SESSION.TSF").await.unwrap();
+    let (_trip_id, session_id) = data_manager.add_tsf_standalone("SESSION.TSF").await.unwrap();
+    assert_eq!(data_manager.get_session(session_id).await.unwrap().track_points.len(), track_points.len());
 }
 
 #[tokio::test]
 async fn tsf_test() {
-    let session = read_tsf("SESSION.TSF");
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = crate::DataManagerConfig::in_dir(data_dir.path());
+    let track_points = write_tsf_fixture(&config, "SESSION.TSF");
 
-    for point in session.track_points {
-        println!("{:.03}, {:.03}", point.latitude, point.longitude)
-    }
+    let session = read_tsf(&config.tsf_dir().join("SESSION.TSF")).unwrap();
+    assert_eq!(session.start_time, track_points[0].timestamp);
+    assert_eq!(session.track_points.iter().map(|p| p.timestamp).collect::<Vec<_>>(), track_points.iter().map(|p| p.timestamp).collect::<Vec<_>>());
 }
\ No newline at end of file
@@ -11,7 +11,7 @@ use embedded_tracker_esp32_s3::{info, log::Logger, sys_info, ExclusiveService, G
 use esp_alloc as _;
 use esp_backtrace as _;
 use esp_hal::{
-    clock::CpuClock, cpu_control::{CpuControl, Stack}, delay::Delay, gpio::{AnyPin, Input, Level, Output, Pull}, peripheral::Peripheral, peripherals, reset::{self}, sha::Sha, spi::AnySpi, timer::{timg::TimerGroup, AnyTimer}, uart::AnyUart
+    clock::CpuClock, cpu_control::{CpuControl, Stack}, delay::Delay, gpio::{AnyPin, Input, Level, Output, Pull}, peripheral::Peripheral, peripherals, reset::{self}, spi::AnySpi, timer::{timg::TimerGroup, AnyTimer}, uart::AnyUart
 };
 
 use esp_hal_embassy::Executor;
@@ -146,7 +146,6 @@ async fn main(spawner: Spawner) {
     info!("Initializing upload service...");
     let upload = init_upload_service(
         CpuControl::new(peripherals.CPU_CTRL), 
-        Sha::new(peripherals.SHA), 
         modem_service.clone(),
         storage_service.clone(),
         state_service.clone(),
@@ -170,7 +169,6 @@ static UPLOAD_SERVICE_LOCK: Signal<CriticalSectionRawMutex, UploadService> = Sig
 
 async fn init_upload_service(
     mut cpu_control: CpuControl<'static>, 
-    sha: Sha<'static>,
     modem_service: ExclusiveService<ModemService>, 
     storage_service: ExclusiveService<StorageService>,
     state_service: ExclusiveService<StateService>,
@@ -179,7 +177,7 @@ async fn init_upload_service(
         static EXECUTOR: StaticCell<Executor> = StaticCell::new();
         let executor = EXECUTOR.init(Executor::new());
         executor.run(|spawner| {
-            spawner.spawn(core1_task(spawner, sha, modem_service, storage_service, state_service)).unwrap();
+            spawner.spawn(core1_task(spawner, modem_service, storage_service, state_service)).unwrap();
         });
     }).unwrap();
     forget(_core1_guard);
@@ -190,12 +188,11 @@ async fn init_upload_service(
 #[embassy_executor::task]
 async fn core1_task(
     spawner: Spawner, 
-    sha: Sha<'static>, 
     modem_service: ExclusiveService<ModemService>, 
     storage_service: ExclusiveService<StorageService>,
     state_service: ExclusiveService<StateService>,
 ) {
-    let upload_service = UploadService::start(&spawner, sha, modem_service, storage_service, state_service).await;
+    let upload_service = UploadService::start(&spawner, modem_service, storage_service, state_service).await;
     UPLOAD_SERVICE_LOCK.signal(upload_service);
 }
 
@@ -2,6 +2,7 @@ use core::str::FromStr;
 
 use esp_println::println;
 use heapless::String;
+use trip_tracker_lib::track_point::QuantizationProfile;
 
 #[derive(Debug)]
 pub struct Configuration {
@@ -15,6 +16,8 @@ pub struct Configuration {
     pub port: u16,
     pub trip_id: i64,
     pub auth_key: [u8; 32],
+    /// Quantization of recorded sessions. `ground` unless set
+    pub quantization_profile: QuantizationProfile,
 }
 
 impl Configuration {
@@ -27,6 +30,7 @@ impl Configuration {
         let mut trip_id = -1;
         let mut port = 0;
         let mut auth_key = [0; 32];
+        let mut quantization_profile = QuantizationProfile::Ground;
 
         for line in input.split('\n') {
             let line = line.trim();
@@ -47,6 +51,7 @@ impl Configuration {
                 "port" => port = u16::from_str(value).unwrap(),
                 "trip_id" => trip_id = i64::from_str(value).unwrap(),
                 "auth_key" => auth_key = hex_to_bytes(value).unwrap(),
+                "quantization_profile" => quantization_profile = QuantizationProfile::from_name(value).unwrap(),
                 _ => {
                     println!("Unknown config key: {}", key);
                 }
@@ -62,6 +67,7 @@ impl Configuration {
             trip_id,
             port,
             auth_key,
+            quantization_profile,
         }
     }
 }
@@ -1,6 +1,5 @@
 mod upload_service;
 pub mod connection_buffer;
 pub mod upload_status;
-mod mac_provider;
 
 pub use upload_service::UploadService;
\ No newline at end of file
@@ -1,15 +1,14 @@
 use core::fmt::{self, Debug};
 
-use alloc::{boxed::Box, format, sync::Arc};
+use alloc::{boxed::Box, format, sync::Arc, vec::Vec};
 use embassy_executor::Spawner;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Timer};
-use esp_hal::sha::Sha;
-use trip_tracker_lib::comms::{HandshakeMessage, MacProvider, MAX_TRACK_POINTS_PER_MESSAGE, SIGNATURE_SIZE};
+use embassy_time::{Duration, Instant, Timer};
+use trip_tracker_lib::{comms::{handshake_signature, FrameSequence, HandshakeMessage, Message, MessageCodec, MessageKind, Points, HANDSHAKE_LENGTH, MAX_MESSAGE_SIZE, MAX_PAYLOAD_SIZE, MAX_TRACK_POINTS_PER_MESSAGE, NONCE_SIZE, SIGNATURE_SIZE}, mac::HmacSha256};
 
 use crate::{info, services::modem::modem_service::{ATError, ATErrorType}, warn, ActorTerminator, Configuration, ExclusiveService, ModemService, Service, StateService, StorageService};
 
-use super::{mac_provider::EmbeddedMacProvider, upload_status::{SessionUploadStatus, UploadStatus}};
+use super::{connection_buffer::ConnectionBuffer, upload_status::{SessionUploadStatus, UploadStatus}};
 
 pub struct UploadService {
     modem_service: ExclusiveService<ModemService>,
@@ -37,7 +36,6 @@ impl Service for UploadService {
 impl UploadService {
     pub async fn start(
         spawner: &Spawner,
-        sha: Sha<'static>,
         modem_service: ExclusiveService<ModemService>,
         storage_service: ExclusiveService<StorageService>,
         state_service: ExclusiveService<StateService>,
@@ -47,7 +45,7 @@ impl UploadService {
 
         let terminator = ActorTerminator::new();
 
-        let mac_provider = Arc::new(Mutex::new(EmbeddedMacProvider::new(sha)));
+        let mac_provider = Arc::new(Mutex::new(HmacSha256));
 
         spawner.must_spawn(upload_actor(
             mac_provider.clone(),
@@ -116,10 +114,11 @@ impl UploadService {
 // Aim to upload data every 6 secs
 const UPLOAD_INTERVAL_SECS: usize = 6;
 const RETRIES_AFTER_STOP: usize = 20; // 200 secs minutes max after stop
+const TELEMETRY_INTERVAL_SECS: u64 = 300;
 
 #[embassy_executor::task]
 async fn upload_actor(
-    mac_provider: Arc<Mutex<CriticalSectionRawMutex, EmbeddedMacProvider>>,
+    mac_provider: Arc<Mutex<CriticalSectionRawMutex, HmacSha256>>,
     upload_status: Arc<Mutex<CriticalSectionRawMutex, UploadStatus>>,
     modem_service: ExclusiveService<ModemService>,
     storage_service: ExclusiveService<StorageService>,
@@ -128,11 +127,13 @@ async fn upload_actor(
 ) {
     // Ensure no connection
     let mut connected_session_id = None;
+    let mut frame_sequence = None;
 
     let config = storage_service.lock().await.get_config();
     let active_session_id = storage_service.lock().await.get_local_session_id();
 
     let mut finish_retries_left = RETRIES_AFTER_STOP;
+    let mut last_telemetry: Option<Instant> = None;
 
     if state_service.lock().await.is_upload_enabled() {
         state_service.lock().await.set_upload_state(Some(false)).await;
@@ -155,14 +156,9 @@ async fn upload_actor(
             continue;
         }
 
-        /*let res = modem_service.lock().await.interrogate_urc("AT+CSQ", "+CSQ", 1000).await;
-        info!("CSQ?: {:?}", res);
-        if let Ok((_, urc)) = res {
-            let (strength, error_rate) = urc.split_once(',').unwrap();
-            let rssi = strength.parse::<u8>().unwrap();
-            let ber = error_rate.parse::<u8>().unwrap();
+        if let Some((rssi, ber)) = read_signal_quality(&modem_service).await {
             state_service.lock().await.set_signal_quality(rssi, ber).await;
-        }*/
+        }
 
         // Start by uploading old unfinished session data
         let status_clone = upload_status.lock().await.clone();
@@ -170,27 +166,32 @@ async fn upload_actor(
         let result: Result<(), ATError> = (async || {
             for session in status_clone.sessions.iter() {
                 let track_point_count = storage_service.lock().await.get_session_track_point_count(session.local_id);
-                let missing = track_point_count - session.uploaded;
 
                 if connected_session_id.is_none() || connected_session_id != Some(session.local_id) {
                     // Start new connection with this id
                     ensure_closed(&modem_service).await;
 
                     if let Some(remote_id) = session.remote_id {
-                        connect(
+                        let (_, sequence, stored_points) = connect(
                             modem_service.clone(), 
                             ConnectStrategy::Reconnect(remote_id), 
                             &config, 
                             &mut *mac_provider.lock().await
                         ).await?;
+                        frame_sequence = Some(sequence);
+
+                        // Resume from what the server has stored, not from what was sent
+                        upload_status.lock().await.set_uploaded(session.local_id, stored_points);
+                        storage_service.lock().await.write_upload_status(&*upload_status.lock().await);
                     } else {
                         let start_time = storage_service.lock().await.read_session_start_timestamp(session.local_id);
-                        let session_id = connect(
+                        let (session_id, sequence, _) = connect(
                             modem_service.clone(), 
                             ConnectStrategy::Connect(start_time), 
                             &config, 
                             &mut *mac_provider.lock().await
                         ).await?;
+                        frame_sequence = Some(sequence);
                         upload_status.lock().await.set_remote_session_id(session.local_id, session_id);
                         storage_service.lock().await.write_upload_status(&*upload_status.lock().await);
                     }
@@ -200,26 +201,49 @@ async fn upload_actor(
                     connected_session_id = Some(session.local_id);
                 }
 
+                let frame_sequence = frame_sequence.as_mut().unwrap(); // Set when connecting
+                let not_current_session = active_session_id != session.local_id;
+
+                let uploaded = upload_status.lock().await.get_uploaded(session.local_id);
+                let missing = track_point_count.saturating_sub(uploaded);
+
                 if missing > 0 {
-                    upload_data(
-                        session, 
+                    let result = upload_data(
+                        session.local_id, 
+                        uploaded,
+                        track_point_count,
+                        upload_status.clone(),
                         mac_provider.clone(), 
+                        frame_sequence,
                         &config, 
-                        missing, 
                         modem_service.clone(), 
                         storage_service.clone()
-                    ).await?;
+                    ).await;
+
+                    // Whatever the server acknowledged is stored, even if the upload failed midway
+                    storage_service.lock().await.write_upload_status(&*upload_status.lock().await);
+                    result?;
 
                     info!("Uploaded {} points", missing);
+                } else if !terminator.is_terminating() && !not_current_session {
+                    // Nothing new to send, but keep the connection alive
+                    let mut mac_provider = mac_provider.lock().await;
+                    let mut codec = MessageCodec::new(&mut *mac_provider, &config.auth_key, Some(&mut *frame_sequence));
+                    send_message(&modem_service, &mut codec, &Message::Heartbeat).await?;
+                }
 
-                    upload_status.lock().await.add_uploaded(session.local_id, missing);
-                    storage_service.lock().await.write_upload_status(&*upload_status.lock().await);
+                let telemetry_due = last_telemetry.map_or(true, |sent| sent.elapsed() >= Duration::from_secs(TELEMETRY_INTERVAL_SECS));
+                if telemetry_due && !terminator.is_terminating() && !not_current_session {
+                    let telemetry = state_service.lock().await.telemetry().await;
+                    let mut mac_provider = mac_provider.lock().await;
+                    let mut codec = MessageCodec::new(&mut *mac_provider, &config.auth_key, Some(&mut *frame_sequence));
+                    send_message(&modem_service, &mut codec, &Message::Telemetry(telemetry)).await?;
+                    last_telemetry = Some(Instant::now());
                 }
 
                 // Missing is now 0
-                let not_current_session = active_session_id != session.local_id;
                 if terminator.is_terminating() || not_current_session {
-                    finish_session(session, upload_status.clone(), storage_service.clone(), modem_service.clone(), &mut *mac_provider.lock().await).await?;
+                    finish_session(session, upload_status.clone(), storage_service.clone(), modem_service.clone(), &mut *mac_provider.lock().await, frame_sequence).await?;
                     ensure_closed(&modem_service).await;
                     info!("Session {} finished", session.local_id);
                 }
@@ -262,34 +286,39 @@ async fn upload_actor(
     terminator.terminated();
 }
 
+/// Signal strength and bit error rate as reported by AT+CSQ, 99 when unknown.
+async fn read_signal_quality(modem_service: &ExclusiveService<ModemService>) -> Option<(u8, u8)> {
+    let (_, urc) = modem_service.lock().await.interrogate_urc("AT+CSQ", "+CSQ", 1000).await.ok()?;
+    let (strength, error_rate) = urc.split_once(',')?;
+    let rssi = strength.trim().parse::<u8>().ok()?;
+    let ber = error_rate.trim().parse::<u8>().ok()?;
+    Some((rssi, ber))
+}
+
 async fn finish_session(
     session: &SessionUploadStatus,
     upload_status: Arc<Mutex<CriticalSectionRawMutex, UploadStatus>>,
     storage_service: ExclusiveService<StorageService>,
     modem_service: ExclusiveService<ModemService>,
-    mac_provider: &mut EmbeddedMacProvider,
+    mac_provider: &mut HmacSha256,
+    frame_sequence: &mut FrameSequence,
 ) -> Result<(), ATError> {
-    // Send single 0 byte to finish session
-    modem_service.lock().await.cip_send_bytes::<0>(&[0]).await?;
-
-    // Receive nonce 
-    let mut nonce_buffer = [0; 16];
+    let key = storage_service.lock().await.get_config().auth_key;
+    let mut codec = MessageCodec::new(mac_provider, &key, Some(frame_sequence));
     let receive_buffer = modem_service.lock().await.get_receive_data_buffer(0);
-    receive_buffer.read_exact_timeout(&mut nonce_buffer, 3000).await.map_err(|_| ATError::new(ATErrorType::Timeout, "Receive nonce timed out"))?;
 
-    // Sign nonce
-    let key = storage_service.lock().await.get_config().auth_key;
-    let signature = mac_provider.sign(&nonce_buffer, &key);
+    send_message(&modem_service, &mut codec, &Message::Finish).await?;
 
-    // Send signature
-    modem_service.lock().await.cip_send_bytes::<0>(&signature).await?;
+    // Prove the finish is authentic by signing the server's nonce
+    let mut buffer = [0; NONCE_SIZE];
+    let Message::FinishChallenge { nonce } = read_message(&receive_buffer, &mut codec, MessageKind::FinishChallenge, &mut buffer).await? else {
+        unreachable!("Decoded as a finish challenge")
+    };
+    let response = codec.finish_response(&nonce);
+    send_message(&modem_service, &mut codec, &response).await?;
 
-    // Read response byte
-    let mut response = [0; 1];
-    receive_buffer.read_exact_timeout(&mut response, 3000).await.map_err(|_| ATError::new(ATErrorType::Timeout, "Receive finish response timed out"))?;
-    if response[0] != 1 {
-        return Err(ATError::new(ATErrorType::TxError, &format!("Finish response not 1! Got {}", response[0])));
-    }
+    // Fails unless the server acknowledges
+    read_message(&receive_buffer, &mut codec, MessageKind::FinishAck, &mut buffer).await?;
 
     // Old session is finished!
     upload_status.lock().await.finish_session(session.local_id);
@@ -299,41 +328,79 @@ async fn finish_session(
 }
 
 async fn upload_data(
-    status: &SessionUploadStatus,
-    mac_provider: Arc<Mutex<CriticalSectionRawMutex, EmbeddedMacProvider>>,
+    local_id: u32,
+    uploaded: usize,
+    track_point_count: usize,
+    upload_status: Arc<Mutex<CriticalSectionRawMutex, UploadStatus>>,
+    mac_provider: Arc<Mutex<CriticalSectionRawMutex, HmacSha256>>,
+    frame_sequence: &mut FrameSequence,
     config: &Configuration,
-    mut missing: usize,
     modem_service: ExclusiveService<ModemService>,
     storage_service: ExclusiveService<StorageService>,
 ) -> Result<(), ATError> {
-    let mut idx = status.uploaded;
-    while missing > 0 {
-        let point_cnt = if missing > MAX_TRACK_POINTS_PER_MESSAGE {
-            MAX_TRACK_POINTS_PER_MESSAGE
-        } else {
-            missing
-        };
+    let header = storage_service.lock().await.read_session_header(local_id);
+    let receive_buffer = modem_service.lock().await.get_receive_data_buffer(0);
 
-        //info!("Uploading {} points", point_cnt);
+    let mut mac_provider = mac_provider.lock().await;
+    let mut codec = MessageCodec::new(&mut *mac_provider, &config.auth_key, Some(frame_sequence));
+    let mut payload = Vec::from([0; MAX_PAYLOAD_SIZE]);
+
+    let mut idx = uploaded;
+    while idx < track_point_count {
+        let point_cnt = (track_point_count - idx).min(MAX_TRACK_POINTS_PER_MESSAGE);
 
-        let mut data = storage_service.lock().await.read_track_points(status.local_id, idx, point_cnt);
-        idx += point_cnt;
+        //info!("Uploading {} points", point_cnt);
 
-        data.insert(0, point_cnt as u8);
+        let track_points = storage_service.lock().await.read_track_points(local_id, idx, point_cnt);
 
-        // Sign data
-        let key = config.auth_key;
-        let signature = mac_provider.lock().await.sign(&data, &key);
-        data.extend_from_slice(&signature);
+        // Points are compressed before sending
+        let points = Points::compress(&track_points, header.start_time, header.profile, &mut payload).unwrap();
+        send_message(&modem_service, &mut codec, &Message::Data(points)).await?;
 
-        modem_service.lock().await.cip_send_bytes::<0>(&data).await?;
+        // Only points the server has stored count as uploaded, so lost frames are sent again
+        let mut ack_buffer = [0; 4];
+        let Message::DataAck { stored_points } = read_message(&receive_buffer, &mut codec, MessageKind::DataAck, &mut ack_buffer).await? else {
+            unreachable!("Decoded as a data ack")
+        };
+        let stored_points = stored_points as usize;
+        upload_status.lock().await.set_uploaded(local_id, stored_points);
 
-        missing -= point_cnt;
+        if stored_points <= idx {
+            return Err(ATError::new(ATErrorType::TxError, &format!("Server stored {} points, expected more than {}", stored_points, idx)));
+        }
+        idx = stored_points;
     }
 
     Ok(())
 }
 
+async fn send_message(modem_service: &ExclusiveService<ModemService>, codec: &mut MessageCodec<'_, HmacSha256>, message: &Message<'_>) -> Result<(), ATError> {
+    let mut buffer = Vec::from([0; MAX_MESSAGE_SIZE]);
+    let length = codec.encode(message, &mut buffer).map_err(|e| ATError::new(ATErrorType::TxError, &format!("Failed to encode message: {:?}", e)))?;
+    modem_service.lock().await.cip_send_bytes::<0>(&buffer[..length]).await
+}
+
+/// Reads one message of the given kind from the server.
+async fn read_message<'b>(
+    receive_buffer: &ConnectionBuffer,
+    codec: &mut MessageCodec<'_, HmacSha256>,
+    kind: MessageKind<'_>,
+    buffer: &'b mut [u8],
+) -> Result<Message<'b>, ATError> {
+    let mut read = 0;
+    loop {
+        let needed = codec.required_length(kind, &buffer[..read]).map_err(|e| ATError::new(ATErrorType::TxError, &format!("Invalid {:?}: {:?}", kind, e)))?;
+        if needed == read {
+            break;
+        }
+        receive_buffer.read_exact_timeout(&mut buffer[read..needed], 3000).await.map_err(|_| ATError::new(ATErrorType::Timeout, &format!("Receive {:?} timed out", kind)))?;
+        read = needed;
+    }
+
+    let (message, _) = codec.decode(kind, &buffer[..read]).map_err(|e| ATError::new(ATErrorType::TxError, &format!("Rejected {:?}: {:?}", kind, e)))?;
+    Ok(message)
+}
+
 #[derive(Debug)]
 enum ConnectStrategy {
     Connect(i64), // timestamp
@@ -386,8 +453,8 @@ async fn connect(
     modem_service: ExclusiveService<ModemService>, 
     connect_strategy: ConnectStrategy, 
     config: &Configuration, 
-    mac_provider: &mut EmbeddedMacProvider
-) -> Result<i64, ATError> {
+    mac_provider: &mut HmacSha256
+) -> Result<(i64, FrameSequence, usize), ATError> {
     info!("{:?} to {}:{}", connect_strategy, config.server, config.port);
 
     // Check NETOPEN status, and NETOPEN if needed
@@ -415,40 +482,45 @@ async fn connect(
         return Err(ATError::new(ATErrorType::NetError(format!("{:?}", code)), &command));
     }
 
-    let mut buffer = [0; 17 + SIGNATURE_SIZE];
-
-    let mut nonce_buffer = [0; 16];
     let receive_buffer = modem_service.lock().await.get_receive_data_buffer(0);
-    receive_buffer.read_exact_timeout(&mut nonce_buffer, 3000).await.map_err(|_| ATError::new(ATErrorType::Timeout, "Receive connect nonce timed out"))?;
+
+    let mut buffer = [0; HANDSHAKE_LENGTH + SIGNATURE_SIZE];
+    let mut codec = MessageCodec::new(&mut *mac_provider, &config.auth_key, None);
+    let Message::HandshakeChallenge { nonce } = read_message(&receive_buffer, &mut codec, MessageKind::HandshakeChallenge, &mut buffer).await? else {
+        unreachable!("Decoded as a handshake challenge")
+    };
 
     let handshake_message = match connect_strategy {
         ConnectStrategy::Connect(timestamp) => HandshakeMessage::new_fresh(config.trip_id, timestamp),
         ConnectStrategy::Reconnect(session_id) => HandshakeMessage::new_reconnect(config.trip_id, session_id),
     };
     let handshake_bytes = handshake_message.serialize();
-    buffer[..17].copy_from_slice(&handshake_bytes);
-
-    let mut to_sign = [0u8; 16 + 17];
-    to_sign[..16].copy_from_slice(&nonce_buffer);
-    to_sign[16..].copy_from_slice(&handshake_bytes);
-
-    let signature = mac_provider.sign(&to_sign, &config.auth_key);
+    let signature = handshake_signature(&mut *mac_provider, &config.auth_key, &nonce, &handshake_bytes);
 
-    buffer[..17].copy_from_slice(&handshake_bytes);
-    buffer[17..].copy_from_slice(&signature);
+    buffer[..HANDSHAKE_LENGTH].copy_from_slice(&handshake_bytes);
+    buffer[HANDSHAKE_LENGTH..].copy_from_slice(&signature);
 
     modem_service.lock().await.cip_send_bytes::<0>(&buffer).await?;
 
-    // If fresh connection, read session id
-    let session_id = match connect_strategy {
-        ConnectStrategy::Reconnect(session_id) => session_id,
+    // The rest of the connection is numbered and signed with the connection key
+    let mut frame_sequence = FrameSequence::new(&config.auth_key, &nonce);
+
+    // A fresh session gets an id, a resumed session tells how many points the server has
+    let mut codec = MessageCodec::new(&mut *mac_provider, &config.auth_key, Some(&mut frame_sequence));
+    let (session_id, stored_points) = match connect_strategy {
+        ConnectStrategy::Reconnect(session_id) => {
+            let Message::SessionResumed { stored_points } = read_message(&receive_buffer, &mut codec, MessageKind::SessionResumed, &mut buffer).await? else {
+                unreachable!("Decoded as a resumed session")
+            };
+            (session_id, stored_points as usize)
+        },
         ConnectStrategy::Connect(_) => {
-            let mut session_id_buffer = [0; 8];
-            receive_buffer.read_exact_timeout(&mut session_id_buffer, 3000).await.map_err(|_| ATError::new(ATErrorType::Timeout, "Receive new session ID timed out"))?;
-            let session_id = i64::from_be_bytes(session_id_buffer);
-            session_id
+            let Message::SessionCreated { session_id } = read_message(&receive_buffer, &mut codec, MessageKind::SessionCreated, &mut buffer).await? else {
+                unreachable!("Decoded as a created session")
+            };
+            (session_id, 0)
         },
     };
 
-    Ok(session_id)
-}
\ No newline at end of file
+    Ok((session_id, frame_sequence, stored_points))
+}
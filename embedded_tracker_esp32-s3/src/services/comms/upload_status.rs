@@ -69,15 +69,23 @@ impl UploadStatus {
         }
     }
 
-    pub fn add_uploaded(&mut self, local_id: u32, uploaded: usize) {
+    /// Sets the number of points the server has confirmed storing.
+    pub fn set_uploaded(&mut self, local_id: u32, uploaded: usize) {
         for session in self.sessions.iter_mut() {
             if session.local_id == local_id {
-                session.uploaded += uploaded;
+                session.uploaded = uploaded;
                 return;
             }
         }
     }
 
+    pub fn get_uploaded(&self, local_id: u32) -> usize {
+        self.sessions.iter()
+            .find(|session| session.local_id == local_id)
+            .map(|session| session.uploaded)
+            .unwrap_or(0)
+    }
+
     pub fn add_session(&mut self, local_id: u32) {
         self.sessions.push(SessionUploadStatus {
             local_id,
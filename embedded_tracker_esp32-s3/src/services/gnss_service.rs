@@ -4,7 +4,7 @@ use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use embassy_executor::Spawner;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
 use embassy_time::Instant;
-use trip_tracker_lib::track_point::TrackPoint;
+use trip_tracker_lib::track_point::{PointQuality, TrackPoint};
 
 use crate::{info, services::modem::ModemService, warn, ActorTerminator, ExclusiveService, Service};
 
@@ -160,6 +160,9 @@ pub async fn gnss_monitor_actor(
 
         time_publisher.send((state.timestamp, Instant::now()));
 
+        let satellites = state.satellites.min(u8::MAX as u32) as u8;
+        let satellites_used = state.satellites_used.min(u8::MAX as u32) as u8;
+
         let track_point = TrackPoint::new(
             state.timestamp,
             state.latitude,
@@ -167,12 +170,20 @@ pub async fn gnss_monitor_actor(
             state.altitude,
             state.speed_kph,
             state.pdop < 1.
-        );
+        ).with_quality(PointQuality {
+            course: Some(state.course),
+            pdop: Some(state.pdop),
+            hdop: Some(state.hdop),
+            vdop: Some(state.vdop),
+            satellites: Some(satellites),
+            satellites_used: Some(satellites_used),
+        });
         
         storage_service.lock().await.append_track_point(track_point);
 
         latest_state.lock().await.replace(state);
 
+        state_service.lock().await.set_satellites(satellites, satellites_used).await;
         state_service.lock().await.set_gnss_state(true).await;
     }
 }
@@ -208,7 +219,7 @@ async fn parse_gnss_info(gnss_info: &str) -> Option<GNSSState> {
         warn!("Speed is unrealistically high: {} km/h. Ignoring this point.", speed_kph);
         return None;
     }
-    let _course: f32 = parts.next().unwrap().parse().ok()?;
+    let course: f32 = parts.next().unwrap().parse().ok()?;
 
     let pdop: f32 = parts.next().unwrap().parse().ok()?;
     let hdop: f32 = parts.next().unwrap().parse().ok()?;
@@ -222,7 +233,7 @@ async fn parse_gnss_info(gnss_info: &str) -> Option<GNSSState> {
         altitude,
         timestamp: datetime,
         speed_kph: speed_kph / 1.852,
-        course: _course,
+        course,
         pdop,
         hdop,
         vdop,
@@ -7,9 +7,16 @@ use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, s
 use embassy_time::{Duration, Instant, Timer, WithTimeout};
 use esp_hal::{analog::adc::{Adc, AdcCalBasic, AdcConfig, AdcPin, Attenuation}, gpio::{AnyPin, GpioPin, Input, Output}, peripheral::Peripheral, peripherals::ADC1, prelude::nb};
 
+use trip_tracker_lib::telemetry::{PowerSource, Telemetry};
+
 use crate::{debug, info, ActorTerminator, Service};
 use alloc::boxed::Box;
 
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Reported by AT+CSQ when the value is not known
+const UNKNOWN_SIGNAL_QUALITY: u8 = 99;
+
 pub static CURRENT_TIME: Watch<CriticalSectionRawMutex, (chrono::DateTime<Utc>, Instant), 5> = Watch::new();
 
 pub fn get_current_time() -> Option<chrono::DateTime<Utc>> {
@@ -81,6 +88,9 @@ struct DeviceState {
     has_gnss_fix: bool,
     signal_strength: SignalStrength,
     signal_error_rate: BitErrorRate,
+    rssi: Option<u8>,
+    ber: Option<u8>,
+    satellites: Option<(u8, u8)>,
 }
 
 pub struct StateService {
@@ -142,6 +152,9 @@ impl StateService {
             has_gnss_fix: false,
             signal_strength: SignalStrength::None,
             signal_error_rate: BitErrorRate::None,
+            rssi: None,
+            ber: None,
+            satellites: None,
         }));
 
         let update_signal = Arc::new(Signal::new());
@@ -167,6 +180,8 @@ impl StateService {
         let mut state = self.device_state.lock().await;
         state.signal_strength = SignalStrength::from_rssi(rssi);
         state.signal_error_rate = BitErrorRate::from_ber(ber);
+        state.rssi = (rssi != UNKNOWN_SIGNAL_QUALITY).then_some(rssi);
+        state.ber = (ber != UNKNOWN_SIGNAL_QUALITY).then_some(ber);
         debug!("Signal strength: {:?}, error rate: {:?}", state.signal_strength, state.signal_error_rate);
         self.update_signal.signal(true);
     }
@@ -182,6 +197,36 @@ impl StateService {
         state.has_gnss_fix = has_gnss_fix;
         self.update_signal.signal(true);
     }
+
+    /// Satellites in view and satellites used for the fix
+    pub async fn set_satellites(&self, satellites: u8, satellites_used: u8) {
+        self.device_state.lock().await.satellites = Some((satellites, satellites_used));
+    }
+
+    /// The current state of the device, as reported to the server.
+    pub async fn telemetry(&self) -> Telemetry<'static> {
+        let state = self.device_state.lock().await;
+
+        // The battery level can't be read while USB is connected
+        let (battery_percent, power_source) = match state.battery_status {
+            BatteryStatus::Unknown => (None, PowerSource::Unknown),
+            BatteryStatus::ChargingUSB => (None, PowerSource::Usb),
+            BatteryStatus::Charging(lvl) => (Some(lvl), PowerSource::Solar),
+            BatteryStatus::Discharging(lvl) => (Some(lvl), PowerSource::Battery),
+        };
+
+        Telemetry {
+            battery_percent,
+            power_source,
+            rssi: state.rssi,
+            ber: state.ber,
+            satellites: state.satellites.map(|(satellites, _)| satellites),
+            satellites_used: state.satellites.map(|(_, used)| used),
+            free_storage_kib: None, // embedded-sdmmc can't count free clusters
+            uptime_secs: Instant::now().as_secs() as u32,
+            firmware_version: FIRMWARE_VERSION,
+        }
+    }
 }
 
 // Handle LEDs
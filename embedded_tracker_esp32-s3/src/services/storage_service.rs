@@ -4,11 +4,11 @@ use chrono::{DateTime, Datelike, Timelike, Utc};
 use embedded_hal_bus::spi::ExclusiveDevice;
 use embedded_sdmmc::{Mode, RawDirectory, RawFile, SdCard, TimeSource, Timestamp, VolumeManager};
 use esp_hal::{delay::Delay, gpio::{AnyPin, Level, Output}, peripheral::PeripheralRef, prelude::*, spi::{master::{Config, Spi}, AnySpi}, Blocking};
-use trip_tracker_lib::track_point::{TrackPoint, ENCODED_LENGTH};
+use trip_tracker_lib::{track_point::TrackPoint, tsf::{encode_fixed_record, RecordLayout, TsfDecoder, TsfHeader, HEADER_LENGTH, MAX_FIXED_RECORD_LENGTH}};
 use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
 use alloc::vec;
 
-use crate::{configuration::Configuration, debug, info, Service};
+use crate::{configuration::Configuration, debug, info, warn, Service};
 
 use super::{comms::upload_status::UploadStatus, state_service};
 
@@ -16,6 +16,9 @@ const MAX_DIRS: usize = 128;
 const MAX_FILES: usize = 128;
 const MAX_VOLUMES: usize = 1;
 
+/// Session files keep the full GNSS quality of each point
+const SESSION_LAYOUT: RecordLayout = RecordLayout::Extended;
+
 type BlockingSPISDCard = SdCard<ExclusiveDevice<Spi<'static, Blocking>, Output<'static>, Delay>, Delay>;
 
 pub struct StorageService {
@@ -29,7 +32,8 @@ pub struct StorageService {
     sessions_dir: RawDirectory,
 
     local_session_id: u32,
-    start_time: Option<DateTime<Utc>>,
+    /// Header of the current session file, once the start time is known
+    session_header: Option<TsfHeader>,
     session_file: RawFile,
     session_log_file: RawFile,
     session_dir: RawDirectory,
@@ -38,7 +42,7 @@ pub struct StorageService {
 #[async_trait::async_trait]
 impl Service for StorageService {
     async fn stop(&mut self) {
-        self.start_time = None;
+        self.session_header = None;
         self.volume_mgr.close_file(self.session_file).unwrap();
         self.volume_mgr.close_file(self.session_log_file).unwrap();
         self.volume_mgr.close_dir(self.session_dir).unwrap();
@@ -57,23 +61,25 @@ impl StorageService {
     }
 
     pub fn set_start_time(&mut self, time: DateTime<Utc>) {
-        self.start_time = Some(time);
+        let header = TsfHeader::with_layout(time, SESSION_LAYOUT).with_profile(self.config.quantization_profile);
+        self.session_header = Some(header);
 
         debug!("Set start time: {}", time);
         
-        let bytes = time.timestamp().to_be_bytes();
+        let bytes = header.to_bytes();
         self.volume_mgr.write(self.session_file, &bytes).unwrap();
         self.volume_mgr.flush_file(self.session_file).unwrap();
     }
 
     pub fn append_track_point(&mut self, track_point: TrackPoint) {
-        let start_time = self.start_time.unwrap();
-        let bytes = track_point.to_bytes(start_time);
+        let header = self.session_header.unwrap();
+        let mut bytes = [0; MAX_FIXED_RECORD_LENGTH];
+        let len = encode_fixed_record(&header, &track_point, &mut bytes).unwrap();
 
         // Seek to the end of the file
         self.volume_mgr.file_seek_from_end(self.session_file, 0).unwrap();
 
-        self.volume_mgr.write(self.session_file, &bytes).unwrap();
+        self.volume_mgr.write(self.session_file, &bytes[..len]).unwrap();
         self.volume_mgr.flush_file(self.session_file).unwrap();
     }
 
@@ -89,18 +95,15 @@ impl StorageService {
     }
 
     pub fn get_session_track_point_count(&mut self, local_id: u32) -> usize {
-        let size = if self.local_session_id == local_id {
-            self.volume_mgr.file_length(self.session_file).unwrap()
-        } else {
-            let session_dir = self.volume_mgr.open_dir(self.sessions_dir, format!("{}", local_id).as_str()).unwrap();
-            let file = self.volume_mgr.open_file_in_dir(session_dir, "SESSION.TSF", Mode::ReadOnly).unwrap();
-            let size = self.volume_mgr.file_length(file).unwrap();
+        let (file, needs_close) = self.open_session_file(local_id);
+        let size = self.volume_mgr.file_length(file).unwrap() as usize;
+        let header = self.read_header(file);
+
+        if needs_close {
             self.volume_mgr.close_file(file).unwrap();
-            self.volume_mgr.close_dir(session_dir).unwrap();
-            size
-        };
+        }
 
-        (size - 8) as usize / ENCODED_LENGTH
+        size.saturating_sub(header.encoded_length()) / header.layout.fixed_record_length().unwrap()
     }
 
     pub fn get_local_session_id(&self) -> u32 {
@@ -108,57 +111,77 @@ impl StorageService {
     }
 
     pub fn read_session_start_timestamp(&mut self, local_id: u32) -> i64 {
-        let (file, needs_close) = if self.local_session_id == local_id {
-            let file = self.session_file; // safe to unwrap because local_session_id is Some
-            (file, false)
-        } else {
-            let session_dir = self.volume_mgr.open_dir(self.sessions_dir, format!("{}", local_id).as_str()).unwrap();
-            let file = self.volume_mgr.open_file_in_dir(session_dir, "SESSION.TSF", Mode::ReadOnly).unwrap();
-            self.volume_mgr.close_dir(session_dir).unwrap();
-            (file, true)
-        };
-        
-        // Timestamp
-        self.volume_mgr.file_seek_from_start(file, 0).unwrap();
-        let mut buffer = [0; 8];
-        self.volume_mgr.read(file, &mut buffer).unwrap();
+        self.read_session_header(local_id).start_time.timestamp()
+    }
+
+    pub fn read_session_header(&mut self, local_id: u32) -> TsfHeader {
+        let (file, needs_close) = self.open_session_file(local_id);
+        let header = self.read_header(file);
 
         if needs_close {
             self.volume_mgr.close_file(file).unwrap();
         }
 
-        i64::from_be_bytes(buffer)
+        header
     }
 
-    /// Returns the start time and the requested points
-    pub fn read_track_points(&mut self, local_id: u32, idx: usize, count: usize) -> Vec<u8> {
-        let (file, needs_close) = if self.local_session_id == local_id {
-            let file = self.session_file; // safe to unwrap because local_session_id is Some
-            (file, false)
-        } else {
-            let session_dir = self.volume_mgr.open_dir(self.sessions_dir, format!("{}", local_id).as_str()).unwrap();
-            let file = self.volume_mgr.open_file_in_dir(session_dir, "SESSION.TSF", Mode::ReadOnly).unwrap();
-            self.volume_mgr.close_dir(session_dir).unwrap();
-            (file, true)
-        };
+    /// Reads `count` points starting at point `idx`. Fewer are returned if the file ends early,
+    /// e.g. with a record that was cut off by a power loss.
+    pub fn read_track_points(&mut self, local_id: u32, idx: usize, count: usize) -> Vec<TrackPoint> {
+        let (file, needs_close) = self.open_session_file(local_id);
+        let header = self.read_header(file);
         
-        // Track points
-        let mut buffer = [0; ENCODED_LENGTH];
-        let mut data_bytes = Vec::with_capacity(count);
-
-        let start_offset = 8 + idx * ENCODED_LENGTH;
+        // Session files are always written with a fixed size layout, so the first point can be seeked to
+        let record_length = header.layout.fixed_record_length().unwrap();
+        let start_offset = header.encoded_length() + idx * record_length;
         self.volume_mgr.file_seek_from_start(file, start_offset as u32).unwrap();
-        
-        for _ in 0..count {
-            self.volume_mgr.read(file, &mut buffer).unwrap();
-            data_bytes.extend_from_slice(&buffer);
+
+        let mut decoder = TsfDecoder::with_header(header);
+        let mut track_points = Vec::with_capacity(count);
+        let mut chunk = [0; 512];
+        while track_points.len() < count {
+            let len = self.volume_mgr.read(file, &mut chunk).unwrap();
+            if len == 0 {
+                break;
+            }
+
+            for track_point in decoder.feed(&chunk[..len]) {
+                match track_point {
+                    Ok(track_point) if track_points.len() < count => track_points.push(track_point),
+                    Ok(_) => (),
+                    Err(e) => {
+                        warn!("Failed to decode track point: {}", e);
+                        break;
+                    },
+                }
+            }
         }
 
         if needs_close {
             self.volume_mgr.close_file(file).unwrap();
         }
 
-        data_bytes
+        track_points
+    }
+
+    /// Returns the TSF file for the session, and whether it must be closed after use
+    fn open_session_file(&mut self, local_id: u32) -> (RawFile, bool) {
+        if self.local_session_id == local_id {
+            (self.session_file, false)
+        } else {
+            let session_dir = self.volume_mgr.open_dir(self.sessions_dir, format!("{}", local_id).as_str()).unwrap();
+            let file = self.volume_mgr.open_file_in_dir(session_dir, "SESSION.TSF", Mode::ReadOnly).unwrap();
+            self.volume_mgr.close_dir(session_dir).unwrap();
+            (file, true)
+        }
+    }
+
+    /// Reads the header of a TSF file. Works for both versioned and legacy files.
+    fn read_header(&mut self, file: RawFile) -> TsfHeader {
+        let mut buffer = [0; HEADER_LENGTH];
+        self.volume_mgr.file_seek_from_start(file, 0).unwrap();
+        let len = self.volume_mgr.read(file, &mut buffer).unwrap();
+        TsfHeader::parse(&buffer[..len]).unwrap()
     }
 
     pub fn read_upload_status(&mut self) -> UploadStatus {
@@ -319,7 +342,7 @@ impl StorageService {
             sessions_dir,
 
             local_session_id,
-            start_time: None,
+            session_header: None,
             session_file,
             session_log_file,
             session_dir,
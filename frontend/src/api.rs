@@ -1,6 +1,6 @@
 use gloo_net::http::Request;
 use serde::de::DeserializeOwned;
-use trip_tracker_lib::{track_session::{SessionUpdate, TrackSession}, trip::Trip};
+use trip_tracker_lib::{polyline, stops::Stop, track_session::{SessionUpdate, TrackSession}, trip::Trip};
 
 pub async fn make_request<ReturnType>(path: &str) -> Result<ReturnType, ()>
 where
@@ -31,6 +31,28 @@ where
     Ok(result)
 }
 
+pub async fn make_text_request(path: &str) -> Result<String, ()> {
+    let response = Request::get(path)
+        .send()
+        .await
+        .map_err(|err| {
+            web_sys::console::error_1(&format!("Request error: {:?}", err).into());
+            ()
+        })?;
+
+    if !response.ok() {
+        web_sys::console::error_1(&format!("Request failed with status {}", response.status()).into());
+        return Err(());
+    }
+
+    response
+        .text()
+        .await
+        .map_err(|err| {
+            web_sys::console::error_1(&format!("Text read error: {:?}", err).into());
+            ()
+        })
+}
 
 // default to newest trip
 pub async fn get_default_trip_id() -> Result<i64, ()> {
@@ -57,6 +79,20 @@ pub async fn get_session(session_id: i64) -> Result<TrackSession, ()> {
     make_request(&format!("/session/{session_id}")).await
 }
 
+/// Positions of the session shown at the zoom level, sent as an encoded polyline
+pub async fn get_session_positions_at_zoom(session_id: i64, zoom: u8) -> Result<Vec<(f64, f64)>, ()> {
+    let encoded = make_text_request(&format!("/polyline/{session_id}/{zoom}")).await?;
+    polyline::decode(&encoded, polyline::GOOGLE_PRECISION)
+        .map_err(|err| {
+            web_sys::console::error_1(&format!("Polyline error: {}", err).into());
+            ()
+        })
+}
+
 pub async fn get_session_update(session_id: i64, timestamp: i64) -> Result<SessionUpdate, ()> {
     make_request(&format!("/session_update/{session_id}/{timestamp}")).await
-}
\ No newline at end of file
+}
+
+pub async fn get_trip_stops(trip_id: i64) -> Result<Vec<Stop>, ()> {
+    make_request(&format!("/stops/{trip_id}")).await
+}
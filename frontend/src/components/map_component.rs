@@ -1,15 +1,15 @@
 use std::collections::HashMap;
 
-use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use gloo_console::info;
 use gloo_utils::document;
-use leaflet::{LatLng, Map, MapOptions, Polyline, PolylineOptions, Popup, PopupOptions, TileLayer, TileLayerOptions, Tooltip, TooltipOptions};
-use trip_tracker_lib::track_session::TrackSession;
+use leaflet::{CircleMarker, LatLng, Map, MapOptions, PathOptions, Polyline, PolylineOptions, Popup, PopupOptions, TileLayer, TileLayerOptions, Tooltip, TooltipOptions};
+use trip_tracker_lib::{geo::BoundingBox, local_time::UtcOffset, simplify::{MAX_LOD_ZOOM, MIN_LOD_ZOOM}, stops::Stop, track_session::TrackSession};
 use wasm_bindgen::{prelude::wasm_bindgen, JsCast, JsValue};
 use web_sys::{js_sys::Array, Element, HtmlElement, Node};
 use yew::prelude::*;
 
-use crate::trip_data::TripData;
+use crate::{api, trip_data::TripData};
 
 #[wasm_bindgen]
 extern "C" {
@@ -21,7 +21,16 @@ pub struct MapComponent {
     map: Map,
     container: HtmlElement,
     polylines: HashMap<i64, Polyline>,
+    stop_markers: Vec<CircleMarker>,
     most_recent_time: DateTime<Utc>,
+    /// Zoom level the points of each session were last requested at
+    detail_zoom: HashMap<i64, u8>,
+}
+
+pub enum Msg {
+    ViewChanged,
+    DetailLoaded(i64, Vec<(f64, f64)>, u8),
+    DetailFailed(i64),
 }
 
 #[derive(PartialEq, Properties, Clone)]
@@ -35,10 +44,39 @@ impl MapComponent {
         let node: &Node = &self.container.clone().into();
         Html::VRef(node.clone())
     }
+
+    /// Requests the points of the sessions in view at the current zoom level, unless already requested
+    fn load_detail(&mut self, ctx: &Context<Self>) {
+        let Some(trip_data) = &ctx.props().trip_data else {
+            return;
+        };
+
+        let zoom = (self.map.get_zoom().round() as u8).clamp(MIN_LOD_ZOOM, MAX_LOD_ZOOM + 1);
+        let bounds = self.map.get_bounds();
+        let (north_east, south_west) = (bounds.get_north_east(), bounds.get_south_west());
+        let mut view = BoundingBox::new((south_west.lat(), south_west.lng()));
+        view.extend((north_east.lat(), north_east.lng()));
+
+        for session in &trip_data.sessions {
+            let session_id = session.session.session_id;
+            let in_view = session.session.statistics.bounding_box.as_ref().is_some_and(|b| b.intersects(&view));
+            if !in_view || self.detail_zoom.get(&session_id) == Some(&zoom) {
+                continue;
+            }
+
+            self.detail_zoom.insert(session_id, zoom);
+            ctx.link().send_future(async move {
+                match api::get_session_positions_at_zoom(session_id, zoom).await {
+                    Ok(positions) => Msg::DetailLoaded(session_id, positions, zoom),
+                    Err(_) => Msg::DetailFailed(session_id),
+                }
+            });
+        }
+    }
 }
 
 impl Component for MapComponent {
-    type Message = ();
+    type Message = Msg;
     type Properties = Props;
 
     fn create(_ctx: &Context<Self>) -> Self {
@@ -52,16 +90,43 @@ impl Component for MapComponent {
             map: leaflet_map,
             container,
             polylines: HashMap::new(),
-            most_recent_time: DateTime::from_timestamp_nanos(0)
+            stop_markers: Vec::new(),
+            most_recent_time: DateTime::from_timestamp_nanos(0),
+            detail_zoom: HashMap::new(),
         }
     }
 
-    fn rendered(&mut self, _ctx: &Context<Self>, first_render: bool) {
+    fn rendered(&mut self, ctx: &Context<Self>, first_render: bool) {
         if first_render {
             self.map.set_max_zoom(18.);
             self.map.set_view(&LatLng::new(56.175188, 10.196123), 8.0);
             add_tile_layer(&self.map);
+
+            // Moving also ends every zoom
+            let view_changed = ctx.link().callback(|_| Msg::ViewChanged);
+            self.map.on_move_end(Box::new(move |_| view_changed.emit(())));
+        }
+    }
+
+    fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
+        match msg {
+            Msg::ViewChanged => self.load_detail(ctx),
+            Msg::DetailLoaded(session_id, positions, zoom) => {
+                // Dropped if the map has been zoomed again since the request
+                if self.detail_zoom.get(&session_id) == Some(&zoom) {
+                    if let Some(polyline) = self.polylines.get(&session_id) {
+                        let points = positions.iter()
+                            .map(|&(latitude, longitude)| LatLng::new(latitude, longitude));
+                        polyline.set_lat_lngs(&Array::from_iter(points));
+                    }
+                }
+            },
+            Msg::DetailFailed(session_id) => {
+                self.detail_zoom.remove(&session_id);
+            },
         }
+
+        false
     }
 
     fn changed(&mut self, ctx: &Context<Self>, old_props: &Self::Properties) -> bool {
@@ -104,7 +169,7 @@ impl Component for MapComponent {
                 } else {
                     // Add session
                     let polyline = make_polyline(&session.session, i);
-                    update_metadata(&polyline, &session.session, session.distance);
+                    update_metadata(&polyline, &session.session, session.distance, trip_data.trip.display_offset(session.session.utc_offset));
                     polyline.add_to(&self.map);
                     self.polylines.insert(session.session.session_id, polyline);
                     if let Some(last_point) = session.session.track_points.last() {
@@ -117,10 +182,26 @@ impl Component for MapComponent {
                     info!("Added new session")
                 }
             }
+            self.load_detail(ctx);
+
+            if old_trip_data.as_ref().map(|td| &td.stops) != Some(&trip_data.stops) {
+                for marker in self.stop_markers.drain(..) {
+                    marker.remove();
+                }
+                for stop in &trip_data.stops {
+                    let marker = make_stop_marker(stop, trip_data.trip.display_offset(stop.utc_offset));
+                    marker.add_to(&self.map);
+                    self.stop_markers.push(marker);
+                }
+            }
         } else {
             for feature in self.polylines.values() {
                 feature.remove();
             }
+            for marker in self.stop_markers.drain(..) {
+                marker.remove();
+            }
+            self.detail_zoom.clear();
         }
 
         true
@@ -139,13 +220,12 @@ impl Component for MapComponent {
     }
 }
 
-fn update_metadata(polyline: &Polyline, track_session: &TrackSession, distance :f64) {
+fn update_metadata(polyline: &Polyline, track_session: &TrackSession, distance :f64, offset: UtcOffset) {
     if track_session.track_points.len() < 1 {
         return;
     }
 
     let first_point = track_session.track_points.first().unwrap();
-    let last_point = track_session.track_points.last().unwrap();
 
     let tooltip_opts = TooltipOptions::default();
     tooltip_opts.set_sticky(true);
@@ -158,19 +238,21 @@ fn update_metadata(polyline: &Polyline, track_session: &TrackSession, distance :
 
     let popup_opts = PopupOptions::default();
     let popup = Popup::new(&popup_opts, None);
-    /*l
-    et duration = last_point.timestamp.signed_duration_since(first_point.timestamp).to_std().unwrap_or(Default::default());
-    let hrs = duration.as_secs() / 3600;
-    let mins = (duration.as_secs() % 3600) / 60;
-    let time = format!("{:02}h {:02}m{}", hrs, mins, if track_session.active { " - Live" } else { "" });
-    */
+
+    let statistics = &track_session.statistics;
+    let time = format!("{} ({} moving)", format_duration(statistics.total_time()), format_duration(statistics.moving_time()));
+    let speed = format!("{:.0} km/h average, {:.0} km/h max", statistics.moving_average_speed_kph(), statistics.max_speed_kph);
+    let elevation = format!("+{:.0} m / -{:.0} m", statistics.elevation_gain, statistics.elevation_loss);
 
     let distance = format!("{:.1}{}", if distance > 1. {distance} else {distance * 1000.}, if distance > 1. { " km" } else { " m" });
-    popup.set_content(&format!("<b>{}</b><br>{}{}<br>{}<br>{}",
+    popup.set_content(&format!("<b>{}</b><br>{}{}<br>{}<br>{}<br>{}<br>{}<br>{}",
         &track_session.title,
-        &FixedOffset::east_opt(2 * 3600).unwrap().from_utc_datetime(&first_point.timestamp.naive_utc()).format("%d/%m/%Y %H:%M (UTC+2)").to_string(),
-        if track_session.active { "<br>Live" } else { "" },//time,
+        &offset.format(first_point.timestamp, "%d/%m/%Y %H:%M"),
+        if track_session.active { "<br>Live" } else { "" },
         distance,
+        time,
+        speed,
+        elevation,
         track_session.description
     ).into());
 
@@ -178,6 +260,10 @@ fn update_metadata(polyline: &Polyline, track_session: &TrackSession, distance :
     .bind_popup(&popup);
 }
 
+fn format_duration(duration: TimeDelta) -> String {
+    format!("{:02}h {:02}m", duration.num_hours(), duration.num_minutes() % 60)
+}
+
 fn get_track_color(track_session: &TrackSession, i: usize) -> String {
     if track_session.active {
         "rgb(41, 138, 67)"
@@ -206,6 +292,30 @@ fn make_polyline(track_session: &TrackSession, i: usize) -> Polyline {
     Polyline::new_with_options(&Array::from_iter(points), &opts)
 }
 
+fn make_stop_marker(stop: &Stop, offset: UtcOffset) -> CircleMarker {
+    let opts = PathOptions::new();
+    opts.set_color("rgb(200, 60, 40)".into());
+    opts.set_fill_opacity(0.8);
+    opts.set_weight(2.);
+
+    let marker = CircleMarker::new_with_options(&LatLng::new(stop.latitude, stop.longitude), &opts);
+    marker.set_radius(6.);
+
+    let tooltip_opts = TooltipOptions::default();
+    tooltip_opts.set_direction("top".into());
+    let tooltip = Tooltip::new(&tooltip_opts, None);
+    let time_format = "%d/%m %H:%M";
+    tooltip.set_content(&format!("Stopped for {}<br>{} - {} ({})",
+        format_duration(stop.duration()),
+        offset.local(stop.arrival).format(time_format),
+        offset.local(stop.departure).format(time_format),
+        offset,
+    ).into());
+    marker.bind_tooltip(&tooltip);
+
+    marker
+}
+
 fn add_tile_layer(map: &Map) {
     let key = include_str!("../../maptiler_key.txt").trim();
     //let url = format!("https://api.maptiler.com/maps/openstreetmap/256/{{z}}/{{x}}/{{y}}.jpg?key={}", key);
@@ -1,3 +1,4 @@
+use trip_tracker_lib::trip::{Trip, TripStatus};
 use yew::prelude::*;
 use yew_router::hooks::use_navigator;
 
@@ -19,7 +20,13 @@ pub fn PanelComponent(props: &PanelProps) -> Html {
     html! {
         if let Some(trip_data) = &props.trip {
             <div class="panel component-container">
+                if let Some(cover_image) = &trip_data.trip.cover_image {
+                    <img class="cover-image" src={cover_image.clone()} alt={trip_data.trip.title.clone()} />
+                }
                 <h1>{format!("{}", trip_data.trip.title)}</h1>
+                if let Some(status) = status_text(&trip_data.trip) {
+                    <label>{status}</label>
+                }
                 <label>
                     {format!("{}", trip_data.trip.description)}
                 </label>
@@ -27,9 +34,11 @@ pub fn PanelComponent(props: &PanelProps) -> Html {
                 <label>{
                     format!("{}", trip_data.trip.country_list.iter().map(|iso_a2| celes::Country::from_alpha2(iso_a2).unwrap().long_name).collect::<Vec<&str>>().join(", "))
                 }</label>
-                <label>{
-                    format!("Currently in {}", trip_data.trip.country_list.last().map(|iso_a2| celes::Country::from_alpha2(iso_a2).unwrap().long_name).unwrap_or(&"???".to_owned()))
-                }</label>
+                if trip_data.trip.status == TripStatus::Live {
+                    <label>{
+                        format!("Currently in {}", trip_data.trip.country_list.last().map(|iso_a2| celes::Country::from_alpha2(iso_a2).unwrap().long_name).unwrap_or(&"???".to_owned()))
+                    }</label>
+                }
                 <label>{
                     format!("Total distance: {} km", (trip_data.sessions.iter().map(|session| session.distance).sum::<f64>()) as u64)
                 }</label>
@@ -45,4 +54,20 @@ pub fn PanelComponent(props: &PanelProps) -> Html {
             </div>
         }
     }
-}
\ No newline at end of file
+}
+
+/// Where the trip is, for trips that aren't live
+fn status_text(trip: &Trip) -> Option<String> {
+    let date_format = "%d/%m/%Y";
+    match trip.status {
+        TripStatus::Planned => Some(match trip.planned_start {
+            Some(start) => format!("Starts {}", start.format(date_format)),
+            None => "Coming soon".into(),
+        }),
+        TripStatus::Live => None,
+        TripStatus::Finished | TripStatus::Archived => Some(match trip.planned_end {
+            Some(end) => format!("Finished {}", end.format(date_format)),
+            None => "Finished".into(),
+        }),
+    }
+}
@@ -6,7 +6,6 @@ use futures::future::join_all;
 use gloo_console::{error, info};
 use gloo_timers::future::sleep;
 use trip_data::{SessionData, TripData};
-use util::filter_anomalies;
 use wasm_bindgen_futures::spawn_local;
 use yew::prelude::*;
 use yew_router::{
@@ -16,7 +15,6 @@ use yew_router::{
 mod api;
 mod components;
 mod trip_data;
-mod util;
 
 #[derive(Clone, Debug, PartialEq, Routable)]
 enum Route {
@@ -74,14 +72,17 @@ fn load_trip_data(trip_id: i64, trip_cb: Callback<TripData>) {
         let mut sessions: Vec<SessionData> = Vec::new();
 
         results.into_iter().filter_map(|r| r.ok()).for_each(|session| {
-            sessions.push(SessionData::from_session(filter_anomalies(session)));
+            sessions.push(SessionData::from_session(session));
         });
 
         sessions.sort_by_key(|s| s.session.track_points.first().map(|p| p.timestamp.timestamp()).unwrap_or(0));
 
+        let stops = api::get_trip_stops(trip_id).await.unwrap_or_default();
+
         let mut trip_data = TripData {
             trip,
             sessions,
+            stops,
         };
 
         trip_cb.emit(trip_data.clone());
@@ -107,6 +108,8 @@ fn load_trip_data(trip_id: i64, trip_cb: Callback<TripData>) {
                 continue;
             };
 
+            // Stops are only detected again when sessions end or start
+            let mut sessions_changed = false;
             for id in session_ids {
                 match trip_data.sessions.iter_mut().find(|s| s.session.session_id == id) {
                     Some(existing) => {
@@ -116,7 +119,9 @@ fn load_trip_data(trip_id: i64, trip_cb: Callback<TripData>) {
                                 existing.session.track_points.extend(update.new_track_points);
                                 existing.session.description = update.description;
                                 existing.session.title = update.title;
+                                sessions_changed |= !update.still_active;
                                 existing.session.active = update.still_active;
+                                existing.session.statistics = update.statistics;
                             }
                             existing.distance = existing.session.distance()
                         }
@@ -124,11 +129,19 @@ fn load_trip_data(trip_id: i64, trip_cb: Callback<TripData>) {
                     None => {
                         if let Ok(session) = api::get_session(id).await {
                             trip_data.sessions.push(SessionData::from_session(session));
+                            sessions_changed = true;
                         }
                     },
                 }
             }
 
+            if sessions_changed {
+                match api::get_trip_stops(trip_data.trip.trip_id).await {
+                    Ok(stops) => trip_data.stops = stops,
+                    Err(_) => error!("Failed to get trip stops"),
+                }
+            }
+
 
             trip_cb.emit(trip_data.clone());
         };
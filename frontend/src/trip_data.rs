@@ -1,4 +1,4 @@
-use trip_tracker_lib::{track_session::TrackSession, trip::Trip};
+use trip_tracker_lib::{stops::Stop, track_session::TrackSession, trip::Trip};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct SessionData {
@@ -21,4 +21,5 @@ impl SessionData {
 pub struct TripData {
     pub trip: Trip,
     pub sessions: Vec<SessionData>,
+    pub stops: Vec<Stop>,
 }
\ No newline at end of file
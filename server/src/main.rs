@@ -1,17 +1,23 @@
 use axum::{
-    body::{Body, Bytes}, extract::{ConnectInfo, Path, State}, handler::HandlerWithoutStateExt, http::{uri::Authority, Request, StatusCode, Uri}, middleware::{from_fn_with_state, Next}, response::{IntoResponse, Redirect, Response}, routing::get, BoxError, Router
+    body::{Body, Bytes}, extract::{ConnectInfo, Path, State}, handler::HandlerWithoutStateExt, http::{header, uri::Authority, HeaderMap, Request, StatusCode, Uri}, middleware::{from_fn_with_state, Next}, response::{IntoResponse, Redirect, Response}, routing::get, BoxError, Router
 };
 use chrono::DateTime;
 use local_ip_address::local_ip;
-use server::{server_state::ServerState, tracker_endpoint};
-use trip_tracker_lib::{haversine_distance, track_point::TrackPoint, track_session::TrackSession};
+use server::{server_state::{LodCache, ServerState}, tracker_endpoint};
+use trip_tracker_lib::{polyline, simplify::LevelsOfDetail, smooth::KalmanSmoother, track_session::TrackSession};
 use std::{collections::HashMap, fs::OpenOptions, net::SocketAddr, sync::Arc};
 use tokio::sync::{broadcast, Mutex};
 use tower_http::services::{ServeDir, ServeFile};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use data_management::DataManager;
+use data_management::{split::SplitConfig, DataManager, DataManagerConfig, DataManagerError};
 use axum_extra::extract::Host;
 
+/// Zoom level of the sessions sent before the map asks for a specific level of detail
+const OVERVIEW_ZOOM: u8 = 8;
+
+/// Most telemetry reports returned for a trip. A day's worth at the default interval.
+const TELEMETRY_LIMIT: u32 = 288;
+
 #[allow(dead_code)]
 #[derive(Clone, Copy)]
 struct Ports {
@@ -42,13 +48,16 @@ async fn main() {
 
     // Set up application state for use with with_state().
     let (tx, _rx) = broadcast::channel(100);
-    let data_manager = DataManager::start().await.unwrap();
+    let mut data_manager = DataManager::start(DataManagerConfig::default()).await.unwrap();
+    data_manager.set_smoother(Some(KalmanSmoother::default()));
+    data_manager.set_auto_split(Some(SplitConfig::default()));
 
     let server_state = Arc::new(ServerState {
         tx,
         data_manager,
         ip_address: local_ip().unwrap(),
         ip_load: Mutex::new(HashMap::new()),
+        lod_cache: Mutex::new(LodCache::default()),
     });
 
     let state_clone = server_state.clone();
@@ -63,10 +72,16 @@ async fn main() {
         .route("/trip/{trip_id}", get(get_trip))
         .route("/session_ids/{trip_id}", get(get_trip_session_ids))
         .route("/session/{session_id}", get(get_session))
+        .route("/session/{session_id}/{zoom}", get(get_session_at_zoom))
         .route(
             "/session_update/{session_id}/{timestamp}",
             get(get_session_update),
         )
+        .route("/telemetry/{trip_id}", get(get_trip_telemetry))
+        .route("/stops/{trip_id}", get(get_trip_stops))
+        .route("/polyline/{session_id}/{zoom}", get(get_session_polyline))
+        .route("/geojson/session/{session_id}", get(get_session_geojson))
+        .route("/geojson/trip/{trip_id}", get(get_trip_geojson))
         .with_state(server_state.clone())
         .layer(from_fn_with_state(server_state.clone(), ip_middleware));
 
@@ -160,22 +175,27 @@ async fn ip_middleware(State(state): State<Arc<ServerState>>, req: Request<Body>
 }
 
 async fn get_trip_ids(State(state): State<Arc<ServerState>>) -> Response {
-    let trips = state.data_manager.get_trips().await.unwrap();
-    let ids = trips.iter().map(|trip| trip.trip_id).collect::<Vec<i64>>();
-    Bytes::from_owner(bincode::serialize(&ids).unwrap()).into_response()
+    match state.data_manager.get_listed_trips().await {
+        Ok(trips) => {
+            let ids = trips.iter().map(|trip| trip.trip_id).collect::<Vec<i64>>();
+            Bytes::from_owner(bincode::serialize(&ids).unwrap()).into_response()
+        },
+        Err(err) => error_response(err),
+    }
 }
 
 async fn get_trip(State(state): State<Arc<ServerState>>, Path(trip_id): Path<i64>) -> Response {
     let trip = state.data_manager.get_trip(trip_id).await;
 
-    if let Ok(mut trip) = trip {
-        trip.api_token = String::new(); // Do not send API token. This is not pretty XD
+    match trip {
+        Ok(mut trip) if trip.is_viewable() => {
+            trip.api_token = String::new(); // Do not send API token. This is not pretty XD
 
-        // Maybe cache, and no copy? TODO
-        Bytes::from_owner(bincode::serialize(&trip).unwrap()).into_response()
-    } else {
-        tracing::error!("Failed to get trip");
-        StatusCode::NOT_FOUND.into_response()
+            // Maybe cache, and no copy? TODO
+            Bytes::from_owner(bincode::serialize(&trip).unwrap()).into_response()
+        },
+        Ok(_) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => error_response(err),
     }
 }
 
@@ -183,75 +203,155 @@ async fn get_session(
     State(state): State<Arc<ServerState>>,
     Path(session_id): Path<i64>,
 ) -> Response {
-    let session = state.data_manager.get_session(session_id).await;
-    match session {
-        Ok(session) => {
-            Bytes::from_owner(bincode::serialize(&filter_anomalies(session)).unwrap()).into_response()
-        },
-        Err(err) => {
-            tracing::error!("Failed to get session {}: {:?}", session_id, err);
-            StatusCode::NOT_FOUND.into_response()
-        },
+    get_session_at_zoom(State(state), Path((session_id, OVERVIEW_ZOOM))).await
+}
+
+async fn get_session_at_zoom(
+    State(state): State<Arc<ServerState>>,
+    Path((session_id, zoom)): Path<(i64, u8)>,
+) -> Response {
+    if let Err(response) = check_session_viewable(&state, session_id).await {
+        return response;
     }
+
+    let (mut session, report) = match state.data_manager.get_filtered_session(session_id).await {
+        Ok(session) => session,
+        Err(err) => return error_response(err),
+    };
+
+    if !report.dropped.is_empty() {
+        tracing::debug!("Filtered {} points from session {}: {:?}", report.dropped.len(), session_id, report.reason_counts());
+    }
+    let levels = levels_of_detail(&state, &session).await;
+    session.track_points = levels.select(&session.track_points, zoom);
+    Bytes::from_owner(bincode::serialize(&session).unwrap()).into_response()
 }
 
-pub fn filter_anomalies(mut session: TrackSession) -> TrackSession {
-    let mut filtered_points = Vec::new();
-    // Filter out points that are very far from its neighbors, and points that go "back" in time.
- 
-    if session.track_points.len() < 3 {
-        return session;
+/// The points of the filtered session shown at the zoom level, as an encoded polyline.
+async fn get_session_polyline(
+    State(state): State<Arc<ServerState>>,
+    Path((session_id, zoom)): Path<(i64, u8)>,
+) -> Response {
+    if let Err(response) = check_session_viewable(&state, session_id).await {
+        return response;
     }
 
-   // session.track_points.sort_by_key(|p| p.timestamp);
+    let (session, _) = match state.data_manager.get_filtered_session(session_id).await {
+        Ok(session) => session,
+        Err(err) => return error_response(err),
+    };
 
-    let mut prev_point = &session.track_points[0];
-    for i in 1..session.track_points.len() - 1 {
-        let curr_point = &session.track_points[i];
-        let next_point = &session.track_points[i + 1];
+    let levels = levels_of_detail(&state, &session).await;
+    polyline::encode_track(&levels.select(&session.track_points, zoom)).into_response()
+}
 
-        // Calculate the distance between the two points
-        let dist_to_prev = haversine_distance((prev_point.latitude, prev_point.longitude), (curr_point.latitude, curr_point.longitude));
-        let dist_to_next = haversine_distance((curr_point.latitude, curr_point.longitude), (next_point.latitude, next_point.longitude));
-        let neighbor_dist = haversine_distance((prev_point.latitude, prev_point.longitude), (next_point.latitude, next_point.longitude));
+async fn get_session_geojson(
+    State(state): State<Arc<ServerState>>,
+    Path(session_id): Path<i64>,
+) -> Response {
+    if let Err(response) = check_session_viewable(&state, session_id).await {
+        return response;
+    }
 
-        if dist_to_prev + dist_to_next > neighbor_dist * 5. {
-            continue;
-        }
+    match state.data_manager.session_geojson(session_id).await {
+        Ok(feature) => ([(header::CONTENT_TYPE, "application/geo+json")], feature.to_string()).into_response(),
+        Err(err) => error_response(err),
+    }
+}
 
-        if dist_to_prev > 5.0 {
-            continue;           
-        }
+async fn get_trip_geojson(
+    State(state): State<Arc<ServerState>>,
+    Path(trip_id): Path<i64>,
+) -> Response {
+    if let Err(response) = check_trip_viewable(&state, trip_id).await {
+        return response;
+    }
 
-        if filtered_points.iter().any(|p: &TrackPoint| p.latitude == curr_point.latitude && p.longitude == curr_point.longitude) {
-            continue;
-        }
+    match state.data_manager.trip_geojson(trip_id).await {
+        Ok(feature) => ([(header::CONTENT_TYPE, "application/geo+json")], feature.to_string()).into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+/// The status of a failed request. Errors on our side are logged, as visitors asking for trips that don't exist is normal.
+fn error_response(err: DataManagerError) -> Response {
+    let status = match &err {
+        DataManagerError::NotFound(..) => StatusCode::NOT_FOUND,
+        DataManagerError::Conflict(_) => StatusCode::CONFLICT,
+        DataManagerError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+        DataManagerError::Storage { .. } | DataManagerError::Io { .. } | DataManagerError::Corrupt(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    if status.is_server_error() {
+        tracing::error!("{}: {:?}", err, err);
+    } else {
+        tracing::debug!("{}", err);
+    }
+    status.into_response()
+}
 
-        filtered_points.push(curr_point.clone());
-        prev_point = curr_point;
+/// Whether visitors can see the trip. Private trips are answered as if they didn't exist.
+async fn check_trip_viewable(state: &ServerState, trip_id: i64) -> Result<(), Response> {
+    match state.data_manager.get_trip(trip_id).await {
+        Ok(trip) if trip.is_viewable() => Ok(()),
+        Ok(_) => Err(StatusCode::NOT_FOUND.into_response()),
+        Err(err) => Err(error_response(err)),
     }
-    
+}
 
-    session.track_points = filtered_points.into_iter().step_by(6).collect();
+/// Whether the request carries the trip's API token as `Authorization: Bearer <token>`.
+async fn check_trip_token(state: &ServerState, trip_id: i64, headers: &HeaderMap) -> Result<(), Response> {
+    let trip = state.data_manager.get_trip(trip_id).await.map_err(error_response)?;
+    let token = headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
 
-    session
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), trip.api_token.as_bytes()) => Ok(()),
+        _ => Err(StatusCode::UNAUTHORIZED.into_response()),
+    }
+}
+
+/// Compares without returning early, so the time taken doesn't tell how much of a token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Whether visitors can see the trip of the session.
+async fn check_session_viewable(state: &ServerState, session_id: i64) -> Result<(), Response> {
+    match state.data_manager.get_session_trip(session_id).await {
+        Ok(trip) if trip.is_viewable() => Ok(()),
+        Ok(_) => Err(StatusCode::NOT_FOUND.into_response()),
+        Err(err) => Err(error_response(err)),
+    }
+}
+
+/// The levels of detail of the filtered session, computed when its points have changed since last time.
+async fn levels_of_detail(state: &ServerState, session: &TrackSession) -> Arc<LevelsOfDetail> {
+    if let Some(levels) = state.lod_cache.lock().await.get(session.session_id, &session.track_points) {
+        return levels;
+    }
+
+    let levels = Arc::new(LevelsOfDetail::new(&session.track_points));
+    state.lod_cache.lock().await.insert(session.session_id, &session.track_points, levels.clone());
+    levels
 }
 
 async fn get_session_update(
     State(state): State<Arc<ServerState>>,
     Path((session_id, timestamp)): Path<(i64, i64)>,
 ) -> Response {
-    let update = state
-        .data_manager
-        .get_session_update(session_id, DateTime::from_timestamp(timestamp, 0).unwrap().to_utc())
-        .await;
+    if let Err(response) = check_session_viewable(&state, session_id).await {
+        return response;
+    }
+    let Some(timestamp) = DateTime::from_timestamp(timestamp, 0) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
 
-    if let Ok(update) = update {
+    match state.data_manager.get_session_update(session_id, timestamp).await {
         // Maybe cache, and no copy? TODO
-        Bytes::from_owner(bincode::serialize(&update).unwrap()).into_response()
-    } else {
-        tracing::error!("Failed to get session update");
-        StatusCode::NOT_FOUND.into_response()
+        Ok(update) => Bytes::from_owner(bincode::serialize(&update).unwrap()).into_response(),
+        Err(err) => error_response(err),
     }
 }
 
@@ -259,14 +359,44 @@ async fn get_trip_session_ids(
     State(state): State<Arc<ServerState>>,
     Path(trip_id): Path<i64>,
 ) -> Response {
-    let ids = state.data_manager.get_nonhidden_trip_session_ids(trip_id).await;
+    if let Err(response) = check_trip_viewable(&state, trip_id).await {
+        return response;
+    }
 
-    if let Ok(ids) = ids {
+    match state.data_manager.get_nonhidden_trip_session_ids(trip_id).await {
         // Maybe cache, and no copy? TODO
-        Bytes::from_owner(bincode::serialize(&ids).unwrap()).into_response()
-    } else {
-        tracing::error!("Failed to get trip session ids");
-        StatusCode::NOT_FOUND.into_response()
+        Ok(ids) => Bytes::from_owner(bincode::serialize(&ids).unwrap()).into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Telemetry of the tracker, like its battery and signal. Only for the owner of the trip, who sends its API token.
+async fn get_trip_telemetry(
+    State(state): State<Arc<ServerState>>,
+    Path(trip_id): Path<i64>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(response) = check_trip_token(&state, trip_id, &headers).await {
+        return response;
+    }
+
+    match state.data_manager.get_trip_telemetry(trip_id, TELEMETRY_LIMIT).await {
+        Ok(telemetry) => Bytes::from_owner(bincode::serialize(&telemetry).unwrap()).into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+async fn get_trip_stops(
+    State(state): State<Arc<ServerState>>,
+    Path(trip_id): Path<i64>,
+) -> Response {
+    if let Err(response) = check_trip_viewable(&state, trip_id).await {
+        return response;
+    }
+
+    match state.data_manager.get_trip_stops(trip_id).await {
+        Ok(stops) => Bytes::from_owner(bincode::serialize(&stops).unwrap()).into_response(),
+        Err(err) => error_response(err),
     }
 }
 
@@ -1,7 +1,11 @@
-use std::{collections::HashMap, net::IpAddr};
+use std::{collections::HashMap, hash::{DefaultHasher, Hash, Hasher}, net::IpAddr, sync::Arc};
 
 use tokio::sync::{broadcast, Mutex};
 use data_management::DataManager;
+use trip_tracker_lib::{simplify::LevelsOfDetail, track_point::TrackPoint};
+
+/// Sessions whose levels of detail are kept. The least recently used are dropped first
+const LOD_CACHE_SIZE: usize = 64;
 
 pub struct ServerState {
     // Channel used to send messages to all connected clients.
@@ -9,4 +13,51 @@ pub struct ServerState {
     pub data_manager: DataManager,
     pub ip_address: IpAddr,
     pub ip_load: Mutex<HashMap<IpAddr, usize>>,
+    pub lod_cache: Mutex<LodCache>,
+}
+
+/// Levels of detail of the filtered points of recently viewed sessions.
+#[derive(Default)]
+pub struct LodCache {
+    /// Fingerprint of the points the levels were made from, when they were last used, and the levels
+    entries: HashMap<i64, (u64, u64, Arc<LevelsOfDetail>)>,
+    uses: u64,
+}
+
+impl LodCache {
+    /// The levels of detail of the session, if they were made from these points.
+    pub fn get(&mut self, session_id: i64, track_points: &[TrackPoint]) -> Option<Arc<LevelsOfDetail>> {
+        let fingerprint = fingerprint(track_points);
+        self.uses += 1;
+        let (cached, last_used, levels) = self.entries.get_mut(&session_id)?;
+        if *cached != fingerprint {
+            return None;
+        }
+        *last_used = self.uses;
+        Some(levels.clone())
+    }
+
+    pub fn insert(&mut self, session_id: i64, track_points: &[TrackPoint], levels: Arc<LevelsOfDetail>) {
+        self.uses += 1;
+        self.entries.insert(session_id, (fingerprint(track_points), self.uses, levels));
+
+        if self.entries.len() > LOD_CACHE_SIZE {
+            let oldest = self.entries.iter().min_by_key(|(_, (_, last_used, _))| *last_used).map(|(session_id, _)| *session_id);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Changes when any point is added, removed or moved, e.g. by a split, an edit or a new filter config.
+fn fingerprint(track_points: &[TrackPoint]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    track_points.len().hash(&mut hasher);
+    for point in track_points {
+        point.timestamp.hash(&mut hasher);
+        point.latitude.to_bits().hash(&mut hasher);
+        point.longitude.to_bits().hash(&mut hasher);
+    }
+    hasher.finish()
 }
\ No newline at end of file
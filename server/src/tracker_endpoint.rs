@@ -1,9 +1,8 @@
 use std::{net::{IpAddr, SocketAddr}, sync::Arc};
 
 use chrono::DateTime;
-use sha2::{Sha256, Digest};
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::Mutex};
-use trip_tracker_lib::{comms::{HandshakeMessage, MacProvider, SIGNATURE_SIZE}, track_point::{TrackPoint, ENCODED_LENGTH}};
+use trip_tracker_lib::{comms::{handshake_signature, FrameSequence, HandshakeMessage, MacProvider, Message, MessageCodec, MessageKind, HANDSHAKE_LENGTH, MAX_MESSAGE_SIZE, NONCE_SIZE, PROTOCOL_VERSION, SIGNATURE_SIZE}, mac::{constant_time_eq, ProtocolMac}, track_point::ENCODED_LENGTH};
 use bimap::BiMap;
 
 use crate::server_state::ServerState;
@@ -56,35 +55,43 @@ pub async fn listen(server_state: Arc<ServerState>) {
 pub async fn handle_connection(mut stream: TcpStream, addr: SocketAddr, endpoint_state: EndpointState, server_state: Arc<ServerState>) -> Result<(), anyhow::Error> {
     // First we do the handshake:
     // 1. Send 16 random bytes to the tracker.
-    // 2. Receive from the tracker: trip id + [session_id OR new session with i64 timestamp] + a signature
+    // 2. Receive from the tracker: protocol version + trip id + [session_id OR new session with i64 timestamp] + a signature
     // 2.5 If resuming a session, the section is [0, session_id(i64)], if new session, the section is [1, timestamp(i64)]
     // 3. Check if the signature is correct for the given trip id.
-    // 4. Start listening to updates from the tracker.
+    // 4. Start listening to updates from the tracker. From protocol version 2, every frame is numbered and signed with a key derived from the nonce in 1.
+    //    From protocol version 2, reconnects and data frames are answered with the number of points stored for the session.
 
-    let random_bytes: [u8; 16] = rand::random();
+    let random_bytes: [u8; NONCE_SIZE] = rand::random();
     stream.write_all(&random_bytes).await?;
 
-    let mut buf = [0; 8 + 1 + 8 + SIGNATURE_SIZE];
-    stream.read_exact(&mut buf).await?;
+    // The first byte is the protocol version, or the message type for trackers predating the version byte.
+    let mut buf = [0; HANDSHAKE_LENGTH + SIGNATURE_SIZE];
+    stream.read_exact(&mut buf[..1]).await?;
+    let handshake_length = HandshakeMessage::message_length(buf[0]).map_err(|e| anyhow::anyhow!("Unsupported handshake: {:?}", e))?;
+    stream.read_exact(&mut buf[1..handshake_length + SIGNATURE_SIZE]).await?;
 
-    let handshake_bytes = &buf[..17];
-    let handshake_message = HandshakeMessage::deserialize(handshake_bytes.try_into().unwrap()).map_err(|_| anyhow::anyhow!("Failed to deserialize handshake message"))?; // Safe unwrap
-    let signature = buf[17..].try_into().unwrap(); // Safe unwrap
+    let handshake_bytes = &buf[..handshake_length];
+    let (handshake_message, version) = HandshakeMessage::deserialize(handshake_bytes).map_err(|_| anyhow::anyhow!("Failed to deserialize handshake message"))?;
+    let signature = &buf[handshake_length..handshake_length + SIGNATURE_SIZE];
 
-    let mut to_sign = [0; 16 + 1 + 8 + 8];
-    to_sign[..16].copy_from_slice(&random_bytes);
-    to_sign[16..].copy_from_slice(&handshake_bytes);
-
-    let trip = server_state.data_manager.get_trip(handshake_message.trip_id()).await.map_err(|_| anyhow::anyhow!("Failed to get trip"))?;
+    let trip = server_state.data_manager.get_trip(handshake_message.trip_id()).await.map_err(|e| anyhow::anyhow!("Failed to get trip: {}", e))?;
     let key = hex::decode(trip.api_token).map_err(|_| anyhow::anyhow!("Failed to decode trip token"))?;
 
-    if !(ServerMacProvider{}).verify(&to_sign, signature, &key) {
+    // The rest of the connection is signed with the MAC of the tracker's protocol version
+    let mut mac = ProtocolMac::for_version(version).map_err(|e| anyhow::anyhow!("Unsupported protocol: {:?}", e))?;
+
+    if !constant_time_eq(&handshake_signature(&mut mac, &key, &random_bytes, handshake_bytes), signature) {
         // The signature is incorrect.
         return Err(anyhow::anyhow!("Signature was incorrect"));
     }
 
     // Authenticated! Now we can start the session.
-    tracing::info!("Tracker authenticated. Starting session");
+    tracing::info!("Tracker authenticated with protocol version {}. Starting session", version);
+
+    // Legacy trackers send unnumbered frames signed with the trip key, and don't expect acknowledgements
+    let acknowledged = version >= PROTOCOL_VERSION;
+    let mut frame_sequence = acknowledged.then(|| FrameSequence::new(&key, &random_bytes));
+    let mut codec = MessageCodec::new(&mut mac, &key, frame_sequence.as_mut());
 
     let (session_id, timestamp) = match handshake_message {
         HandshakeMessage::FreshSession { trip_id, timestamp } => {
@@ -92,8 +99,8 @@ pub async fn handle_connection(mut stream: TcpStream, addr: SocketAddr, endpoint
             let Some(ts) = DateTime::from_timestamp(timestamp, 0) else {
                 return Err(anyhow::anyhow!("Invalid timestamp"));
             };
-            let session = server_state.data_manager.register_new_live_session(trip_id, format!("Unnamed {}", ts.date_naive()), "".into()).await.map_err(|_| anyhow::anyhow!("Failed to register new session"))?;
-            stream.write_all(&session.session_id.to_be_bytes()).await.map_err(|_| anyhow::anyhow!("Failed to send session id"))?;
+            let session = server_state.data_manager.register_new_live_session(trip_id, format!("Unnamed {}", ts.date_naive()), "".into()).await.map_err(|e| anyhow::anyhow!("Failed to register new session: {}", e))?;
+            send_message(&mut stream, &mut codec, &Message::SessionCreated { session_id: session.session_id }).await.map_err(|_| anyhow::anyhow!("Failed to send session id"))?;
             tracing::info!("New session created with id {}", session.session_id);
             (session.session_id, ts)
         },
@@ -104,7 +111,12 @@ pub async fn handle_connection(mut stream: TcpStream, addr: SocketAddr, endpoint
                 tracing::warn!("Session id already has active connection");
                 // TODO ???
             }
-            let session = server_state.data_manager.get_session(session_id).await.map_err(|_| anyhow::anyhow!("Failed to get session"))?;
+            let session = server_state.data_manager.get_session_info(session_id).await.map_err(|e| anyhow::anyhow!("Failed to get session: {}", e))?;
+            if acknowledged {
+                // The tracker resumes from the points we actually have
+                let stored_points = server_state.data_manager.get_session_point_count(session_id).await.map_err(|e| anyhow::anyhow!("Failed to count points of session: {}", e))? as u32;
+                send_message(&mut stream, &mut codec, &Message::SessionResumed { stored_points }).await.map_err(|_| anyhow::anyhow!("Failed to send stored point count"))?;
+            }
             tracing::info!("Resumed session with id {}", session_id);
             (session_id, session.start_time)
         },
@@ -116,87 +128,114 @@ pub async fn handle_connection(mut stream: TcpStream, addr: SocketAddr, endpoint
     let mut buffer = [0; 1 + 256 * ENCODED_LENGTH + SIGNATURE_SIZE]; // Max package size. ~4 minutes worth of data
 
     loop {
-        if stream.read_exact(&mut buffer[..1]).await.is_err() {
-            break;
-        }
-        let header = buffer[0];
-
-        if header == 0 {
-            // Terminate session
-            let random_bytes: [u8; 16] = rand::random();
-            if stream.write_all(&random_bytes).await.is_err() {
-                tracing::error!("Failed to send random bytes");
-                break;
-            }
-
-            // Read signature
-            let mut sig_buf = [0; SIGNATURE_SIZE];
-            if stream.read_exact(&mut sig_buf).await.is_err() {
-                tracing::error!("Failed to read signature");
+        let length = match read_message(&mut stream, &codec, MessageKind::Frame, &mut buffer).await {
+            Ok(length) => length,
+            Err(e) => {
+                tracing::warn!("Stopped reading from tracker: {}", e);
                 break;
-            }
+            },
+        };
 
-            // Verify
-            if !(ServerMacProvider{}).verify(&random_bytes, &sig_buf, &key) {
-                tracing::error!("Signature was incorrect when terminating session! Expected {:?}, got {:?}", (ServerMacProvider{}).sign(&random_bytes, &key), sig_buf);
+        let message = match codec.decode(MessageKind::Frame, &buffer[..length]) {
+            Ok((message, _)) => message,
+            Err(e) => {
+                tracing::error!("Rejecting frame: {:?}", e);
                 break;
-            }
-
-            // Terminate session
-            server_state.data_manager.end_session(session_id).await.map_err(|_| anyhow::anyhow!("Failed to end session"))?;
-            
-            stream.write(&[1; 1]).await.map_err(|_| anyhow::anyhow!("Failed to send termination confirmation"))?;
-
-            tracing::info!("Session terminated");
-
-            break;
-        }
-
-        let bytes_to_read = header as usize * ENCODED_LENGTH + SIGNATURE_SIZE;
-
-        if stream.read_exact(&mut buffer[1..bytes_to_read + 1]).await.is_err() {
-            tracing::error!("Failed to read data");
-            break;
-        }
-        
-        let data = &buffer[..bytes_to_read - 16 + 1];
-        let signature = &buffer[bytes_to_read - 16 + 1..bytes_to_read + 1];
-
-        if !(ServerMacProvider{}).verify(data, signature, &key) {
-            tracing::error!("Signature is incorrect!");
-            break;
-        }
+            },
+        };
 
-        // Message authenticated, now we can store the data.
+        match message {
+            Message::Data(points) => {
+                let points = match points.track_points(timestamp).collect::<Result<Vec<_>, _>>() {
+                    Ok(points) => points,
+                    Err(e) => {
+                        tracing::error!("Failed to decode points: {}", e);
+                        break;
+                    },
+                };
+
+                if server_state.data_manager.append_gps_points(session_id, &points).await.is_err() {
+                    tracing::error!("Failed to append points to session {}", session_id);
+                    break;
+                }
+
+                if acknowledged {
+                    let Ok(stored_points) = server_state.data_manager.get_session_point_count(session_id).await else {
+                        tracing::error!("Failed to count points of session {}", session_id);
+                        break;
+                    };
+                    if let Err(e) = send_message(&mut stream, &mut codec, &Message::DataAck { stored_points: stored_points as u32 }).await {
+                        tracing::error!("Failed to acknowledge points: {}", e);
+                        break;
+                    }
+                }
+            },
+            Message::Telemetry(telemetry) => {
+                // Losing a report is not worth dropping the connection over
+                if let Err(e) = server_state.data_manager.record_telemetry(session_id, &telemetry).await {
+                    tracing::error!("Failed to record telemetry of session {}: {:?}", session_id, e);
+                }
+            },
+            Message::Heartbeat => {},
+            Message::Finish => {
+                // The tracker must sign a fresh nonce to finish the session
+                let nonce: [u8; NONCE_SIZE] = rand::random();
+                if let Err(e) = send_message(&mut stream, &mut codec, &Message::FinishChallenge { nonce }).await {
+                    tracing::error!("Failed to send finish challenge: {}", e);
+                    break;
+                }
+
+                let kind = MessageKind::FinishResponse { nonce: &nonce };
+                let response = match read_message(&mut stream, &codec, kind, &mut buffer).await {
+                    Ok(length) => codec.decode(kind, &buffer[..length]).map(|_| ()),
+                    Err(e) => {
+                        tracing::error!("Failed to read finish response: {}", e);
+                        break;
+                    },
+                };
+                if let Err(e) = response {
+                    tracing::error!("Finish response was rejected: {:?}", e);
+                    break;
+                }
+
+                // Terminate session
+                server_state.data_manager.end_session(session_id).await.map_err(|e| anyhow::anyhow!("Failed to end session: {}", e))?;
+
+                send_message(&mut stream, &mut codec, &Message::FinishAck).await.map_err(|_| anyhow::anyhow!("Failed to send termination confirmation"))?;
+
+                tracing::info!("Session terminated");
 
-        let data_manager = &server_state.data_manager;
-        let mut points = Vec::new();
-        for i in 0..header as usize {
-            points.push(TrackPoint::from_bytes(&data[i * 15 + 1..i * 15 + 15 + 1], timestamp));
-        }
-        
-        if data_manager.append_gps_points(session_id, &points).await.is_err() {
-            tracing::error!("Failed to append points to session {}", session_id);
-            break;
+                break;
+            },
+            message => {
+                tracing::error!("Unexpected message from tracker: {:?}", message);
+                break;
+            },
         }
-
-        // tracing::info!("Received {} points succesfully", header);
     }
 
     Ok(())
 }
 
-pub struct ServerMacProvider {  }
-
-impl MacProvider for ServerMacProvider {
-    fn sign(&mut self, data: &[u8], token: &[u8]) -> [u8; SIGNATURE_SIZE] {
-        let mut hasher = Sha256::new();
-
-        hasher.update(data);
-        hasher.update(token);
-
-        let result = hasher.finalize();
+async fn send_message<M: MacProvider>(stream: &mut TcpStream, codec: &mut MessageCodec<'_, M>, message: &Message<'_>) -> Result<(), anyhow::Error> {
+    let mut buffer = [0; MAX_MESSAGE_SIZE];
+    let length = codec.encode(message, &mut buffer).map_err(|e| anyhow::anyhow!("Failed to encode message: {:?}", e))?;
+    stream.write_all(&buffer[..length]).await?;
+    Ok(())
+}
 
-        result[..SIGNATURE_SIZE].try_into().unwrap()
+/// Reads one message of the given kind into the buffer, and returns its length.
+async fn read_message<M: MacProvider>(stream: &mut TcpStream, codec: &MessageCodec<'_, M>, kind: MessageKind<'_>, buffer: &mut [u8]) -> Result<usize, anyhow::Error> {
+    let mut read = 0;
+    loop {
+        let needed = codec.required_length(kind, &buffer[..read]).map_err(|e| anyhow::anyhow!("Invalid message: {:?}", e))?;
+        if needed == read {
+            return Ok(read);
+        }
+        if needed > buffer.len() {
+            return Err(anyhow::anyhow!("Message of {} bytes is too large", needed));
+        }
+        stream.read_exact(&mut buffer[read..needed]).await?;
+        read = needed;
     }
-}
\ No newline at end of file
+}
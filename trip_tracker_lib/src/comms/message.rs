@@ -0,0 +1,551 @@
+//! The messages exchanged after the handshake.
+//!
+//! Frames sent by the tracker start with a tag byte:
+//! - `0x00`: finish the session.
+//! - `0x01..=0x7D`: data, the tag is the number of standard records (Ground profile) that follow.
+//! - `0x7E`: telemetry. The report ends with the firmware version, prefixed by its length (see `Telemetry`).
+//! - `0x7F`: heartbeat.
+//! - `0x80 | count`: data as compressed records. The tag is followed by the quantization profile id
+//!   and the payload length as a big endian u16, and the payload always starts with a keyframe.
+//!
+//! From protocol version 2, the tag of every frame but finish is followed by the frame's sequence number
+//! as a big endian u32, and the frame is signed with the connection key (see `FrameSequence`).
+//! Every frame but finish ends with a signature of everything before it.
+//!
+//! Messages sent by the server have no tag, so the receiver has to know what to expect.
+//! From protocol version 2, the server acknowledges every data frame with the number of points it has stored
+//! for the session in total, and answers a reconnect with the same count, so the tracker knows where to resume.
+
+use chrono::{DateTime, Utc};
+
+use crate::{telemetry::{Telemetry, FIXED_LENGTH as TELEMETRY_FIXED_LENGTH, MAX_FIRMWARE_VERSION_LENGTH}, track_point::{QuantizationProfile, TrackPoint, ENCODED_LENGTH}, tsf::{CompressedDecoder, CompressedEncoder, TsfError, MAX_COMPRESSED_RECORD_LENGTH}};
+
+use super::{CommsError, FrameSequence, MacProvider, COMPRESSED_FLAG, MAX_TRACK_POINTS_PER_MESSAGE, NONCE_SIZE, SEQUENCE_NUMBER_SIZE, SIGNATURE_SIZE};
+
+const FINISH_TAG: u8 = 0x00;
+const TELEMETRY_TAG: u8 = 0x7E;
+const HEARTBEAT_TAG: u8 = 0x7F;
+const FINISH_ACK: u8 = 1;
+
+/// Largest number of points in a frame of standard records.
+const MAX_STANDARD_COUNT: u8 = TELEMETRY_TAG - 1;
+/// Largest number of points in a frame of compressed records.
+const MAX_COMPRESSED_COUNT: u8 = !COMPRESSED_FLAG;
+
+/// Size of the buffer needed to compress `MAX_TRACK_POINTS_PER_MESSAGE` points.
+pub const MAX_PAYLOAD_SIZE: usize = MAX_TRACK_POINTS_PER_MESSAGE * MAX_COMPRESSED_RECORD_LENGTH;
+
+/// What the receiver expects next. Needed because only frames sent by the tracker are tagged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageKind<'n> {
+    HandshakeChallenge,
+    SessionCreated,
+    SessionResumed,
+    /// Data, telemetry, heartbeat or finish, told apart by their tag.
+    Frame,
+    DataAck,
+    FinishChallenge,
+    /// The response must be a signature of the challenge nonce.
+    FinishResponse { nonce: &'n [u8; NONCE_SIZE] },
+    FinishAck,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Message<'a> {
+    /// Server → tracker: the nonce the handshake must be signed with.
+    HandshakeChallenge { nonce: [u8; NONCE_SIZE] },
+    /// Server → tracker: the id of the session registered by a fresh session handshake.
+    SessionCreated { session_id: i64 },
+    /// Server → tracker: the number of points stored for the session a reconnect handshake resumed.
+    SessionResumed { stored_points: u32 },
+    /// Tracker → server: track points of the session.
+    Data(Points<'a>),
+    /// Server → tracker: the number of points stored for the session, after storing a data frame.
+    DataAck { stored_points: u32 },
+    /// Tracker → server: the state of the tracker, sent every now and then.
+    Telemetry(Telemetry<'a>),
+    /// Tracker → server: keeps the connection alive when there are no points to send.
+    Heartbeat,
+    /// Tracker → server: the session is over.
+    Finish,
+    /// Server → tracker: the nonce the tracker must sign to prove the finish is authentic.
+    FinishChallenge { nonce: [u8; NONCE_SIZE] },
+    /// Tracker → server: the signed finish nonce. Created with `MessageCodec::finish_response`.
+    FinishResponse { signature: [u8; SIGNATURE_SIZE] },
+    /// Server → tracker: the session has been ended.
+    FinishAck,
+}
+
+/// The track points of a data frame, still encoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Points<'a> {
+    /// Standard records of the Ground profile, as sent by legacy trackers.
+    Standard { count: u8, records: &'a [u8] },
+    Compressed { count: u8, profile: QuantizationProfile, payload: &'a [u8] },
+}
+
+impl<'a> Points<'a> {
+    /// Compresses the points into the buffer, which must have room for `MAX_COMPRESSED_RECORD_LENGTH` bytes per point.
+    /// Every frame gets its own encoder, so it can be decoded on its own.
+    pub fn compress(track_points: &[TrackPoint], session_start: DateTime<Utc>, profile: QuantizationProfile, buffer: &'a mut [u8]) -> Result<Self, CommsError> {
+        if track_points.is_empty() || track_points.len() > MAX_COMPRESSED_COUNT as usize || buffer.len() < track_points.len() * MAX_COMPRESSED_RECORD_LENGTH {
+            return Err(CommsError::EncodeError);
+        }
+
+        let mut encoder = CompressedEncoder::new(session_start, profile);
+        let mut record = [0; MAX_COMPRESSED_RECORD_LENGTH];
+        let mut length = 0;
+        for track_point in track_points {
+            let record_length = encoder.encode(track_point, &mut record);
+            buffer[length..length + record_length].copy_from_slice(&record[..record_length]);
+            length += record_length;
+        }
+
+        Ok(Self::Compressed {
+            count: track_points.len() as u8,
+            profile,
+            payload: &buffer[..length],
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        match self {
+            Self::Standard { count, .. } | Self::Compressed { count, .. } => *count as usize,
+        }
+    }
+
+    /// Decodes the points. Yields an error if the records are invalid or there are bytes left after the last point.
+    pub fn track_points(&self, session_start: DateTime<Utc>) -> TrackPointIter<'a> {
+        let decoder = match self {
+            Self::Standard { .. } => None,
+            Self::Compressed { profile, .. } => Some(CompressedDecoder::new(session_start, *profile)),
+        };
+
+        TrackPointIter {
+            points: *self,
+            session_start,
+            decoder,
+            position: 0,
+            decoded: 0,
+            done: false,
+        }
+    }
+
+    fn bytes(&self) -> &'a [u8] {
+        match self {
+            Self::Standard { records, .. } => records,
+            Self::Compressed { payload, .. } => payload,
+        }
+    }
+}
+
+pub struct TrackPointIter<'a> {
+    points: Points<'a>,
+    session_start: DateTime<Utc>,
+    decoder: Option<CompressedDecoder>,
+    position: usize,
+    decoded: usize,
+    done: bool,
+}
+
+impl Iterator for TrackPointIter<'_> {
+    type Item = Result<TrackPoint, TsfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let bytes = self.points.bytes();
+        if self.decoded == self.points.count() {
+            self.done = true;
+            return (self.position != bytes.len()).then_some(Err(TsfError::InvalidRecord));
+        }
+
+        let result = match self.decoder.as_mut() {
+            Some(decoder) => decoder.decode(&bytes[self.position..]),
+            None => match bytes.get(self.position..self.position + ENCODED_LENGTH) {
+                Some(record) => Ok((TrackPoint::from_bytes(record, self.session_start, QuantizationProfile::Ground), ENCODED_LENGTH)),
+                None => Err(TsfError::TruncatedRecord),
+            },
+        };
+
+        match result {
+            Ok((track_point, length)) => {
+                self.position += length;
+                self.decoded += 1;
+                Some(Ok(track_point))
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+/// Encodes and decodes the messages of one connection, signing frames as negotiated by the handshake.
+///
+/// `key` is the trip key. Frames are signed with it directly unless `sequence` is given,
+/// in which case they are numbered and signed with the connection key.
+pub struct MessageCodec<'a, M> {
+    mac: &'a mut M,
+    key: &'a [u8],
+    sequence: Option<&'a mut FrameSequence>,
+}
+
+impl<'a, M: MacProvider> MessageCodec<'a, M> {
+    pub fn new(mac: &'a mut M, key: &'a [u8], sequence: Option<&'a mut FrameSequence>) -> Self {
+        Self {
+            mac,
+            key,
+            sequence,
+        }
+    }
+
+    /// The number of bytes needed to decode the message starting with `bytes`, as far as can be told from them.
+    /// Read until `bytes` is this long, and then decode.
+    pub fn required_length(&self, kind: MessageKind, bytes: &[u8]) -> Result<usize, CommsError> {
+        match self.layout(kind, bytes) {
+            Err(CommsError::Incomplete { needed }) => Ok(needed),
+            result => result.map(|layout| layout.length),
+        }
+    }
+
+    /// Decodes the message at the start of `bytes`, verifying signatures and sequence numbers.
+    /// Returns the message and its length.
+    pub fn decode<'b>(&mut self, kind: MessageKind, bytes: &'b [u8]) -> Result<(Message<'b>, usize), CommsError> {
+        let layout = self.layout(kind, bytes)?;
+        if bytes.len() < layout.length {
+            return Err(CommsError::Incomplete { needed: layout.length });
+        }
+        let bytes = &bytes[..layout.length];
+
+        let message = match kind {
+            MessageKind::HandshakeChallenge => Message::HandshakeChallenge { nonce: bytes.try_into().unwrap() }, // Safe unwrap
+            MessageKind::SessionCreated => Message::SessionCreated { session_id: i64::from_be_bytes(bytes.try_into().unwrap()) }, // Safe unwrap
+            MessageKind::SessionResumed => Message::SessionResumed { stored_points: u32::from_be_bytes(bytes.try_into().unwrap()) }, // Safe unwrap
+            MessageKind::DataAck => Message::DataAck { stored_points: u32::from_be_bytes(bytes.try_into().unwrap()) }, // Safe unwrap
+            MessageKind::FinishChallenge => Message::FinishChallenge { nonce: bytes.try_into().unwrap() }, // Safe unwrap
+            MessageKind::FinishResponse { nonce } => {
+                if !self.mac.verify(nonce, bytes, self.key) {
+                    return Err(CommsError::WrongSignature);
+                }
+                Message::FinishResponse { signature: bytes.try_into().unwrap() } // Safe unwrap
+            },
+            MessageKind::FinishAck => match bytes[0] {
+                FINISH_ACK => Message::FinishAck,
+                _ => return Err(CommsError::DecodeError),
+            },
+            MessageKind::Frame if bytes[0] == FINISH_TAG => Message::Finish,
+            MessageKind::Frame => {
+                let (data, signature) = bytes.split_at(layout.length - SIGNATURE_SIZE);
+                let key = frame_key(&self.sequence, self.key);
+                if !self.mac.verify(data, signature, key) {
+                    return Err(CommsError::WrongSignature);
+                }
+
+                // Only accepted once the signature shows the sequence number is authentic
+                if let Some(sequence) = self.sequence.as_mut() {
+                    let sequence_number = u32::from_be_bytes(data[1..1 + SEQUENCE_NUMBER_SIZE].try_into().unwrap()); // Safe unwrap
+                    sequence.accept(sequence_number)?;
+                }
+
+                let tag = data[0];
+                let payload = &data[layout.header_length..];
+                if tag == HEARTBEAT_TAG {
+                    Message::Heartbeat
+                } else if tag == TELEMETRY_TAG {
+                    Message::Telemetry(Telemetry::from_bytes(payload)?)
+                } else if tag & COMPRESSED_FLAG != 0 {
+                    let profile = QuantizationProfile::from_id(data[layout.header_length - 3]).ok_or(CommsError::DecodeError)?;
+                    Message::Data(Points::Compressed { count: tag & !COMPRESSED_FLAG, profile, payload })
+                } else {
+                    Message::Data(Points::Standard { count: tag, records: payload })
+                }
+            },
+        };
+
+        Ok((message, layout.length))
+    }
+
+    /// Encodes the message into `out`, numbering and signing frames. Returns the encoded length.
+    pub fn encode(&mut self, message: &Message, out: &mut [u8]) -> Result<usize, CommsError> {
+        let mut telemetry_bytes = [0; TELEMETRY_FIXED_LENGTH + MAX_FIRMWARE_VERSION_LENGTH];
+        let (tag, points, body): (_, Option<&Points>, &[u8]) = match message {
+            Message::HandshakeChallenge { nonce } | Message::FinishChallenge { nonce } => return write(out, nonce),
+            Message::SessionCreated { session_id } => return write(out, &session_id.to_be_bytes()),
+            Message::SessionResumed { stored_points } | Message::DataAck { stored_points } => return write(out, &stored_points.to_be_bytes()),
+            Message::FinishResponse { signature } => return write(out, signature),
+            Message::FinishAck => return write(out, &[FINISH_ACK]),
+            Message::Finish => return write(out, &[FINISH_TAG]),
+            Message::Heartbeat => (HEARTBEAT_TAG, None, &[]),
+            Message::Telemetry(telemetry) => {
+                let length = telemetry.to_bytes(&mut telemetry_bytes)?;
+                (TELEMETRY_TAG, None, &telemetry_bytes[..length])
+            },
+            Message::Data(points @ Points::Standard { count, records }) => {
+                if *count == 0 || *count > MAX_STANDARD_COUNT || records.len() != *count as usize * ENCODED_LENGTH {
+                    return Err(CommsError::EncodeError);
+                }
+                (*count, Some(points), records)
+            },
+            Message::Data(points @ Points::Compressed { count, payload, .. }) => {
+                if *count == 0 || *count > MAX_COMPRESSED_COUNT || payload.len() > *count as usize * MAX_COMPRESSED_RECORD_LENGTH {
+                    return Err(CommsError::EncodeError);
+                }
+                (COMPRESSED_FLAG | count, Some(points), payload)
+            },
+        };
+
+        let header_length = 1
+            + if self.sequence.is_some() { SEQUENCE_NUMBER_SIZE } else { 0 }
+            + if let Some(Points::Compressed { .. }) = points { 3 } else { 0 };
+        let length = header_length + body.len() + SIGNATURE_SIZE;
+        if out.len() < length {
+            return Err(CommsError::EncodeError);
+        }
+
+        out[0] = tag;
+        let mut position = 1;
+        if let Some(sequence) = self.sequence.as_mut() {
+            out[position..position + SEQUENCE_NUMBER_SIZE].copy_from_slice(&sequence.next_sequence_number().to_be_bytes());
+            position += SEQUENCE_NUMBER_SIZE;
+        }
+        if let Some(Points::Compressed { profile, payload, .. }) = points {
+            out[position] = profile.id();
+            out[position + 1..position + 3].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+            position += 3;
+        }
+        out[position..position + body.len()].copy_from_slice(body);
+        position += body.len();
+
+        let key = frame_key(&self.sequence, self.key);
+        let signature = self.mac.sign(&out[..position], key);
+        out[position..length].copy_from_slice(&signature);
+
+        Ok(length)
+    }
+
+    /// Signs the finish challenge with the trip key.
+    pub fn finish_response(&mut self, nonce: &[u8; NONCE_SIZE]) -> Message<'static> {
+        Message::FinishResponse { signature: self.mac.sign(nonce, self.key) }
+    }
+
+    fn layout(&self, kind: MessageKind, bytes: &[u8]) -> Result<Layout, CommsError> {
+        let fixed = |length| Ok(Layout { header_length: 0, length });
+
+        match kind {
+            MessageKind::HandshakeChallenge | MessageKind::FinishChallenge => fixed(NONCE_SIZE),
+            MessageKind::SessionCreated => fixed(8),
+            MessageKind::SessionResumed | MessageKind::DataAck => fixed(4),
+            MessageKind::FinishResponse { .. } => fixed(SIGNATURE_SIZE),
+            MessageKind::FinishAck => fixed(1),
+            MessageKind::Frame => {
+                let require = |needed| if bytes.len() < needed { Err(CommsError::Incomplete { needed }) } else { Ok(()) };
+
+                require(1)?;
+                let tag = bytes[0];
+                if tag == FINISH_TAG {
+                    return fixed(1);
+                }
+
+                let mut header_length = 1;
+                if self.sequence.is_some() {
+                    header_length += SEQUENCE_NUMBER_SIZE;
+                }
+
+                let payload_length = if tag == HEARTBEAT_TAG {
+                    0
+                } else if tag == TELEMETRY_TAG {
+                    require(header_length + TELEMETRY_FIXED_LENGTH)?;
+                    TELEMETRY_FIXED_LENGTH + bytes[header_length + TELEMETRY_FIXED_LENGTH - 1] as usize
+                } else if tag & COMPRESSED_FLAG != 0 {
+                    let count = (tag & !COMPRESSED_FLAG) as usize;
+                    require(header_length + 3)?;
+                    let payload_length = u16::from_be_bytes([bytes[header_length + 1], bytes[header_length + 2]]) as usize;
+                    if count == 0 || payload_length > count * MAX_COMPRESSED_RECORD_LENGTH {
+                        return Err(CommsError::DecodeError);
+                    }
+                    header_length += 3;
+                    payload_length
+                } else {
+                    tag as usize * ENCODED_LENGTH
+                };
+
+                Ok(Layout {
+                    header_length,
+                    length: header_length + payload_length + SIGNATURE_SIZE,
+                })
+            },
+        }
+    }
+}
+
+struct Layout {
+    /// Where the payload of a data frame starts.
+    header_length: usize,
+    length: usize,
+}
+
+fn frame_key<'k>(sequence: &'k Option<&mut FrameSequence>, key: &'k [u8]) -> &'k [u8] {
+    sequence.as_deref().map_or(key, FrameSequence::key)
+}
+
+fn write(out: &mut [u8], bytes: &[u8]) -> Result<usize, CommsError> {
+    let Some(out) = out.get_mut(..bytes.len()) else {
+        return Err(CommsError::EncodeError);
+    };
+    out.copy_from_slice(bytes);
+    Ok(bytes.len())
+}
+
+#[cfg(test)]
+fn test_points() -> (DateTime<Utc>, std::vec::Vec<TrackPoint>) {
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let points = (0..MAX_TRACK_POINTS_PER_MESSAGE as i64)
+        .map(|i| TrackPoint::new(start + chrono::Duration::seconds(i * 5), 55.5 + i as f64 * 0.0003, 10.1, 40., 12., true))
+        .collect();
+    (start, points)
+}
+
+#[test]
+fn message_roundtrip_test() {
+    use crate::mac::HmacSha256;
+
+    let (start, points) = test_points();
+    let key = b"trip key";
+    let nonce = [3; NONCE_SIZE];
+
+    let mut records = std::vec::Vec::new();
+    for point in &points[..10] {
+        records.extend_from_slice(&point.to_bytes(start, QuantizationProfile::Ground));
+    }
+    let mut payload = [0; MAX_PAYLOAD_SIZE];
+    let compressed = Points::compress(&points, start, QuantizationProfile::Ground, &mut payload).unwrap();
+    let standard = Points::Standard { count: 10, records: &records };
+
+    let messages = [
+        Message::HandshakeChallenge { nonce },
+        Message::SessionCreated { session_id: 42 },
+        Message::SessionResumed { stored_points: 1234 },
+        Message::Data(compressed),
+        Message::DataAck { stored_points: 1284 },
+        Message::Data(standard),
+        Message::Heartbeat,
+        Message::Telemetry(Telemetry {
+            battery_percent: Some(8),
+            power_source: crate::telemetry::PowerSource::Battery,
+            rssi: Some(14),
+            ber: Some(0),
+            satellites: None,
+            satellites_used: None,
+            free_storage_kib: Some(3_900_000),
+            uptime_secs: 1234,
+            firmware_version: "0.3.1",
+        }),
+        Message::Finish,
+        Message::FinishChallenge { nonce },
+        MessageCodec::new(&mut HmacSha256, key, None).finish_response(&nonce),
+        Message::FinishAck,
+    ];
+
+    // Legacy connections and numbered connections
+    for numbered in [false, true] {
+        let (mut tracker_sequence, mut server_sequence) = (FrameSequence::new(key, &nonce), FrameSequence::new(key, &nonce));
+        let (mut tracker_mac, mut server_mac) = (HmacSha256, HmacSha256);
+        let mut tracker = MessageCodec::new(&mut tracker_mac, key, numbered.then_some(&mut tracker_sequence));
+        let mut server = MessageCodec::new(&mut server_mac, key, numbered.then_some(&mut server_sequence));
+
+        for message in messages {
+            let kind = match message {
+                Message::HandshakeChallenge { .. } => MessageKind::HandshakeChallenge,
+                Message::SessionCreated { .. } => MessageKind::SessionCreated,
+                Message::SessionResumed { .. } => MessageKind::SessionResumed,
+                Message::DataAck { .. } => MessageKind::DataAck,
+                Message::Data(_) | Message::Telemetry(_) | Message::Heartbeat | Message::Finish => MessageKind::Frame,
+                Message::FinishChallenge { .. } => MessageKind::FinishChallenge,
+                Message::FinishResponse { .. } => MessageKind::FinishResponse { nonce: &nonce },
+                Message::FinishAck => MessageKind::FinishAck,
+            };
+
+            let mut out = [0; super::MAX_MESSAGE_SIZE];
+            let length = tracker.encode(&message, &mut out).unwrap();
+
+            // Read the way a stream is read: only as much as is known to be needed
+            let mut read = 0;
+            loop {
+                let needed = server.required_length(kind, &out[..read]).unwrap();
+                if needed == read {
+                    break;
+                }
+                read = needed;
+            }
+            assert_eq!(read, length);
+
+            let (decoded, decoded_length) = server.decode(kind, &out[..length]).unwrap();
+            assert_eq!(decoded_length, length);
+            assert_eq!(decoded, message);
+        }
+    }
+
+    let decoded: std::vec::Vec<_> = compressed.track_points(start).collect::<Result<_, _>>().unwrap();
+    assert_eq!(decoded.len(), points.len());
+    assert_eq!(standard.track_points(start).count(), 10);
+}
+
+#[test]
+fn message_rejection_test() {
+    use crate::mac::HmacSha256;
+
+    let (start, points) = test_points();
+    let key = b"trip key";
+    let nonce = [3; NONCE_SIZE];
+    let mut payload = [0; MAX_PAYLOAD_SIZE];
+    let data = Message::Data(Points::compress(&points[..5], start, QuantizationProfile::Ground, &mut payload).unwrap());
+
+    let mut tracker_sequence = FrameSequence::new(key, &nonce);
+    let mut server_sequence = FrameSequence::new(key, &nonce);
+    let mut frames = std::vec::Vec::new();
+    let (mut tracker_mac, mut server_mac) = (HmacSha256, HmacSha256);
+    let mut tracker = MessageCodec::new(&mut tracker_mac, key, Some(&mut tracker_sequence));
+    for message in [data, Message::Heartbeat, data] {
+        let mut out = [0; super::MAX_MESSAGE_SIZE];
+        let length = tracker.encode(&message, &mut out).unwrap();
+        frames.push(out[..length].to_vec());
+    }
+
+    let mut server = MessageCodec::new(&mut server_mac, key, Some(&mut server_sequence));
+
+    // Tampered frames
+    let mut tampered = frames[0].clone();
+    tampered[10] ^= 1;
+    assert_eq!(server.decode(MessageKind::Frame, &tampered), Err(CommsError::WrongSignature));
+
+    // Replayed and reordered frames
+    assert!(server.decode(MessageKind::Frame, &frames[0]).is_ok());
+    assert_eq!(server.decode(MessageKind::Frame, &frames[0]), Err(CommsError::UnexpectedSequenceNumber { expected: 1, received: 0 }));
+    assert_eq!(server.decode(MessageKind::Frame, &frames[2]), Err(CommsError::UnexpectedSequenceNumber { expected: 1, received: 2 }));
+    assert_eq!(server.decode(MessageKind::Frame, &frames[1]).unwrap().0, Message::Heartbeat);
+
+    // Truncated frames
+    let length = frames[2].len();
+    assert_eq!(server.decode(MessageKind::Frame, &frames[2][..length - 1]), Err(CommsError::Incomplete { needed: length }));
+
+    // Finish responses signed with the wrong key
+    let Message::FinishResponse { signature } = MessageCodec::new(&mut HmacSha256, b"other key", None).finish_response(&nonce) else {
+        unreachable!()
+    };
+    assert_eq!(server.decode(MessageKind::FinishResponse { nonce: &nonce }, &signature), Err(CommsError::WrongSignature));
+    assert_eq!(server.decode(MessageKind::FinishAck, &[0]), Err(CommsError::DecodeError));
+
+    // Payloads with bytes left after the last point
+    let Points::Compressed { payload, .. } = Points::compress(&points[..1], start, QuantizationProfile::Ground, &mut payload).unwrap() else {
+        unreachable!()
+    };
+    let mut payload = payload.to_vec();
+    payload.push(0);
+    let points = Points::Compressed { count: 1, profile: QuantizationProfile::Ground, payload: &payload };
+    let decoded: std::vec::Vec<_> = points.track_points(start).collect();
+    assert_eq!(decoded.last(), Some(&Err(TsfError::InvalidRecord)));
+}
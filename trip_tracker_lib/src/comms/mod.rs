@@ -0,0 +1,256 @@
+use crate::{mac::{connection_key, constant_time_eq, DIGEST_SIZE}, track_point::ENCODED_LENGTH, tsf::MAX_COMPRESSED_RECORD_LENGTH};
+
+pub const SIGNATURE_SIZE: usize = 16; // bytes
+pub const NONCE_SIZE: usize = 16; // bytes
+pub const SEQUENCE_NUMBER_SIZE: usize = 4; // bytes
+pub const MAX_TRACK_POINTS_PER_MESSAGE: usize = 50;
+pub const MAX_MESSAGE_SIZE: usize = calc_max_message_size();
+
+mod message;
+
+pub use message::*;
+
+/// Set in the tag byte of a data frame when the points are sent as compressed TSF records. See `message` for the layout.
+pub const COMPRESSED_FLAG: u8 = 0x80;
+
+/// Protocol version sent first in the handshake.
+/// Version 2 signs with HMAC-SHA256 and numbers data frames to prevent replays.
+pub const PROTOCOL_VERSION: u8 = 2;
+/// Trackers without a version byte sign with SHA256(data || token).
+pub const LEGACY_PROTOCOL_VERSION: u8 = 1;
+
+pub const HANDSHAKE_LENGTH: usize = 1 + 1 + 8 + 8;
+pub const LEGACY_HANDSHAKE_LENGTH: usize = 1 + 8 + 8;
+
+const fn calc_max_message_size() -> usize {
+    let standard_size = 1 + MAX_TRACK_POINTS_PER_MESSAGE * ENCODED_LENGTH + SIGNATURE_SIZE;
+    let compressed_size = 1 + SEQUENCE_NUMBER_SIZE + 1 + 2 + MAX_TRACK_POINTS_PER_MESSAGE * MAX_COMPRESSED_RECORD_LENGTH + SIGNATURE_SIZE;
+    let size = if standard_size > compressed_size { standard_size } else { compressed_size };
+    assert!(size <= 1500, "Message size is too large. Max allowed is 1500 bytes");
+    assert!(MAX_TRACK_POINTS_PER_MESSAGE < COMPRESSED_FLAG as usize, "Point count must not overlap the compressed flag");
+    size
+}
+
+pub trait MacProvider {
+    /// Signs the data using the token as the key, truncated to `SIGNATURE_SIZE` bytes.
+    /// See `mac` for the implementations used by each protocol version.
+    fn sign(&mut self, data: &[u8], token: &[u8]) -> [u8; SIGNATURE_SIZE];
+
+    fn verify(&mut self, data: &[u8], signature: &[u8], key: &[u8]) -> bool {
+        constant_time_eq(&Self::sign(self, data, key), signature)
+    }
+}
+
+/// Signs a handshake message together with the server's challenge nonce, so it can't be replayed.
+pub fn handshake_signature<M: MacProvider>(mac: &mut M, key: &[u8], nonce: &[u8; NONCE_SIZE], handshake: &[u8]) -> [u8; SIGNATURE_SIZE] {
+    let mut data = [0; NONCE_SIZE + HANDSHAKE_LENGTH];
+    data[..NONCE_SIZE].copy_from_slice(nonce);
+    data[NONCE_SIZE..NONCE_SIZE + handshake.len()].copy_from_slice(handshake);
+    mac.sign(&data[..NONCE_SIZE + handshake.len()], key)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommsError {
+    /// More bytes are needed to decode the message.
+    Incomplete { needed: usize },
+    DecodeError,
+    EncodeError,
+    WrongSignature,
+    UnsupportedVersion(u8),
+    UnexpectedSequenceNumber { expected: u32, received: u32 },
+}
+
+/// Signing state for the data frames of one connection.
+///
+/// Frames are numbered from 0 on every connection and signed with a key derived from the handshake nonce,
+/// so a captured frame can neither be replayed into a later connection nor repeated or reordered within one.
+pub struct FrameSequence {
+    key: [u8; DIGEST_SIZE],
+    next: u32,
+}
+
+impl FrameSequence {
+    pub fn new(key: &[u8], nonce: &[u8; NONCE_SIZE]) -> Self {
+        Self {
+            key: connection_key(key, nonce),
+            next: 0,
+        }
+    }
+
+    /// The key frames of this connection are signed with.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Sequence number for the next frame to send.
+    pub fn next_sequence_number(&mut self) -> u32 {
+        let sequence_number = self.next;
+        self.next += 1;
+        sequence_number
+    }
+
+    /// Accepts the sequence number of an authenticated frame, if it is the one expected next.
+    pub fn accept(&mut self, sequence_number: u32) -> Result<(), CommsError> {
+        if sequence_number != self.next {
+            return Err(CommsError::UnexpectedSequenceNumber { expected: self.next, received: sequence_number });
+        }
+        self.next += 1;
+        Ok(())
+    }
+}
+
+pub enum HandshakeMessage {
+    FreshSession {
+        trip_id: i64,
+        timestamp: i64,
+    },
+    Reconnect {
+        trip_id: i64,
+        session_id: i64,
+    },
+}
+
+impl HandshakeMessage {
+    pub fn new_fresh(trip_id: i64, timestamp: i64) -> Self {
+        Self::FreshSession {
+            trip_id,
+            timestamp,
+        }
+    }
+
+    pub fn new_reconnect(trip_id: i64, session_id: i64) -> Self {
+        Self::Reconnect {
+            trip_id,
+            session_id,
+        }
+    }
+
+    pub fn trip_id(&self) -> i64 {
+        match self {
+            Self::FreshSession { trip_id, .. } => *trip_id,
+            Self::Reconnect { trip_id, .. } => *trip_id,
+        }
+    }
+
+    pub fn session_id(&self) -> i64 {
+        match self {
+            Self::FreshSession { timestamp, .. } => *timestamp,
+            Self::Reconnect { session_id, .. } => *session_id,
+        }
+    }
+
+    pub fn is_fresh_session(&self) -> bool {
+        match self {
+            Self::FreshSession { .. } => true,
+            Self::Reconnect { .. } => false,
+        }
+    }
+}
+
+impl HandshakeMessage {
+    /// The length of a handshake message, given its first byte.
+    /// Legacy messages start directly with the message type (0 or 1), newer ones with the protocol version.
+    pub fn message_length(first_byte: u8) -> Result<usize, CommsError> {
+        match first_byte {
+            0 | 1 => Ok(LEGACY_HANDSHAKE_LENGTH),
+            PROTOCOL_VERSION => Ok(HANDSHAKE_LENGTH),
+            version => Err(CommsError::UnsupportedVersion(version)),
+        }
+    }
+
+    pub fn serialize(&self) -> [u8; HANDSHAKE_LENGTH] {
+        let mut data = [0; HANDSHAKE_LENGTH];
+        data[0] = PROTOCOL_VERSION;
+        data[1..].copy_from_slice(&self.serialize_legacy());
+        data
+    }
+
+    pub fn serialize_legacy(&self) -> [u8; LEGACY_HANDSHAKE_LENGTH] {
+        let mut data = [0; LEGACY_HANDSHAKE_LENGTH];
+
+        match self {
+            Self::FreshSession { trip_id, timestamp } => {
+                data[0] = 0;
+                data[1..9].copy_from_slice(&trip_id.to_be_bytes());
+                data[9..17].copy_from_slice(&timestamp.to_be_bytes());
+            },
+            Self::Reconnect { trip_id, session_id } => {
+                data[0] = 1;
+                data[1..9].copy_from_slice(&trip_id.to_be_bytes());
+                data[9..17].copy_from_slice(&session_id.to_be_bytes());
+            },
+        }
+
+        data
+    }
+
+    /// Returns the message and the protocol version the tracker speaks.
+    pub fn deserialize(data: &[u8]) -> Result<(Self, u8), CommsError> {
+        let Some(&first_byte) = data.first() else {
+            return Err(CommsError::DecodeError);
+        };
+        let length = Self::message_length(first_byte)?;
+        if data.len() != length {
+            return Err(CommsError::DecodeError);
+        }
+
+        let (version, data) = if length == LEGACY_HANDSHAKE_LENGTH {
+            (LEGACY_PROTOCOL_VERSION, data)
+        } else {
+            (first_byte, &data[1..])
+        };
+
+        let message_type = data[0];
+        let trip_id = i64::from_be_bytes(data[1..9].try_into().unwrap());
+        let session_id_or_timestamp = i64::from_be_bytes(data[9..17].try_into().unwrap());
+        
+        match message_type {
+            0 => Ok((Self::new_fresh(trip_id, session_id_or_timestamp), version)),
+            1 => Ok((Self::new_reconnect(trip_id, session_id_or_timestamp), version)),
+            _ => Err(CommsError::DecodeError),
+        }
+    }
+}
+
+#[test]
+fn handshake_test() {
+    let message = HandshakeMessage::new_reconnect(3, 1_700_000_000);
+
+    let bytes = message.serialize();
+    assert_eq!(HandshakeMessage::message_length(bytes[0]).unwrap(), HANDSHAKE_LENGTH);
+    let (decoded, version) = HandshakeMessage::deserialize(&bytes).unwrap();
+    assert_eq!(version, PROTOCOL_VERSION);
+    assert!(!decoded.is_fresh_session());
+    assert_eq!((decoded.trip_id(), decoded.session_id()), (3, 1_700_000_000));
+
+    // Old trackers send the message without a version
+    let legacy = HandshakeMessage::new_fresh(3, 42).serialize_legacy();
+    assert_eq!(HandshakeMessage::message_length(legacy[0]).unwrap(), LEGACY_HANDSHAKE_LENGTH);
+    let (decoded, version) = HandshakeMessage::deserialize(&legacy).unwrap();
+    assert_eq!(version, LEGACY_PROTOCOL_VERSION);
+    assert!(decoded.is_fresh_session());
+    assert_eq!((decoded.trip_id(), decoded.session_id()), (3, 42));
+
+    assert!(matches!(HandshakeMessage::message_length(7), Err(CommsError::UnsupportedVersion(7))));
+    assert!(HandshakeMessage::deserialize(&bytes[..LEGACY_HANDSHAKE_LENGTH]).is_err());
+}
+
+#[test]
+fn frame_sequence_test() {
+    let key = b"secret";
+    let nonce = [7; NONCE_SIZE];
+
+    let mut tracker = FrameSequence::new(key, &nonce);
+    let mut server = FrameSequence::new(key, &nonce);
+    assert_eq!(tracker.key(), server.key());
+
+    let first = tracker.next_sequence_number();
+    let second = tracker.next_sequence_number();
+    assert!(server.accept(first).is_ok());
+    assert!(matches!(server.accept(first), Err(CommsError::UnexpectedSequenceNumber { expected: 1, received: 0 })));
+    assert!(server.accept(second + 1).is_err());
+    assert!(server.accept(second).is_ok());
+
+    // Another connection gets another key
+    assert_ne!(FrameSequence::new(key, &[8; NONCE_SIZE]).key(), tracker.key());
+}
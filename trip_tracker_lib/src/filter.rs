@@ -0,0 +1,264 @@
+//! Removal of points the tracker can't have been at, from poor fixes and GNSS glitches.
+//!
+//! The stages run in order, and a point is dropped by the first stage that rejects it. Stages comparing
+//! a point to its neighbours only compare it to points that have been kept.
+
+use std::{collections::{BTreeMap, HashSet}, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{geo, track_point::TrackPoint};
+
+/// Thresholds of the filter stages. Stages set to `None` or `false` are skipped.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilterConfig {
+    /// Drop points at exactly the same position as an earlier point
+    pub remove_duplicates: bool,
+    /// Drop points that weren't recorded with good precision
+    pub require_good_precision: bool,
+    /// Drop points with a higher HDOP. Points with an unknown HDOP are kept
+    pub max_hdop: Option<f32>,
+    /// Drop points that aren't later than the last kept point
+    pub remove_out_of_order: bool,
+    /// Drop points further from the last kept point than this, in metres
+    pub max_jump: Option<f64>,
+    /// Drop points reached faster than this from the last kept point
+    pub max_speed_kph: Option<f64>,
+    /// Drop points that need a larger change of speed than this, in m/s²
+    pub max_acceleration: Option<f64>,
+    /// Drop points where the detour through them is this many times longer than going straight between their neighbours
+    pub max_spike_ratio: Option<f64>,
+}
+
+impl Default for FilterConfig {
+    /// The thresholds used before filters could be configured
+    fn default() -> Self {
+        Self {
+            remove_duplicates: true,
+            require_good_precision: false,
+            max_hdop: None,
+            remove_out_of_order: true,
+            max_jump: Some(5000.),
+            max_speed_kph: None,
+            max_acceleration: None,
+            max_spike_ratio: Some(5.),
+        }
+    }
+}
+
+/// Why a point was dropped, with the value that broke the threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropReason {
+    Duplicate,
+    PoorPrecision,
+    Hdop(f32),
+    OutOfOrder,
+    /// Metres from the last kept point
+    Jump(f64),
+    Speed(f64),
+    /// m/s²
+    Acceleration(f64),
+    /// Detour length over the direct length
+    Spike(f64),
+}
+
+impl DropReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Duplicate => "duplicate",
+            Self::PoorPrecision => "poor precision",
+            Self::Hdop(_) => "hdop",
+            Self::OutOfOrder => "out of order",
+            Self::Jump(_) => "jump",
+            Self::Speed(_) => "speed",
+            Self::Acceleration(_) => "acceleration",
+            Self::Spike(_) => "spike",
+        }
+    }
+}
+
+impl Display for DropReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Duplicate | Self::PoorPrecision | Self::OutOfOrder => write!(f, "{}", self.name()),
+            Self::Hdop(hdop) => write!(f, "hdop {:.1}", hdop),
+            Self::Jump(distance) => write!(f, "jump of {:.0} m", distance),
+            Self::Speed(speed) => write!(f, "speed of {:.0} km/h", speed),
+            Self::Acceleration(acceleration) => write!(f, "acceleration of {:.1} m/s²", acceleration),
+            Self::Spike(ratio) => write!(f, "spike {:.1} times longer than its base", ratio),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DroppedPoint {
+    pub index: usize,
+    pub reason: DropReason,
+}
+
+/// The outcome of filtering a track. Indices are into the points that were filtered, in order.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FilterReport {
+    pub kept: Vec<usize>,
+    pub dropped: Vec<DroppedPoint>,
+}
+
+impl FilterReport {
+    /// The kept points. `track_points` must be the points that were filtered.
+    pub fn select(&self, track_points: &[TrackPoint]) -> Vec<TrackPoint> {
+        self.kept.iter().map(|&i| track_points[i].clone()).collect()
+    }
+
+    /// How many points each stage dropped, by the name of the reason.
+    pub fn reason_counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for dropped in &self.dropped {
+            *counts.entry(dropped.reason.name()).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+impl FilterConfig {
+    pub fn filter(&self, track_points: &[TrackPoint]) -> FilterReport {
+        let mut report = FilterReport::default();
+
+        // Stages that only look at the point itself
+        let mut seen = HashSet::new();
+        let mut candidates = Vec::with_capacity(track_points.len());
+        for (index, point) in track_points.iter().enumerate() {
+            match self.check_point(point, &mut seen) {
+                Some(reason) => report.dropped.push(DroppedPoint { index, reason }),
+                None => candidates.push(index),
+            }
+        }
+
+        // Stages that compare the point to the last kept point, and to the next candidate
+        let mut last_kept: Option<usize> = None;
+        let mut last_speed: Option<f64> = None;
+        for (i, &index) in candidates.iter().enumerate() {
+            let point = &track_points[index];
+            let Some(previous) = last_kept.map(|previous| &track_points[previous]) else {
+                report.kept.push(index);
+                last_kept = Some(index);
+                continue;
+            };
+            let next = candidates.get(i + 1).map(|&next| &track_points[next]);
+
+            let secs = (point.timestamp - previous.timestamp).num_milliseconds() as f64 / 1000.;
+            let distance = geo::distance(previous.position(), point.position());
+            let speed = (secs > 0.).then(|| distance / secs);
+
+            match self.check_movement(previous, point, next, secs, distance, speed, last_speed) {
+                Some(reason) => report.dropped.push(DroppedPoint { index, reason }),
+                None => {
+                    report.kept.push(index);
+                    last_kept = Some(index);
+                    last_speed = speed;
+                },
+            }
+        }
+
+        report.dropped.sort_by_key(|dropped| dropped.index);
+        report
+    }
+
+    /// Filters the points, and returns the kept points with the report.
+    pub fn apply(&self, track_points: Vec<TrackPoint>) -> (Vec<TrackPoint>, FilterReport) {
+        let report = self.filter(&track_points);
+        (report.select(&track_points), report)
+    }
+
+    fn check_point(&self, point: &TrackPoint, seen: &mut HashSet<(u64, u64)>) -> Option<DropReason> {
+        if self.require_good_precision && !point.good_precision {
+            return Some(DropReason::PoorPrecision);
+        }
+        if let (Some(max_hdop), Some(hdop)) = (self.max_hdop, point.quality.hdop) {
+            if hdop > max_hdop {
+                return Some(DropReason::Hdop(hdop));
+            }
+        }
+        if self.remove_duplicates && !seen.insert((point.latitude.to_bits(), point.longitude.to_bits())) {
+            return Some(DropReason::Duplicate);
+        }
+        None
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn check_movement(&self, previous: &TrackPoint, point: &TrackPoint, next: Option<&TrackPoint>, secs: f64, distance: f64, speed: Option<f64>, last_speed: Option<f64>) -> Option<DropReason> {
+        if self.remove_out_of_order && secs <= 0. {
+            return Some(DropReason::OutOfOrder);
+        }
+        if self.max_jump.is_some_and(|max_jump| distance > max_jump) {
+            return Some(DropReason::Jump(distance));
+        }
+        if let (Some(max_speed_kph), Some(speed)) = (self.max_speed_kph, speed) {
+            if speed * 3.6 > max_speed_kph {
+                return Some(DropReason::Speed(speed * 3.6));
+            }
+        }
+        if let (Some(max_acceleration), Some(speed), Some(last_speed)) = (self.max_acceleration, speed, last_speed) {
+            let acceleration = (speed - last_speed).abs() / secs;
+            if acceleration > max_acceleration {
+                return Some(DropReason::Acceleration(acceleration));
+            }
+        }
+        if let (Some(max_spike_ratio), Some(next)) = (self.max_spike_ratio, next) {
+            let detour = distance + geo::distance(point.position(), next.position());
+            let direct = geo::distance(previous.position(), next.position());
+            if detour > direct * max_spike_ratio {
+                return Some(DropReason::Spike(detour / direct));
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn filter_test() {
+    use chrono::DateTime;
+
+    // North at 36 km/h, 100 m every 10 seconds
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let step = 100. / 111_250.;
+    let mut track: Vec<TrackPoint> = (0..10)
+        .map(|i| TrackPoint::new(start + chrono::Duration::seconds(i * 10), 55. + i as f64 * step, 10., 0., 36., true))
+        .collect();
+    track[2].longitude += 0.1; // 6 km off to the side
+    track[4] = track[3].clone(); // Duplicate
+    track[4].timestamp += chrono::Duration::seconds(5);
+    track[6].good_precision = false;
+    track[7].timestamp = track[5].timestamp; // Recorded out of order
+    track[8].quality.hdop = Some(4.);
+
+    let report = FilterConfig::default().filter(&track);
+    assert_eq!(report.kept, vec![0, 1, 3, 5, 6, 8, 9]);
+    assert_eq!(report.dropped.iter().map(|d| d.index).collect::<Vec<_>>(), vec![2, 4, 7]);
+    assert!(matches!(report.dropped[0].reason, DropReason::Jump(distance) if distance > 5000.));
+    assert_eq!(report.dropped[1].reason, DropReason::Duplicate);
+    assert_eq!(report.dropped[2].reason, DropReason::OutOfOrder);
+    assert_eq!(report.reason_counts().get("jump"), Some(&1));
+
+    let strict = FilterConfig {
+        require_good_precision: true,
+        max_hdop: Some(2.),
+        max_jump: None,
+        max_speed_kph: Some(100.),
+        ..Default::default()
+    };
+    let (kept, report) = strict.apply(track.clone());
+    assert_eq!(kept.len(), 5);
+    assert!(matches!(report.dropped[0].reason, DropReason::Speed(speed) if speed > 2000.));
+    assert_eq!(report.dropped[2].reason, DropReason::PoorPrecision);
+    assert_eq!(report.dropped[4].reason, DropReason::Hdop(4.));
+
+    // A spike within the jump limit, which also needs a sudden acceleration
+    let mut track: Vec<TrackPoint> = track.iter().map(|p| TrackPoint { quality: Default::default(), good_precision: true, ..p.clone() }).collect();
+    track.truncate(4);
+    track[2].longitude = 10.01;
+    track[3].timestamp = track[1].timestamp + chrono::Duration::seconds(1000);
+    let spikes = FilterConfig { max_spike_ratio: Some(3.), ..Default::default() };
+    assert!(matches!(spikes.filter(&track).dropped[..], [DroppedPoint { index: 2, reason: DropReason::Spike(_) }]));
+    let acceleration = FilterConfig { max_spike_ratio: None, max_acceleration: Some(1.), ..Default::default() };
+    assert!(matches!(acceleration.filter(&track).dropped[..], [DroppedPoint { index: 2, reason: DropReason::Acceleration(_) }]));
+}
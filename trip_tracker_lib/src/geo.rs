@@ -0,0 +1,407 @@
+//! Geodesy on the WGS84 ellipsoid.
+//!
+//! Positions are `(latitude, longitude)` in degrees, distances are in metres and bearings are in degrees
+//! clockwise from north, in `0..360`. Distances, bearings and destinations are solved on the ellipsoid with
+//! Vincenty's formulae, which are accurate to well below a millimetre. Interpolation and distances to segments
+//! are solved on a sphere of the mean radius, which is plenty for the short segments between track points.
+
+use core::f64::consts::PI;
+
+use libm::{atan, atan2, cos, fabs, sin, sqrt, tan};
+
+/// WGS84 semi-major axis
+pub const SEMI_MAJOR_AXIS: f64 = 6_378_137.0;
+/// WGS84 flattening
+pub const FLATTENING: f64 = 1. / 298.257_223_563;
+pub const SEMI_MINOR_AXIS: f64 = SEMI_MAJOR_AXIS * (1. - FLATTENING);
+/// Radius of the sphere with the same mean radius as the ellipsoid
+pub const MEAN_RADIUS: f64 = 6_371_008.8;
+
+const CONVERGENCE_LIMIT: f64 = 1e-12;
+const MAX_ITERATIONS: usize = 200;
+
+/// The solution of the inverse geodesic problem between two positions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Geodesic {
+    pub distance: f64,
+    /// Bearing at the start
+    pub initial_bearing: f64,
+    /// Bearing at the end, when arriving
+    pub final_bearing: f64,
+}
+
+/// Solves the inverse problem with Vincenty's formula. Nearly antipodal positions, where it does not converge,
+/// are solved on the sphere instead, which is off by up to 0.5%.
+pub fn inverse(p1: (f64, f64), p2: (f64, f64)) -> Geodesic {
+    let (u1, u2) = (reduced_latitude(p1.0), reduced_latitude(p2.0));
+    let (sin_u1, cos_u1) = (sin(u1), cos(u1));
+    let (sin_u2, cos_u2) = (sin(u2), cos(u2));
+    let l = (p2.1 - p1.1).to_radians();
+
+    let mut lambda = l;
+    for _ in 0..MAX_ITERATIONS {
+        let (sin_lambda, cos_lambda) = (sin(lambda), cos(lambda));
+        let x = cos_u2 * sin_lambda;
+        let y = cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda;
+        let sin_sigma = sqrt(x * x + y * y);
+        if sin_sigma == 0. {
+            // Same position
+            return Geodesic { distance: 0., initial_bearing: 0., final_bearing: 0. };
+        }
+
+        let cos_sigma = sin_u1 * sin_u2 + cos_u1 * cos_u2 * cos_lambda;
+        let sigma = atan2(sin_sigma, cos_sigma);
+        let sin_alpha = cos_u1 * cos_u2 * sin_lambda / sin_sigma;
+        let cos2_alpha = 1. - sin_alpha * sin_alpha;
+        // Both positions on the equator
+        let cos_2sigma_m = if cos2_alpha == 0. { 0. } else { cos_sigma - 2. * sin_u1 * sin_u2 / cos2_alpha };
+
+        let c = FLATTENING / 16. * cos2_alpha * (4. + FLATTENING * (4. - 3. * cos2_alpha));
+        let previous = lambda;
+        lambda = l + (1. - c) * FLATTENING * sin_alpha
+            * (sigma + c * sin_sigma * (cos_2sigma_m + c * cos_sigma * (-1. + 2. * cos_2sigma_m * cos_2sigma_m)));
+
+        if fabs(lambda) > PI {
+            break;
+        }
+
+        if fabs(lambda - previous) < CONVERGENCE_LIMIT {
+            let (a, b) = series_coefficients(cos2_alpha);
+            let delta_sigma = delta_sigma(b, sin_sigma, cos_sigma, cos_2sigma_m);
+            let (sin_lambda, cos_lambda) = (sin(lambda), cos(lambda));
+
+            return Geodesic {
+                distance: SEMI_MINOR_AXIS * a * (sigma - delta_sigma),
+                initial_bearing: normalize_bearing(atan2(cos_u2 * sin_lambda, cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda).to_degrees()),
+                final_bearing: normalize_bearing(atan2(cos_u1 * sin_lambda, -sin_u1 * cos_u2 + cos_u1 * sin_u2 * cos_lambda).to_degrees()),
+            };
+        }
+    }
+
+    Geodesic {
+        distance: MEAN_RADIUS * angular_distance(p1, p2),
+        initial_bearing: spherical_bearing(p1, p2),
+        final_bearing: normalize_bearing(spherical_bearing(p2, p1) + 180.),
+    }
+}
+
+/// Distance in metres along the ellipsoid.
+pub fn distance(p1: (f64, f64), p2: (f64, f64)) -> f64 {
+    inverse(p1, p2).distance
+}
+
+pub fn initial_bearing(p1: (f64, f64), p2: (f64, f64)) -> f64 {
+    inverse(p1, p2).initial_bearing
+}
+
+pub fn final_bearing(p1: (f64, f64), p2: (f64, f64)) -> f64 {
+    inverse(p1, p2).final_bearing
+}
+
+/// The position reached by travelling `distance` metres from `start` with the given initial bearing,
+/// solved with Vincenty's direct formula.
+pub fn destination(start: (f64, f64), bearing: f64, distance: f64) -> (f64, f64) {
+    let alpha1 = bearing.to_radians();
+    let (sin_alpha1, cos_alpha1) = (sin(alpha1), cos(alpha1));
+
+    let tan_u1 = (1. - FLATTENING) * tan(start.0.to_radians());
+    let cos_u1 = 1. / sqrt(1. + tan_u1 * tan_u1);
+    let sin_u1 = tan_u1 * cos_u1;
+
+    let sigma1 = atan2(tan_u1, cos_alpha1);
+    let sin_alpha = cos_u1 * sin_alpha1;
+    let cos2_alpha = 1. - sin_alpha * sin_alpha;
+    let (a, b) = series_coefficients(cos2_alpha);
+
+    let mut sigma = distance / (SEMI_MINOR_AXIS * a);
+    let mut cos_2sigma_m = cos(2. * sigma1 + sigma);
+    for _ in 0..MAX_ITERATIONS {
+        cos_2sigma_m = cos(2. * sigma1 + sigma);
+        let delta_sigma = delta_sigma(b, sin(sigma), cos(sigma), cos_2sigma_m);
+        let previous = sigma;
+        sigma = distance / (SEMI_MINOR_AXIS * a) + delta_sigma;
+        if fabs(sigma - previous) < CONVERGENCE_LIMIT {
+            break;
+        }
+    }
+
+    let (sin_sigma, cos_sigma) = (sin(sigma), cos(sigma));
+    let x = sin_u1 * sin_sigma - cos_u1 * cos_sigma * cos_alpha1;
+    let latitude = atan2(
+        sin_u1 * cos_sigma + cos_u1 * sin_sigma * cos_alpha1,
+        (1. - FLATTENING) * sqrt(sin_alpha * sin_alpha + x * x),
+    );
+    let lambda = atan2(sin_sigma * sin_alpha1, cos_u1 * cos_sigma - sin_u1 * sin_sigma * cos_alpha1);
+    let c = FLATTENING / 16. * cos2_alpha * (4. + FLATTENING * (4. - 3. * cos2_alpha));
+    let l = lambda - (1. - c) * FLATTENING * sin_alpha
+        * (sigma + c * sin_sigma * (cos_2sigma_m + c * cos_sigma * (-1. + 2. * cos_2sigma_m * cos_2sigma_m)));
+
+    (latitude.to_degrees(), normalize_longitude(start.1 + l.to_degrees()))
+}
+
+/// The position halfway along the great circle between the two positions.
+pub fn midpoint(p1: (f64, f64), p2: (f64, f64)) -> (f64, f64) {
+    interpolate(p1, p2, 0.5)
+}
+
+/// The position `fraction` of the way along the great circle from `p1` to `p2`.
+/// Antipodal positions have no single great circle between them, so `p1` is returned.
+pub fn interpolate(p1: (f64, f64), p2: (f64, f64), fraction: f64) -> (f64, f64) {
+    let delta = angular_distance(p1, p2);
+    let sin_delta = sin(delta);
+    if sin_delta == 0. {
+        return p1;
+    }
+
+    let a = sin((1. - fraction) * delta) / sin_delta;
+    let b = sin(fraction * delta) / sin_delta;
+
+    let (lat1, lon1) = (p1.0.to_radians(), p1.1.to_radians());
+    let (lat2, lon2) = (p2.0.to_radians(), p2.1.to_radians());
+    let x = a * cos(lat1) * cos(lon1) + b * cos(lat2) * cos(lon2);
+    let y = a * cos(lat1) * sin(lon1) + b * cos(lat2) * sin(lon2);
+    let z = a * sin(lat1) + b * sin(lat2);
+
+    (atan2(z, sqrt(x * x + y * y)).to_degrees(), atan2(y, x).to_degrees())
+}
+
+/// Shortest distance in metres from `point` to the great circle segment between `start` and `end`.
+pub fn distance_to_segment(point: (f64, f64), start: (f64, f64), end: (f64, f64)) -> f64 {
+    let to_point = angular_distance(start, point);
+    if to_point == 0. {
+        return 0.;
+    }
+    let segment = angular_distance(start, end);
+    if segment == 0. {
+        return MEAN_RADIUS * to_point;
+    }
+
+    let angle = (spherical_bearing(start, point) - spherical_bearing(start, end)).to_radians();
+    if cos(angle) < 0. {
+        // Behind the start
+        return MEAN_RADIUS * to_point;
+    }
+
+    let cross_track = libm::asin(sin(to_point) * sin(angle));
+    let along_track = libm::acos((cos(to_point) / cos(cross_track)).clamp(-1., 1.));
+    if along_track > segment {
+        return MEAN_RADIUS * angular_distance(end, point);
+    }
+
+    MEAN_RADIUS * fabs(cross_track)
+}
+
+/// The smallest latitude and longitude ranges containing a set of positions.
+/// Boxes crossing the antimeridian are not supported, so they span the whole globe the other way around instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "std", derive(serde::Serialize, serde::Deserialize))]
+pub struct BoundingBox {
+    pub min_latitude: f64,
+    pub min_longitude: f64,
+    pub max_latitude: f64,
+    pub max_longitude: f64,
+}
+
+impl BoundingBox {
+    pub fn new(point: (f64, f64)) -> Self {
+        Self {
+            min_latitude: point.0,
+            min_longitude: point.1,
+            max_latitude: point.0,
+            max_longitude: point.1,
+        }
+    }
+
+    /// `None` if there are no positions.
+    pub fn from_points(points: impl IntoIterator<Item = (f64, f64)>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let mut bounding_box = Self::new(points.next()?);
+        points.for_each(|point| bounding_box.extend(point));
+        Some(bounding_box)
+    }
+
+    /// The box containing every position within `radius` metres of `center`.
+    pub fn around(center: (f64, f64), radius: f64) -> Self {
+        let angular_radius = radius / MEAN_RADIUS;
+        let latitude = center.0.to_radians();
+        let min_latitude = latitude - angular_radius;
+        let max_latitude = latitude + angular_radius;
+
+        // Boxes reaching a pole contain every longitude
+        if min_latitude <= -PI / 2. || max_latitude >= PI / 2. {
+            return Self {
+                min_latitude: min_latitude.max(-PI / 2.).to_degrees(),
+                min_longitude: -180.,
+                max_latitude: max_latitude.min(PI / 2.).to_degrees(),
+                max_longitude: 180.,
+            };
+        }
+
+        let delta_longitude = libm::asin(sin(angular_radius) / cos(latitude)).to_degrees();
+        Self {
+            min_latitude: min_latitude.to_degrees(),
+            min_longitude: (center.1 - delta_longitude).max(-180.),
+            max_latitude: max_latitude.to_degrees(),
+            max_longitude: (center.1 + delta_longitude).min(180.),
+        }
+    }
+
+    pub fn extend(&mut self, point: (f64, f64)) {
+        self.min_latitude = self.min_latitude.min(point.0);
+        self.min_longitude = self.min_longitude.min(point.1);
+        self.max_latitude = self.max_latitude.max(point.0);
+        self.max_longitude = self.max_longitude.max(point.1);
+    }
+
+    pub fn contains(&self, point: (f64, f64)) -> bool {
+        (self.min_latitude..=self.max_latitude).contains(&point.0) && (self.min_longitude..=self.max_longitude).contains(&point.1)
+    }
+
+    pub fn intersects(&self, other: &BoundingBox) -> bool {
+        self.min_latitude <= other.max_latitude
+            && other.min_latitude <= self.max_latitude
+            && self.min_longitude <= other.max_longitude
+            && other.min_longitude <= self.max_longitude
+    }
+
+    pub fn center(&self) -> (f64, f64) {
+        ((self.min_latitude + self.max_latitude) / 2., (self.min_longitude + self.max_longitude) / 2.)
+    }
+}
+
+fn reduced_latitude(latitude: f64) -> f64 {
+    atan((1. - FLATTENING) * tan(latitude.to_radians()))
+}
+
+/// Vincenty's A and B, from the squared cosine of the azimuth at the equator.
+fn series_coefficients(cos2_alpha: f64) -> (f64, f64) {
+    let u2 = cos2_alpha * (SEMI_MAJOR_AXIS * SEMI_MAJOR_AXIS - SEMI_MINOR_AXIS * SEMI_MINOR_AXIS) / (SEMI_MINOR_AXIS * SEMI_MINOR_AXIS);
+    let a = 1. + u2 / 16384. * (4096. + u2 * (-768. + u2 * (320. - 175. * u2)));
+    let b = u2 / 1024. * (256. + u2 * (-128. + u2 * (74. - 47. * u2)));
+    (a, b)
+}
+
+fn delta_sigma(b: f64, sin_sigma: f64, cos_sigma: f64, cos_2sigma_m: f64) -> f64 {
+    b * sin_sigma * (cos_2sigma_m + b / 4. * (cos_sigma * (-1. + 2. * cos_2sigma_m * cos_2sigma_m)
+        - b / 6. * cos_2sigma_m * (-3. + 4. * sin_sigma * sin_sigma) * (-3. + 4. * cos_2sigma_m * cos_2sigma_m)))
+}
+
+/// Central angle between the positions on a sphere, in radians.
+fn angular_distance(p1: (f64, f64), p2: (f64, f64)) -> f64 {
+    let d_lat = (p2.0 - p1.0).to_radians();
+    let d_lon = (p2.1 - p1.1).to_radians();
+    let (sin_lat, sin_lon) = (sin(d_lat / 2.), sin(d_lon / 2.));
+    let a = sin_lat * sin_lat + cos(p1.0.to_radians()) * cos(p2.0.to_radians()) * sin_lon * sin_lon;
+    2. * atan2(sqrt(a), sqrt(1. - a))
+}
+
+fn spherical_bearing(p1: (f64, f64), p2: (f64, f64)) -> f64 {
+    let (lat1, lat2) = (p1.0.to_radians(), p2.0.to_radians());
+    let d_lon = (p2.1 - p1.1).to_radians();
+    let y = sin(d_lon) * cos(lat2);
+    let x = cos(lat1) * sin(lat2) - sin(lat1) * cos(lat2) * cos(d_lon);
+    normalize_bearing(atan2(y, x).to_degrees())
+}
+
+fn normalize_bearing(bearing: f64) -> f64 {
+    (bearing % 360. + 360.) % 360.
+}
+
+fn normalize_longitude(longitude: f64) -> f64 {
+    (longitude + 540.) % 360. - 180.
+}
+
+#[cfg(test)]
+fn dms(degrees: f64, minutes: f64, seconds: f64) -> f64 {
+    degrees.signum() * (fabs(degrees) + minutes / 60. + seconds / 3600.)
+}
+
+#[test]
+fn vincenty_test() {
+    // Flinders Peak to Buninyong, the example from Vincenty's paper as worked by Geoscience Australia
+    let flinders_peak = (dms(-37., 57., 3.72030), dms(144., 25., 29.52440));
+    let buninyong = (dms(-37., 39., 10.15610), dms(143., 55., 35.38390));
+
+    let geodesic = inverse(flinders_peak, buninyong);
+    assert!(fabs(geodesic.distance - 54_972.271) < 0.001);
+    assert!(fabs(geodesic.initial_bearing - dms(306., 52., 5.37)) < 1e-5);
+    assert!(fabs(geodesic.final_bearing - dms(307., 10., 25.07)) < 1e-5);
+
+    let destination = destination(flinders_peak, geodesic.initial_bearing, geodesic.distance);
+    assert!(fabs(destination.0 - buninyong.0) < 1e-9);
+    assert!(fabs(destination.1 - buninyong.1) < 1e-9);
+
+    // A meridian quadrant is 10 001 965.729 m on WGS84
+    assert!(fabs(distance((0., 0.), (90., 0.)) - 10_001_965.729) < 0.001);
+    assert_eq!(distance(buninyong, buninyong), 0.);
+
+    // Antipodal positions fall back to the sphere
+    let antipodal = distance((0., 0.), (0.5, 179.7));
+    assert!(fabs(antipodal - 19_936_288.6) / antipodal < 0.005);
+}
+
+#[test]
+fn destination_test() {
+    // Across the antimeridian
+    let (latitude, longitude) = destination((0., 179.9), 90., 50_000.);
+    assert!(fabs(latitude) < 1e-9);
+    assert!(longitude < -179.);
+
+    for bearing in [0., 45., 135., 200., 315.] {
+        let end = destination((55.5, 10.1), bearing, 12_345.);
+        let geodesic = inverse((55.5, 10.1), end);
+        assert!(fabs(geodesic.distance - 12_345.) < 1e-6);
+        assert!(fabs(geodesic.initial_bearing - bearing) < 1e-9);
+    }
+}
+
+#[test]
+fn interpolation_test() {
+    let (latitude, longitude) = midpoint((0., 0.), (0., 90.));
+    assert!(fabs(latitude) < 1e-12 && fabs(longitude - 45.) < 1e-12);
+
+    let start = (55.5, 10.1);
+    let end = (56.2, 12.6);
+    assert_eq!(interpolate(start, end, 0.), start);
+    let end_interpolated = interpolate(start, end, 1.);
+    assert!(fabs(end_interpolated.0 - end.0) < 1e-12 && fabs(end_interpolated.1 - end.1) < 1e-12);
+
+    let quarter = interpolate(start, end, 0.25);
+    let ratio = angular_distance(start, quarter) / angular_distance(start, end);
+    assert!(fabs(ratio - 0.25) < 1e-12);
+}
+
+#[test]
+fn segment_distance_test() {
+    let start = (0., 0.);
+    let end = (0., 1.);
+    let one_degree = MEAN_RADIUS * 1f64.to_radians();
+
+    // Beside, behind and beyond the segment
+    assert!(fabs(distance_to_segment((1., 0.5), start, end) - one_degree) < 1.);
+    assert!(fabs(distance_to_segment((0., -1.), start, end) - one_degree) < 1e-6);
+    assert!(fabs(distance_to_segment((0., 2.), start, end) - one_degree) < 1e-6);
+    assert!(distance_to_segment((0., 0.5), start, end) < 1e-6);
+    assert!(fabs(distance_to_segment((0., 1.), start, start) - one_degree) < 1e-6);
+}
+
+#[test]
+fn bounding_box_test() {
+    assert_eq!(BoundingBox::from_points([]), None);
+
+    let bounding_box = BoundingBox::from_points([(55.5, 10.1), (56.2, 9.8), (55.9, 12.6)]).unwrap();
+    assert_eq!(bounding_box, BoundingBox { min_latitude: 55.5, min_longitude: 9.8, max_latitude: 56.2, max_longitude: 12.6 });
+    assert!(bounding_box.contains((56., 11.)));
+    assert!(!bounding_box.contains((57., 11.)));
+
+    // Every position within the radius is inside
+    let around = BoundingBox::around((55.5, 10.1), 10_000.);
+    for bearing in (0..360).step_by(15) {
+        assert!(around.contains(destination((55.5, 10.1), bearing as f64, 9_990.)));
+    }
+    assert!(around.intersects(&bounding_box));
+    assert!(!around.intersects(&BoundingBox::new((0., 0.))));
+
+    let polar = BoundingBox::around((89.99, 0.), 10_000.);
+    assert_eq!((polar.min_longitude, polar.max_longitude, polar.max_latitude), (-180., 180., 90.));
+}
@@ -0,0 +1,126 @@
+//! Local times, for showing followers times as they were where the trip was.
+//!
+//! Offsets are fixed, so a place observing daylight saving time has another offset in the summer.
+
+use std::{fmt::Display, str::FromStr};
+
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Largest offset in use, east or west of UTC
+pub const MAX_OFFSET_MINUTES: i32 = 14 * 60;
+
+/// An offset from UTC, in whole minutes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct UtcOffset {
+    minutes: i16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseOffsetError;
+
+impl UtcOffset {
+    pub const UTC: Self = Self { minutes: 0 };
+
+    pub fn from_minutes(minutes: i32) -> Option<Self> {
+        (minutes.abs() <= MAX_OFFSET_MINUTES).then_some(Self { minutes: minutes as i16 })
+    }
+
+    /// Fractional hours, like 5.75 for Nepal.
+    pub fn from_hours(hours: f64) -> Option<Self> {
+        Self::from_minutes((hours * 60.).round() as i32)
+    }
+
+    /// The nautical time zone of the longitude, which is whole hours and 15 degrees wide.
+    pub fn nautical(longitude: f64) -> Self {
+        Self { minutes: (longitude / 15.).round() as i16 * 60 }
+    }
+
+    pub fn minutes(&self) -> i32 {
+        self.minutes as i32
+    }
+
+    pub fn fixed_offset(&self) -> FixedOffset {
+        FixedOffset::east_opt(self.minutes() * 60).unwrap() // Safe unwrap, always within a day
+    }
+
+    pub fn local(&self, time: DateTime<Utc>) -> DateTime<FixedOffset> {
+        self.fixed_offset().from_utc_datetime(&time.naive_utc())
+    }
+
+    /// The time in this offset with a chrono format string, followed by the offset, like "24/05/2025 14:03 (UTC+2)".
+    pub fn format(&self, time: DateTime<Utc>, format: &str) -> String {
+        format!("{} ({})", self.local(time).format(format), self)
+    }
+}
+
+/// "UTC", "UTC+2" or "UTC-3:30"
+impl Display for UtcOffset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (hours, minutes) = (self.minutes.abs() / 60, self.minutes.abs() % 60);
+        let sign = if self.minutes < 0 { '-' } else { '+' };
+        match (hours, minutes) {
+            (0, 0) => write!(f, "UTC"),
+            (_, 0) => write!(f, "UTC{}{}", sign, hours),
+            _ => write!(f, "UTC{}{}:{:02}", sign, hours, minutes),
+        }
+    }
+}
+
+/// Parses offsets like "UTC", "UTC+2", "+02:00", "-3:30" and "+0545".
+impl FromStr for UtcOffset {
+    type Err = ParseOffsetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_prefix("UTC").or_else(|| s.strip_prefix("GMT")).unwrap_or(s);
+        if s.is_empty() || s == "Z" {
+            return Ok(Self::UTC);
+        }
+
+        let (sign, s) = match (s.strip_prefix('+'), s.strip_prefix('-')) {
+            (Some(rest), _) => (1, rest),
+            (_, Some(rest)) => (-1, rest),
+            _ => return Err(ParseOffsetError),
+        };
+        if s.is_empty() || !s.chars().all(|c| c.is_ascii_digit() || c == ':') {
+            return Err(ParseOffsetError);
+        }
+        let (hours, minutes) = match s.split_once(':') {
+            Some((hours, minutes)) => (hours, minutes),
+            None if s.len() == 4 => s.split_at(2),
+            None => (s, "0"),
+        };
+
+        let hours: i32 = hours.parse().map_err(|_| ParseOffsetError)?;
+        let minutes: i32 = minutes.parse().map_err(|_| ParseOffsetError)?;
+        if !(0..60).contains(&minutes) {
+            return Err(ParseOffsetError);
+        }
+        Self::from_minutes(sign * (hours * 60 + minutes)).ok_or(ParseOffsetError)
+    }
+}
+
+#[test]
+fn utc_offset_test() {
+    let time = DateTime::from_timestamp(1_748_095_380, 0).unwrap(); // 24/05/2025 14:03 UTC
+
+    let armenia: UtcOffset = "UTC+4".parse().unwrap();
+    assert_eq!(armenia.format(time, "%d/%m/%Y %H:%M"), "24/05/2025 18:03 (UTC+4)");
+    assert_eq!(UtcOffset::from_hours(-3.5).unwrap().format(time, "%H:%M"), "10:33 (UTC-3:30)");
+
+    for (text, minutes) in [("UTC", 0), ("Z", 0), ("+02:00", 120), ("-3:30", -210), ("+0545", 345), ("GMT-10", -600)] {
+        assert_eq!(text.parse::<UtcOffset>().map(|offset| offset.minutes()), Ok(minutes), "{}", text);
+    }
+    for text in ["UTC+15", "2", "+2:75", "UTC+two", "+", "+-3", "−2"] {
+        assert_eq!(text.parse::<UtcOffset>(), Err(ParseOffsetError), "{}", text);
+    }
+    for minutes in [0, 240, -210, 345] {
+        let offset = UtcOffset::from_minutes(minutes).unwrap();
+        assert_eq!(offset.to_string().parse(), Ok(offset));
+    }
+
+    assert_eq!(UtcOffset::nautical(10.2), UtcOffset::from_minutes(60).unwrap());
+    assert_eq!(UtcOffset::nautical(44.5).minutes(), 180);
+    assert_eq!(UtcOffset::nautical(-70.), UtcOffset::from_minutes(-300).unwrap());
+}
@@ -0,0 +1,165 @@
+use sha2::{Digest, Sha256};
+
+use crate::comms::{CommsError, MacProvider, LEGACY_PROTOCOL_VERSION, NONCE_SIZE, PROTOCOL_VERSION, SIGNATURE_SIZE};
+
+const BLOCK_SIZE: usize = 64;
+pub const DIGEST_SIZE: usize = 32;
+
+const CONNECTION_KEY_LABEL: &[u8] = b"trip tracker frames";
+
+/// HMAC-SHA256 as specified in RFC 2104.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; DIGEST_SIZE] {
+    // Keys longer than a block are hashed first, shorter keys are zero padded
+    let mut block_key = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block_key[..DIGEST_SIZE].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner_pad = [0x36; BLOCK_SIZE];
+    let mut outer_pad = [0x5c; BLOCK_SIZE];
+    for i in 0..BLOCK_SIZE {
+        inner_pad[i] ^= block_key[i];
+        outer_pad[i] ^= block_key[i];
+    }
+
+    let inner = Sha256::new()
+        .chain_update(inner_pad)
+        .chain_update(data)
+        .finalize();
+
+    Sha256::new()
+        .chain_update(outer_pad)
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+/// Derives the key data frames are signed with from the trip key and the handshake nonce,
+/// so frames captured on one connection don't verify on any other.
+pub fn connection_key(key: &[u8], nonce: &[u8; NONCE_SIZE]) -> [u8; DIGEST_SIZE] {
+    let mut data = [0u8; CONNECTION_KEY_LABEL.len() + NONCE_SIZE];
+    data[..CONNECTION_KEY_LABEL.len()].copy_from_slice(CONNECTION_KEY_LABEL);
+    data[CONNECTION_KEY_LABEL.len()..].copy_from_slice(nonce);
+    hmac_sha256(key, &data)
+}
+
+/// Compares two signatures without exiting early, so the time taken does not reveal how many bytes matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Truncated HMAC-SHA256, used from protocol version 2.
+pub struct HmacSha256;
+
+impl MacProvider for HmacSha256 {
+    fn sign(&mut self, data: &[u8], token: &[u8]) -> [u8; SIGNATURE_SIZE] {
+        hmac_sha256(token, data)[..SIGNATURE_SIZE].try_into().unwrap()
+    }
+}
+
+/// Truncated SHA256(data || token), as signed by trackers speaking protocol version 1.
+pub struct LegacySha256;
+
+impl MacProvider for LegacySha256 {
+    fn sign(&mut self, data: &[u8], token: &[u8]) -> [u8; SIGNATURE_SIZE] {
+        let digest = Sha256::new()
+            .chain_update(data)
+            .chain_update(token)
+            .finalize();
+        digest[..SIGNATURE_SIZE].try_into().unwrap()
+    }
+}
+
+/// The MAC negotiated by the handshake.
+pub enum ProtocolMac {
+    Legacy(LegacySha256),
+    Hmac(HmacSha256),
+}
+
+impl ProtocolMac {
+    pub fn for_version(version: u8) -> Result<Self, CommsError> {
+        match version {
+            LEGACY_PROTOCOL_VERSION => Ok(Self::Legacy(LegacySha256)),
+            PROTOCOL_VERSION => Ok(Self::Hmac(HmacSha256)),
+            _ => Err(CommsError::UnsupportedVersion(version)),
+        }
+    }
+}
+
+impl MacProvider for ProtocolMac {
+    fn sign(&mut self, data: &[u8], token: &[u8]) -> [u8; SIGNATURE_SIZE] {
+        match self {
+            Self::Legacy(mac) => mac.sign(data, token),
+            Self::Hmac(mac) => mac.sign(data, token),
+        }
+    }
+}
+
+#[cfg(test)]
+fn hex(s: &str) -> std::vec::Vec<u8> {
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+}
+
+#[test]
+fn rfc4231_test() {
+    // Test cases 1-4, 6 and 7 from RFC 4231. Case 5 only checks a 128 bit truncation, which is covered below.
+    let cases: [(std::vec::Vec<u8>, std::vec::Vec<u8>, &str); 6] = [
+        (
+            [0x0b; 20].to_vec(),
+            b"Hi There".to_vec(),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+        ),
+        (
+            b"Jefe".to_vec(),
+            b"what do ya want for nothing?".to_vec(),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        ),
+        (
+            [0xaa; 20].to_vec(),
+            [0xdd; 50].to_vec(),
+            "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+        ),
+        (
+            hex("0102030405060708090a0b0c0d0e0f10111213141516171819"),
+            [0xcd; 50].to_vec(),
+            "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+        ),
+        (
+            [0xaa; 131].to_vec(),
+            b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+        ),
+        (
+            [0xaa; 131].to_vec(),
+            b"This is a test using a larger than block-size key and a larger than block-size data. The key needs to be hashed before being used by the HMAC algorithm.".to_vec(),
+            "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+        ),
+    ];
+
+    for (key, data, expected) in cases {
+        assert_eq!(hmac_sha256(&key, &data).to_vec(), hex(expected));
+    }
+
+    // Test case 5, truncated to 128 bits like our signatures
+    let key = [0x0c; 20];
+    let signature = HmacSha256.sign(b"Test With Truncation", &key);
+    assert_eq!(signature.to_vec(), hex("a3b6167473100ee06e0c796c2955552b"));
+    assert!(HmacSha256.verify(b"Test With Truncation", &signature, &key));
+    assert!(!HmacSha256.verify(b"Test With Truncation!", &signature, &key));
+}
+
+#[test]
+fn protocol_mac_test() {
+    let key = b"secret";
+    let data = b"data";
+
+    assert_eq!(ProtocolMac::for_version(PROTOCOL_VERSION).unwrap().sign(data, key), HmacSha256.sign(data, key));
+    assert_eq!(ProtocolMac::for_version(LEGACY_PROTOCOL_VERSION).unwrap().sign(data, key), LegacySha256.sign(data, key));
+    assert_ne!(HmacSha256.sign(data, key), LegacySha256.sign(data, key));
+    assert!(matches!(ProtocolMac::for_version(3), Err(CommsError::UnsupportedVersion(3))));
+}
@@ -0,0 +1,111 @@
+//! Google's encoded polyline format, which packs a line into a short ASCII string.
+//!
+//! Every coordinate is stored as the rounded difference from the previous one, in chunks of 5 bits.
+//! Latitude comes before longitude.
+
+use std::fmt::Display;
+
+use crate::track_point::TrackPoint;
+
+/// Decimal places kept by Google Maps. OSRM and Valhalla use 6
+pub const GOOGLE_PRECISION: u32 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolylineError {
+    /// A character outside of the encoding's range, at the byte index
+    InvalidCharacter(usize),
+    /// The string ends in the middle of a coordinate
+    Truncated,
+}
+
+impl Display for PolylineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidCharacter(index) => write!(f, "invalid character at {}", index),
+            Self::Truncated => write!(f, "polyline ends in the middle of a coordinate"),
+        }
+    }
+}
+
+/// Encodes the positions, given as latitude and longitude.
+pub fn encode(positions: impl IntoIterator<Item = (f64, f64)>, precision: u32) -> String {
+    let factor = 10f64.powi(precision as i32);
+    let mut encoded = String::new();
+    let mut previous = (0, 0);
+    for (latitude, longitude) in positions {
+        let current = ((latitude * factor).round() as i64, (longitude * factor).round() as i64);
+        encode_value(current.0 - previous.0, &mut encoded);
+        encode_value(current.1 - previous.1, &mut encoded);
+        previous = current;
+    }
+    encoded
+}
+
+/// The positions of the points, with Google's precision.
+pub fn encode_track(track_points: &[TrackPoint]) -> String {
+    encode(track_points.iter().map(|p| p.position()), GOOGLE_PRECISION)
+}
+
+/// Decodes the positions, as latitude and longitude.
+pub fn decode(encoded: &str, precision: u32) -> Result<Vec<(f64, f64)>, PolylineError> {
+    let factor = 10f64.powi(precision as i32);
+    let mut bytes = encoded.bytes().enumerate();
+    let mut positions = Vec::new();
+    let mut current = (0i64, 0i64);
+    while bytes.len() > 0 {
+        current.0 += decode_value(&mut bytes)?;
+        current.1 += decode_value(&mut bytes)?;
+        positions.push((current.0 as f64 / factor, current.1 as f64 / factor));
+    }
+    Ok(positions)
+}
+
+fn encode_value(value: i64, encoded: &mut String) {
+    // The sign goes in the lowest bit, so small negative values stay short
+    let mut value = if value < 0 { !(value << 1) } else { value << 1 } as u64;
+    while value >= 0x20 {
+        encoded.push((((value & 0x1F) | 0x20) as u8 + 63) as char);
+        value >>= 5;
+    }
+    encoded.push((value as u8 + 63) as char);
+}
+
+fn decode_value(bytes: &mut impl Iterator<Item = (usize, u8)>) -> Result<i64, PolylineError> {
+    let mut value: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let (index, byte) = bytes.next().ok_or(PolylineError::Truncated)?;
+        if !(63..=126).contains(&byte) || shift > 60 {
+            return Err(PolylineError::InvalidCharacter(index));
+        }
+        let chunk = (byte - 63) as u64;
+        value |= (chunk & 0x1F) << shift;
+        shift += 5;
+        if chunk < 0x20 {
+            break;
+        }
+    }
+    let value = value as i64;
+    Ok(if value & 1 == 1 { !(value >> 1) } else { value >> 1 })
+}
+
+#[test]
+fn polyline_test() {
+    // The example from Google's documentation
+    let positions = [(38.5, -120.2), (40.7, -120.95), (43.252, -126.453)];
+    let encoded = encode(positions, GOOGLE_PRECISION);
+    assert_eq!(encoded, "_p~iF~ps|U_ulLnnqC_mqNvxq`@");
+    assert_eq!(decode(&encoded, GOOGLE_PRECISION), Ok(positions.to_vec()));
+
+    let precise = [(55.676098, 12.568337), (-33.868820, 151.209295), (0., 0.)];
+    let decoded = decode(&encode(precise, 6), 6).unwrap();
+    for (position, expected) in decoded.iter().zip(precise) {
+        assert!((position.0 - expected.0).abs() < 1e-9 && (position.1 - expected.1).abs() < 1e-9);
+    }
+
+    assert_eq!(encode([], GOOGLE_PRECISION), "");
+    assert_eq!(decode("", GOOGLE_PRECISION), Ok(Vec::new()));
+    assert_eq!(decode("_p~iF", GOOGLE_PRECISION), Err(PolylineError::Truncated));
+    assert_eq!(decode("_p~iF~ps|U_ulLnnqC_mqNvxq`", GOOGLE_PRECISION), Err(PolylineError::Truncated));
+    assert_eq!(decode("_p~iF ps|U", GOOGLE_PRECISION), Err(PolylineError::InvalidCharacter(5)));
+}
@@ -0,0 +1,251 @@
+//! Shape preserving simplification of tracks, for drawing them with fewer points.
+//!
+//! Simplifications return the indices of the points to keep, in order. The first and last point are always kept.
+
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use crate::{geo, track_point::TrackPoint};
+
+/// Metres per pixel at zoom level 0 on the equator, for the 256 pixel tiles used by Leaflet
+const EQUATOR_METRES_PER_PIXEL: f64 = 156_543.033_92;
+/// How far a simplified track may stray from the original, in pixels on screen
+pub const PIXEL_TOLERANCE: f64 = 1.;
+
+/// The coarsest zoom level with its own level of detail. Zooming out further shows the same points.
+pub const MIN_LOD_ZOOM: u8 = 4;
+/// The finest zoom level with its own level of detail. Zooming in further shows every point.
+pub const MAX_LOD_ZOOM: u8 = 17;
+
+/// Distance in metres that `PIXEL_TOLERANCE` covers at the zoom level and latitude.
+pub fn tolerance_for_zoom(zoom: u8, latitude: f64) -> f64 {
+    EQUATOR_METRES_PER_PIXEL * latitude.to_radians().cos() / 2f64.powi(zoom as i32) * PIXEL_TOLERANCE
+}
+
+/// Douglas–Peucker: keeps the points that are further than `tolerance` metres from the simplified track.
+pub fn douglas_peucker(track_points: &[TrackPoint], tolerance: f64) -> Vec<usize> {
+    if track_points.len() < 3 {
+        return (0..track_points.len()).collect();
+    }
+
+    let mut keep = vec![false; track_points.len()];
+    keep[0] = true;
+    keep[track_points.len() - 1] = true;
+    refine(track_points, 0, track_points.len() - 1, tolerance, |i| keep[i] = true);
+
+    (0..track_points.len()).filter(|&i| keep[i]).collect()
+}
+
+/// Visvalingam–Whyatt: drops the point forming the smallest triangle with its neighbours,
+/// until every remaining triangle is at least `min_area` square metres.
+pub fn visvalingam_whyatt(track_points: &[TrackPoint], min_area: f64) -> Vec<usize> {
+    let n = track_points.len();
+    if n < 3 {
+        return (0..n).collect();
+    }
+
+    // Neighbours that are still kept, as a linked list over the indices
+    let mut previous: Vec<usize> = (0..n).map(|i| i.wrapping_sub(1)).collect();
+    let mut next: Vec<usize> = (1..=n).collect();
+    let mut area = vec![f64::INFINITY; n];
+    let mut heap = BinaryHeap::new();
+
+    for i in 1..n - 1 {
+        area[i] = triangle_area(&track_points[i - 1], &track_points[i], &track_points[i + 1]);
+        heap.push(Candidate { area: area[i], index: i });
+    }
+
+    while let Some(Candidate { area: smallest, index }) = heap.pop() {
+        if smallest != area[index] {
+            // Outdated, the area changed when a neighbour was dropped
+            continue;
+        }
+        if smallest >= min_area {
+            break;
+        }
+
+        area[index] = f64::NAN;
+        let (before, after) = (previous[index], next[index]);
+        next[before] = after;
+        previous[after] = before;
+
+        for neighbour in [before, after] {
+            if neighbour == 0 || neighbour == n - 1 {
+                continue;
+            }
+            // A neighbour never gets a smaller area than the point dropped before it, so it isn't dropped before it either
+            let new_area = triangle_area(&track_points[previous[neighbour]], &track_points[neighbour], &track_points[next[neighbour]]).max(smallest);
+            area[neighbour] = new_area;
+            heap.push(Candidate { area: new_area, index: neighbour });
+        }
+    }
+
+    (0..n).filter(|&i| !area[i].is_nan()).collect()
+}
+
+/// Douglas–Peucker simplifications of a track for every zoom level, nested so that zooming in only adds points.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelsOfDetail {
+    /// For every point, the lowest zoom level it is shown at
+    min_zoom: Vec<u8>,
+}
+
+impl LevelsOfDetail {
+    pub fn new(track_points: &[TrackPoint]) -> Self {
+        let n = track_points.len();
+        let mut min_zoom = vec![MAX_LOD_ZOOM + 1; n];
+        if n == 0 {
+            return Self { min_zoom };
+        }
+        min_zoom[0] = MIN_LOD_ZOOM;
+        min_zoom[n - 1] = MIN_LOD_ZOOM;
+
+        let latitude = track_points.iter().map(|p| p.latitude).sum::<f64>() / n as f64;
+        for zoom in MIN_LOD_ZOOM..=MAX_LOD_ZOOM {
+            let tolerance = tolerance_for_zoom(zoom, latitude);
+
+            // Refine between the points of the coarser levels, so they are all kept
+            let kept: Vec<usize> = (0..n).filter(|&i| min_zoom[i] < zoom || i == 0 || i == n - 1).collect();
+            for range in kept.windows(2) {
+                refine(track_points, range[0], range[1], tolerance, |i| min_zoom[i] = zoom);
+            }
+        }
+
+        Self { min_zoom }
+    }
+
+    /// Indices of the points shown at the zoom level.
+    pub fn indices(&self, zoom: u8) -> impl Iterator<Item = usize> + '_ {
+        let zoom = zoom.max(MIN_LOD_ZOOM);
+        self.min_zoom.iter().enumerate().filter(move |(_, &min_zoom)| min_zoom <= zoom).map(|(i, _)| i)
+    }
+
+    /// The points shown at the zoom level. `track_points` must be the points the levels were made from.
+    pub fn select(&self, track_points: &[TrackPoint], zoom: u8) -> Vec<TrackPoint> {
+        self.indices(zoom).map(|i| track_points[i].clone()).collect()
+    }
+
+    pub fn point_count(&self) -> usize {
+        self.min_zoom.len()
+    }
+}
+
+/// Calls `keep` with every point between `first` and `last` that Douglas–Peucker keeps.
+fn refine(track_points: &[TrackPoint], first: usize, last: usize, tolerance: f64, mut keep: impl FnMut(usize)) {
+    let mut ranges = vec![(first, last)];
+    while let Some((first, last)) = ranges.pop() {
+        if last - first < 2 {
+            continue;
+        }
+
+        let (start, end) = (track_points[first].position(), track_points[last].position());
+        let (farthest, distance) = (first + 1..last)
+            .map(|i| (i, geo::distance_to_segment(track_points[i].position(), start, end)))
+            .fold((first, -1.), |farthest, candidate| if candidate.1 > farthest.1 { candidate } else { farthest });
+
+        if distance > tolerance {
+            keep(farthest);
+            ranges.push((first, farthest));
+            ranges.push((farthest, last));
+        }
+    }
+}
+
+/// Area in square metres, on a plane tangent to the middle point.
+fn triangle_area(a: &TrackPoint, b: &TrackPoint, c: &TrackPoint) -> f64 {
+    let scale = b.latitude.to_radians().cos();
+    let project = |p: &TrackPoint| (
+        (p.longitude - b.longitude).to_radians() * scale * geo::MEAN_RADIUS,
+        (p.latitude - b.latitude).to_radians() * geo::MEAN_RADIUS,
+    );
+    let (a, c) = (project(a), project(c));
+    (a.0 * c.1 - c.0 * a.1).abs() / 2.
+}
+
+/// Smallest area first
+struct Candidate {
+    area: f64,
+    index: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.area.total_cmp(&self.area).then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+#[cfg(test)]
+fn test_track() -> Vec<TrackPoint> {
+    use chrono::DateTime;
+
+    // East along a straight road for 1 km, a sharp corner, then north for 1 km. 10 m between points
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let step = 10. / 111_250.;
+    (0..=200)
+        .map(|i| {
+            let (latitude, longitude) = if i <= 100 {
+                (55., 10. + i as f64 * step * 1.75)
+            } else {
+                (55. + (i - 100) as f64 * step, 10. + 100. * step * 1.75)
+            };
+            TrackPoint::new(start + chrono::Duration::seconds(i), latitude, longitude, 0., 36., true)
+        })
+        .collect()
+}
+
+#[test]
+fn simplification_test() {
+    let track = test_track();
+
+    // The corner survives, the straight lines don't
+    assert_eq!(douglas_peucker(&track, 1.), vec![0, 100, 200]);
+    assert_eq!(visvalingam_whyatt(&track, 100.), vec![0, 100, 200]);
+
+    // Nothing is dropped with no tolerance
+    let mut wiggly = track[..=100].to_vec();
+    for (i, point) in wiggly.iter_mut().enumerate() {
+        point.latitude += if i % 2 == 0 { 0.0001 } else { 0. };
+    }
+    assert_eq!(douglas_peucker(&wiggly, 0.).len(), wiggly.len());
+    assert_eq!(visvalingam_whyatt(&wiggly, 0.).len(), wiggly.len());
+    assert_eq!(douglas_peucker(&track[..2], 100.), vec![0, 1]);
+}
+
+#[test]
+fn levels_of_detail_test() {
+    // A zigzag of growing amplitude, so every zoom level adds points
+    let mut track = test_track();
+    for (i, point) in track.iter_mut().enumerate() {
+        let amplitude = (i % 20) as f64 * 0.00001;
+        point.latitude += if i % 2 == 0 { amplitude } else { -amplitude };
+    }
+
+    let levels = LevelsOfDetail::new(&track);
+    assert_eq!(levels.point_count(), track.len());
+
+    let mut previous: Vec<usize> = levels.indices(0).collect();
+    assert!(previous.len() < 10);
+    for zoom in MIN_LOD_ZOOM..=MAX_LOD_ZOOM + 1 {
+        let indices: Vec<usize> = levels.indices(zoom).collect();
+        // Nested, and never less detailed than a plain simplification at the same tolerance
+        assert!(previous.iter().all(|i| indices.contains(i)));
+        assert!(indices.len() >= douglas_peucker(&track, tolerance_for_zoom(zoom, 55.)).len());
+        previous = indices;
+    }
+    assert_eq!(previous.len(), track.len());
+    assert_eq!(levels.select(&track, MAX_LOD_ZOOM + 1), track);
+    assert!(LevelsOfDetail::new(&[]).indices(10).next().is_none());
+}
@@ -0,0 +1,282 @@
+//! Kalman smoothing of tracks, which removes the jitter of GNSS fixes without cutting corners like simplification does.
+//!
+//! Every axis is modelled as a position moving at a constant velocity, disturbed by random accelerations.
+//! Positions are measured by the fixes, weighted by their precision, and velocities by the reported speed
+//! and course. Standing still is measured as zero velocity, which holds the track still at stops.
+//! Positions are smoothed in metres east and north of the first point.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{geo, track_point::TrackPoint};
+
+/// Points reported slower than this are standing still
+const STATIONARY_SPEED_KPH: f32 = 1.;
+/// Velocity uncertainty before the first measurement, in m/s
+const INITIAL_VELOCITY_ERROR: f64 = 50.;
+
+type Vector = [f64; 2];
+type Matrix = [[f64; 2]; 2];
+
+/// Standard deviations of the model and the measurements.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KalmanSmoother {
+    /// How much the velocity changes between points, in m/s²
+    pub acceleration_error: f64,
+    /// Position error of fixes with good precision and no known HDOP, in metres
+    pub good_precision_error: f64,
+    /// Position error of other fixes with no known HDOP, in metres
+    pub poor_precision_error: f64,
+    /// Position error per unit of HDOP, in metres
+    pub hdop_error: f64,
+    /// Error of the reported speed, in m/s
+    pub speed_error: f64,
+    /// Run the Rauch–Tung–Striebel backward pass, so every point is smoothed with the points after it too
+    pub backward_pass: bool,
+}
+
+impl Default for KalmanSmoother {
+    fn default() -> Self {
+        Self {
+            acceleration_error: 1.,
+            good_precision_error: 5.,
+            poor_precision_error: 25.,
+            hdop_error: 5.,
+            speed_error: 1.,
+            backward_pass: true,
+        }
+    }
+}
+
+/// The estimate of one axis after a point
+#[derive(Debug, Clone, Copy)]
+struct Estimate {
+    state: Vector,
+    covariance: Matrix,
+}
+
+impl KalmanSmoother {
+    /// The points moved to their smoothed positions. Everything but the positions is left as it is.
+    pub fn smooth(&self, track_points: &[TrackPoint]) -> Vec<TrackPoint> {
+        let Some(origin) = track_points.first() else {
+            return Vec::new();
+        };
+        let scale = origin.latitude.to_radians().cos() * geo::MEAN_RADIUS;
+        let to_metres = |p: &TrackPoint| [
+            (p.longitude - origin.longitude).to_radians() * scale,
+            (p.latitude - origin.latitude).to_radians() * geo::MEAN_RADIUS,
+        ];
+
+        let positions: Vec<Vector> = track_points.iter().map(to_metres).collect();
+        let velocities: Vec<Option<Vector>> = track_points.iter().map(measured_velocity).collect();
+
+        let mut smoothed = track_points.to_vec();
+        for axis in 0..2 {
+            let measurements: Vec<(f64, Option<f64>)> = positions.iter().zip(&velocities)
+                .map(|(position, velocity)| (position[axis], velocity.map(|v| v[axis])))
+                .collect();
+
+            for (point, estimate) in smoothed.iter_mut().zip(self.smooth_axis(track_points, &measurements)) {
+                match axis {
+                    0 => point.longitude = origin.longitude + (estimate / scale).to_degrees(),
+                    _ => point.latitude = origin.latitude + (estimate / geo::MEAN_RADIUS).to_degrees(),
+                }
+            }
+        }
+
+        smoothed
+    }
+
+    /// Smoothed positions along one axis, from the measured positions and velocities.
+    fn smooth_axis(&self, track_points: &[TrackPoint], measurements: &[(f64, Option<f64>)]) -> Vec<f64> {
+        let n = track_points.len();
+        // Filtered estimates, and the predictions they were updated from
+        let mut filtered = Vec::with_capacity(n);
+        let mut predicted = Vec::with_capacity(n);
+
+        for (i, (point, &(position, velocity))) in track_points.iter().zip(measurements).enumerate() {
+            let position_error = self.position_error(point);
+            let prediction = match filtered.last() {
+                None => Estimate {
+                    state: [position, 0.],
+                    covariance: [[position_error.powi(2), 0.], [0., INITIAL_VELOCITY_ERROR.powi(2)]],
+                },
+                Some(previous) => self.predict(previous, seconds_between(&track_points[i - 1], point)),
+            };
+            predicted.push(prediction);
+            filtered.push(update(&prediction, position, position_error, velocity.map(|v| (v, self.speed_error))));
+        }
+
+        if !self.backward_pass {
+            return filtered.iter().map(|estimate| estimate.state[0]).collect();
+        }
+
+        // Rauch–Tung–Striebel: corrects every estimate by how much the next one was corrected by the points after it
+        let mut smoothed = vec![filtered[n - 1].state; n];
+        for i in (0..n - 1).rev() {
+            let dt = seconds_between(&track_points[i], &track_points[i + 1]);
+            let gain = multiply(&multiply(&filtered[i].covariance, &transpose(&transition(dt))), &inverse(&predicted[i + 1].covariance));
+            let correction = [smoothed[i + 1][0] - predicted[i + 1].state[0], smoothed[i + 1][1] - predicted[i + 1].state[1]];
+            let adjustment = apply(&gain, &correction);
+            smoothed[i] = [filtered[i].state[0] + adjustment[0], filtered[i].state[1] + adjustment[1]];
+        }
+
+        smoothed.iter().map(|state| state[0]).collect()
+    }
+
+    fn predict(&self, estimate: &Estimate, dt: f64) -> Estimate {
+        let f = transition(dt);
+        let q = self.acceleration_error.powi(2);
+        let noise = [
+            [q * dt.powi(3) / 3., q * dt.powi(2) / 2.],
+            [q * dt.powi(2) / 2., q * dt],
+        ];
+        Estimate {
+            state: apply(&f, &estimate.state),
+            covariance: add(&multiply(&multiply(&f, &estimate.covariance), &transpose(&f)), &noise),
+        }
+    }
+
+    fn position_error(&self, point: &TrackPoint) -> f64 {
+        match point.quality.hdop {
+            Some(hdop) => (hdop as f64 * self.hdop_error).max(1.),
+            None if point.good_precision => self.good_precision_error,
+            None => self.poor_precision_error,
+        }
+    }
+}
+
+/// Velocity east and north in m/s, when the point says which way it was going or that it was standing still.
+fn measured_velocity(point: &TrackPoint) -> Option<Vector> {
+    let speed = point.speed_kph as f64 / 3.6;
+    match point.quality.course {
+        Some(course) => {
+            let course = (course as f64).to_radians();
+            Some([speed * course.sin(), speed * course.cos()])
+        },
+        None if point.speed_kph < STATIONARY_SPEED_KPH => Some([0., 0.]),
+        None => None,
+    }
+}
+
+/// Time between the points, where points out of order are taken as simultaneous
+fn seconds_between(a: &TrackPoint, b: &TrackPoint) -> f64 {
+    ((b.timestamp - a.timestamp).num_milliseconds() as f64 / 1000.).max(0.)
+}
+
+/// Updates the prediction with the measured position, and the measured velocity if any, given with their errors.
+fn update(prediction: &Estimate, position: f64, position_error: f64, velocity: Option<(f64, f64)>) -> Estimate {
+    let [[p00, p01], [p10, p11]] = prediction.covariance;
+    let [x, v] = prediction.state;
+
+    match velocity {
+        None => {
+            let s = p00 + position_error.powi(2);
+            let gain = [p00 / s, p10 / s];
+            let innovation = position - x;
+            Estimate {
+                state: [x + gain[0] * innovation, v + gain[1] * innovation],
+                covariance: [
+                    [(1. - gain[0]) * p00, (1. - gain[0]) * p01],
+                    [p10 - gain[1] * p00, p11 - gain[1] * p01],
+                ],
+            }
+        },
+        Some((velocity, velocity_error)) => {
+            let s = [[p00 + position_error.powi(2), p01], [p10, p11 + velocity_error.powi(2)]];
+            let gain = multiply(&prediction.covariance, &inverse(&s));
+            let correction = apply(&gain, &[position - x, velocity - v]);
+            let identity_minus_gain = [[1. - gain[0][0], -gain[0][1]], [-gain[1][0], 1. - gain[1][1]]];
+            Estimate {
+                state: [x + correction[0], v + correction[1]],
+                covariance: multiply(&identity_minus_gain, &prediction.covariance),
+            }
+        },
+    }
+}
+
+fn transition(dt: f64) -> Matrix {
+    [[1., dt], [0., 1.]]
+}
+
+fn apply(m: &Matrix, v: &Vector) -> Vector {
+    [m[0][0] * v[0] + m[0][1] * v[1], m[1][0] * v[0] + m[1][1] * v[1]]
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    [
+        [a[0][0] * b[0][0] + a[0][1] * b[1][0], a[0][0] * b[0][1] + a[0][1] * b[1][1]],
+        [a[1][0] * b[0][0] + a[1][1] * b[1][0], a[1][0] * b[0][1] + a[1][1] * b[1][1]],
+    ]
+}
+
+fn add(a: &Matrix, b: &Matrix) -> Matrix {
+    [[a[0][0] + b[0][0], a[0][1] + b[0][1]], [a[1][0] + b[1][0], a[1][1] + b[1][1]]]
+}
+
+fn transpose(m: &Matrix) -> Matrix {
+    [[m[0][0], m[1][0]], [m[0][1], m[1][1]]]
+}
+
+fn inverse(m: &Matrix) -> Matrix {
+    let determinant = m[0][0] * m[1][1] - m[0][1] * m[1][0];
+    [[m[1][1] / determinant, -m[0][1] / determinant], [-m[1][0] / determinant, m[0][0] / determinant]]
+}
+
+#[cfg(test)]
+fn track_distance(track_points: &[TrackPoint]) -> f64 {
+    track_points.windows(2).map(|w| geo::distance(w[0].position(), w[1].position())).sum()
+}
+
+#[test]
+fn stationary_jitter_test() {
+    use chrono::DateTime;
+
+    // Ten minutes parked, with fixes jumping up to 15 m around
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let mut seed: u32 = 17;
+    let mut jitter = || {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        ((seed >> 16) % 300) as f64 / 100. - 1.5
+    };
+    let track: Vec<TrackPoint> = (0..600)
+        .map(|i| TrackPoint::new(start + chrono::Duration::seconds(i), 55. + jitter() / 10_000., 10. + jitter() / 10_000., 0., 0.3, i % 3 != 0))
+        .collect();
+
+    let raw = track_distance(&track);
+    let forward = track_distance(&KalmanSmoother { backward_pass: false, ..Default::default() }.smooth(&track));
+    let smoothed = track_distance(&KalmanSmoother::default().smooth(&track));
+    assert!(raw > 3000.);
+    assert!(forward < raw / 5.);
+    assert!(smoothed < raw / 20.);
+}
+
+#[test]
+fn moving_track_test() {
+    use chrono::DateTime;
+
+    // East at 72 km/h for 5 minutes, with the course known for every other point
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let step = 20. / (111_250. * 55f64.to_radians().cos());
+    let track: Vec<TrackPoint> = (0..300)
+        .map(|i| {
+            let point = TrackPoint::new(start + chrono::Duration::seconds(i), 55., 10. + i as f64 * step, 0., 72., true);
+            let latitude_jitter = if i % 2 == 0 { 0.00003 } else { -0.00003 };
+            TrackPoint { latitude: point.latitude + latitude_jitter, ..point }.with_quality(crate::track_point::PointQuality {
+                course: (i % 2 == 0).then_some(90.),
+                ..Default::default()
+            })
+        })
+        .collect();
+
+    let smoothed = KalmanSmoother::default().smooth(&track);
+    assert_eq!(smoothed.len(), track.len());
+    assert_eq!(smoothed[10].timestamp, track[10].timestamp);
+
+    // The zigzag is gone, and the distance is what was driven
+    let distance = track_distance(&smoothed);
+    assert!((distance - 299. * 20.).abs() < 60., "{}", distance);
+    for point in &smoothed[10..290] {
+        assert!((point.latitude - 55.).abs() < 0.00001);
+    }
+    assert!(KalmanSmoother::default().smooth(&[]).is_empty());
+}
@@ -0,0 +1,131 @@
+//! Detection of stops, the places the tracker stayed at for a while, like a camp for the night or a border crossing.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{geo, local_time::UtcOffset, track_point::TrackPoint};
+
+/// A place the tracker stayed within `StopDetector::radius` of.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stop {
+    /// Centroid of the points recorded during the stop
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Time of the first point of the stop
+    pub arrival: DateTime<Utc>,
+    /// Time of the last point of the stop
+    pub departure: DateTime<Utc>,
+    pub point_count: usize,
+    /// Local offset at the stop, when known
+    pub utc_offset: Option<UtcOffset>,
+}
+
+impl Stop {
+    /// The stop made up of the points, which must not be empty.
+    pub fn from_points(track_points: &[TrackPoint]) -> Self {
+        let n = track_points.len() as f64;
+        Self {
+            latitude: track_points.iter().map(|p| p.latitude).sum::<f64>() / n,
+            longitude: track_points.iter().map(|p| p.longitude).sum::<f64>() / n,
+            arrival: track_points[0].timestamp,
+            departure: track_points[track_points.len() - 1].timestamp,
+            point_count: track_points.len(),
+            utc_offset: None,
+        }
+    }
+
+    pub fn duration(&self) -> TimeDelta {
+        self.departure - self.arrival
+    }
+
+    pub fn position(&self) -> (f64, f64) {
+        (self.latitude, self.longitude)
+    }
+}
+
+/// Finds stops as runs of points that stay within a radius of the first point of the run for long enough.
+/// Gaps in the track count as stopped when the tracker is still in the same place after them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StopDetector {
+    /// In metres
+    pub radius: f64,
+    pub min_duration: TimeDelta,
+}
+
+impl Default for StopDetector {
+    fn default() -> Self {
+        Self {
+            radius: 100.,
+            min_duration: TimeDelta::minutes(10),
+        }
+    }
+}
+
+impl StopDetector {
+    /// The stops of the track, in order.
+    pub fn detect(&self, track_points: &[TrackPoint]) -> Vec<Stop> {
+        let mut stops = Vec::new();
+
+        let mut first = 0;
+        while first < track_points.len() {
+            let start = track_points[first].position();
+            let end = track_points[first + 1..].iter()
+                .position(|p| geo::distance(start, p.position()) > self.radius)
+                .map_or(track_points.len(), |i| first + 1 + i);
+
+            if track_points[end - 1].timestamp - track_points[first].timestamp >= self.min_duration {
+                stops.push(Stop::from_points(&track_points[first..end]));
+                first = end;
+            } else {
+                first += 1;
+            }
+        }
+
+        stops
+    }
+}
+
+#[test]
+fn stop_detection_test() {
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let step = 10. / 111_250.;
+    let mut track = Vec::new();
+    let mut time = start;
+    let mut latitude = 55.;
+    let drive = |track: &mut Vec<TrackPoint>, time: &mut DateTime<Utc>, latitude: &mut f64, secs: i64, moving: bool| {
+        for i in 0..secs {
+            if moving {
+                *latitude += step;
+            }
+            let jitter = if moving || i % 2 == 0 { 0. } else { 0.0002 };
+            track.push(TrackPoint::new(*time, *latitude, 10. + jitter, 0., 36., true));
+            *time += TimeDelta::seconds(1);
+        }
+    };
+
+    drive(&mut track, &mut time, &mut latitude, 300, true);
+    // 30 minutes at a border, moving back and forth by 13 m
+    drive(&mut track, &mut time, &mut latitude, 1800, false);
+    drive(&mut track, &mut time, &mut latitude, 300, true);
+    // Too short
+    drive(&mut track, &mut time, &mut latitude, 300, false);
+    drive(&mut track, &mut time, &mut latitude, 300, true);
+    // Tracker off for the night
+    time += TimeDelta::hours(9);
+    drive(&mut track, &mut time, &mut latitude, 300, true);
+
+    let stops = StopDetector::default().detect(&track);
+    assert_eq!(stops.len(), 2);
+
+    // Arriving and leaving includes the points within the radius
+    let border = &stops[0];
+    assert!((border.arrival - track[300].timestamp).abs() <= TimeDelta::seconds(10));
+    assert!((border.departure - track[2099].timestamp).abs() <= TimeDelta::seconds(10));
+    assert!(geo::distance(border.position(), (track[300].latitude, 10.0001)) < 1.);
+
+    let night = &stops[1];
+    assert!(night.duration() > TimeDelta::hours(9) && night.duration() < TimeDelta::hours(9) + TimeDelta::minutes(1));
+
+    assert!(StopDetector::default().detect(&track[..300]).is_empty());
+    assert!(StopDetector::default().detect(&[]).is_empty());
+}
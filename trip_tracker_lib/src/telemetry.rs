@@ -0,0 +1,178 @@
+use crate::comms::CommsError;
+
+/// Longest firmware version that can be reported.
+pub const MAX_FIRMWARE_VERSION_LENGTH: usize = 32;
+
+/// Encoded length of a report without the firmware version, which follows it.
+/// The last byte is the length of the firmware version.
+pub(crate) const FIXED_LENGTH: usize = 15;
+
+const UNKNOWN: u8 = 0xFF;
+const UNKNOWN_STORAGE: u32 = 0xFFFFFFFF;
+
+/// Where the tracker draws its power from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "std", derive(serde::Serialize, serde::Deserialize))]
+pub enum PowerSource {
+    #[default]
+    Unknown = 0,
+    Battery = 1,
+    Usb = 2,
+    Solar = 3,
+}
+
+impl PowerSource {
+    pub const ALL: [Self; 4] = [Self::Unknown, Self::Battery, Self::Usb, Self::Solar];
+
+    /// Ids from newer trackers are reported as `Unknown`.
+    pub fn from_id(id: u8) -> Self {
+        Self::ALL.into_iter().find(|source| source.id() == id).unwrap_or_default()
+    }
+
+    pub fn id(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown",
+            Self::Battery => "battery",
+            Self::Usb => "usb",
+            Self::Solar => "solar",
+        }
+    }
+}
+
+/// The state of the tracker at the time it was sent. Values the tracker doesn't know are `None`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Telemetry<'a> {
+    pub battery_percent: Option<u8>,
+    pub power_source: PowerSource,
+    /// Signal strength as reported by AT+CSQ, 0-31
+    pub rssi: Option<u8>,
+    /// Bit error rate as reported by AT+CSQ, 0-7
+    pub ber: Option<u8>,
+    /// Satellites in view
+    pub satellites: Option<u8>,
+    /// Satellites used for the fix
+    pub satellites_used: Option<u8>,
+    pub free_storage_kib: Option<u32>,
+    pub uptime_secs: u32,
+    pub firmware_version: &'a str,
+}
+
+impl<'a> Telemetry<'a> {
+    pub fn encoded_length(&self) -> usize {
+        FIXED_LENGTH + self.firmware_version.len()
+    }
+
+    pub(crate) fn to_bytes(self, out: &mut [u8]) -> Result<usize, CommsError> {
+        let length = self.encoded_length();
+        if self.firmware_version.len() > MAX_FIRMWARE_VERSION_LENGTH || out.len() < length {
+            return Err(CommsError::EncodeError);
+        }
+
+        out[0] = self.battery_percent.unwrap_or(UNKNOWN);
+        out[1] = self.power_source.id();
+        out[2] = self.rssi.unwrap_or(UNKNOWN);
+        out[3] = self.ber.unwrap_or(UNKNOWN);
+        out[4] = self.satellites.unwrap_or(UNKNOWN);
+        out[5] = self.satellites_used.unwrap_or(UNKNOWN);
+        out[6..10].copy_from_slice(&self.free_storage_kib.unwrap_or(UNKNOWN_STORAGE).to_be_bytes());
+        out[10..14].copy_from_slice(&self.uptime_secs.to_be_bytes());
+        out[14] = self.firmware_version.len() as u8;
+        out[FIXED_LENGTH..length].copy_from_slice(self.firmware_version.as_bytes());
+
+        Ok(length)
+    }
+
+    /// Decodes a report, which must be exactly as long as it says.
+    pub(crate) fn from_bytes(bytes: &'a [u8]) -> Result<Self, CommsError> {
+        if bytes.len() < FIXED_LENGTH || bytes.len() != FIXED_LENGTH + bytes[FIXED_LENGTH - 1] as usize {
+            return Err(CommsError::DecodeError);
+        }
+
+        let known = |value: u8| (value != UNKNOWN).then_some(value);
+        let free_storage_kib = u32::from_be_bytes(bytes[6..10].try_into().unwrap()); // Safe unwrap
+
+        Ok(Self {
+            battery_percent: known(bytes[0]),
+            power_source: PowerSource::from_id(bytes[1]),
+            rssi: known(bytes[2]),
+            ber: known(bytes[3]),
+            satellites: known(bytes[4]),
+            satellites_used: known(bytes[5]),
+            free_storage_kib: (free_storage_kib != UNKNOWN_STORAGE).then_some(free_storage_kib),
+            uptime_secs: u32::from_be_bytes(bytes[10..14].try_into().unwrap()), // Safe unwrap
+            firmware_version: core::str::from_utf8(&bytes[FIXED_LENGTH..]).map_err(|_| CommsError::DecodeError)?,
+        })
+    }
+}
+
+/// A telemetry report as stored by the server.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TelemetryRecord {
+    pub session_id: i64,
+    /// When the server received the report
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub battery_percent: Option<u8>,
+    pub power_source: PowerSource,
+    pub rssi: Option<u8>,
+    pub ber: Option<u8>,
+    pub satellites: Option<u8>,
+    pub satellites_used: Option<u8>,
+    pub free_storage_kib: Option<u32>,
+    pub uptime_secs: u32,
+    pub firmware_version: String,
+}
+
+#[cfg(feature = "std")]
+impl TelemetryRecord {
+    pub fn new(session_id: i64, timestamp: chrono::DateTime<chrono::Utc>, telemetry: &Telemetry) -> Self {
+        Self {
+            session_id,
+            timestamp,
+            battery_percent: telemetry.battery_percent,
+            power_source: telemetry.power_source,
+            rssi: telemetry.rssi,
+            ber: telemetry.ber,
+            satellites: telemetry.satellites,
+            satellites_used: telemetry.satellites_used,
+            free_storage_kib: telemetry.free_storage_kib,
+            uptime_secs: telemetry.uptime_secs,
+            firmware_version: telemetry.firmware_version.to_string(),
+        }
+    }
+}
+
+#[test]
+fn telemetry_bytes_test() {
+    let telemetry = Telemetry {
+        battery_percent: Some(17),
+        power_source: PowerSource::Solar,
+        rssi: Some(21),
+        ber: None,
+        satellites: Some(12),
+        satellites_used: Some(9),
+        free_storage_kib: None,
+        uptime_secs: 86_400 * 3,
+        firmware_version: "0.3.1",
+    };
+
+    let mut out = [0; FIXED_LENGTH + MAX_FIRMWARE_VERSION_LENGTH];
+    let length = telemetry.to_bytes(&mut out).unwrap();
+    assert_eq!(length, telemetry.encoded_length());
+    assert_eq!(Telemetry::from_bytes(&out[..length]), Ok(telemetry));
+
+    // Wrong version lengths and invalid versions
+    assert_eq!(Telemetry::from_bytes(&out[..length - 1]), Err(CommsError::DecodeError));
+    out[FIXED_LENGTH] = 0xC0;
+    assert_eq!(Telemetry::from_bytes(&out[..length]), Err(CommsError::DecodeError));
+
+    let long_version = Telemetry { firmware_version: "0.3.1-with-a-very-long-build-suffix", ..telemetry };
+    assert_eq!(long_version.to_bytes(&mut out), Err(CommsError::EncodeError));
+
+    // Power sources from newer trackers
+    assert_eq!(PowerSource::from_id(200), PowerSource::Unknown);
+}
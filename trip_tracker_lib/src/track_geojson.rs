@@ -0,0 +1,275 @@
+//! GeoJSON of sessions and trips, for tools like QGIS and uMap.
+//!
+//! A session is a LineString, and a trip a MultiLineString with a line per session. Positions are longitude,
+//! latitude and altitude. The times of the points are in the `coordTimes` property, as written by togeojson
+//! for GPX tracks, with a list per line for MultiLineStrings.
+
+use std::fmt::Display;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use geojson::{feature::Id, Feature, Geometry, JsonObject, JsonValue, LineStringType, Value};
+use serde_json::json;
+
+use crate::{geo::BoundingBox, track_point::TrackPoint, track_session::TrackSession, trip::Trip};
+
+/// Times of the points, as RFC 3339
+const COORD_TIMES: &str = "coordTimes";
+/// Speeds of the points in km/h, which aren't part of any convention
+const SPEEDS: &str = "speeds";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeoJsonError {
+    MissingGeometry,
+    /// Anything but a LineString or MultiLineString, by its type name
+    UnsupportedGeometry(&'static str),
+    /// A position with fewer than 2 coordinates
+    InvalidPosition,
+    InvalidTime(String),
+}
+
+impl Display for GeoJsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingGeometry => write!(f, "feature has no geometry"),
+            Self::UnsupportedGeometry(name) => write!(f, "{} is not a LineString or MultiLineString", name),
+            Self::InvalidPosition => write!(f, "position has fewer than 2 coordinates"),
+            Self::InvalidTime(time) => write!(f, "invalid time: {}", time),
+        }
+    }
+}
+
+impl TrackSession {
+    /// The session as a LineString feature with the session id as its id.
+    pub fn to_geojson(&self) -> Feature {
+        let statistics = &self.statistics;
+        let mut properties = JsonObject::new();
+        properties.insert("session_id".into(), json!(self.session_id));
+        properties.insert("trip_id".into(), json!(self.trip_id));
+        properties.insert("title".into(), json!(self.title));
+        properties.insert("description".into(), json!(self.description));
+        properties.insert("start_time".into(), json!(format_time(self.start_time)));
+        properties.insert("end_time".into(), json!(statistics.end_time.map(format_time)));
+        properties.insert("active".into(), json!(self.active));
+        properties.insert("distance_km".into(), json!(self.distance()));
+        properties.insert("elevation_gain".into(), json!(statistics.elevation_gain));
+        properties.insert("elevation_loss".into(), json!(statistics.elevation_loss));
+        properties.insert(COORD_TIMES.into(), times(&self.track_points));
+        properties.insert(SPEEDS.into(), speeds(&self.track_points));
+
+        Feature {
+            bbox: statistics.bounding_box.as_ref().map(bbox),
+            geometry: Some(Geometry::new(Value::LineString(line(&self.track_points)))),
+            id: Some(Id::Number(self.session_id.into())),
+            properties: Some(properties),
+            foreign_members: None,
+        }
+    }
+
+    /// The session of a LineString or MultiLineString feature, where the lines are joined. Properties that
+    /// are missing are left empty, and points without a time get the start time, like in GPX imports.
+    pub fn from_geojson(feature: &Feature) -> Result<Self, GeoJsonError> {
+        let start_time = property_time(feature, "start_time")?.unwrap_or_default();
+        let lines = read_lines(feature, start_time)?;
+        let track_points: Vec<TrackPoint> = lines.into_iter().flatten().collect();
+
+        let session_id = feature.property("session_id").and_then(JsonValue::as_i64).or(feature_id(feature)).unwrap_or(-1);
+        let trip_id = feature.property("trip_id").and_then(JsonValue::as_i64).unwrap_or(0);
+        let start_time = match track_points.first() {
+            Some(first) if start_time == DateTime::<Utc>::default() => first.timestamp,
+            _ => start_time,
+        };
+        Ok(TrackSession::new(session_id, trip_id, property_string(feature, "title"), property_string(feature, "description"),
+            start_time, false, track_points, false))
+    }
+}
+
+impl Trip {
+    /// The trip as a MultiLineString feature with a line per session, in the order given.
+    pub fn to_geojson(&self, sessions: &[TrackSession]) -> Feature {
+        let mut properties = JsonObject::new();
+        properties.insert("trip_id".into(), json!(self.trip_id));
+        properties.insert("title".into(), json!(self.title));
+        properties.insert("description".into(), json!(self.description));
+        properties.insert("status".into(), json!(self.status.name()));
+        properties.insert("countries".into(), json!(self.country_list));
+        properties.insert("start_time".into(), json!(format_time(self.timestamp)));
+        properties.insert("distance_km".into(), json!(sessions.iter().map(|session| session.distance()).sum::<f64>()));
+        properties.insert("session_ids".into(), json!(sessions.iter().map(|session| session.session_id).collect::<Vec<_>>()));
+        properties.insert("session_titles".into(), json!(sessions.iter().map(|session| session.title.as_str()).collect::<Vec<_>>()));
+        properties.insert(COORD_TIMES.into(), JsonValue::Array(sessions.iter().map(|session| times(&session.track_points)).collect()));
+        properties.insert(SPEEDS.into(), JsonValue::Array(sessions.iter().map(|session| speeds(&session.track_points)).collect()));
+
+        let bounding_box = BoundingBox::from_points(sessions.iter().flat_map(|session| session.track_points.iter().map(|p| p.position())));
+        Feature {
+            bbox: bounding_box.as_ref().map(bbox),
+            geometry: Some(Geometry::new(Value::MultiLineString(sessions.iter().map(|session| line(&session.track_points)).collect()))),
+            id: Some(Id::Number(self.trip_id.into())),
+            properties: Some(properties),
+            foreign_members: None,
+        }
+    }
+
+    /// The trip of a LineString or MultiLineString feature, with a session per line. Sessions are titled by
+    /// `session_titles`, or after the trip. The trip has no API token.
+    pub fn from_geojson(feature: &Feature) -> Result<(Self, Vec<TrackSession>), GeoJsonError> {
+        let start_time = property_time(feature, "start_time")?;
+        let lines = read_lines(feature, start_time.unwrap_or_default())?;
+
+        let trip_id = feature.property("trip_id").and_then(JsonValue::as_i64).or(feature_id(feature)).unwrap_or(-1);
+        let title = property_string(feature, "title");
+        let first_time = lines.iter().flatten().next().map(|p| p.timestamp);
+        let mut trip = Trip::new(trip_id, title.clone(), property_string(feature, "description"),
+            start_time.or(first_time).unwrap_or_default(), String::new());
+        if let Some(countries) = feature.property("countries").and_then(JsonValue::as_array) {
+            trip.country_list = countries.iter().filter_map(|country| country.as_str().map(String::from)).collect();
+        }
+
+        let session_ids = feature.property("session_ids").and_then(JsonValue::as_array);
+        let session_titles = feature.property("session_titles").and_then(JsonValue::as_array);
+        let line_count = lines.len();
+        let sessions = lines.into_iter().enumerate()
+            .map(|(i, track_points)| {
+                let session_id = session_ids.and_then(|ids| ids.get(i)).and_then(JsonValue::as_i64).unwrap_or(-1);
+                let session_title = match session_titles.and_then(|titles| titles.get(i)).and_then(JsonValue::as_str) {
+                    Some(session_title) => session_title.to_string(),
+                    None if line_count == 1 => title.clone(),
+                    None => format!("{} ({}/{})", title, i + 1, line_count),
+                };
+                let start_time = track_points.first().map_or(trip.timestamp, |p| p.timestamp);
+                TrackSession::new(session_id, trip_id, session_title, String::new(), start_time, false, track_points, false)
+            })
+            .collect();
+
+        Ok((trip, sessions))
+    }
+}
+
+fn line(track_points: &[TrackPoint]) -> LineStringType {
+    track_points.iter().map(|p| vec![p.longitude, p.latitude, p.altitude as f64]).collect()
+}
+
+fn times(track_points: &[TrackPoint]) -> JsonValue {
+    JsonValue::Array(track_points.iter().map(|p| json!(format_time(p.timestamp))).collect())
+}
+
+fn speeds(track_points: &[TrackPoint]) -> JsonValue {
+    JsonValue::Array(track_points.iter().map(|p| json!(p.speed_kph)).collect())
+}
+
+/// West, south, east and north
+fn bbox(bounding_box: &BoundingBox) -> Vec<f64> {
+    vec![bounding_box.min_longitude, bounding_box.min_latitude, bounding_box.max_longitude, bounding_box.max_latitude]
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+fn parse_time(value: &JsonValue) -> Result<DateTime<Utc>, GeoJsonError> {
+    value.as_str()
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map(|time| time.to_utc())
+        .ok_or_else(|| GeoJsonError::InvalidTime(value.to_string()))
+}
+
+fn property_time(feature: &Feature, key: &str) -> Result<Option<DateTime<Utc>>, GeoJsonError> {
+    feature.property(key).filter(|value| !value.is_null()).map(parse_time).transpose()
+}
+
+fn property_string(feature: &Feature, key: &str) -> String {
+    feature.property(key).and_then(JsonValue::as_str).unwrap_or_default().to_string()
+}
+
+fn feature_id(feature: &Feature) -> Option<i64> {
+    match &feature.id {
+        Some(Id::Number(id)) => id.as_i64(),
+        Some(Id::String(id)) => id.parse().ok(),
+        None => None,
+    }
+}
+
+/// The points of every line of the feature, with their times and speeds taken from the properties.
+fn read_lines(feature: &Feature, default_time: DateTime<Utc>) -> Result<Vec<Vec<TrackPoint>>, GeoJsonError> {
+    let geometry = feature.geometry.as_ref().ok_or(GeoJsonError::MissingGeometry)?;
+    let times = feature.property(COORD_TIMES).and_then(JsonValue::as_array);
+    let speeds = feature.property(SPEEDS).and_then(JsonValue::as_array);
+
+    match &geometry.value {
+        Value::LineString(positions) => Ok(vec![read_line(positions, times, speeds, default_time)?]),
+        Value::MultiLineString(lines) => lines.iter().enumerate()
+            .map(|(i, positions)| {
+                let line_times = times.and_then(|times| times.get(i)).and_then(JsonValue::as_array);
+                let line_speeds = speeds.and_then(|speeds| speeds.get(i)).and_then(JsonValue::as_array);
+                read_line(positions, line_times, line_speeds, default_time)
+            })
+            .collect(),
+        other => Err(GeoJsonError::UnsupportedGeometry(other.type_name())),
+    }
+}
+
+fn read_line(positions: &LineStringType, times: Option<&Vec<JsonValue>>, speeds: Option<&Vec<JsonValue>>, default_time: DateTime<Utc>) -> Result<Vec<TrackPoint>, GeoJsonError> {
+    positions.iter().enumerate()
+        .map(|(i, position)| {
+            let [longitude, latitude, rest @ ..] = position.as_slice() else {
+                return Err(GeoJsonError::InvalidPosition);
+            };
+            let altitude = rest.first().copied().unwrap_or(0.) as f32;
+            let timestamp = match times.and_then(|times| times.get(i)) {
+                Some(time) => parse_time(time)?,
+                None => default_time,
+            };
+            let speed_kph = speeds.and_then(|speeds| speeds.get(i)).and_then(JsonValue::as_f64).unwrap_or(0.) as f32;
+            Ok(TrackPoint::new(timestamp, *latitude, *longitude, altitude, speed_kph, true))
+        })
+        .collect()
+}
+
+#[test]
+fn geojson_test() {
+    use chrono::TimeDelta;
+
+    let start = DateTime::from_timestamp(1_748_095_380, 0).unwrap();
+    let track_points: Vec<TrackPoint> = (0..5)
+        .map(|i| TrackPoint::new(start + TimeDelta::milliseconds(i * 1500), 40.1 + i as f64 * 0.001, 44.5, 950. + i as f32, 36., true))
+        .collect();
+    let session = TrackSession::new(7, 3, "Yerevan".into(), "Out of town".into(), start, false, track_points.clone(), false);
+
+    let feature = session.to_geojson();
+    assert_eq!(feature.property("coordTimes").unwrap()[1], json!("2025-05-24T14:03:01.500Z"));
+    assert_eq!(feature.bbox, Some(vec![44.5, 40.1, 44.5, 40.104]));
+
+    // Through text, as other tools would read it
+    let parsed: Feature = feature.to_string().parse().unwrap();
+    let read = TrackSession::from_geojson(&parsed).unwrap();
+    assert_eq!((read.session_id, read.trip_id, read.title.as_str(), read.start_time), (7, 3, "Yerevan", start));
+    for (point, expected) in read.track_points.iter().zip(&track_points) {
+        assert_eq!((point.timestamp, point.altitude, point.speed_kph), (expected.timestamp, expected.altitude, expected.speed_kph));
+        assert!((point.latitude - expected.latitude).abs() < 1e-12 && point.longitude == expected.longitude);
+    }
+    assert_eq!(read.track_points.len(), track_points.len());
+
+    let mut trip = Trip::new(3, "Tour de Lada".into(), String::new(), start, "secret".into());
+    trip.country_list = vec!["AM".into(), "GE".into()];
+    let second = TrackSession::new(8, 3, "Tbilisi".into(), String::new(), start, false, track_points[3..].to_vec(), false);
+    let feature = trip.to_geojson(&[session, second]);
+    let (read_trip, sessions) = Trip::from_geojson(&feature.to_string().parse().unwrap()).unwrap();
+    assert_eq!((read_trip.trip_id, read_trip.api_token.as_str()), (3, ""));
+    assert_eq!(read_trip.country_list, trip.country_list);
+    assert_eq!(sessions.iter().map(|s| (s.session_id, s.track_points.len())).collect::<Vec<_>>(), vec![(7, 5), (8, 2)]);
+    assert_eq!(sessions[1].title, "Tbilisi");
+
+    // Drawn in another tool, without times
+    let drawn: Feature = r#"{"type": "Feature", "properties": {"title": "Drawn"}, "geometry":
+        {"type": "MultiLineString", "coordinates": [[[12.5, 55.6], [12.6, 55.7]], [[12.7, 55.8]]]}}"#.parse().unwrap();
+    let session = TrackSession::from_geojson(&drawn).unwrap();
+    assert_eq!(session.track_points.len(), 3);
+    assert_eq!(session.track_points[2].position(), (55.8, 12.7));
+    let (_, sessions) = Trip::from_geojson(&drawn).unwrap();
+    assert_eq!(sessions[1].title, "Drawn (2/2)");
+
+    let point: Feature = r#"{"type": "Feature", "properties": null, "geometry": {"type": "Point", "coordinates": [1, 2]}}"#.parse().unwrap();
+    assert_eq!(TrackSession::from_geojson(&point), Err(GeoJsonError::UnsupportedGeometry("Point")));
+    let bad_time: Feature = r#"{"type": "Feature", "properties": {"coordTimes": ["yesterday"]}, "geometry":
+        {"type": "LineString", "coordinates": [[1, 2]]}}"#.parse().unwrap();
+    assert!(matches!(TrackSession::from_geojson(&bad_time), Err(GeoJsonError::InvalidTime(_))));
+}
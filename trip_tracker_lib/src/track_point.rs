@@ -3,34 +3,9 @@ use core::fmt::Display;
 use chrono::{DateTime, Utc};
 
 pub const ENCODED_LENGTH: usize = 15;
-
-// Todo, move to tsf_util?
-#[cfg(feature = "std")]
-pub fn parse_tsf(bytes: &[u8]) -> Result<(Vec<TrackPoint>, DateTime<Utc>), &'static str> {
-    let mut buffer = [0; ENCODED_LENGTH];
-    let timestamp = i64::from_be_bytes(bytes[..8].try_into().map_err(|_| "Less than 8 bytes")?);
-    let start_time = DateTime::from_timestamp(timestamp, 0).unwrap().to_utc();
-    let mut track_points = Vec::new();
-    let mut i = 8;
-    while i < bytes.len() {
-        buffer.copy_from_slice(&bytes[i..i + ENCODED_LENGTH]);
-        let tp = TrackPoint::from_bytes(&buffer, start_time);
-        track_points.push(tp);
-        i += ENCODED_LENGTH;
-    }
-    Ok((track_points, start_time))
-}
-
-// Todo, move to tsf_util?
-#[cfg(feature = "std")]
-pub fn write_tsf(start_time: DateTime<Utc>, track_points: &[TrackPoint]) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.extend_from_slice(&start_time.timestamp().to_be_bytes());
-    for tp in track_points {
-        bytes.extend_from_slice(&tp.to_bytes(start_time));
-    }
-    bytes
-}
+/// Standard record followed by the `PointQuality`
+pub const EXTENDED_ENCODED_LENGTH: usize = ENCODED_LENGTH + QUALITY_ENCODED_LENGTH;
+pub const QUALITY_ENCODED_LENGTH: usize = 7;
 
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "std", derive(serde::Serialize, serde::Deserialize))]
@@ -42,9 +17,33 @@ pub struct TrackPoint {
     pub speed_kph: f32,           // 2 bytes when compressed to u16
     /// HDOP was < 1.0, and the fix was good
     pub good_precision: bool,     // 1 bit - pack into position fields ^^^
+    /// Only kept by the extended and compressed layouts
+    pub quality: PointQuality,    // 7 bytes
 }
 // 15 bytes total, maybe 5 byte (32 bit) MAC?
 
+/// Fix details reported by the GNSS receiver. Fields are `None` when unknown,
+/// e.g. for points recorded before these were stored, or imported from GPX files without them.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "std", derive(serde::Serialize, serde::Deserialize))]
+pub struct PointQuality {
+    /// Course over ground in degrees clockwise from north. 0.1 degree precision
+    pub course: Option<f32>,
+    /// Dilution of precision. 0.1 precision, and capped at 25.4
+    pub pdop: Option<f32>,
+    pub hdop: Option<f32>,
+    pub vdop: Option<f32>,
+    /// Satellites in view
+    pub satellites: Option<u8>,
+    pub satellites_used: Option<u8>,
+}
+
+impl PointQuality {
+    pub fn is_unknown(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
 impl TrackPoint {
     pub fn new(timestamp: DateTime<Utc>, latitude: f64, longitude: f64, altitude: f32, speed_kph: f32, good_precision: bool) -> Self {
         Self {
@@ -54,8 +53,19 @@ impl TrackPoint {
             altitude,
             speed_kph,
             good_precision,
+            quality: PointQuality::default(),
         }
     }
+
+    pub fn with_quality(mut self, quality: PointQuality) -> Self {
+        self.quality = quality;
+        self
+    }
+
+    /// `(latitude, longitude)`, as taken by `geo`.
+    pub fn position(&self) -> (f64, f64) {
+        (self.latitude, self.longitude)
+    }
 }
 
 impl Display for TrackPoint {
@@ -65,33 +75,220 @@ impl Display for TrackPoint {
 }
 
 impl TrackPoint {
-    pub fn to_bytes(&self, session_start: DateTime<Utc>) -> [u8; ENCODED_LENGTH] {
+    pub fn to_bytes(&self, session_start: DateTime<Utc>, profile: QuantizationProfile) -> [u8; ENCODED_LENGTH] {
+        self.quantize(session_start, profile).to_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8], session_start: DateTime<Utc>, profile: QuantizationProfile) -> TrackPoint {
+        QuantizedPoint::from_bytes(bytes).to_track_point(session_start, profile)
+    }
+
+    /// Standard record followed by the quality
+    pub fn to_extended_bytes(&self, session_start: DateTime<Utc>, profile: QuantizationProfile) -> [u8; EXTENDED_ENCODED_LENGTH] {
+        self.quantize(session_start, profile).to_extended_bytes()
+    }
+
+    pub fn from_extended_bytes(bytes: &[u8], session_start: DateTime<Utc>, profile: QuantizationProfile) -> TrackPoint {
+        QuantizedPoint::from_extended_bytes(bytes).to_track_point(session_start, profile)
+    }
+
+    pub(crate) fn quantize(&self, session_start: DateTime<Utc>, profile: QuantizationProfile) -> QuantizedPoint {
+        let time_offset = (self.timestamp - session_start).num_milliseconds() / profile.time_resolution_ms();
+        QuantizedPoint {
+            // Only 3 bytes are stored
+            time_offset: time_offset as u32 & MAX_TIME_OFFSET,
+            latitude: encode_lat(self.latitude) & 0x7FFFFFFF,
+            longitude: encode_lon(self.longitude),
+            altitude: profile.encode_alt(self.altitude),
+            speed: profile.encode_speed(self.speed_kph),
+            good_precision: self.good_precision,
+            quality: encode_quality(&self.quality),
+        }
+    }
+}
+
+/// Ranges and resolutions used when storing track points. The profile of a file is recorded in its header.
+/// Values outside the range of the profile are clamped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "std", derive(serde::Serialize, serde::Deserialize))]
+pub enum QuantizationProfile {
+    /// -10 to 6000 m, up to 500 km/h, 1 s timestamps for up to ~194 days. The only profile before it was configurable
+    #[default]
+    Ground = 0,
+    /// -500 to 20000 m, up to 1500 km/h, 1 s timestamps for up to ~194 days
+    Aviation = 1,
+    /// Same ranges as `Ground`, but 0.1 s timestamps for up to ~19 days
+    HighRate = 2,
+}
+
+/// Largest offset from the session start that fits in the 3 byte timestamp
+const MAX_TIME_OFFSET: u32 = 0xFFFFFF;
+
+impl QuantizationProfile {
+    pub const ALL: [Self; 3] = [Self::Ground, Self::Aviation, Self::HighRate];
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|profile| profile.id() == id)
+    }
+
+    pub fn id(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|profile| profile.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ground => "ground",
+            Self::Aviation => "aviation",
+            Self::HighRate => "high-rate",
+        }
+    }
+
+    pub fn time_resolution_ms(&self) -> i64 {
+        match self {
+            Self::Ground | Self::Aviation => 1000,
+            Self::HighRate => 100,
+        }
+    }
+
+    /// The longest a session can last, measured from its start time
+    pub fn max_duration(&self) -> chrono::TimeDelta {
+        chrono::TimeDelta::milliseconds(MAX_TIME_OFFSET as i64 * self.time_resolution_ms())
+    }
+
+    fn altitude_range(&self) -> (f32, f32) {
+        match self {
+            Self::Ground | Self::HighRate => (-10., 6_000.),
+            Self::Aviation => (-500., 20_000.),
+        }
+    }
+
+    fn max_speed(&self) -> f32 {
+        match self {
+            Self::Ground | Self::HighRate => 500.,
+            Self::Aviation => 1_500.,
+        }
+    }
+
+    /// Whether the point can be stored without clamping, or losing timestamp precision.
+    pub fn fits(&self, track_point: &TrackPoint, session_start: DateTime<Utc>) -> bool {
+        let (alt_min, alt_max) = self.altitude_range();
+        let offset = (track_point.timestamp - session_start).num_milliseconds();
+
+        (alt_min..=alt_max).contains(&track_point.altitude)
+            && track_point.speed_kph <= self.max_speed()
+            && offset >= 0
+            && offset % self.time_resolution_ms() == 0
+            && offset / self.time_resolution_ms() <= MAX_TIME_OFFSET as i64
+    }
+
+    /// Picks the first profile that fits all the points, preferring `Ground`.
+    /// If none fit all of them, the one that fits the most points is used.
+    pub fn select(track_points: &[TrackPoint], session_start: DateTime<Utc>) -> Self {
+        let fitting = |profile: &Self| track_points.iter().filter(|tp| profile.fits(tp, session_start)).count();
+
+        Self::ALL.into_iter()
+            .find(|profile| fitting(profile) == track_points.len())
+            .unwrap_or_else(|| Self::ALL.into_iter().rev().max_by_key(fitting).unwrap())
+    }
+
+    fn encode_alt(&self, altitude: f32) -> u16 {
+        let (alt_min, alt_max) = self.altitude_range();
+        if altitude < alt_min {
+            return u16::MIN;
+        }
+        if altitude > alt_max {
+            return u16::MAX;
+        }
+        ((altitude - alt_min) / (alt_max - alt_min) * (u16::MAX as f32)) as u16
+    }
+
+    fn decode_alt(&self, encoded: u16) -> f32 {
+        let (alt_min, alt_max) = self.altitude_range();
+        let max_error = (alt_max - alt_min) / (u16::MAX as f32);
+        alt_min + (encoded as f32) / (u16::MAX as f32) * (alt_max - alt_min) + max_error / 2.
+    }
+
+    fn encode_speed(&self, speed: f32) -> u16 {
+        if speed > self.max_speed() {
+            return u16::MAX;
+        }
+        (speed.max(0.) / self.max_speed() * (u16::MAX as f32)) as u16
+    }
+
+    fn decode_speed(&self, encoded: u16) -> f32 {
+        let max_error = self.max_speed() / (u16::MAX as f32);
+        (encoded as f32) / (u16::MAX as f32) * self.max_speed() + max_error / 2.
+    }
+}
+
+/// The integer values a track point is stored as. Encodings that build on this are lossless
+/// relative to the standard `ENCODED_LENGTH` byte record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct QuantizedPoint {
+    /// Time since session start, in the resolution of the profile
+    pub time_offset: u32,
+    pub latitude: u32,
+    pub longitude: u32,
+    pub altitude: u16,
+    pub speed: u16,
+    pub good_precision: bool,
+    pub quality: [u8; QUALITY_ENCODED_LENGTH],
+}
+
+impl QuantizedPoint {
+    pub fn to_bytes(self) -> [u8; ENCODED_LENGTH] {
         let mut bytes = [0; ENCODED_LENGTH];
-        bytes[..3].copy_from_slice(&((self.timestamp - session_start)).num_seconds().to_be_bytes()[5..]);
-        //println!("{:?}", &((self.timestamp - session_start)).num_seconds().to_be_bytes()[5..]);
+        bytes[..3].copy_from_slice(&self.time_offset.to_be_bytes()[1..]);
         let lat_lon = encode_lat_lon_precision(self.latitude, self.longitude, self.good_precision);
         bytes[3..11].copy_from_slice(&lat_lon.to_be_bytes());
-        bytes[11..13].copy_from_slice(&encode_alt(self.altitude).to_be_bytes());
-        bytes[13..].copy_from_slice(&encode_speed(self.speed_kph).to_be_bytes());
+        bytes[11..13].copy_from_slice(&self.altitude.to_be_bytes());
+        bytes[13..].copy_from_slice(&self.speed.to_be_bytes());
         bytes
     }
 
-    pub fn from_bytes(bytes: &[u8], session_start: DateTime<Utc>) -> TrackPoint {
-        let timestamp = i64::from_be_bytes([0, 0, 0, 0, 0, bytes[0], bytes[1], bytes[2]]);
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let time_offset = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
         let lat_lon = u64::from_be_bytes(bytes[3..11].try_into().unwrap());
-        let altitude = decode_alt(u16::from_be_bytes(bytes[11..13].try_into().unwrap()));
-        let speed = decode_speed(u16::from_be_bytes(bytes[13..].try_into().unwrap()));
         let (latitude, longitude, good_precision) = decode_lat_lon_precision(lat_lon);
 
-        let datetime = session_start + chrono::Duration::seconds(timestamp);
-
-        TrackPoint {
-            timestamp: datetime,
+        Self {
+            time_offset,
             latitude,
             longitude,
-            altitude,
-            speed_kph: speed,
+            altitude: u16::from_be_bytes(bytes[11..13].try_into().unwrap()),
+            speed: u16::from_be_bytes(bytes[13..15].try_into().unwrap()),
             good_precision,
+            quality: UNKNOWN_QUALITY,
+        }
+    }
+
+    pub fn to_extended_bytes(self) -> [u8; EXTENDED_ENCODED_LENGTH] {
+        let mut bytes = [0; EXTENDED_ENCODED_LENGTH];
+        bytes[..ENCODED_LENGTH].copy_from_slice(&self.to_bytes());
+        bytes[ENCODED_LENGTH..].copy_from_slice(&self.quality);
+        bytes
+    }
+
+    pub fn from_extended_bytes(bytes: &[u8]) -> Self {
+        Self {
+            quality: bytes[ENCODED_LENGTH..EXTENDED_ENCODED_LENGTH].try_into().unwrap(),
+            ..Self::from_bytes(bytes)
+        }
+    }
+
+    pub fn to_track_point(self, session_start: DateTime<Utc>, profile: QuantizationProfile) -> TrackPoint {
+        TrackPoint {
+            timestamp: session_start + chrono::Duration::milliseconds(self.time_offset as i64 * profile.time_resolution_ms()),
+            latitude: decode_lat(self.latitude),
+            longitude: decode_lon(self.longitude),
+            altitude: profile.decode_alt(self.altitude),
+            speed_kph: profile.decode_speed(self.speed),
+            good_precision: self.good_precision,
+            quality: decode_quality(&self.quality),
         }
     }
 }
@@ -114,44 +311,38 @@ fn decode_lon(encoded: u32) -> f64 {
     (encoded as f64 - (u32::MAX as f64 / 2.0)) / (u32::MAX as f64 / 360.0)
 }
 
-// Altitude
-const ALT_MIN: f32 = -10.0;
-const ALT_MAX: f32 = 6_000.0;
-const MAX_ALT_ERROR: f32 = (ALT_MAX - ALT_MIN) / (u16::MAX as f32);
+// Quality. All ones means unknown for every field
+pub(crate) const UNKNOWN_QUALITY: [u8; QUALITY_ENCODED_LENGTH] = [u8::MAX; QUALITY_ENCODED_LENGTH];
 
-fn encode_alt(altitude: f32) -> u16 {
-    if altitude < ALT_MIN {
-        return u16::MIN;
-    }
-    if altitude > ALT_MAX {
-        return u16::MAX;
-    }
-    ((altitude - ALT_MIN) / (ALT_MAX - ALT_MIN) * (u16::MAX as f32)) as u16
-}
+fn encode_quality(quality: &PointQuality) -> [u8; QUALITY_ENCODED_LENGTH] {
+    let encode_dop = |dop: Option<f32>| dop.map_or(u8::MAX, |dop| (dop * 10. + 0.5).clamp(0., 254.) as u8);
+    let course = quality.course.map_or(u16::MAX, |course| ((course % 360. + 360.) % 360. * 10. + 0.5) as u16 % 3600);
 
-fn decode_alt(encoded: u16) -> f32 {
-    ALT_MIN + (encoded as f32) / (u16::MAX as f32) * (ALT_MAX - ALT_MIN) + MAX_ALT_ERROR / 2.
+    let mut bytes = [0; QUALITY_ENCODED_LENGTH];
+    bytes[..2].copy_from_slice(&course.to_be_bytes());
+    bytes[2] = encode_dop(quality.pdop);
+    bytes[3] = encode_dop(quality.hdop);
+    bytes[4] = encode_dop(quality.vdop);
+    bytes[5] = quality.satellites.map_or(u8::MAX, |sats| sats.min(254));
+    bytes[6] = quality.satellites_used.map_or(u8::MAX, |sats| sats.min(254));
+    bytes
 }
 
-// Speed
-const SPEED_MIN: f32 = 0.0;
-const SPEED_MAX: f32 = 500.0;
-const MAX_SPEED_ERROR: f32 = (SPEED_MAX - SPEED_MIN) / (u16::MAX as f32);
-
-fn encode_speed(speed: f32) -> u16 {
-    if speed > SPEED_MAX {
-        return u16::MAX;
+fn decode_quality(bytes: &[u8; QUALITY_ENCODED_LENGTH]) -> PointQuality {
+    let decode_dop = |dop: u8| (dop != u8::MAX).then(|| dop as f32 / 10.);
+    let course = u16::from_be_bytes([bytes[0], bytes[1]]);
+
+    PointQuality {
+        course: (course != u16::MAX).then(|| course as f32 / 10.),
+        pdop: decode_dop(bytes[2]),
+        hdop: decode_dop(bytes[3]),
+        vdop: decode_dop(bytes[4]),
+        satellites: (bytes[5] != u8::MAX).then_some(bytes[5]),
+        satellites_used: (bytes[6] != u8::MAX).then_some(bytes[6]),
     }
-    ((speed - SPEED_MIN) / (SPEED_MAX - SPEED_MIN) * (u16::MAX as f32)) as u16
-}
-
-fn decode_speed(encoded: u16) -> f32 {
-    SPEED_MIN + (encoded as f32) / (u16::MAX as f32) * (SPEED_MAX - SPEED_MIN) + MAX_SPEED_ERROR / 2.
 }
 
-fn encode_lat_lon_precision(lat: f64, lon: f64, precise: bool) -> u64 {
-    let lat = encode_lat(lat) & 0x7FFFFFFF;
-    let lon = encode_lon(lon);
+fn encode_lat_lon_precision(lat: u32, lon: u32, precise: bool) -> u64 {
     // Shift into u64, and take the first 55 bits
     let mut lat_lon = ((lat as u64) << 32) | (lon as u64);
     
@@ -163,9 +354,9 @@ fn encode_lat_lon_precision(lat: f64, lon: f64, precise: bool) -> u64 {
     lat_lon
 }
 
-fn decode_lat_lon_precision(encoded: u64) -> (f64, f64, bool) {
-    let lat = decode_lat((encoded >> 32) as u32 & 0x7FFFFFFF);
-    let lon = decode_lon(encoded as u32);
+fn decode_lat_lon_precision(encoded: u64) -> (u32, u32, bool) {
+    let lat = (encoded >> 32) as u32 & 0x7FFFFFFF;
+    let lon = encoded as u32;
     let precise = (encoded & 0x8000000000000000) != 0;
 
     (lat, lon, precise)
@@ -174,7 +365,7 @@ fn decode_lat_lon_precision(encoded: u64) -> (f64, f64, bool) {
 #[test]
 fn test() {
     let tp = TrackPoint::new(DateTime::from_timestamp_millis(1233456).unwrap().to_utc(), -90., 180., 10.0, 50.0, true);
-    let llp = encode_lat_lon_precision(tp.latitude, tp.longitude, tp.good_precision);
+    let llp = encode_lat_lon_precision(encode_lat(tp.latitude) & 0x7FFFFFFF, encode_lon(tp.longitude), tp.good_precision);
 
     println!("{llp:064b}");
 
@@ -185,23 +376,63 @@ fn test() {
 fn encode_decode_test() {
     let start_time = DateTime::from_timestamp(0, 0).unwrap().to_utc();
     let tp = TrackPoint::new(DateTime::from_timestamp(3, 0).unwrap().to_utc(), -90., 180., 10.0, 50.0, true);
-    let bytes = tp.to_bytes(start_time);
-    let tp2 = TrackPoint::from_bytes(&bytes, start_time);
+    let bytes = tp.to_bytes(start_time, QuantizationProfile::Ground);
+    let tp2 = TrackPoint::from_bytes(&bytes, start_time, QuantizationProfile::Ground);
 
     println!("{:?}", tp2);
 }
 
 #[test]
-#[cfg(feature = "std")]
-fn write_parse_test() {
+fn quantization_profile_test() {
     let start_time = DateTime::from_timestamp(0, 0).unwrap().to_utc();
-    let track_points = vec![
-        TrackPoint::new(DateTime::from_timestamp(3, 0).unwrap().to_utc(), -90., 180., 10.0, 50.0, true),
-        TrackPoint::new(DateTime::from_timestamp(4, 0).unwrap().to_utc(), -90., 180., 10.0, 50.0, true),
-        TrackPoint::new(DateTime::from_timestamp(5, 0).unwrap().to_utc(), -90., 180., 10.0, 50.0, true),
-    ];
-    let bytes = write_tsf(start_time, &track_points);
-    let (track_points2, _start_time2) = parse_tsf(&bytes).unwrap();
-
-    println!("{:?}", track_points2);
-}
\ No newline at end of file
+    let flight = TrackPoint::new(DateTime::from_timestamp(3, 0).unwrap().to_utc(), 55.5, 10.1, 11_000., 850., true);
+    let fast_fix = TrackPoint::new(DateTime::from_timestamp_millis(3_300).unwrap().to_utc(), 55.5, 10.1, 10., 50., true);
+
+    // Ground clamps the flight, aviation keeps it
+    let ground = TrackPoint::from_bytes(&flight.to_bytes(start_time, QuantizationProfile::Ground), start_time, QuantizationProfile::Ground);
+    assert!(ground.altitude < 6_001. && ground.speed_kph < 501.);
+    let aviation = TrackPoint::from_bytes(&flight.to_bytes(start_time, QuantizationProfile::Aviation), start_time, QuantizationProfile::Aviation);
+    assert!((aviation.altitude - flight.altitude).abs() < 0.5);
+    assert!((aviation.speed_kph - flight.speed_kph).abs() < 0.05);
+
+    // Sub-second timestamps need the high rate profile
+    let high_rate = TrackPoint::from_bytes(&fast_fix.to_bytes(start_time, QuantizationProfile::HighRate), start_time, QuantizationProfile::HighRate);
+    assert_eq!(high_rate.timestamp, fast_fix.timestamp);
+
+    assert_eq!(QuantizationProfile::select(&[fast_fix.clone(), fast_fix.clone()], start_time), QuantizationProfile::HighRate);
+    assert_eq!(QuantizationProfile::select(&[fast_fix, flight.clone()], start_time), QuantizationProfile::Aviation);
+    assert_eq!(QuantizationProfile::select(&[], start_time), QuantizationProfile::Ground);
+
+    for profile in QuantizationProfile::ALL {
+        assert_eq!(QuantizationProfile::from_id(profile.id()), Some(profile));
+        assert_eq!(QuantizationProfile::from_name(profile.name()), Some(profile));
+    }
+}
+
+#[test]
+fn extended_encode_decode_test() {
+    let start_time = DateTime::from_timestamp(0, 0).unwrap().to_utc();
+    let quality = PointQuality {
+        course: Some(359.96),
+        pdop: Some(1.23),
+        hdop: Some(0.8),
+        vdop: Some(40.),
+        satellites: Some(31),
+        satellites_used: None,
+    };
+    let tp = TrackPoint::new(DateTime::from_timestamp(3, 0).unwrap().to_utc(), 55.5, 10.1, 10.0, 50.0, true).with_quality(quality);
+
+    let decoded = TrackPoint::from_extended_bytes(&tp.to_extended_bytes(start_time, QuantizationProfile::Ground), start_time, QuantizationProfile::Ground);
+    assert_eq!(decoded.quality, PointQuality {
+        course: Some(0.),
+        pdop: Some(1.2),
+        hdop: Some(0.8),
+        vdop: Some(25.4),
+        satellites: Some(31),
+        satellites_used: None,
+    });
+    assert_eq!(decoded.timestamp, tp.timestamp);
+
+    // Standard records do not carry the quality
+    assert!(TrackPoint::from_bytes(&tp.to_bytes(start_time, QuantizationProfile::Ground), start_time, QuantizationProfile::Ground).quality.is_unknown());
+}
@@ -1,11 +1,11 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "sqlx")]
 use sqlx::{sqlite::SqliteRow, FromRow, Row};
-use crate::haversine_distance;
+use crate::{geo::{self, BoundingBox}, local_time::UtcOffset};
 #[cfg(feature = "sqlx")]
-use crate::track_point::parse_tsf;
+use crate::tsf::parse_tsf;
 
 use super::track_point::TrackPoint;
 
@@ -16,18 +16,24 @@ pub struct SessionUpdate {
     pub description: String,
     pub new_track_points: Vec<TrackPoint>,
     pub still_active: bool,
+    /// Of all points of the session, not just the new ones
+    pub statistics: SessionStatistics,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TrackSession {
     pub session_id: i64,
     pub trip_id: i64,
-    pub start_time: DateTime<Utc>, // TODO: Handle non-utc time zones
+    pub start_time: DateTime<Utc>,
     pub title: String,
     pub description: String,
     pub active: bool,
     pub track_points: Vec<TrackPoint>,
     pub hidden: bool,
+    /// Of the points as they are stored, so filtering or smoothing the points for display does not change them
+    pub statistics: SessionStatistics,
+    /// Local offset where the session started, when known
+    pub utc_offset: Option<UtcOffset>,
 }
 
 #[cfg(feature = "sqlx")]
@@ -37,7 +43,12 @@ impl FromRow<'_, SqliteRow> for TrackSession {
         let track_points = if track_point_bytes.is_empty() {
             Vec::new()
         } else {
-            parse_tsf(&track_point_bytes).unwrap().0
+            parse_tsf(&track_point_bytes).map_err(|e| sqlx::Error::Decode(Box::new(e)))?.0
+        };
+        // Stored with the session, as its points are stored apart from it
+        let statistics = match row.try_get::<Option<Vec<u8>>, _>(8)? {
+            Some(bytes) => bincode::deserialize(&bytes).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+            None => SessionStatistics::from_points(&track_points),
         };
 
         Ok(Self {
@@ -47,8 +58,10 @@ impl FromRow<'_, SqliteRow> for TrackSession {
             description: row.get(3),
             start_time: row.get(4),
             active: row.get(5),
+            statistics,
             track_points,
-            hidden: row.get(7)
+            hidden: row.get(7),
+            utc_offset: None,
         })
     }
 }
@@ -63,18 +76,184 @@ impl TrackSession {
             description,
             start_time: timestamp,
             active,
+            statistics: SessionStatistics::from_points(&track_points),
             track_points,
             hidden,
+            utc_offset: None,
         }
     }
 
+    /// Length of the track in km.
     pub fn distance(&self) -> f64 {
-        let mut distance = 0.;
-        for i in 1..self.track_points.len() {
-            let prev = &self.track_points[i - 1];
-            let curr = &self.track_points[i];
-            distance += haversine_distance((prev.latitude, prev.longitude), (curr.latitude, curr.longitude));
+        self.statistics.distance / 1000.
+    }
+
+    /// Replaces the points and recomputes the statistics.
+    pub fn set_track_points(&mut self, track_points: Vec<TrackPoint>) {
+        self.statistics = SessionStatistics::from_points(&track_points);
+        self.track_points = track_points;
+    }
+
+    /// Appends points, updating the statistics without going through the existing points.
+    pub fn append_track_points(&mut self, track_points: &[TrackPoint]) {
+        self.statistics.extend(track_points);
+        self.track_points.extend_from_slice(track_points);
+    }
+}
+
+/// Segments slower than this are stopped, which also keeps GNSS jitter while standing still out of the moving time
+pub const MOVING_SPEED_KPH: f64 = 1.5;
+/// Segments with longer gaps between their points are stopped, as the tracker was off or had no fix
+pub const MAX_MOVING_GAP_SECS: f64 = 300.;
+/// Altitude changes smaller than this are not counted as climbing or descending, as they are mostly noise
+pub const ELEVATION_HYSTERESIS: f32 = 5.;
+
+/// Statistics of a track, updated one point at a time as points are appended.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct SessionStatistics {
+    pub point_count: usize,
+    pub good_precision_count: usize,
+    /// Metres
+    pub distance: f64,
+    /// Metres covered while moving
+    pub moving_distance: f64,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub moving_secs: f64,
+    /// Highest speed reported by the receiver
+    pub max_speed_kph: f32,
+    /// Metres climbed
+    pub elevation_gain: f32,
+    /// Metres descended
+    pub elevation_loss: f32,
+    pub min_altitude: Option<f32>,
+    pub max_altitude: Option<f32>,
+    pub bounding_box: Option<BoundingBox>,
+    last_point: Option<TrackPoint>,
+    /// The altitude climbs and descents are measured from
+    elevation_reference: Option<f32>,
+}
+
+impl SessionStatistics {
+    pub fn from_points(track_points: &[TrackPoint]) -> Self {
+        let mut statistics = Self::default();
+        statistics.extend(track_points);
+        statistics
+    }
+
+    pub fn extend(&mut self, track_points: &[TrackPoint]) {
+        track_points.iter().for_each(|track_point| self.push(track_point));
+    }
+
+    pub fn push(&mut self, track_point: &TrackPoint) {
+        self.point_count += 1;
+        if track_point.good_precision {
+            self.good_precision_count += 1;
+        }
+
+        if let Some(last_point) = &self.last_point {
+            let distance = geo::distance(last_point.position(), track_point.position());
+            let secs = (track_point.timestamp - last_point.timestamp).num_milliseconds() as f64 / 1000.;
+            self.distance += distance;
+
+            if secs > 0. && secs <= MAX_MOVING_GAP_SECS && distance / secs * 3.6 >= MOVING_SPEED_KPH {
+                self.moving_distance += distance;
+                self.moving_secs += secs;
+            }
+        }
+
+        self.start_time = Some(self.start_time.map_or(track_point.timestamp, |start| start.min(track_point.timestamp)));
+        self.end_time = Some(self.end_time.map_or(track_point.timestamp, |end| end.max(track_point.timestamp)));
+        self.max_speed_kph = self.max_speed_kph.max(track_point.speed_kph);
+        self.min_altitude = Some(self.min_altitude.map_or(track_point.altitude, |min| min.min(track_point.altitude)));
+        self.max_altitude = Some(self.max_altitude.map_or(track_point.altitude, |max| max.max(track_point.altitude)));
+
+        match self.elevation_reference {
+            Some(reference) if track_point.altitude - reference >= ELEVATION_HYSTERESIS => {
+                self.elevation_gain += track_point.altitude - reference;
+                self.elevation_reference = Some(track_point.altitude);
+            },
+            Some(reference) if reference - track_point.altitude >= ELEVATION_HYSTERESIS => {
+                self.elevation_loss += reference - track_point.altitude;
+                self.elevation_reference = Some(track_point.altitude);
+            },
+            Some(_) => {},
+            None => self.elevation_reference = Some(track_point.altitude),
+        }
+
+        match &mut self.bounding_box {
+            Some(bounding_box) => bounding_box.extend(track_point.position()),
+            None => self.bounding_box = Some(BoundingBox::new(track_point.position())),
+        }
+
+        self.last_point = Some(track_point.clone());
+    }
+
+    /// From the first to the last point
+    pub fn total_time(&self) -> TimeDelta {
+        match (self.start_time, self.end_time) {
+            (Some(start), Some(end)) => end - start,
+            _ => TimeDelta::zero(),
         }
-        distance
     }
+
+    pub fn moving_time(&self) -> TimeDelta {
+        TimeDelta::milliseconds((self.moving_secs * 1000.) as i64)
+    }
+
+    pub fn stopped_time(&self) -> TimeDelta {
+        self.total_time() - self.moving_time()
+    }
+
+    /// Over the total time
+    pub fn average_speed_kph(&self) -> f64 {
+        speed_kph(self.distance, self.total_time().num_milliseconds() as f64 / 1000.)
+    }
+
+    /// Over the time spent moving
+    pub fn moving_average_speed_kph(&self) -> f64 {
+        speed_kph(self.moving_distance, self.moving_secs)
+    }
+}
+
+fn speed_kph(distance: f64, secs: f64) -> f64 {
+    if secs > 0. {
+        distance / secs * 3.6
+    } else {
+        0.
+    }
+}
+
+#[test]
+fn statistics_test() {
+    let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let point = |secs: i64, latitude: f64, altitude: f32| TrackPoint::new(start + TimeDelta::seconds(secs), latitude, 10., altitude, 36., secs % 20 == 0);
+
+    // 10 m/s north for 100 s, 10 minutes standing still, and 100 s more after a gap with the tracker off
+    let step = 100. / 111_250.;
+    let mut points: Vec<_> = (0..=10).map(|i| point(i * 10, 55. + i as f64 * step, 100. + i as f32)).collect();
+    points.extend((1..=60).map(|i| point(100 + i * 10, 55. + 10. * step, 110. + (i % 2) as f32 * 3.)));
+    points.extend((0..=10).map(|i| point(1300 + i * 10, 56. + i as f64 * step, 90.)));
+
+    let statistics = SessionStatistics::from_points(&points);
+    assert_eq!(statistics.point_count, points.len());
+    assert_eq!(statistics.good_precision_count, points.iter().filter(|p| p.good_precision).count());
+    assert_eq!(statistics.total_time(), TimeDelta::seconds(1400));
+    assert!((statistics.moving_secs - 200.).abs() < 1e-9);
+    assert_eq!(statistics.stopped_time(), TimeDelta::seconds(1200));
+    assert!((statistics.moving_average_speed_kph() - 36.).abs() < 0.2);
+    assert!(statistics.distance > statistics.moving_distance + 100_000.);
+    assert_eq!(statistics.max_speed_kph, 36.);
+
+    // The 3 m bobbing is below the hysteresis
+    assert_eq!(statistics.elevation_gain, 10.);
+    assert_eq!(statistics.elevation_loss, 20.);
+    assert_eq!((statistics.min_altitude, statistics.max_altitude), (Some(90.), Some(113.)));
+    assert_eq!(statistics.bounding_box.unwrap().max_latitude, points.last().unwrap().latitude);
+
+    // Appending gives the same result as computing from scratch
+    let mut incremental = SessionStatistics::from_points(&points[..7]);
+    incremental.extend(&points[7..40]);
+    incremental.extend(&points[40..]);
+    assert_eq!(incremental, statistics);
 }
\ No newline at end of file
@@ -1,5 +1,9 @@
-use chrono::{DateTime, Utc};
+use std::{fmt::Display, str::FromStr};
+
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
+
+use crate::local_time::UtcOffset;
 #[cfg(feature = "sqlx")]
 use sqlx::{prelude::*, sqlite::SqliteRow};
 
@@ -11,6 +15,120 @@ pub struct Trip {
     pub description: String,
     pub api_token: String,
     pub country_list: Vec<String>,
+    /// Offset all times of the trip are shown in. When `None`, times are shown in the local time where they were recorded
+    pub utc_offset: Option<UtcOffset>,
+    pub planned_start: Option<NaiveDate>,
+    pub planned_end: Option<NaiveDate>,
+    pub status: TripStatus,
+    pub visibility: TripVisibility,
+    /// URL or path of the image shown with the trip
+    pub cover_image: Option<String>,
+}
+
+/// Where the trip is in its lifecycle.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TripStatus {
+    /// Not started yet. The trip goes live when its first live session starts
+    #[default]
+    Planned = 0,
+    Live = 1,
+    /// Over, so no new live sessions are accepted
+    Finished = 2,
+    /// Kept, but no longer listed and no new sessions are accepted
+    Archived = 3,
+}
+
+/// Who can see the trip.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TripVisibility {
+    /// Listed, and shown to anyone
+    Public = 0,
+    /// Shown to anyone with the link, but not listed
+    Unlisted = 1,
+    /// Only seen through the CLI
+    #[default]
+    Private = 2,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseTripError;
+
+impl TripStatus {
+    pub const ALL: [Self; 4] = [Self::Planned, Self::Live, Self::Finished, Self::Archived];
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|status| status.id() == id)
+    }
+
+    pub fn id(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Planned => "planned",
+            Self::Live => "live",
+            Self::Finished => "finished",
+            Self::Archived => "archived",
+        }
+    }
+
+    pub fn accepts_live_sessions(&self) -> bool {
+        matches!(self, Self::Planned | Self::Live)
+    }
+
+    /// Whether sessions can be added, like imported GPX tracks
+    pub fn accepts_sessions(&self) -> bool {
+        *self != Self::Archived
+    }
+}
+
+impl TripVisibility {
+    pub const ALL: [Self; 3] = [Self::Public, Self::Unlisted, Self::Private];
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|visibility| visibility.id() == id)
+    }
+
+    pub fn id(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Public => "public",
+            Self::Unlisted => "unlisted",
+            Self::Private => "private",
+        }
+    }
+}
+
+impl Display for TripStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl Display for TripVisibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for TripStatus {
+    type Err = ParseTripError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter().find(|status| status.name().eq_ignore_ascii_case(s.trim())).ok_or(ParseTripError)
+    }
+}
+
+impl FromStr for TripVisibility {
+    type Err = ParseTripError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter().find(|visibility| visibility.name().eq_ignore_ascii_case(s.trim())).ok_or(ParseTripError)
+    }
 }
 
 #[cfg(feature = "sqlx")]
@@ -30,6 +148,12 @@ impl FromRow<'_, SqliteRow> for Trip {
             description: row.get(3),
             api_token: row.get(4),
             country_list,
+            utc_offset: row.get::<Option<i32>, _>(6).and_then(UtcOffset::from_minutes),
+            planned_start: row.get(7),
+            planned_end: row.get(8),
+            status: TripStatus::from_id(row.get(9)).unwrap_or_default(),
+            visibility: TripVisibility::from_id(row.get(10)).unwrap_or_default(),
+            cover_image: row.get(11),
         })
     }
 }
@@ -43,6 +167,53 @@ impl Trip {
             description,
             api_token,
             country_list: Vec::new(),
+            utc_offset: None,
+            planned_start: None,
+            planned_end: None,
+            status: TripStatus::default(),
+            visibility: TripVisibility::default(),
+            cover_image: None,
         }
     }
-}
\ No newline at end of file
+
+    /// Whether the trip is in the list of trips shown to visitors.
+    pub fn is_listed(&self) -> bool {
+        self.visibility == TripVisibility::Public && self.status != TripStatus::Archived
+    }
+
+    /// Whether visitors can see the trip when they have its id.
+    pub fn is_viewable(&self) -> bool {
+        self.visibility != TripVisibility::Private
+    }
+
+    /// The offset to show a time in, given the local offset where it was recorded, if known.
+    pub fn display_offset(&self, local: Option<UtcOffset>) -> UtcOffset {
+        self.utc_offset.or(local).unwrap_or_default()
+    }
+}
+
+#[test]
+fn trip_lifecycle_test() {
+    let mut trip = Trip::new(1, "Tour de Lada".into(), String::new(), DateTime::from_timestamp(1_748_095_380, 0).unwrap(), String::new());
+    assert_eq!((trip.status, trip.visibility), (TripStatus::Planned, TripVisibility::Private));
+    assert!(!trip.is_viewable() && !trip.is_listed());
+
+    trip.visibility = TripVisibility::Unlisted;
+    assert!(trip.is_viewable() && !trip.is_listed());
+    trip.visibility = TripVisibility::Public;
+    assert!(trip.is_listed());
+    trip.status = TripStatus::Archived;
+    assert!(trip.is_viewable() && !trip.is_listed());
+
+    assert!(TripStatus::Live.accepts_live_sessions());
+    assert!(!TripStatus::Finished.accepts_live_sessions() && TripStatus::Finished.accepts_sessions());
+    assert!(!TripStatus::Archived.accepts_sessions());
+
+    for status in TripStatus::ALL {
+        assert_eq!(TripStatus::from_id(status.id()), Some(status));
+        assert_eq!(status.to_string().parse(), Ok(status));
+    }
+    assert_eq!("Unlisted".parse(), Ok(TripVisibility::Unlisted));
+    assert_eq!("secret".parse::<TripVisibility>(), Err(ParseTripError));
+    assert_eq!(TripVisibility::from_id(7), None);
+}
@@ -0,0 +1,265 @@
+use chrono::{DateTime, Utc};
+
+use crate::track_point::{QuantizationProfile, QuantizedPoint, TrackPoint, ENCODED_LENGTH, QUALITY_ENCODED_LENGTH, UNKNOWN_QUALITY};
+
+use super::TsfError;
+
+// Compressed records store each point as the difference to the previous point, using the same
+// quantization as the standard layout, so no precision is lost compared to it.
+//
+// Every record starts with a tag byte:
+//  bit 7:    Keyframe. The tag is followed by a full standard record
+//  bit 6:    Good precision
+//  bit 5:    Quality differs from the previous point (or is known, for keyframes). The encoded
+//            `PointQuality` comes last in the record
+//  bit 0-4:  Time steps of the profile's resolution since the previous point. 31 means a varint
+//            with the time delta follows
+//
+// Delta records then contain zig-zag varints for latitude, longitude, altitude and speed.
+
+/// A keyframe is inserted at least this often, so decoding can resume from any keyframe
+pub const KEYFRAME_INTERVAL: usize = 64;
+
+/// Worst case: tag + time(4) + lat(5) + lon(5) + alt(3) + speed(3) + quality
+pub const MAX_COMPRESSED_RECORD_LENGTH: usize = 21 + QUALITY_ENCODED_LENGTH;
+
+const KEYFRAME_BIT: u8 = 0x80;
+const PRECISION_BIT: u8 = 0x40;
+const QUALITY_BIT: u8 = 0x20;
+const TIME_MASK: u8 = 0x1F;
+
+pub struct CompressedEncoder {
+    session_start: DateTime<Utc>,
+    profile: QuantizationProfile,
+    previous: Option<QuantizedPoint>,
+    since_keyframe: usize,
+}
+
+impl CompressedEncoder {
+    pub fn new(session_start: DateTime<Utc>, profile: QuantizationProfile) -> Self {
+        Self {
+            session_start,
+            profile,
+            previous: None,
+            since_keyframe: 0,
+        }
+    }
+
+    /// Encodes the point into `out`, and returns the number of bytes written.
+    pub fn encode(&mut self, track_point: &TrackPoint, out: &mut [u8; MAX_COMPRESSED_RECORD_LENGTH]) -> usize {
+        let point = track_point.quantize(self.session_start, self.profile);
+
+        let previous = match self.previous {
+            Some(previous) if self.since_keyframe < KEYFRAME_INTERVAL => previous,
+            _ => {
+                out[0] = KEYFRAME_BIT;
+                out[1..1 + ENCODED_LENGTH].copy_from_slice(&point.to_bytes());
+                let mut len = 1 + ENCODED_LENGTH;
+                if point.quality != UNKNOWN_QUALITY {
+                    out[0] |= QUALITY_BIT;
+                    out[len..len + QUALITY_ENCODED_LENGTH].copy_from_slice(&point.quality);
+                    len += QUALITY_ENCODED_LENGTH;
+                }
+                self.previous = Some(point);
+                self.since_keyframe = 1;
+                return len;
+            }
+        };
+
+        let mut len = 1;
+        let time_delta = point.time_offset as i64 - previous.time_offset as i64;
+        out[0] = if point.good_precision { PRECISION_BIT } else { 0 };
+        if (0..TIME_MASK as i64).contains(&time_delta) {
+            out[0] |= time_delta as u8;
+        } else {
+            out[0] |= TIME_MASK;
+            len += write_signed(time_delta, &mut out[len..]);
+        }
+
+        len += write_signed(point.latitude as i64 - previous.latitude as i64, &mut out[len..]);
+        len += write_signed(point.longitude as i64 - previous.longitude as i64, &mut out[len..]);
+        len += write_signed(point.altitude as i64 - previous.altitude as i64, &mut out[len..]);
+        len += write_signed(point.speed as i64 - previous.speed as i64, &mut out[len..]);
+
+        if point.quality != previous.quality {
+            out[0] |= QUALITY_BIT;
+            out[len..len + QUALITY_ENCODED_LENGTH].copy_from_slice(&point.quality);
+            len += QUALITY_ENCODED_LENGTH;
+        }
+
+        self.previous = Some(point);
+        self.since_keyframe += 1;
+        len
+    }
+}
+
+pub struct CompressedDecoder {
+    session_start: DateTime<Utc>,
+    profile: QuantizationProfile,
+    previous: Option<QuantizedPoint>,
+}
+
+impl CompressedDecoder {
+    pub fn new(session_start: DateTime<Utc>, profile: QuantizationProfile) -> Self {
+        Self {
+            session_start,
+            profile,
+            previous: None,
+        }
+    }
+
+    /// Decodes the record at the start of `bytes`, and returns the point and the number of bytes consumed.
+    /// The decoder state is left untouched if the record is not complete.
+    pub fn decode(&mut self, bytes: &[u8]) -> Result<(TrackPoint, usize), TsfError> {
+        let tag = *bytes.first().ok_or(TsfError::TruncatedRecord)?;
+
+        if tag & KEYFRAME_BIT != 0 {
+            let record = bytes.get(1..1 + ENCODED_LENGTH).ok_or(TsfError::TruncatedRecord)?;
+            let mut point = QuantizedPoint::from_bytes(record);
+            let mut len = 1 + ENCODED_LENGTH;
+            if tag & QUALITY_BIT != 0 {
+                point.quality = read_quality(bytes, &mut len)?;
+            }
+            self.previous = Some(point);
+            return Ok((point.to_track_point(self.session_start, self.profile), len));
+        }
+
+        let previous = self.previous.ok_or(TsfError::MissingKeyframe)?;
+
+        let mut len = 1;
+        let time_delta = if tag & TIME_MASK == TIME_MASK {
+            read_signed(bytes, &mut len)?
+        } else {
+            (tag & TIME_MASK) as i64
+        };
+
+        let point = QuantizedPoint {
+            time_offset: (previous.time_offset as i64 + time_delta) as u32,
+            latitude: (previous.latitude as i64 + read_signed(bytes, &mut len)?) as u32,
+            longitude: (previous.longitude as i64 + read_signed(bytes, &mut len)?) as u32,
+            altitude: (previous.altitude as i64 + read_signed(bytes, &mut len)?) as u16,
+            speed: (previous.speed as i64 + read_signed(bytes, &mut len)?) as u16,
+            good_precision: tag & PRECISION_BIT != 0,
+            quality: if tag & QUALITY_BIT != 0 { read_quality(bytes, &mut len)? } else { previous.quality },
+        };
+
+        self.previous = Some(point);
+        Ok((point.to_track_point(self.session_start, self.profile), len))
+    }
+}
+
+fn read_quality(bytes: &[u8], pos: &mut usize) -> Result<[u8; QUALITY_ENCODED_LENGTH], TsfError> {
+    let quality = bytes.get(*pos..*pos + QUALITY_ENCODED_LENGTH).ok_or(TsfError::TruncatedRecord)?;
+    *pos += QUALITY_ENCODED_LENGTH;
+    Ok(quality.try_into().unwrap())
+}
+
+fn write_signed(value: i64, out: &mut [u8]) -> usize {
+    // Zig-zag, so small negative numbers are small too
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out[len] = byte;
+            return len + 1;
+        }
+        out[len] = byte | 0x80;
+        len += 1;
+    }
+}
+
+fn read_signed(bytes: &[u8], pos: &mut usize) -> Result<i64, TsfError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*pos).ok_or(TsfError::TruncatedRecord)?;
+        *pos += 1;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+        }
+    }
+    Err(TsfError::InvalidRecord)
+}
+
+#[cfg(test)]
+use crate::track_point::{PointQuality, EXTENDED_ENCODED_LENGTH};
+
+#[cfg(test)]
+fn test_points(start_time: DateTime<Utc>) -> [TrackPoint; 200] {
+    core::array::from_fn(|i| {
+        // Quality is only known for the later points, and changes every few seconds
+        let quality = match i {
+            0..50 => PointQuality::default(),
+            _ => PointQuality {
+                course: Some((i / 4) as f32 * 1.5),
+                pdop: Some(1.1),
+                hdop: Some(0.7),
+                vdop: Some(0.9),
+                satellites: Some(24),
+                satellites_used: Some(12 + (i / 20) as u8),
+            },
+        };
+        let i = i as f64;
+        TrackPoint::new(
+            start_time + chrono::Duration::seconds(i as i64 + if i > 100. { 500 } else { 0 }),
+            55.5 + i * 0.0001,
+            10.1 - i * 0.00015,
+            40. + (i as f32 * 0.3).sin() * 5.,
+            (i * 0.7) as f32,
+            !(i as usize).is_multiple_of(3),
+        ).with_quality(quality)
+    })
+}
+
+#[test]
+fn varint_test() {
+    let mut buffer = [0; 10];
+    for value in [0, 1, -1, 63, -64, 64, 1 << 20, -(1 << 33), i64::MAX, i64::MIN] {
+        let len = write_signed(value, &mut buffer);
+        let mut pos = 0;
+        assert_eq!(read_signed(&buffer, &mut pos), Ok(value));
+        assert_eq!(pos, len);
+    }
+}
+
+#[test]
+fn compressed_roundtrip_test() {
+    let start_time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let points = test_points(start_time);
+
+    let mut encoder = CompressedEncoder::new(start_time, QuantizationProfile::Ground);
+    let mut decoder = CompressedDecoder::new(start_time, QuantizationProfile::Ground);
+    let mut buffer = [0; MAX_COMPRESSED_RECORD_LENGTH];
+    let mut total = 0;
+
+    for (i, point) in points.iter().enumerate() {
+        let len = encoder.encode(point, &mut buffer);
+        total += len;
+
+        // Incomplete records are reported, and do not affect the decoder
+        assert_eq!(decoder.decode(&buffer[..len - 1]).map(|(_, len)| len), Err(TsfError::TruncatedRecord));
+
+        let (decoded, decoded_len) = decoder.decode(&buffer[..len]).unwrap();
+        assert_eq!(decoded_len, len);
+        // Must be identical to what the extended layout would give
+        let extended = point.to_extended_bytes(start_time, QuantizationProfile::Ground);
+        assert_eq!(decoded, TrackPoint::from_extended_bytes(&extended, start_time, QuantizationProfile::Ground), "point {i}");
+    }
+
+    assert!(total * 2 < points.len() * EXTENDED_ENCODED_LENGTH, "Compressed to {total} bytes");
+}
+
+#[test]
+fn missing_keyframe_test() {
+    let start_time = DateTime::from_timestamp(0, 0).unwrap();
+    let points = test_points(start_time);
+
+    let mut encoder = CompressedEncoder::new(start_time, QuantizationProfile::Ground);
+    let mut buffer = [0; MAX_COMPRESSED_RECORD_LENGTH];
+    encoder.encode(&points[0], &mut buffer);
+    let len = encoder.encode(&points[1], &mut buffer);
+
+    assert_eq!(CompressedDecoder::new(start_time, QuantizationProfile::Ground).decode(&buffer[..len]), Err(TsfError::MissingKeyframe));
+}
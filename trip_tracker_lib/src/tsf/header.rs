@@ -0,0 +1,196 @@
+use chrono::{DateTime, Utc};
+
+use crate::track_point::{QuantizationProfile, ENCODED_LENGTH, EXTENDED_ENCODED_LENGTH};
+
+use super::TsfError;
+
+/// Magic bytes at the start of every versioned TSF file.
+/// Legacy files start with a big endian timestamp, so their first byte is always 0 for any sane date.
+pub const TSF_MAGIC: [u8; 4] = *b"TTSF";
+pub const TSF_VERSION: u8 = 1;
+
+/// Length of a versioned header: magic(4) + version(1) + layout(1) + profile(1) + flags(1) + start time(8)
+pub const HEADER_LENGTH: usize = 16;
+/// Legacy files only have the 8 byte start timestamp.
+pub const LEGACY_HEADER_LENGTH: usize = 8;
+
+/// Identifies how the records following the header are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordLayout {
+    /// Fixed size records of `ENCODED_LENGTH` bytes, as produced by `TrackPoint::to_bytes`.
+    Standard = 0,
+    /// Variable size delta records. See `CompressedEncoder`.
+    Compressed = 1,
+    /// Fixed size records of `EXTENDED_ENCODED_LENGTH` bytes, which also carry the `PointQuality`.
+    Extended = 2,
+}
+
+impl RecordLayout {
+    pub fn from_id(id: u8) -> Result<Self, TsfError> {
+        match id {
+            0 => Ok(Self::Standard),
+            1 => Ok(Self::Compressed),
+            2 => Ok(Self::Extended),
+            _ => Err(TsfError::UnknownRecordLayout(id)),
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        *self as u8
+    }
+
+    /// The size of a single record in bytes, if all records have the same size.
+    pub fn fixed_record_length(&self) -> Option<usize> {
+        match self {
+            Self::Standard => Some(ENCODED_LENGTH),
+            Self::Compressed => None,
+            Self::Extended => Some(EXTENDED_ENCODED_LENGTH),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TsfHeader {
+    /// 0 for legacy headerless files.
+    pub version: u8,
+    pub layout: RecordLayout,
+    pub profile: QuantizationProfile,
+    /// Reserved for future use. Unknown flags are preserved but ignored.
+    pub flags: u8,
+    pub start_time: DateTime<Utc>,
+}
+
+impl TsfHeader {
+    /// A header for a new file in the current version, with standard layout.
+    pub fn new(start_time: DateTime<Utc>) -> Self {
+        Self::with_layout(start_time, RecordLayout::Standard)
+    }
+
+    pub fn with_layout(start_time: DateTime<Utc>, layout: RecordLayout) -> Self {
+        Self {
+            version: TSF_VERSION,
+            layout,
+            profile: QuantizationProfile::Ground,
+            flags: 0,
+            start_time,
+        }
+    }
+
+    pub fn with_profile(mut self, profile: QuantizationProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 0
+    }
+
+    /// The number of bytes the header occupied in the file it was read from.
+    pub fn encoded_length(&self) -> usize {
+        if self.is_legacy() {
+            LEGACY_HEADER_LENGTH
+        } else {
+            HEADER_LENGTH
+        }
+    }
+
+    /// Encodes the header in the current version. Legacy headers are never written.
+    pub fn to_bytes(&self) -> [u8; HEADER_LENGTH] {
+        let mut bytes = [0; HEADER_LENGTH];
+        bytes[..4].copy_from_slice(&TSF_MAGIC);
+        bytes[4] = TSF_VERSION;
+        bytes[5] = self.layout.id();
+        bytes[6] = self.profile.id();
+        bytes[7] = self.flags;
+        bytes[8..16].copy_from_slice(&self.start_time.timestamp().to_be_bytes());
+        bytes
+    }
+
+    /// Parses the header at the start of `bytes`, falling back to the legacy format if the magic is missing.
+    /// Only the header is read, so `bytes` may be just the first `HEADER_LENGTH` bytes of a file.
+    pub fn parse(bytes: &[u8]) -> Result<Self, TsfError> {
+        if bytes.len() >= TSF_MAGIC.len() && bytes[..TSF_MAGIC.len()] == TSF_MAGIC {
+            if bytes.len() < HEADER_LENGTH {
+                return Err(TsfError::TooShort);
+            }
+
+            let version = bytes[4];
+            if version == 0 || version > TSF_VERSION {
+                return Err(TsfError::UnsupportedVersion(version));
+            }
+
+            let layout = RecordLayout::from_id(bytes[5])?;
+            let profile = QuantizationProfile::from_id(bytes[6]).ok_or(TsfError::UnknownQuantizationProfile(bytes[6]))?;
+            let flags = bytes[7];
+            let start_time = parse_timestamp(&bytes[8..16])?;
+
+            Ok(Self {
+                version,
+                layout,
+                profile,
+                flags,
+                start_time,
+            })
+        } else {
+            if bytes.len() < LEGACY_HEADER_LENGTH {
+                return Err(TsfError::TooShort);
+            }
+
+            Ok(Self {
+                version: 0,
+                layout: RecordLayout::Standard,
+                profile: QuantizationProfile::Ground,
+                flags: 0,
+                start_time: parse_timestamp(&bytes[..8])?,
+            })
+        }
+    }
+}
+
+fn parse_timestamp(bytes: &[u8]) -> Result<DateTime<Utc>, TsfError> {
+    let timestamp = i64::from_be_bytes(bytes.try_into().unwrap()); // Callers always pass 8 bytes
+    DateTime::from_timestamp(timestamp, 0).ok_or(TsfError::InvalidTimestamp(timestamp))
+}
+
+#[test]
+fn header_roundtrip_test() {
+    let header = TsfHeader::new(DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+    let bytes = header.to_bytes();
+
+    assert_eq!(TsfHeader::parse(&bytes), Ok(header));
+    assert_eq!(header.encoded_length(), HEADER_LENGTH);
+
+    let header = TsfHeader::with_layout(header.start_time, RecordLayout::Compressed).with_profile(QuantizationProfile::Aviation);
+    assert_eq!(TsfHeader::parse(&header.to_bytes()), Ok(header));
+}
+
+#[test]
+fn legacy_header_test() {
+    let bytes = 1_700_000_000i64.to_be_bytes();
+    let header = TsfHeader::parse(&bytes).unwrap();
+
+    assert!(header.is_legacy());
+    assert_eq!(header.layout, RecordLayout::Standard);
+    assert_eq!(header.encoded_length(), LEGACY_HEADER_LENGTH);
+    assert_eq!(header.start_time.timestamp(), 1_700_000_000);
+}
+
+#[test]
+fn bad_header_test() {
+    let mut bytes = TsfHeader::new(DateTime::from_timestamp(0, 0).unwrap()).to_bytes();
+    assert_eq!(TsfHeader::parse(&bytes[..10]), Err(TsfError::TooShort));
+
+    bytes[4] = TSF_VERSION + 1;
+    assert_eq!(TsfHeader::parse(&bytes), Err(TsfError::UnsupportedVersion(TSF_VERSION + 1)));
+
+    bytes[4] = TSF_VERSION;
+    bytes[5] = 200;
+    assert_eq!(TsfHeader::parse(&bytes), Err(TsfError::UnknownRecordLayout(200)));
+
+    bytes[5] = RecordLayout::Standard.id();
+    bytes[6] = 200;
+    assert_eq!(TsfHeader::parse(&bytes), Err(TsfError::UnknownQuantizationProfile(200)));
+
+    assert_eq!(TsfHeader::parse(&[0; 4]), Err(TsfError::TooShort));
+    assert!(matches!(TsfHeader::parse(&i64::MAX.to_be_bytes()), Err(TsfError::InvalidTimestamp(_))));
+}
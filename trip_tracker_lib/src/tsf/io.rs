@@ -0,0 +1,199 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use crate::track_point::TrackPoint;
+
+use super::{TsfDecoder, TsfEncoder, TsfError, TsfHeader, HEADER_LENGTH, MAX_RECORD_LENGTH};
+
+const READ_BUFFER_LENGTH: usize = 4096;
+
+#[derive(Debug)]
+pub enum TsfReadError {
+    Io(io::Error),
+    Tsf(TsfError),
+}
+
+impl std::fmt::Display for TsfReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Failed to read TSF: {e}"),
+            Self::Tsf(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for TsfReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Tsf(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for TsfReadError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<TsfError> for TsfReadError {
+    fn from(e: TsfError) -> Self {
+        Self::Tsf(e)
+    }
+}
+
+/// Reads track points from a TSF file, one buffered chunk at a time.
+///
+/// Iteration stops at the first error. A partially written final record is not an error,
+/// but is reported by `finish`.
+pub struct TsfReader<R> {
+    reader: R,
+    decoder: TsfDecoder,
+    header: TsfHeader,
+    buffer: Vec<u8>,
+    position: usize,
+    length: usize,
+    done: bool,
+}
+
+impl<R: Read> TsfReader<R> {
+    /// Reads the header, so it is available before any points are read.
+    pub fn new(mut reader: R) -> Result<Self, TsfReadError> {
+        let mut buffer = vec![0; READ_BUFFER_LENGTH];
+
+        let mut length = 0;
+        while length < HEADER_LENGTH {
+            match reader.read(&mut buffer[length..HEADER_LENGTH]) {
+                Ok(0) => break,
+                Ok(n) => length += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // Legacy headers are shorter, so the rest of the bytes read are records
+        let header = TsfHeader::parse(&buffer[..length])?;
+
+        Ok(Self {
+            reader,
+            decoder: TsfDecoder::with_header(header),
+            header,
+            buffer,
+            position: header.encoded_length(),
+            length,
+            done: false,
+        })
+    }
+
+    pub fn header(&self) -> &TsfHeader {
+        &self.header
+    }
+
+    /// The number of bytes that made up the header and complete records so far.
+    pub fn valid_length(&self) -> usize {
+        self.header.encoded_length() + self.decoder.valid_length()
+    }
+
+    /// Call when all points have been read. Returns `TruncatedRecord` if the file ended in the middle of a record.
+    pub fn finish(&self) -> Result<(), TsfError> {
+        self.decoder.finish().map(|_| ())
+    }
+}
+
+impl<R: Read> Iterator for TsfReader<R> {
+    type Item = Result<TrackPoint, TsfReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if self.position < self.length {
+                if let Some(result) = self.decoder.decode_next(&self.buffer[..self.length], &mut self.position) {
+                    self.done = result.is_err();
+                    return Some(result.map_err(TsfReadError::Tsf));
+                }
+            }
+
+            match self.reader.read(&mut self.buffer) {
+                Ok(0) => self.done = true,
+                Ok(n) => {
+                    self.position = 0;
+                    self.length = n;
+                },
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                },
+            }
+        }
+        None
+    }
+}
+
+/// Writes track points to a TSF file as they arrive.
+pub struct TsfWriter<W> {
+    writer: W,
+    encoder: TsfEncoder,
+}
+
+impl<W: Write> TsfWriter<W> {
+    /// Writes the header, for a new file.
+    pub fn new(mut writer: W, header: TsfHeader) -> io::Result<Self> {
+        writer.write_all(&header.to_bytes())?;
+        Ok(Self::append(writer, header))
+    }
+
+    /// Continues a file that already has the given header. Compressed files start over with a keyframe.
+    pub fn append(writer: W, header: TsfHeader) -> Self {
+        Self {
+            writer,
+            encoder: TsfEncoder::new(header),
+        }
+    }
+
+    pub fn header(&self) -> &TsfHeader {
+        self.encoder.header()
+    }
+
+    pub fn write_point(&mut self, track_point: &TrackPoint) -> io::Result<()> {
+        let mut record = [0; MAX_RECORD_LENGTH];
+        let length = self.encoder.encode(track_point, &mut record);
+        self.writer.write_all(&record[..length])
+    }
+
+    pub fn write_points(&mut self, track_points: &[TrackPoint]) -> io::Result<()> {
+        track_points.iter().try_for_each(|track_point| self.write_point(track_point))
+    }
+
+    /// Flushes and returns the underlying writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[test]
+fn reader_writer_test() {
+    use chrono::DateTime;
+    use super::RecordLayout;
+
+    let start_time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let points: Vec<_> = (0..1000).map(|i| TrackPoint::new(start_time + chrono::Duration::seconds(i), 55.5 + i as f64 * 0.0001, 10.1, 40., 12., true)).collect();
+
+    for layout in [RecordLayout::Standard, RecordLayout::Extended, RecordLayout::Compressed] {
+        let mut writer = TsfWriter::new(Vec::new(), TsfHeader::with_layout(start_time, layout)).unwrap();
+        writer.write_points(&points).unwrap();
+        let bytes = writer.into_inner().unwrap();
+
+        // A power cut while writing the last record
+        let truncated = &bytes[..bytes.len() - 2];
+
+        let mut reader = TsfReader::new(truncated).unwrap();
+        assert_eq!(reader.header().layout, layout);
+        let read = reader.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(read.len(), points.len() - 1);
+        assert_eq!(read.last().unwrap().timestamp, points[points.len() - 2].timestamp);
+        assert_eq!(reader.finish(), Err(TsfError::TruncatedRecord));
+        assert!(reader.valid_length() < truncated.len());
+    }
+}
@@ -0,0 +1,167 @@
+//! TSF (track session file) is the binary format used for track points on the tracker SD card,
+//! in the server buffer files and in the database.
+//!
+//! A file starts with a `TsfHeader`, followed by records in the layout given by the header.
+//! Files written before the header was introduced only contain an 8 byte start timestamp,
+//! and are still read as standard layout.
+//!
+//! `TsfDecoder` and `TsfEncoder` work on chunks without allocating, for the tracker.
+//! `TsfReader` and `TsfWriter` wrap them for `std::io`.
+
+mod header;
+mod compressed;
+mod stream;
+#[cfg(feature = "std")]
+mod io;
+
+pub use header::*;
+pub use compressed::*;
+pub use stream::*;
+#[cfg(feature = "std")]
+pub use io::*;
+
+use crate::track_point::{TrackPoint, ENCODED_LENGTH, EXTENDED_ENCODED_LENGTH};
+#[cfg(feature = "std")]
+use crate::track_point::QuantizationProfile;
+#[cfg(feature = "std")]
+use chrono::{DateTime, Utc};
+
+/// The size of the largest fixed size record
+pub const MAX_FIXED_RECORD_LENGTH: usize = EXTENDED_ENCODED_LENGTH;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TsfError {
+    /// Not enough bytes for a header.
+    TooShort,
+    UnsupportedVersion(u8),
+    UnknownRecordLayout(u8),
+    UnknownQuantizationProfile(u8),
+    InvalidTimestamp(i64),
+    /// The record ended before it was complete.
+    TruncatedRecord,
+    /// A delta record was found before any keyframe.
+    MissingKeyframe,
+    InvalidRecord,
+}
+
+impl core::fmt::Display for TsfError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::TooShort => write!(f, "Not enough bytes for a TSF header"),
+            Self::UnsupportedVersion(version) => write!(f, "Unsupported TSF version {version}"),
+            Self::UnknownRecordLayout(layout) => write!(f, "Unknown TSF record layout {layout}"),
+            Self::UnknownQuantizationProfile(profile) => write!(f, "Unknown TSF quantization profile {profile}"),
+            Self::InvalidTimestamp(timestamp) => write!(f, "Invalid TSF start timestamp {timestamp}"),
+            Self::TruncatedRecord => write!(f, "Truncated TSF record"),
+            Self::MissingKeyframe => write!(f, "TSF delta record without a preceding keyframe"),
+            Self::InvalidRecord => write!(f, "Invalid TSF record"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TsfError {}
+
+/// Encodes a point as a record of the fixed size layout of the header, and returns the number of bytes written.
+pub fn encode_fixed_record(header: &TsfHeader, track_point: &TrackPoint, out: &mut [u8; MAX_FIXED_RECORD_LENGTH]) -> Result<usize, TsfError> {
+    match header.layout {
+        RecordLayout::Standard => out[..ENCODED_LENGTH].copy_from_slice(&track_point.to_bytes(header.start_time, header.profile)),
+        RecordLayout::Extended => out[..EXTENDED_ENCODED_LENGTH].copy_from_slice(&track_point.to_extended_bytes(header.start_time, header.profile)),
+        RecordLayout::Compressed => return Err(TsfError::InvalidRecord),
+    }
+    Ok(header.layout.fixed_record_length().unwrap())
+}
+
+/// Decodes a single record of the fixed size layout of the header. `bytes` must contain at least the record.
+pub fn decode_fixed_record(header: &TsfHeader, bytes: &[u8]) -> Result<TrackPoint, TsfError> {
+    let record_length = header.layout.fixed_record_length().ok_or(TsfError::InvalidRecord)?;
+    if bytes.len() < record_length {
+        return Err(TsfError::TruncatedRecord);
+    }
+    match header.layout {
+        RecordLayout::Extended => Ok(TrackPoint::from_extended_bytes(bytes, header.start_time, header.profile)),
+        _ => Ok(TrackPoint::from_bytes(bytes, header.start_time, header.profile)),
+    }
+}
+
+/// Parses a complete TSF file, versioned or legacy.
+/// A partially written final record is skipped, as the points before it are still valid.
+#[cfg(feature = "std")]
+pub fn parse_tsf(bytes: &[u8]) -> Result<(Vec<TrackPoint>, TsfHeader), TsfError> {
+    let mut decoder = TsfDecoder::new();
+    let track_points = decoder.feed(bytes).collect::<Result<Vec<_>, _>>()?;
+    let header = *decoder.header().ok_or(TsfError::TooShort)?;
+    Ok((track_points, header))
+}
+
+/// Writes a versioned TSF file with the given record layout, and the quantization profile that fits the points best.
+#[cfg(feature = "std")]
+pub fn write_tsf(start_time: DateTime<Utc>, track_points: &[TrackPoint], layout: RecordLayout) -> Vec<u8> {
+    let profile = QuantizationProfile::select(track_points, start_time);
+    let header = TsfHeader::with_layout(start_time, layout).with_profile(profile);
+
+    // Writing to a Vec never fails
+    let mut writer = TsfWriter::new(Vec::with_capacity(HEADER_LENGTH + track_points.len() * ENCODED_LENGTH), header).unwrap();
+    writer.write_points(track_points).unwrap();
+    writer.into_inner().unwrap()
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn write_parse_test() {
+    let start_time = DateTime::from_timestamp(0, 0).unwrap().to_utc();
+    let track_points = vec![
+        TrackPoint::new(DateTime::from_timestamp(3, 0).unwrap().to_utc(), -90., 180., 10.0, 50.0, true),
+        TrackPoint::new(DateTime::from_timestamp(4, 0).unwrap().to_utc(), -90., 180., 10.0, 50.0, true)
+            .with_quality(crate::track_point::PointQuality { hdop: Some(0.7), satellites_used: Some(9), ..Default::default() }),
+        TrackPoint::new(DateTime::from_timestamp(5, 0).unwrap().to_utc(), -90., 180., 10.0, 50.0, true),
+    ];
+    for layout in [RecordLayout::Standard, RecordLayout::Compressed, RecordLayout::Extended] {
+        let bytes = write_tsf(start_time, &track_points, layout);
+        let (track_points2, header) = parse_tsf(&bytes).unwrap();
+
+        assert_eq!(header.start_time, start_time);
+        assert_eq!(header.layout, layout);
+        assert_eq!(track_points2.len(), track_points.len());
+        // Only the standard layout drops the quality
+        assert_eq!(track_points2[1].quality == track_points[1].quality, layout != RecordLayout::Standard);
+    }
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn parse_legacy_test() {
+    let start_time = DateTime::from_timestamp(1_700_000_000, 0).unwrap().to_utc();
+    let tp = TrackPoint::new(DateTime::from_timestamp(1_700_000_010, 0).unwrap().to_utc(), 55.5, 10.1, 42.0, 12.0, true);
+
+    // Headerless files as written before TSF was versioned
+    let mut bytes = start_time.timestamp().to_be_bytes().to_vec();
+    bytes.extend_from_slice(&tp.to_bytes(start_time, QuantizationProfile::Ground));
+    bytes.extend_from_slice(&tp.to_bytes(start_time, QuantizationProfile::Ground));
+
+    // Cut off in the middle of a record
+    bytes.extend_from_slice(&tp.to_bytes(start_time, QuantizationProfile::Ground)[..4]);
+
+    let (track_points, header) = parse_tsf(&bytes).unwrap();
+
+    assert!(header.is_legacy());
+    assert_eq!(header.start_time, start_time);
+    assert_eq!(track_points.len(), 2);
+    assert_eq!(track_points[0].timestamp, tp.timestamp);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn write_flight_test() {
+    let start_time = DateTime::from_timestamp(0, 0).unwrap().to_utc();
+    let track_points = vec![
+        TrackPoint::new(DateTime::from_timestamp(3, 0).unwrap().to_utc(), 55.5, 10.1, 400., 250., true),
+        TrackPoint::new(DateTime::from_timestamp(4, 0).unwrap().to_utc(), 55.5, 10.1, 10_500., 880., true),
+    ];
+
+    let (track_points2, header) = parse_tsf(&write_tsf(start_time, &track_points, RecordLayout::Compressed)).unwrap();
+
+    assert_eq!(header.profile, QuantizationProfile::Aviation);
+    assert!((track_points2[1].altitude - 10_500.).abs() < 0.5);
+    assert!((track_points2[1].speed_kph - 880.).abs() < 0.05);
+}
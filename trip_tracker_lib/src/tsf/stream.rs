@@ -0,0 +1,351 @@
+use crate::track_point::TrackPoint;
+
+use super::{decode_fixed_record, encode_fixed_record, CompressedDecoder, CompressedEncoder, RecordLayout, TsfError, TsfHeader, HEADER_LENGTH, MAX_COMPRESSED_RECORD_LENGTH, MAX_FIXED_RECORD_LENGTH};
+
+/// The size of the largest record in any layout
+pub const MAX_RECORD_LENGTH: usize = if MAX_FIXED_RECORD_LENGTH > MAX_COMPRESSED_RECORD_LENGTH { MAX_FIXED_RECORD_LENGTH } else { MAX_COMPRESSED_RECORD_LENGTH };
+
+const PENDING_LENGTH: usize = if MAX_RECORD_LENGTH > HEADER_LENGTH { MAX_RECORD_LENGTH } else { HEADER_LENGTH };
+
+enum RecordDecoder {
+    Fixed(TsfHeader),
+    Compressed(CompressedDecoder),
+}
+
+impl RecordDecoder {
+    fn new(header: TsfHeader) -> Self {
+        match header.layout {
+            RecordLayout::Compressed => Self::Compressed(CompressedDecoder::new(header.start_time, header.profile)),
+            _ => Self::Fixed(header),
+        }
+    }
+
+    fn decode(&mut self, bytes: &[u8]) -> Result<(TrackPoint, usize), TsfError> {
+        match self {
+            Self::Fixed(header) => Ok((decode_fixed_record(header, bytes)?, header.layout.fixed_record_length().unwrap())),
+            Self::Compressed(decoder) => decoder.decode(bytes),
+        }
+    }
+}
+
+/// Decodes a TSF file that arrives in chunks of any size, without needing the whole file in memory.
+///
+/// Bytes of a header or record that is split between chunks are kept until the next chunk arrives.
+/// Once the input ends, `finish` tells whether it ended with a partially written record,
+/// which happens if the writer lost power. All points before it are still returned.
+pub struct TsfDecoder {
+    header: Option<TsfHeader>,
+    records: Option<RecordDecoder>,
+    pending: [u8; PENDING_LENGTH],
+    pending_length: usize,
+    /// Bytes of the input that were part of the header or a complete record
+    valid_length: usize,
+    failed: bool,
+}
+
+impl Default for TsfDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TsfDecoder {
+    /// A decoder for a file from the start, header included.
+    pub fn new() -> Self {
+        Self {
+            header: None,
+            records: None,
+            pending: [0; PENDING_LENGTH],
+            pending_length: 0,
+            valid_length: 0,
+            failed: false,
+        }
+    }
+
+    /// A decoder for records only, e.g. after seeking past the header and some records of a fixed size layout.
+    pub fn with_header(header: TsfHeader) -> Self {
+        Self {
+            header: Some(header),
+            records: Some(RecordDecoder::new(header)),
+            ..Self::new()
+        }
+    }
+
+    /// The header, once enough bytes have been fed to parse it.
+    pub fn header(&self) -> Option<&TsfHeader> {
+        self.header.as_ref()
+    }
+
+    /// The number of bytes that made up the header and complete records so far.
+    /// A file can be truncated to this length to drop a partially written record.
+    pub fn valid_length(&self) -> usize {
+        self.valid_length
+    }
+
+    /// Bytes held back because they do not yet form a complete header or record.
+    pub fn pending_length(&self) -> usize {
+        self.pending_length
+    }
+
+    /// Returns an iterator over the points that can be decoded with this chunk.
+    /// The iterator must be run to completion before the next chunk is fed.
+    pub fn feed<'a>(&'a mut self, chunk: &'a [u8]) -> TsfChunkIter<'a> {
+        TsfChunkIter {
+            decoder: self,
+            chunk,
+            position: 0,
+        }
+    }
+
+    /// Call when the input has ended. Returns the header if everything was decoded,
+    /// or `TruncatedRecord` if the input ended in the middle of a record.
+    pub fn finish(&self) -> Result<TsfHeader, TsfError> {
+        let header = self.header.ok_or(TsfError::TooShort)?;
+        if self.pending_length > 0 {
+            return Err(TsfError::TruncatedRecord);
+        }
+        Ok(header)
+    }
+
+    /// Decodes the next point from `chunk`, starting at `position`, and advances `position` past the consumed bytes.
+    /// Returns `None` when the rest of the chunk has been consumed into the pending bytes.
+    pub fn decode_next(&mut self, chunk: &[u8], position: &mut usize) -> Option<Result<TrackPoint, TsfError>> {
+        if self.failed {
+            return None;
+        }
+
+        if self.records.is_none() {
+            if let Err(e) = self.decode_header(chunk, position)? {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+
+        let records = self.records.as_mut().unwrap();
+        let remaining = &chunk[*position..];
+
+        // Nothing pending, so decode directly from the chunk
+        if self.pending_length == 0 {
+            return match records.decode(remaining) {
+                Ok((track_point, length)) => {
+                    *position += length;
+                    self.valid_length += length;
+                    Some(Ok(track_point))
+                },
+                Err(TsfError::TruncatedRecord) => {
+                    self.pending[..remaining.len()].copy_from_slice(remaining);
+                    self.pending_length = remaining.len();
+                    *position = chunk.len();
+                    None
+                },
+                Err(e) => {
+                    self.failed = true;
+                    Some(Err(e))
+                },
+            };
+        }
+
+        // Complete the pending record with the start of the chunk
+        let copied = remaining.len().min(PENDING_LENGTH - self.pending_length);
+        self.pending[self.pending_length..self.pending_length + copied].copy_from_slice(&remaining[..copied]);
+
+        match records.decode(&self.pending[..self.pending_length + copied]) {
+            Ok((track_point, length)) => {
+                *position += length - self.pending_length;
+                self.valid_length += length;
+                self.pending_length = 0;
+                Some(Ok(track_point))
+            },
+            Err(TsfError::TruncatedRecord) if copied == remaining.len() => {
+                self.pending_length += copied;
+                *position = chunk.len();
+                None
+            },
+            Err(TsfError::TruncatedRecord) => {
+                // No record is longer than the pending buffer
+                self.failed = true;
+                Some(Err(TsfError::InvalidRecord))
+            },
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            },
+        }
+    }
+
+    /// Collects header bytes until the header can be parsed.
+    fn decode_header(&mut self, chunk: &[u8], position: &mut usize) -> Option<Result<(), TsfError>> {
+        let remaining = &chunk[*position..];
+        let copied = remaining.len().min(HEADER_LENGTH - self.pending_length);
+        self.pending[self.pending_length..self.pending_length + copied].copy_from_slice(&remaining[..copied]);
+        let available = self.pending_length + copied;
+
+        let header = match TsfHeader::parse(&self.pending[..available]) {
+            Ok(header) => header,
+            Err(TsfError::TooShort) => {
+                self.pending_length = available;
+                *position = chunk.len();
+                return None;
+            },
+            Err(e) => return Some(Err(e)),
+        };
+
+        // Legacy headers are shorter, so some of the copied bytes may belong to the first record.
+        // Those are handed back to the chunk
+        let header_length = header.encoded_length();
+        *position += header_length - self.pending_length;
+        self.pending_length = 0;
+        self.valid_length += header_length;
+        self.header = Some(header);
+        self.records = Some(RecordDecoder::new(header));
+        Some(Ok(()))
+    }
+}
+
+/// Iterator over the points decoded from a single chunk. See `TsfDecoder::feed`.
+pub struct TsfChunkIter<'a> {
+    decoder: &'a mut TsfDecoder,
+    chunk: &'a [u8],
+    position: usize,
+}
+
+impl Iterator for TsfChunkIter<'_> {
+    type Item = Result<TrackPoint, TsfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.chunk.len() {
+            return None;
+        }
+        self.decoder.decode_next(self.chunk, &mut self.position)
+    }
+}
+
+/// Encodes points one at a time in the layout of the header. The header itself is written with `TsfHeader::to_bytes`.
+pub struct TsfEncoder {
+    header: TsfHeader,
+    compressed: Option<CompressedEncoder>,
+}
+
+impl TsfEncoder {
+    pub fn new(header: TsfHeader) -> Self {
+        Self {
+            header,
+            compressed: match header.layout {
+                RecordLayout::Compressed => Some(CompressedEncoder::new(header.start_time, header.profile)),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn header(&self) -> &TsfHeader {
+        &self.header
+    }
+
+    /// Encodes the point into `out`, and returns the number of bytes written.
+    pub fn encode(&mut self, track_point: &TrackPoint, out: &mut [u8; MAX_RECORD_LENGTH]) -> usize {
+        match &mut self.compressed {
+            Some(encoder) => encoder.encode(track_point, (&mut out[..MAX_COMPRESSED_RECORD_LENGTH]).try_into().unwrap()),
+            None => encode_fixed_record(&self.header, track_point, (&mut out[..MAX_FIXED_RECORD_LENGTH]).try_into().unwrap()).unwrap(), // Never fails for fixed size layouts
+        }
+    }
+}
+
+#[cfg(test)]
+use chrono::DateTime;
+
+#[cfg(test)]
+fn encode_test_file(layout: RecordLayout) -> ([u8; 1024], usize, [TrackPoint; 20]) {
+    let start_time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let points = core::array::from_fn(|i| TrackPoint::new(start_time + chrono::Duration::seconds(i as i64 * 2), 55.5 + i as f64 * 0.001, 10.1, 40., 12., true));
+
+    let header = TsfHeader::with_layout(start_time, layout);
+    let mut file = [0; 1024];
+    file[..HEADER_LENGTH].copy_from_slice(&header.to_bytes());
+    let mut length = HEADER_LENGTH;
+
+    let mut encoder = TsfEncoder::new(header);
+    let mut record = [0; MAX_RECORD_LENGTH];
+    for point in points.iter() {
+        let len = encoder.encode(point, &mut record);
+        file[length..length + len].copy_from_slice(&record[..len]);
+        length += len;
+    }
+
+    (file, length, points)
+}
+
+#[test]
+fn chunked_decode_test() {
+    for layout in [RecordLayout::Standard, RecordLayout::Extended, RecordLayout::Compressed] {
+        let (file, length, points) = encode_test_file(layout);
+
+        // Any chunk size must give the same result
+        for chunk_size in [1, 2, 7, 16, 64, 1024] {
+            let mut decoder = TsfDecoder::new();
+            let mut decoded = 0;
+            for chunk in file[..length].chunks(chunk_size) {
+                for point in decoder.feed(chunk) {
+                    assert_eq!(point.unwrap().timestamp, points[decoded].timestamp, "{layout:?}, chunk size {chunk_size}");
+                    decoded += 1;
+                }
+            }
+
+            assert_eq!(decoded, points.len());
+            assert_eq!(decoder.finish().map(|header| header.layout), Ok(layout));
+            assert_eq!(decoder.valid_length(), length);
+        }
+    }
+}
+
+#[test]
+fn truncated_decode_test() {
+    for layout in [RecordLayout::Standard, RecordLayout::Extended, RecordLayout::Compressed] {
+        let (file, length, points) = encode_test_file(layout);
+
+        // The last record is only partially written
+        let mut decoder = TsfDecoder::new();
+        let decoded = decoder.feed(&file[..length - 3]).count();
+
+        assert_eq!(decoded, points.len() - 1);
+        assert_eq!(decoder.finish(), Err(TsfError::TruncatedRecord));
+        assert_eq!(decoder.valid_length() + decoder.pending_length(), length - 3);
+    }
+
+    // Not even a complete header
+    let (file, _, _) = encode_test_file(RecordLayout::Standard);
+    let mut decoder = TsfDecoder::new();
+    assert_eq!(decoder.feed(&file[..HEADER_LENGTH - 1]).count(), 0);
+    assert_eq!(decoder.finish(), Err(TsfError::TooShort));
+}
+
+#[test]
+fn legacy_decode_test() {
+    let start_time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    let point = TrackPoint::new(start_time + chrono::Duration::seconds(5), 55.5, 10.1, 40., 12., true);
+
+    let mut file = [0; 8 + 2 * crate::track_point::ENCODED_LENGTH];
+    file[..8].copy_from_slice(&start_time.timestamp().to_be_bytes());
+    file[8..23].copy_from_slice(&point.to_bytes(start_time, Default::default()));
+    file[23..].copy_from_slice(&point.to_bytes(start_time, Default::default()));
+
+    // The first chunk holds more than the legacy header
+    let mut decoder = TsfDecoder::new();
+    let mut decoded = decoder.feed(&file[..12]).count();
+    decoded += decoder.feed(&file[12..]).count();
+
+    assert_eq!(decoded, 2);
+    assert!(decoder.finish().unwrap().is_legacy());
+}
+
+#[test]
+fn corrupt_decode_test() {
+    let (mut file, length, _) = encode_test_file(RecordLayout::Compressed);
+
+    // Turn the first keyframe into a delta record
+    file[HEADER_LENGTH] = 0;
+
+    let mut decoder = TsfDecoder::new();
+    let mut points = decoder.feed(&file[..length]);
+    assert_eq!(points.next(), Some(Err(TsfError::MissingKeyframe)));
+    assert_eq!(points.next(), None);
+}